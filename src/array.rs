@@ -0,0 +1,46 @@
+use std::convert::TryFrom;
+
+use ndarray::Array3;
+
+use error::{
+    DecodeError,
+    DecodeErrorKind,
+    DecodeResult,
+};
+use image::{
+    Image,
+    ImageData,
+};
+
+// An image as a height x width x samples array in standard (row major) layout.
+// The decoder interleaves planar images, so `array[[y, x, sample]]` is the same
+// sample whatever the file's PlanarConfiguration; `permuted_axes([2, 0, 1])`
+// gives a samples x height x width view of it. The element type must match the
+// image's `ImageData` variant, masks are `u8`.
+macro_rules! array_from_image {
+    ($ty:ty, $name:expr, $($variant:ident)|+) => {
+        impl TryFrom<Image> for Array3<$ty> {
+            type Error = DecodeError;
+
+            fn try_from(image: Image) -> DecodeResult<Array3<$ty>> {
+                let shape = (image.header().height() as usize, image.header().width() as usize, image.header().samples_per_pixel());
+                let data = match image.into_data() {
+                    $(ImageData::$variant(data))|+ => data,
+                    data => return Err(DecodeError::from(DecodeErrorKind::SampleTypeMismatch { requested: $name, actual: data.sample_type() })),
+                };
+                let len = data.len();
+
+                Array3::from_shape_vec(shape, data).map_err(|_| {
+                    DecodeError::from(DecodeErrorKind::IncorrectBufferSize { calc: shape.0 * shape.1 * shape.2, sum: len })
+                })
+            }
+        }
+    }
+}
+
+array_from_image!(u8, "u8", U8 | Mask);
+array_from_image!(u16, "u16", U16);
+array_from_image!(i16, "i16", I16);
+array_from_image!(i32, "i32", I32);
+array_from_image!(f32, "f32", F32);
+array_from_image!(f64, "f64", F64);
@@ -0,0 +1,625 @@
+// Decoding from tokio's `AsyncRead + AsyncSeek`, for files behind a network
+// stream or in object storage.
+//
+// The synchronous `Decoder` does the work over a cache of the parts of the file
+// fetched so far. Bytes it asks for that haven't been fetched read as zeros and
+// are noted; when a call needed any, they are fetched and the call is run again.
+// A call takes as many rounds as it has levels of indirection (the IFD, then the
+// values it points at, then the strips they list), and each round fetches what
+// it found missing together.
+
+use std::future::Future;
+use std::io::{
+    self,
+    Read,
+    Seek,
+    SeekFrom,
+};
+use std::marker::PhantomData;
+use std::mem;
+use std::pin::Pin;
+use std::sync::{
+    Arc,
+    Mutex,
+    MutexGuard,
+};
+use std::task::{
+    Context,
+    Poll,
+};
+
+use tokio::io::{
+    AsyncRead,
+    AsyncSeek,
+    ReadBuf,
+};
+
+use byte::Endian;
+use decode::{
+    self,
+    DecoderBuilder,
+};
+use error::{
+    DecodeError,
+    DecodeResult,
+    DecodeWarning,
+};
+use gps::Gps;
+use header::FileHeader;
+use ifd::{
+    IFD,
+    Entry,
+    AnyValues,
+};
+use range::{
+    self,
+    Chunks,
+    READ_AHEAD,
+};
+use image::{
+    Image,
+    ImageHeader,
+    PhotometricInterpretation,
+    Compression,
+    Resolution,
+    Sample,
+};
+use tag::TagType;
+
+// The fetched parts of the file and what the last call found missing.
+#[derive(Debug, Default)]
+struct Cache {
+    chunks: Chunks,
+    missing: Vec<(u64, u64)>,
+}
+
+impl Cache {
+    // The missing ranges merged, and extended as `range::fetch_len` says without
+    // running into a fetched chunk or each other.
+    fn plan(&mut self, len: u64) -> Vec<(u64, u64)> {
+        let mut missing = mem::take(&mut self.missing);
+        missing.sort();
+        let mut merged: Vec<(u64, u64)> = vec![];
+        for (start, count) in missing {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(start + count),
+                _ => merged.push((start, start + count)),
+            }
+        }
+
+        let mut ranges = vec![];
+        for (i, &(start, end)) in merged.iter().enumerate() {
+            let limit = self.chunks.gap_end(start, merged.get(i + 1).map(|x| x.0).unwrap_or(len));
+            ranges.push((start, range::fetch_len(start, end - start, limit)));
+        }
+
+        ranges
+    }
+}
+
+fn lock(cache: &Mutex<Cache>) -> MutexGuard<'_, Cache> {
+    cache.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// The file as the synchronous decoder sees it.
+#[derive(Debug)]
+struct Ranges {
+    cache: Arc<Mutex<Cache>>,
+    pos: u64,
+    len: u64,
+}
+
+impl Read for Ranges {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let mut cache = lock(&self.cache);
+        let n = match cache.chunks.get(self.pos) {
+            Some(bytes) => {
+                let n = bytes.len().min(buf.len());
+                buf[..n].copy_from_slice(&bytes[..n]);
+                n
+            }
+            None => {
+                let end = cache.chunks.gap_end(self.pos, self.len);
+                let n = (end - self.pos).min(buf.len() as u64) as usize;
+                buf[..n].iter_mut().for_each(|x| *x = 0);
+                cache.missing.push((self.pos, n as u64));
+                n
+            }
+        };
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl Seek for Ranges {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = range::seek_to(pos, self.pos, self.len)?;
+        Ok(self.pos)
+    }
+}
+
+#[derive(Debug)]
+enum FetchState {
+    Seek,
+    Seeking,
+    Reading(Vec<u8>, usize),
+}
+
+// Reads ranges of the file into the cache, one after another.
+#[derive(Debug)]
+struct Fetch {
+    ranges: Vec<(u64, u64)>,
+    state: FetchState,
+}
+
+impl Fetch {
+    fn new(mut ranges: Vec<(u64, u64)>) -> Fetch {
+        ranges.reverse();
+        Fetch {
+            ranges,
+            state: FetchState::Seek,
+        }
+    }
+
+    fn poll<R>(&mut self, reader: &mut R, cache: &Mutex<Cache>, cx: &mut Context) -> Poll<io::Result<()>>
+        where R: AsyncRead + AsyncSeek + Unpin
+    {
+        loop {
+            let (start, count) = match self.ranges.last() {
+                Some(&range) => range,
+                None => return Poll::Ready(Ok(())),
+            };
+            match self.state {
+                FetchState::Seek => {
+                    Pin::new(&mut *reader).start_seek(SeekFrom::Start(start))?;
+                    self.state = FetchState::Seeking;
+                }
+                FetchState::Seeking => {
+                    match Pin::new(&mut *reader).poll_complete(cx) {
+                        Poll::Ready(Ok(_)) => self.state = FetchState::Reading(vec![0; count as usize], 0),
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                FetchState::Reading(ref mut buf, ref mut filled) if *filled < buf.len() => {
+                    let mut read_buf = ReadBuf::new(&mut buf[*filled..]);
+                    match Pin::new(&mut *reader).poll_read(cx, &mut read_buf) {
+                        Poll::Ready(Ok(())) if read_buf.filled().is_empty() => {
+                            // the file was shorter than when it was opened
+                            return Poll::Ready(Err(io::Error::from(io::ErrorKind::UnexpectedEof)));
+                        }
+                        Poll::Ready(Ok(())) => *filled += read_buf.filled().len(),
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                FetchState::Reading(ref mut buf, _) => {
+                    lock(cache).chunks.insert(start, mem::take(buf));
+                    self.ranges.pop();
+                    self.state = FetchState::Seek;
+                }
+            }
+        }
+    }
+}
+
+pub struct Decoder<R> {
+    reader: R,
+    inner: decode::Decoder<Ranges>,
+    cache: Arc<Mutex<Cache>>,
+    len: u64,
+    warnings: Vec<DecodeWarning>,
+}
+
+// The futures' state borrows nothing from itself.
+struct Run<'a, R: 'a, T, F> {
+    decoder: &'a mut Decoder<R>,
+    run: F,
+    fetch: Option<Fetch>,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<'a, R, T, F> Unpin for Run<'a, R, T, F> {}
+
+impl<'a, R, T, F> Future for Run<'a, R, T, F>
+    where R: AsyncRead + AsyncSeek + Unpin,
+          F: FnMut(&mut decode::Decoder<Ranges>) -> DecodeResult<T>
+{
+    type Output = DecodeResult<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<DecodeResult<T>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(ref mut fetch) = this.fetch {
+                match fetch.poll(&mut this.decoder.reader, &this.decoder.cache, cx) {
+                    Poll::Ready(Ok(())) => {},
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(DecodeError::from(e))),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            this.fetch = None;
+
+            let result = (this.run)(&mut this.decoder.inner);
+            // warnings about the zeros standing in for missing bytes are dropped
+            let warnings = this.decoder.inner.take_warnings();
+            let len = this.decoder.len;
+            let mut cache = lock(&this.decoder.cache);
+            if cache.missing.is_empty() {
+                for warning in warnings {
+                    if !this.decoder.warnings.contains(&warning) {
+                        this.decoder.warnings.push(warning);
+                    }
+                }
+                return Poll::Ready(result);
+            }
+            this.fetch = Some(Fetch::new(cache.plan(len)));
+        }
+    }
+}
+
+#[derive(Debug)]
+enum OpenState {
+    Seek,
+    Seeking,
+    Fetching(Fetch, u64),
+    Done,
+}
+
+struct Open<R> {
+    reader: Option<R>,
+    builder: DecoderBuilder,
+    cache: Arc<Mutex<Cache>>,
+    state: OpenState,
+}
+
+impl<R> Unpin for Open<R> {}
+
+impl<R> Future for Open<R> where R: AsyncRead + AsyncSeek + Unpin {
+    type Output = DecodeResult<Decoder<R>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<DecodeResult<Decoder<R>>> {
+        let this = self.get_mut();
+        loop {
+            let reader = match this.reader {
+                Some(ref mut reader) => reader,
+                None => panic!("`Open` polled after completion"),
+            };
+            match this.state {
+                OpenState::Seek => {
+                    Pin::new(&mut *reader).start_seek(SeekFrom::End(0))?;
+                    this.state = OpenState::Seeking;
+                }
+                OpenState::Seeking => {
+                    match Pin::new(&mut *reader).poll_complete(cx) {
+                        Poll::Ready(Ok(len)) => {
+                            let fetch = Fetch::new(if len > 0 { vec![(0, len.min(READ_AHEAD))] } else { vec![] });
+                            this.state = OpenState::Fetching(fetch, len);
+                        }
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(DecodeError::from(e))),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                OpenState::Fetching(ref mut fetch, len) => {
+                    match fetch.poll(reader, &this.cache, cx) {
+                        Poll::Ready(Ok(())) => {},
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(DecodeError::from(e))),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                    this.state = OpenState::Done;
+                    // the file header lies within the first fetch
+                    let ranges = Ranges {
+                        cache: this.cache.clone(),
+                        pos: 0,
+                        len,
+                    };
+                    let builder = mem::replace(&mut this.builder, DecoderBuilder::new());
+                    let inner = builder.build(ranges)?;
+                    let decoder = Decoder {
+                        reader: this.reader.take().unwrap(),
+                        inner,
+                        cache: this.cache.clone(),
+                        len,
+                        warnings: vec![],
+                    };
+
+                    return Poll::Ready(Ok(decoder));
+                }
+                OpenState::Done => panic!("`Open` polled after completion"),
+            }
+        }
+    }
+}
+
+impl DecoderBuilder {
+    pub fn build_async<R>(self, reader: R) -> impl Future<Output = DecodeResult<Decoder<R>>>
+        where R: AsyncRead + AsyncSeek + Unpin
+    {
+        Open {
+            reader: Some(reader),
+            builder: self,
+            cache: Arc::new(Mutex::new(Cache::default())),
+            state: OpenState::Seek,
+        }
+    }
+}
+
+// Each method does what the one of `rustiff::Decoder` with the same name does.
+impl<R> Decoder<R> where R: AsyncRead + AsyncSeek + Unpin {
+    pub fn new(reader: R) -> impl Future<Output = DecodeResult<Decoder<R>>> {
+        DecoderBuilder::new().build_async(reader)
+    }
+
+    fn run<'a, T, F>(&'a mut self, run: F) -> Run<'a, R, T, F>
+        where F: FnMut(&mut decode::Decoder<Ranges>) -> DecodeResult<T>
+    {
+        Run {
+            decoder: self,
+            run,
+            fetch: None,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn endian(&self) -> Endian {
+        self.inner.endian()
+    }
+
+    pub fn file_header(&self) -> FileHeader {
+        self.inner.file_header()
+    }
+
+    pub fn is_lenient(&self) -> bool {
+        self.inner.is_lenient()
+    }
+
+    pub fn warnings(&self) -> &[DecodeWarning] {
+        &self.warnings
+    }
+
+    pub fn current_ifd_index(&self) -> Option<usize> {
+        self.inner.current_ifd_index()
+    }
+
+    pub fn current_ifd_offset(&self) -> u64 {
+        self.inner.current_ifd_offset()
+    }
+
+    pub fn ifd_count<'a>(&'a mut self) -> impl Future<Output = DecodeResult<usize>> + 'a {
+        self.run(|d| d.ifd_count())
+    }
+
+    pub fn ifd_offsets<'a>(&'a mut self) -> impl Future<Output = DecodeResult<Vec<u64>>> + 'a {
+        self.run(|d| d.ifd_offsets())
+    }
+
+    pub fn ifd<'a>(&'a mut self) -> impl Future<Output = DecodeResult<IFD>> + 'a {
+        self.run(|d| d.ifd())
+    }
+
+    pub fn change_ifd<'a>(&'a mut self, index: usize) -> impl Future<Output = DecodeResult<()>> + 'a {
+        self.run(move |d| d.change_ifd(index))
+    }
+
+    pub fn load_ifd_at<'a>(&'a mut self, offset: u64) -> impl Future<Output = DecodeResult<()>> + 'a {
+        self.run(move |d| d.load_ifd_at(offset))
+    }
+
+    pub fn sub_ifds<'a>(&'a mut self) -> impl Future<Output = DecodeResult<Vec<u64>>> + 'a {
+        self.run(|d| d.sub_ifds())
+    }
+
+    pub fn load_sub_ifd<'a>(&'a mut self, index: usize) -> impl Future<Output = DecodeResult<()>> + 'a {
+        self.run(move |d| d.load_sub_ifd(index))
+    }
+
+    pub fn exif<'a>(&'a mut self) -> impl Future<Output = DecodeResult<Option<IFD>>> + 'a {
+        self.run(|d| d.exif())
+    }
+
+    pub fn gps<'a>(&'a mut self) -> impl Future<Output = DecodeResult<Option<Gps>>> + 'a {
+        self.run(|d| d.gps())
+    }
+
+    pub fn resolution<'a>(&'a mut self) -> impl Future<Output = DecodeResult<Option<Resolution>>> + 'a {
+        self.run(|d| d.resolution())
+    }
+
+    pub fn icc_profile<'a>(&'a mut self) -> impl Future<Output = DecodeResult<Option<Vec<u8>>>> + 'a {
+        self.run(|d| d.icc_profile())
+    }
+
+    pub fn xmp<'a>(&'a mut self) -> impl Future<Output = DecodeResult<Option<Vec<u8>>>> + 'a {
+        self.run(|d| d.xmp())
+    }
+
+    pub fn dimensions<'a>(&'a mut self) -> impl Future<Output = DecodeResult<(u32, u32)>> + 'a {
+        self.run(|d| d.dimensions())
+    }
+
+    pub fn compression<'a>(&'a mut self) -> impl Future<Output = DecodeResult<Compression>> + 'a {
+        self.run(|d| d.compression())
+    }
+
+    pub fn photometric<'a>(&'a mut self) -> impl Future<Output = DecodeResult<PhotometricInterpretation>> + 'a {
+        self.run(|d| d.photometric())
+    }
+
+    pub fn get_value<'a, T>(&'a mut self, ifd: &IFD, tag: T) -> impl Future<Output = DecodeResult<T::Value>> + 'a
+        where T: TagType + 'a
+    {
+        let ifd = ifd.clone();
+        self.run(move |d| d.get_value(&ifd, tag))
+    }
+
+    pub fn get_optional_value<'a, T>(&'a mut self, ifd: &IFD, tag: T) -> impl Future<Output = DecodeResult<Option<T::Value>>> + 'a
+        where T: TagType + 'a
+    {
+        let ifd = ifd.clone();
+        self.run(move |d| d.get_optional_value(&ifd, tag))
+    }
+
+    pub fn get_any_values<'a>(&'a mut self, entry: &Entry) -> impl Future<Output = DecodeResult<AnyValues>> + 'a {
+        let entry = entry.clone();
+        self.run(move |d| d.get_any_values(&entry))
+    }
+
+    pub fn header<'a>(&'a mut self) -> impl Future<Output = DecodeResult<ImageHeader>> + 'a {
+        self.run(|d| d.header())
+    }
+
+    pub fn image<'a>(&'a mut self) -> impl Future<Output = DecodeResult<Image>> + 'a {
+        self.run(|d| d.image())
+    }
+
+    // Only the strips, or rows of tiles, that the region's rows fall in are fetched.
+    pub fn read_region<'a>(&'a mut self, x: u32, y: u32, width: u32, height: u32) -> impl Future<Output = DecodeResult<Image>> + 'a {
+        self.run(move |d| d.read_region(x, y, width, height))
+    }
+
+    pub fn read_image_as<'a, T: Sample + 'a>(&'a mut self) -> impl Future<Output = DecodeResult<Vec<T>>> + 'a {
+        self.run(|d| d.read_image_as())
+    }
+
+    pub fn image_buffer_size<'a>(&'a mut self) -> impl Future<Output = DecodeResult<usize>> + 'a {
+        self.run(|d| d.image_buffer_size())
+    }
+
+    pub fn read_image_into<'a>(&'a mut self, buffer: &'a mut [u8]) -> impl Future<Output = DecodeResult<ImageHeader>> + 'a {
+        self.run(move |d| d.read_image_into(buffer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decode;
+    use std::io::Cursor;
+    use std::task::Waker;
+    use testutil::{
+        Page,
+        TiffBuilder,
+    };
+    use tokio::runtime::{
+        Builder,
+        Runtime,
+    };
+
+    // An in-memory file that is pending every other time it is polled, and
+    // counts the seeks it is asked for.
+    struct Slow {
+        file: Cursor<Vec<u8>>,
+        ready: bool,
+        seeks: usize,
+    }
+
+    impl Slow {
+        fn new(file: Vec<u8>) -> Slow {
+            Slow { file: Cursor::new(file), ready: false, seeks: 0 }
+        }
+
+        fn ready(&mut self, cx: &mut Context) -> bool {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+            }
+            self.ready
+        }
+    }
+
+    impl AsyncRead for Slow {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut ReadBuf) -> Poll<io::Result<()>> {
+            let this = self.get_mut();
+            if !this.ready(cx) {
+                return Poll::Pending;
+            }
+            Pin::new(&mut this.file).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncSeek for Slow {
+        fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+            let this = self.get_mut();
+            this.seeks += 1;
+            Pin::new(&mut this.file).start_seek(position)
+        }
+
+        fn poll_complete(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<u64>> {
+            let this = self.get_mut();
+            if !this.ready(cx) {
+                return Poll::Pending;
+            }
+            Pin::new(&mut this.file).poll_complete(cx)
+        }
+    }
+
+    fn runtime() -> Runtime {
+        Builder::new_current_thread().build().unwrap()
+    }
+
+    // Two pages whose strips come first, so that the second IFD and the strips
+    // of both lie past the first `READ_AHEAD` bytes.
+    fn file() -> Vec<u8> {
+        let first = (0..400 * 300).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+        let second = (0..40 * 30).map(|x| (x % 13) as u8).collect::<Vec<_>>();
+        TiffBuilder::new(Endian::Little)
+            .page(Page::gray8(400, 300, first))
+            .page(Page::gray8(40, 30, second))
+            .build()
+    }
+
+    #[test]
+    fn reads_like_the_synchronous_decoder() {
+        let file = file();
+        let mut sync = decode::Decoder::new(Cursor::new(file.clone())).unwrap();
+        let runtime = runtime();
+        let mut decoder = runtime.block_on(Decoder::new(Slow::new(file))).unwrap();
+
+        assert_eq!(runtime.block_on(decoder.ifd_count()).unwrap(), 2);
+        assert_eq!(runtime.block_on(decoder.dimensions()).unwrap(), (400, 300));
+        assert_eq!(runtime.block_on(decoder.image()).unwrap().data(), sync.image().unwrap().data());
+        let region = runtime.block_on(decoder.read_region(390, 100, 10, 5)).unwrap();
+        assert_eq!(region.data(), sync.read_region(390, 100, 10, 5).unwrap().data());
+
+        runtime.block_on(decoder.change_ifd(1)).unwrap();
+        sync.change_ifd(1).unwrap();
+        assert_eq!(runtime.block_on(decoder.dimensions()).unwrap(), (40, 30));
+        assert_eq!(runtime.block_on(decoder.image()).unwrap().data(), sync.image().unwrap().data());
+        assert!(decoder.warnings().is_empty());
+
+        // everything has been fetched, so reading again takes no seeks
+        let seeks = decoder.reader.seeks;
+        runtime.block_on(decoder.change_ifd(0)).unwrap();
+        runtime.block_on(decoder.image()).unwrap();
+        assert_eq!(decoder.reader.seeks, seeks);
+    }
+
+    #[test]
+    fn open_errors() {
+        let runtime = runtime();
+        // the same errors as the synchronous decoder gives for the same bytes
+        for file in &[vec![], b"not a TIFF file".to_vec(), file()[..6].to_vec()] {
+            let result = runtime.block_on(Decoder::new(Slow::new(file.clone())));
+            let expected = decode::Decoder::new(Cursor::new(file.clone())).err().unwrap();
+            assert_eq!(result.err().unwrap().to_string(), expected.to_string());
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "`Open` polled after completion")]
+    fn open_polled_after_completion() {
+        let mut open = DecoderBuilder::new().build_async(Cursor::new(file()));
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut open = Pin::new(&mut open);
+        loop {
+            if let Poll::Ready(result) = open.as_mut().poll(&mut cx) {
+                assert!(result.is_ok());
+                break;
+            }
+        }
+        let _ = open.as_mut().poll(&mut cx);
+    }
+}
@@ -0,0 +1,87 @@
+extern crate clap;
+extern crate rustiff;
+
+use clap::{
+    App,
+    Arg,
+};
+use rustiff::{
+    tag,
+    validate,
+    DecodeResult,
+    Decoder,
+    DecoderBuilder,
+};
+use std::fs::File;
+use std::io::{
+    self,
+    Read,
+    Seek,
+    Write,
+};
+use std::process;
+
+fn main() {
+    let matches = App::new("tiffdump")
+        .about("Prints the header and IFDs of a TIFF file and checks it against baseline TIFF 6.0")
+        .arg(Arg::with_name("FILE").required(true))
+        .arg(Arg::with_name("strips").long("strips").help("Also print the strip or tile layout of each page"))
+        .get_matches();
+    let path = matches.value_of("FILE").unwrap();
+
+    // 0: valid, 1: validator warnings, 2: validator errors or no TIFF at all
+    let code = match dump(path, matches.is_present("strips")) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            2
+        }
+    };
+    process::exit(code);
+}
+
+fn dump(path: &str, strips: bool) -> Result<i32, Box<dyn std::error::Error>> {
+    let mut decoder = DecoderBuilder::new().lenient(true).build(File::open(path)?).map_err(|e| e.to_string())?;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    decoder.dump(&mut out).map_err(|e| e.to_string())?;
+    if strips {
+        for (page, offset) in decoder.ifd_offsets().map_err(|e| e.to_string())?.into_iter().enumerate() {
+            if let Err(e) = chunks(&mut decoder, &mut out, page, offset) {
+                writeln!(out, "IFD {} at {}: {}", page, offset, e)?;
+            }
+        }
+    }
+
+    let report = validate::validate(File::open(path)?);
+    write!(out, "{}", report)?;
+    if !report.is_valid() {
+        Ok(2)
+    } else if !report.warnings().is_empty() {
+        Ok(1)
+    } else {
+        Ok(0)
+    }
+}
+
+// Prints the offset and byte count of each strip, or each tile with the tile
+// size for tiled pages.
+fn chunks<R: Read + Seek, W: Write>(decoder: &mut Decoder<R>, out: &mut W, page: usize, offset: u64) -> DecodeResult<()> {
+    decoder.load_ifd_at(offset)?;
+    let ifd = decoder.ifd()?;
+    let (name, offsets, counts) = if ifd.get(tag::TileOffsets).is_some() {
+        let width = decoder.get_value(&ifd, tag::TileWidth)?;
+        let length = decoder.get_value(&ifd, tag::TileLength)?;
+        writeln!(out, "IFD {} tiles, {}x{}:", page, width, length)?;
+        ("tile", decoder.get_value(&ifd, tag::TileOffsets)?, decoder.get_value(&ifd, tag::TileByteCounts)?)
+    } else {
+        writeln!(out, "IFD {} strips:", page)?;
+        ("strip", decoder.get_value(&ifd, tag::StripOffsets)?, decoder.get_value(&ifd, tag::StripByteCounts)?)
+    };
+    writeln!(out, "  {:>7} {:>10} {:>10}", name, "offset", "bytes")?;
+    for (i, (offset, count)) in offsets.iter().zip(counts.iter()).enumerate() {
+        writeln!(out, "  {:>7} {:>10} {:>10}", i, offset, count)?;
+    }
+
+    Ok(())
+}
@@ -7,23 +7,37 @@ use byteorder::{
     LittleEndian,
     BigEndian,
     ReadBytesExt,
+    WriteBytesExt,
 };
 
 use std::{
+    collections::HashMap,
     io::{
         self,
         Read,
         Seek,
+        Write,
         Cursor,
     },
 };
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Endian {
     Big,
     Little,
 }
 
+impl Endian {
+    // The byte order of the machine the code runs on.
+    pub fn native() -> Endian {
+        if cfg!(target_endian = "big") {
+            Endian::Big
+        } else {
+            Endian::Little
+        }
+    }
+}
+
 pub trait EndianReadExt: Read {
     fn read_u8(&mut self) -> io::Result<u8> {
         <Self as ReadBytesExt>::read_u8(self)
@@ -42,20 +56,58 @@ pub trait EndianReadExt: Read {
             Endian::Little => <Self as ReadBytesExt>::read_u32::<LittleEndian>(self),
         }
     }
+
+    fn read_u64(&mut self, byte_order: Endian) -> io::Result<u64> {
+        match byte_order {
+            Endian::Big => <Self as ReadBytesExt>::read_u64::<BigEndian>(self),
+            Endian::Little => <Self as ReadBytesExt>::read_u64::<LittleEndian>(self),
+        }
+    }
 }
 
 impl<R: Read> EndianReadExt for R {}
 
+pub trait EndianWriteExt: Write {
+    fn write_u16(&mut self, n: u16, byte_order: Endian) -> io::Result<()> {
+        match byte_order {
+            Endian::Big => <Self as WriteBytesExt>::write_u16::<BigEndian>(self, n),
+            Endian::Little => <Self as WriteBytesExt>::write_u16::<LittleEndian>(self, n),
+        }
+    }
+
+    fn write_u32(&mut self, n: u32, byte_order: Endian) -> io::Result<()> {
+        match byte_order {
+            Endian::Big => <Self as WriteBytesExt>::write_u32::<BigEndian>(self, n),
+            Endian::Little => <Self as WriteBytesExt>::write_u32::<LittleEndian>(self, n),
+        }
+    }
+
+    fn write_u64(&mut self, n: u64, byte_order: Endian) -> io::Result<()> {
+        match byte_order {
+            Endian::Big => <Self as WriteBytesExt>::write_u64::<BigEndian>(self, n),
+            Endian::Little => <Self as WriteBytesExt>::write_u64::<LittleEndian>(self, n),
+        }
+    }
+}
+
+impl<W: Write> EndianWriteExt for W {}
+
 pub trait ReadExt: Read {
     fn read_2byte(&mut self) -> io::Result<[u8; 2]> {
         let mut val = [0u8; 2];
-        let _ = self.read_exact(&mut val)?;
+        self.read_exact(&mut val)?;
         Ok(val)
     }
 
     fn read_4byte(&mut self) -> io::Result<[u8; 4]> {
         let mut val = [0u8; 4];
-        let _ = self.read_exact(&mut val)?;
+        self.read_exact(&mut val)?;
+        Ok(val)
+    }
+
+    fn read_bytes(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        let mut val = vec![0u8; n];
+        self.read_exact(&mut val)?;
         Ok(val)
     }
 }
@@ -72,21 +124,180 @@ pub trait SeekExt: Seek {
 
 impl<S: Seek> SeekExt for S {}
 
+pub trait AlignExt: Write + Seek {
+    // values, strips and IFDs must start on a word boundary; pad with one zero byte
+    // when the current position is odd and return the aligned position.
+    fn align_to_word(&mut self) -> io::Result<u64> {
+        let position = self.stream_position()?;
+        if position % 2 == 0 {
+            return Ok(position);
+        }
+
+        self.write_all(&[0])?;
+        Ok(position + 1)
+    }
+}
+
+impl<W: Write + Seek> AlignExt for W {}
+
+// Offset of the next word boundary at or after `offset`, for laying out data
+// before it is written.
+pub fn word_aligned(offset: u64) -> u64 {
+    offset + offset % 2
+}
+
 #[derive(Debug)]
 pub struct LZWReader(Cursor<Vec<u8>>);
 
+pub fn lzw_decompress(compressed: &[u8]) -> io::Result<Vec<u8>> {
+    lzw_decompress_bounded(compressed, usize::MAX, usize::MAX).map(|(uncompressed, _)| uncompressed)
+}
+
+// Keeps the first `keep` bytes of the decompressed data and counts the rest,
+// stopping once there are more than `max` in all.
+pub fn lzw_decompress_bounded(compressed: &[u8], keep: usize, max: usize) -> io::Result<(Vec<u8>, usize)> {
+    let mut uncompressed = vec![];
+    let mut surplus = 0usize;
+    let mut decoder = ::lzw::DecoderEarlyChange::new(::lzw::MsbReader::new(), 8);
+    let mut read = 0;
+    while read < compressed.len() && uncompressed.len().saturating_add(surplus) <= max {
+        let (len, bytes) = decoder.decode_bytes(&compressed[read..])?;
+        if len == 0 {
+            break;
+        }
+        read += len;
+        let kept = bytes.len().min(keep - uncompressed.len());
+        uncompressed.extend_from_slice(&bytes[..kept]);
+        surplus = surplus.saturating_add(bytes.len() - kept);
+    }
+
+    Ok((uncompressed, surplus))
+}
+
+const LZW_CLEAR: u16 = 256;
+const LZW_EOI: u16 = 257;
+const LZW_FIRST: u16 = 258;
+const LZW_MAX: u16 = 4094;
+
+// TIFF flavoured LZW: MSB-first codes starting at 9 bits, widened one code early
+// to match what readers (and `lzw_decompress`) expect.
+pub fn lzw_compress(uncompressed: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter::new();
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut width = 9;
+    let mut next = LZW_FIRST;
+    let mut prefix: Option<u16> = None;
+
+    writer.write(LZW_CLEAR, width);
+    for &byte in uncompressed {
+        let code = match prefix {
+            None => {
+                prefix = Some(byte as u16);
+                continue;
+            }
+            Some(code) => code,
+        };
+        if let Some(&found) = table.get(&(code, byte)) {
+            prefix = Some(found);
+            continue;
+        }
+
+        writer.write(code, width);
+        table.insert((code, byte), next);
+        next += 1;
+        if next == LZW_MAX {
+            writer.write(LZW_CLEAR, width);
+            table.clear();
+            width = 9;
+            next = LZW_FIRST;
+        } else if next > (1 << width) - 1 {
+            width += 1;
+        }
+        prefix = Some(byte as u16);
+    }
+
+    if let Some(code) = prefix {
+        writer.write(code, width);
+        // the reader adds a table entry for this code too, which can widen the EOI
+        next += 1;
+        if next < LZW_MAX && next > (1 << width) - 1 {
+            width += 1;
+        }
+    }
+    writer.write(LZW_EOI, width);
+
+    writer.finish()
+}
+
+// PackBits as in Apple's MacPaint: runs of two or more bytes become a header of
+// 1 - n and the byte, anything else a header of n - 1 and n literal bytes, with
+// n up to 128 either way. A literal stretch only stops for a run of three, which
+// is where coding it as a run starts to pay.
+pub fn packbits_compress(uncompressed: &[u8], out: &mut Vec<u8>) {
+    let repeats = |i: usize, n: usize| i + n <= uncompressed.len() && uncompressed[i..i + n].iter().all(|x| *x == uncompressed[i]);
+
+    let mut i = 0;
+    while i < uncompressed.len() {
+        let mut run = 1;
+        while run < 128 && i + run < uncompressed.len() && uncompressed[i + run] == uncompressed[i] {
+            run += 1;
+        }
+        if run >= 2 {
+            out.push((1 - run as i16) as u8);
+            out.push(uncompressed[i]);
+            i += run;
+            continue;
+        }
+
+        let start = i;
+        i += 1;
+        while i < uncompressed.len() && i - start < 128 && !repeats(i, 3) {
+            i += 1;
+        }
+        out.push((i - start - 1) as u8);
+        out.extend_from_slice(&uncompressed[start..i]);
+    }
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    bits: u8,
+}
+
+impl BitWriter {
+    fn new() -> BitWriter {
+        BitWriter {
+            bytes: vec![],
+            buffer: 0,
+            bits: 0,
+        }
+    }
+
+    fn write(&mut self, code: u16, width: u8) {
+        self.buffer = (self.buffer << width) | code as u32;
+        self.bits += width;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.buffer >> self.bits) as u8);
+        }
+        self.buffer &= (1 << self.bits) - 1;
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.bytes.push((self.buffer << (8 - self.bits)) as u8);
+        }
+
+        self.bytes
+    }
+}
+
 impl LZWReader {
     pub fn new<R>(reader: &mut R, compressed_len: usize) -> io::Result<(LZWReader, usize)> where R: Read {
-        let mut compressed = vec![0; compressed_len as usize];
+        let mut compressed = vec![0; compressed_len];
         reader.read_exact(&mut compressed)?;
-        let mut uncompressed = vec![];
-        let mut decoder = ::lzw::DecoderEarlyChange::new(::lzw::MsbReader::new(), 8);
-        let mut read = 0;
-        while read < compressed_len {
-            let (len, bytes) = decoder.decode_bytes(&compressed[read..])?;
-            read += len;
-            uncompressed.extend_from_slice(bytes);
-        }
+        let uncompressed = lzw_decompress(&compressed)?;
 
         let bytes = uncompressed.len();
         let reader = LZWReader(io::Cursor::new(uncompressed));
@@ -101,3 +312,88 @@ impl Read for LZWReader {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // bytes that rarely repeat, so nearly every byte costs a code
+    fn noise(len: usize) -> Vec<u8> {
+        let mut x = 0x2545_f491u32;
+        (0..len).map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            x as u8
+        }).collect()
+    }
+
+    // The widths of the codes in `compressed`, and how many CLEARs it holds,
+    // following the same rules as `lzw_compress`.
+    fn code_widths(compressed: &[u8]) -> (Vec<u8>, usize) {
+        let bit = |i: usize| compressed.get(i / 8).map_or(0, |x| x >> (7 - i % 8) & 1) as u16;
+        let (mut widths, mut clears) = (vec![], 0);
+        let (mut at, mut width, mut next) = (0, 9, LZW_FIRST);
+        loop {
+            let code = (at..at + width as usize).fold(0, |code, i| code << 1 | bit(i));
+            at += width as usize;
+            widths.push(width);
+            match code {
+                LZW_CLEAR => {
+                    clears += 1;
+                    width = 9;
+                    next = LZW_FIRST;
+                }
+                LZW_EOI => return (widths, clears),
+                _ => {
+                    next += 1;
+                    if next < LZW_MAX && next > (1 << width) - 1 {
+                        width += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lzw_round_trips_through_every_width() {
+        let data = noise(10_000);
+        let compressed = lzw_compress(&data);
+        let (widths, clears) = code_widths(&compressed);
+        for width in 9..13 {
+            assert!(widths.contains(&width), "no {}-bit codes", width);
+        }
+        // the first CLEAR, and one each time the table reaches LZW_MAX
+        assert!(clears >= 3, "{} clears", clears);
+        assert_eq!(lzw_decompress(&compressed).unwrap(), data);
+    }
+
+    #[test]
+    fn lzw_round_trips_at_the_width_steps() {
+        // lengths of `noise` around those whose EOI is the first code of a new
+        // width (254, 767, 1812 and, after the reset, 4197), and the one whose
+        // table fills up just before its last code (3943)
+        let data = noise(4_400);
+        let (mut widened_at_eoi, mut reset_at_end) = (0, 0);
+        for &end in &[254, 767, 1812, 3943, 4197] {
+            for len in end - 8..end + 8 {
+                let compressed = lzw_compress(&data[..len]);
+                let (widths, clears) = code_widths(&compressed);
+                if widths[widths.len() - 1] != widths[widths.len() - 2] {
+                    widened_at_eoi += 1;
+                }
+                // ..., 12-bit CLEAR, the last code and EOI at 9 bits
+                if clears == 2 && widths[widths.len() - 3..] == [12, 9, 9] {
+                    reset_at_end += 1;
+                }
+                assert_eq!(lzw_decompress(&compressed).unwrap(), &data[..len], "{} bytes", len);
+            }
+        }
+        assert_eq!(widened_at_eoi, 4);
+        assert_eq!(reset_at_end, 1);
+        for len in 0..4 {
+            assert_eq!(lzw_decompress(&lzw_compress(&data[..len])).unwrap(), &data[..len]);
+        }
+        assert_eq!(lzw_decompress(&lzw_compress(&[7; 100_000])).unwrap(), vec![7; 100_000]);
+    }
+}
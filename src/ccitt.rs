@@ -0,0 +1,673 @@
+
+// CCITT bilevel coding as used by fax TIFFs: Modified Huffman (2), T.4 (3) and
+// T.6 (4). A line is kept as its changing elements, the positions where the
+// colour flips, starting from white. Black pixels decode to 1 bits.
+
+use std::collections::HashMap;
+
+pub const EOL: &str = "000000000001";
+
+pub const WHITE_CODES: &[(u16, &str)] = &[
+    (0, "00110101"), (1, "000111"), (2, "0111"), (3, "1000"),
+    (4, "1011"), (5, "1100"), (6, "1110"), (7, "1111"),
+    (8, "10011"), (9, "10100"), (10, "00111"), (11, "01000"),
+    (12, "001000"), (13, "000011"), (14, "110100"), (15, "110101"),
+    (16, "101010"), (17, "101011"), (18, "0100111"), (19, "0001100"),
+    (20, "0001000"), (21, "0010111"), (22, "0000011"), (23, "0000100"),
+    (24, "0101000"), (25, "0101011"), (26, "0010011"), (27, "0100100"),
+    (28, "0011000"), (29, "00000010"), (30, "00000011"), (31, "00011010"),
+    (32, "00011011"), (33, "00010010"), (34, "00010011"), (35, "00010100"),
+    (36, "00010101"), (37, "00010110"), (38, "00010111"), (39, "00101000"),
+    (40, "00101001"), (41, "00101010"), (42, "00101011"), (43, "00101100"),
+    (44, "00101101"), (45, "00000100"), (46, "00000101"), (47, "00001010"),
+    (48, "00001011"), (49, "01010010"), (50, "01010011"), (51, "01010100"),
+    (52, "01010101"), (53, "00100100"), (54, "00100101"), (55, "01011000"),
+    (56, "01011001"), (57, "01011010"), (58, "01011011"), (59, "01001010"),
+    (60, "01001011"), (61, "00110010"), (62, "00110011"), (63, "00110100"),
+    (64, "11011"), (128, "10010"), (192, "010111"), (256, "0110111"),
+    (320, "00110110"), (384, "00110111"), (448, "01100100"), (512, "01100101"),
+    (576, "01101000"), (640, "01100111"), (704, "011001100"), (768, "011001101"),
+    (832, "011010010"), (896, "011010011"), (960, "011010100"), (1024, "011010101"),
+    (1088, "011010110"), (1152, "011010111"), (1216, "011011000"), (1280, "011011001"),
+    (1344, "011011010"), (1408, "011011011"), (1472, "010011000"), (1536, "010011001"),
+    (1600, "010011010"), (1664, "011000"), (1728, "010011011"),
+];
+
+pub const BLACK_CODES: &[(u16, &str)] = &[
+    (0, "0000110111"), (1, "010"), (2, "11"), (3, "10"),
+    (4, "011"), (5, "0011"), (6, "0010"), (7, "00011"),
+    (8, "000101"), (9, "000100"), (10, "0000100"), (11, "0000101"),
+    (12, "0000111"), (13, "00000100"), (14, "00000111"), (15, "000011000"),
+    (16, "0000010111"), (17, "0000011000"), (18, "0000001000"), (19, "00001100111"),
+    (20, "00001101000"), (21, "00001101100"), (22, "00000110111"), (23, "00000101000"),
+    (24, "00000010111"), (25, "00000011000"), (26, "000011001010"), (27, "000011001011"),
+    (28, "000011001100"), (29, "000011001101"), (30, "000001101000"), (31, "000001101001"),
+    (32, "000001101010"), (33, "000001101011"), (34, "000011010010"), (35, "000011010011"),
+    (36, "000011010100"), (37, "000011010101"), (38, "000011010110"), (39, "000011010111"),
+    (40, "000001101100"), (41, "000001101101"), (42, "000011011010"), (43, "000011011011"),
+    (44, "000001010100"), (45, "000001010101"), (46, "000001010110"), (47, "000001010111"),
+    (48, "000001100100"), (49, "000001100101"), (50, "000001010010"), (51, "000001010011"),
+    (52, "000000100100"), (53, "000000110111"), (54, "000000111000"), (55, "000000100111"),
+    (56, "000000101000"), (57, "000001011000"), (58, "000001011001"), (59, "000000101011"),
+    (60, "000000101100"), (61, "000001011010"), (62, "000001100110"), (63, "000001100111"),
+    (64, "0000001111"), (128, "000011001000"), (192, "000011001001"), (256, "000001011011"),
+    (320, "000000110011"), (384, "000000110100"), (448, "000000110101"), (512, "0000001101100"),
+    (576, "0000001101101"), (640, "0000001001010"), (704, "0000001001011"), (768, "0000001001100"),
+    (832, "0000001001101"), (896, "0000001110010"), (960, "0000001110011"), (1024, "0000001110100"),
+    (1088, "0000001110101"), (1152, "0000001110110"), (1216, "0000001110111"), (1280, "0000001010010"),
+    (1344, "0000001010011"), (1408, "0000001010100"), (1472, "0000001010101"), (1536, "0000001011010"),
+    (1600, "0000001011011"), (1664, "0000001100100"), (1728, "0000001100101"),
+];
+
+// Makeup codes beyond 1728, shared by both colours.
+pub const EXTENDED_CODES: &[(u16, &str)] = &[
+    (1792, "00000001000"), (1856, "00000001100"), (1920, "00000001101"), (1984, "000000010010"),
+    (2048, "000000010011"), (2112, "000000010100"), (2176, "000000010101"), (2240, "000000010110"),
+    (2304, "000000010111"), (2368, "000000011100"), (2432, "000000011101"), (2496, "000000011110"),
+    (2560, "000000011111"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mode {
+    Pass,
+    Horizontal,
+    // a1 relative to b1
+    Vertical(i8),
+    Extension,
+    Eol,
+}
+
+pub const MODE_CODES: &[(Mode, &str)] = &[
+    (Mode::Pass, "0001"),
+    (Mode::Horizontal, "001"),
+    (Mode::Vertical(0), "1"),
+    (Mode::Vertical(1), "011"),
+    (Mode::Vertical(2), "000011"),
+    (Mode::Vertical(3), "0000011"),
+    (Mode::Vertical(-1), "010"),
+    (Mode::Vertical(-2), "000010"),
+    (Mode::Vertical(-3), "0000010"),
+    (Mode::Extension, "0000001"),
+    (Mode::Eol, EOL),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Coding {
+    // Modified Huffman: 1D lines, each starting on a byte boundary
+    Huffman,
+    // T.4: EOL before each line, 1D or (with T4Options bit 0) 2D lines
+    Group3,
+    // T.6: 2D lines only, ended by EOFB
+    Group4,
+}
+
+pub type Table<T> = HashMap<(u8, u16), T>;
+
+pub fn table<T: Copy>(codes: &[(T, &str)]) -> Table<T> {
+    codes.iter()
+        .map(|&(value, code)| ((code.len() as u8, u16::from_str_radix(code, 2).unwrap_or(0)), value))
+        .collect()
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data,
+            position: 0,
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        (self.data.len() * 8).saturating_sub(self.position)
+    }
+
+    // The next `n` bits, reading zeros past the end.
+    fn peek(&self, n: u8) -> u16 {
+        let mut value = 0;
+        for i in 0..n as usize {
+            let position = self.position + i;
+            let bit = self.data.get(position / 8).map_or(0, |x| (x >> (7 - position % 8)) & 1);
+            value = (value << 1) | bit as u16;
+        }
+        value
+    }
+
+    // Nothing but zero padding left.
+    fn is_exhausted(&self) -> bool {
+        (self.position..self.data.len() * 8).all(|position| (self.data[position / 8] >> (7 - position % 8)) & 1 == 0)
+    }
+
+    fn consume(&mut self, n: u8) {
+        self.position += n as usize;
+    }
+
+    fn align(&mut self) {
+        self.position = self.position.div_ceil(8) * 8;
+    }
+
+    fn read<T: Copy>(&mut self, table: &Table<T>) -> Option<T> {
+        for len in 1..14 {
+            if len as usize > self.remaining() {
+                return None;
+            }
+            if let Some(&value) = table.get(&(len, self.peek(len))) {
+                self.consume(len);
+                return Some(value);
+            }
+        }
+        None
+    }
+}
+
+pub struct Decoder {
+    coding: Coding,
+    width: usize,
+    two_dimensional: bool,
+    white: Table<u16>,
+    black: Table<u16>,
+    modes: Table<Mode>,
+}
+
+impl Decoder {
+    pub fn new(coding: Coding, width: usize, two_dimensional: bool) -> Decoder {
+        let mut white = table(WHITE_CODES);
+        let mut black = table(BLACK_CODES);
+        white.extend(table(EXTENDED_CODES));
+        black.extend(table(EXTENDED_CODES));
+
+        Decoder {
+            coding,
+            width,
+            two_dimensional,
+            white,
+            black,
+            modes: table(MODE_CODES),
+        }
+    }
+
+    // Decodes up to `rows` lines into packed rows. Data that runs out early ends
+    // the output there; malformed codes are an error.
+    pub fn decode(&self, data: &[u8], rows: usize) -> Result<Vec<u8>, &'static str> {
+        let row_bytes = self.width.div_ceil(8);
+        let mut bits = BitReader::new(data);
+        let mut out = Vec::with_capacity(rows * row_bytes);
+        let mut reference = vec![];
+        for _ in 0..rows {
+            let line = match self.coding {
+                Coding::Huffman => {
+                    if bits.is_exhausted() {
+                        break;
+                    }
+                    let line = self.line_1d(&mut bits)?;
+                    bits.align();
+                    line
+                }
+                Coding::Group3 => {
+                    skip_eol(&mut bits);
+                    if bits.is_exhausted() || bits.peek(12) == 1 {
+                        break;
+                    }
+                    if !self.two_dimensional {
+                        self.line_1d(&mut bits)?
+                    } else {
+                        let one_dimensional = bits.peek(1) == 1;
+                        bits.consume(1);
+                        if one_dimensional {
+                            self.line_1d(&mut bits)?
+                        } else {
+                            match self.line_2d(&mut bits, &reference)? {
+                                Some(line) => line,
+                                None => break,
+                            }
+                        }
+                    }
+                }
+                Coding::Group4 => {
+                    if bits.is_exhausted() {
+                        break;
+                    }
+                    match self.line_2d(&mut bits, &reference)? {
+                        Some(line) => line,
+                        None => break,
+                    }
+                }
+            };
+            pack(&line, self.width, &mut out);
+            reference = line;
+        }
+
+        Ok(out)
+    }
+
+    fn run(&self, bits: &mut BitReader, white: bool) -> Result<usize, &'static str> {
+        let table = if white { &self.white } else { &self.black };
+        let mut total = 0;
+        loop {
+            let run = bits.read(table).ok_or("invalid run length code")? as usize;
+            total += run;
+            if run < 64 {
+                return Ok(total);
+            }
+        }
+    }
+
+    fn line_1d(&self, bits: &mut BitReader) -> Result<Vec<usize>, &'static str> {
+        let mut line = vec![];
+        let mut position = 0;
+        let mut white = true;
+        while position < self.width {
+            position += self.run(bits, white)?;
+            if position < self.width {
+                line.push(position);
+            }
+            white = !white;
+        }
+
+        Ok(line)
+    }
+
+    // `None` when the line is an EOL/EOFB instead of data.
+    fn line_2d(&self, bits: &mut BitReader, reference: &[usize]) -> Result<Option<Vec<usize>>, &'static str> {
+        let width = self.width as isize;
+        let mut line = vec![];
+        // a0 starts on an imaginary white element just before the line
+        let mut a0: isize = -1;
+        let mut white = true;
+        while a0 < width {
+            let mode = bits.read(&self.modes).ok_or("invalid mode code")?;
+            // b1 is the first change on the reference line past a0 to the opposite
+            // colour of a0; changes to black sit at even indices
+            let mut i = if white { 0 } else { 1 };
+            while i < reference.len() && reference[i] as isize <= a0 {
+                i += 2;
+            }
+            let b1 = reference.get(i).map_or(width, |x| *x as isize);
+            let b2 = reference.get(i + 1).map_or(width, |x| *x as isize);
+
+            match mode {
+                Mode::Pass => a0 = b2,
+                Mode::Horizontal => {
+                    let start = a0.max(0);
+                    let a1 = start + self.run(bits, white)? as isize;
+                    let a2 = a1 + self.run(bits, !white)? as isize;
+                    for &a in &[a1, a2] {
+                        if a < width {
+                            line.push(a as usize);
+                        }
+                    }
+                    a0 = a2;
+                }
+                Mode::Vertical(delta) => {
+                    let a1 = b1 + delta as isize;
+                    if a1 < 0 || a1 > width {
+                        return Err("vertical mode points outside the line");
+                    }
+                    if a1 < width {
+                        line.push(a1 as usize);
+                    }
+                    a0 = a1;
+                    white = !white;
+                }
+                Mode::Extension => return Err("uncompressed mode is not supported"),
+                Mode::Eol if a0 < 0 => return Ok(None),
+                Mode::Eol => return Err("line ended early"),
+            }
+        }
+
+        Ok(Some(line))
+    }
+}
+
+// Codes lines with T.6 (Group 4), each against the one above it.
+pub struct Encoder {
+    width: usize,
+    white: HashMap<u16, &'static str>,
+    black: HashMap<u16, &'static str>,
+    modes: HashMap<Mode, &'static str>,
+}
+
+impl Encoder {
+    pub fn new(width: usize) -> Encoder {
+        let mut white = WHITE_CODES.iter().cloned().collect::<HashMap<_, _>>();
+        let mut black = BLACK_CODES.iter().cloned().collect::<HashMap<_, _>>();
+        white.extend(EXTENDED_CODES.iter().cloned());
+        black.extend(EXTENDED_CODES.iter().cloned());
+
+        Encoder {
+            width,
+            white,
+            black,
+            modes: MODE_CODES.iter().cloned().collect(),
+        }
+    }
+
+    // Codes `rows` packed rows, black pixels as 1 bits, ending with an EOFB. The
+    // first row is coded against an all-white one.
+    pub fn encode(&self, packed: &[u8], rows: usize) -> Vec<u8> {
+        let row_bytes = self.width.div_ceil(8);
+        let mut bits = BitWriter::default();
+        let mut reference = vec![];
+        for row in packed.chunks(row_bytes.max(1)).take(rows) {
+            let line = unpack(row, self.width);
+            self.line_2d(&mut bits, &line, &reference);
+            reference = line;
+        }
+        bits.push(EOL);
+        bits.push(EOL);
+
+        bits.finish()
+    }
+
+    // The mirror of `Decoder::line_2d`.
+    fn line_2d(&self, bits: &mut BitWriter, line: &[usize], reference: &[usize]) {
+        let width = self.width as isize;
+        let change = |changes: &[usize], i: usize| changes.get(i).map_or(width, |x| *x as isize);
+        let mut a0: isize = -1;
+        let mut white = true;
+        while a0 < width {
+            let next = line.iter().position(|&x| x as isize > a0).unwrap_or(line.len());
+            let (a1, a2) = (change(line, next), change(line, next + 1));
+            let mut i = if white { 0 } else { 1 };
+            while i < reference.len() && reference[i] as isize <= a0 {
+                i += 2;
+            }
+            let (b1, b2) = (change(reference, i), change(reference, i + 1));
+
+            if b2 < a1 {
+                bits.push(self.modes[&Mode::Pass]);
+                a0 = b2;
+            } else if (a1 - b1).abs() <= 3 {
+                bits.push(self.modes[&Mode::Vertical((a1 - b1) as i8)]);
+                a0 = a1;
+                white = !white;
+            } else {
+                bits.push(self.modes[&Mode::Horizontal]);
+                self.run(bits, (a1 - a0.max(0)) as usize, white);
+                self.run(bits, (a2 - a1) as usize, !white);
+                a0 = a2;
+            }
+        }
+    }
+
+    // Makeup codes for whole multiples of 64, the largest 2560, then a
+    // terminating code for the rest.
+    fn run(&self, bits: &mut BitWriter, mut run: usize, white: bool) {
+        let codes = if white { &self.white } else { &self.black };
+        while run >= 64 {
+            let makeup = (run / 64 * 64).min(2560);
+            bits.push(codes[&(makeup as u16)]);
+            run -= makeup;
+        }
+        bits.push(codes[&(run as u16)]);
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    byte: u8,
+    len: u8,
+}
+
+impl BitWriter {
+    fn push(&mut self, code: &str) {
+        for bit in code.bytes() {
+            self.byte = self.byte << 1 | (bit - b'0');
+            self.len += 1;
+            if self.len == 8 {
+                self.out.push(self.byte);
+                self.byte = 0;
+                self.len = 0;
+            }
+        }
+    }
+
+    // The bits so far, the last byte padded with zeros.
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.out.push(self.byte << (8 - self.len));
+        }
+        self.out
+    }
+}
+
+// The changing elements of a packed row.
+fn unpack(row: &[u8], width: usize) -> Vec<usize> {
+    let mut line = vec![];
+    let mut black = false;
+    for x in 0..width {
+        if (row[x / 8] & (0x80 >> (x % 8)) != 0) != black {
+            line.push(x);
+            black = !black;
+        }
+    }
+
+    line
+}
+
+// Consumes an EOL and any fill bits in front of it, if there is one.
+fn skip_eol(bits: &mut BitReader) {
+    while bits.remaining() >= 12 {
+        match bits.peek(12) {
+            1 => {
+                bits.consume(12);
+                return;
+            }
+            0 => bits.consume(1),
+            _ => return,
+        }
+    }
+}
+
+// Appends one packed row with black pixels as 1 bits.
+pub fn pack(line: &[usize], width: usize, out: &mut Vec<u8>) {
+    let mut row = vec![0u8; width.div_ceil(8)];
+    let mut start = 0;
+    let mut black = false;
+    for &change in line.iter().chain(Some(&width)) {
+        let change = change.min(width).max(start);
+        if black {
+            for x in start..change {
+                row[x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        start = change;
+        black = !black;
+    }
+    out.extend_from_slice(&row);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byte::Endian;
+    use decode::Decoder as TiffDecoder;
+    use image::ImageData;
+    use std::io::Cursor;
+    use testutil::{
+        Page,
+        TiffBuilder,
+        Value,
+    };
+
+    // Packs a string of 0s and 1s into bytes, padding the last with zeros.
+    fn bits(code: &str) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        writer.push(code);
+        writer.finish()
+    }
+
+    // Rows of `width` pixels from the positions of their black pixels.
+    fn rows(width: usize, black: &[Vec<usize>]) -> Vec<u8> {
+        let mut packed = vec![];
+        for row in black {
+            let mut bytes = vec![0u8; width.div_ceil(8)];
+            for &x in row {
+                bytes[x / 8] |= 0x80 >> (x % 8);
+            }
+            packed.extend(bytes);
+        }
+        packed
+    }
+
+    fn round_trip(width: usize, packed: &[u8]) {
+        let rows = packed.len() / width.div_ceil(8);
+        let coded = Encoder::new(width).encode(packed, rows);
+        assert_eq!(Decoder::new(Coding::Group4, width, true).decode(&coded, rows).unwrap(), packed);
+    }
+
+    #[test]
+    fn each_mode() {
+        let packed = rows(16, &[(4..8).collect(), (4..8).collect(), vec![]]);
+        let coded = Encoder::new(16).encode(&packed, 3);
+        let expected = [
+            // against white: horizontal, 4 white then 4 black, then vertical 0 to
+            // the end of the row
+            "001", "1011", "011", "1",
+            // the same again: vertical 0 for each change and the end of the row
+            "1", "1", "1",
+            // white under a black run: pass, then vertical 0 to the end
+            "0001", "1",
+            // EOFB
+            EOL, EOL,
+        ];
+        assert_eq!(coded, bits(&expected.concat()));
+        round_trip(16, &packed);
+    }
+
+    #[test]
+    fn vertical_offsets() {
+        // each row shifts the black run by -3 to 3 against the one above it
+        let starts = [20, 17, 19, 20, 23, 22, 20];
+        let packed = rows(40, &starts.iter().map(|&x| (x..x + 6).collect()).collect::<Vec<_>>());
+        round_trip(40, &packed);
+    }
+
+    #[test]
+    fn long_runs_chain_makeup_codes() {
+        // 100 white is a makeup code of 64 and a terminating 36; 5300 black is two
+        // makeup codes of 2560, one of 128 and a terminating 52
+        let width = 6000;
+        let packed = rows(width, &[(100..5400).collect(), (0..width).collect(), (2600..2601).collect(), vec![]]);
+        round_trip(width, &packed);
+
+        let coded = Encoder::new(width).encode(&packed[..width / 8], 1);
+        let expected = ["001", "11011", "00010101", "000000011111", "000000011111", "000011001000", "000000100100"].concat();
+        assert_eq!(&coded[..expected.len() / 8], &bits(&expected)[..expected.len() / 8]);
+    }
+
+    #[test]
+    fn width_not_a_multiple_of_8() {
+        for &width in &[1, 7, 13, 61] {
+            // a pseudo-random pattern, the padding bits of each row left clear
+            let mut seed = width as u32;
+            let black = (0..9).map(|_| {
+                (0..width).filter(|_| {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    seed >> 16 & 3 == 0
+                }).collect()
+            }).collect::<Vec<_>>();
+            round_trip(width, &rows(width, &black));
+        }
+    }
+
+    // Three rows of 16: 4 white, 4 black and 8 white; all white; all black.
+    fn three_rows() -> Vec<u8> {
+        rows(16, &[(4..8).collect(), vec![], (0..16).collect()])
+    }
+
+    // One of the three rows coded one-dimensionally.
+    fn row_1d(row: usize) -> String {
+        let runs: &[&str] = match row {
+            // 4 white, 4 black, 8 white
+            0 => &["1011", "011", "10011"],
+            // 16 white
+            1 => &["101010"],
+            // 0 white, 16 black
+            _ => &["00110101", "0000010111"],
+        };
+        runs.concat()
+    }
+
+    fn decode(coding: Coding, two_dimensional: bool, code: &str, rows: usize) -> Result<Vec<u8>, &'static str> {
+        Decoder::new(coding, 16, two_dimensional).decode(&bits(code), rows)
+    }
+
+    // The three rows as Modified Huffman, each padded to a whole byte.
+    fn huffman() -> String {
+        (0..3).map(|row| format!("{:0<width$}", row_1d(row), width = row_1d(row).len().div_ceil(8) * 8)).collect()
+    }
+
+    #[test]
+    fn modified_huffman_rows_start_on_byte_boundaries() {
+        let code = huffman();
+        assert_eq!(decode(Coding::Huffman, false, &code, 3).unwrap(), three_rows());
+        // without the padding the second row is read from the middle of the first byte
+        assert_ne!(decode(Coding::Huffman, false, &(0..3).map(row_1d).collect::<String>(), 3), Ok(three_rows()));
+        // data that runs out ends the image early
+        assert_eq!(decode(Coding::Huffman, false, &code, 5).unwrap(), three_rows());
+    }
+
+    #[test]
+    fn group3_1d_skips_eols_and_fill() {
+        let code = [
+            EOL, &row_1d(0),
+            // fill bits in front of an EOL
+            "0000", EOL, &row_1d(1),
+            EOL, &row_1d(2),
+            // RTC, six EOLs
+            &EOL.repeat(6),
+        ].concat();
+        assert_eq!(decode(Coding::Group3, false, &code, 3).unwrap(), three_rows());
+        assert_eq!(decode(Coding::Group3, false, &code, 10).unwrap(), three_rows());
+        // the first EOL is optional
+        assert_eq!(decode(Coding::Group3, false, &code[EOL.len()..], 3).unwrap(), three_rows());
+    }
+
+    #[test]
+    fn group3_2d_mixes_1d_and_2d_lines() {
+        let code = [
+            // a 1D line
+            EOL, "1", &row_1d(0),
+            // the same 2D: vertical 0 for both changes and the end of the line
+            EOL, "0", "111",
+            // all white 2D: pass under the black run, then vertical 0
+            EOL, "0", "0001", "1",
+            EOL, "1", &row_1d(2),
+            &EOL.repeat(6),
+        ].concat();
+        let mut expected = rows(16, &[(4..8).collect(), (4..8).collect()]);
+        expected.extend(rows(16, &[vec![], (0..16).collect()]));
+        assert_eq!(decode(Coding::Group3, true, &code, 4).unwrap(), expected);
+    }
+
+    // A 16x3 page of `compression` with `code` as its one strip.
+    fn fax_page(compression: u16, code: &str, t4_options: u32) -> Vec<u8> {
+        let page = Page::new()
+            .tag(256, Value::Long(vec![16]))
+            .tag(257, Value::Long(vec![3]))
+            .tag(258, Value::Short(vec![1]))
+            .tag(259, Value::Short(vec![compression]))
+            .tag(262, Value::Short(vec![0]))
+            .tag(292, Value::Long(vec![t4_options]))
+            .strips(vec![bits(code)]);
+        TiffBuilder::new(Endian::Little).page(page).build()
+    }
+
+    #[test]
+    fn fax_pages_decode() {
+        let group3 = (0..3).map(|row| [EOL, &row_1d(row)].concat()).collect::<String>();
+        let group3_2d = (0..3).map(|row| [EOL, "1", &row_1d(row)].concat()).collect::<String>();
+        // decoded black-is-zero, so white pixels are 1
+        let expected = ImageData::U8(three_rows().iter().flat_map(|&byte| (0..8).map(move |x| byte >> (7 - x) & 1 ^ 1)).collect());
+        for (compression, code, options) in [(2, huffman(), 0), (3, group3, 0), (3, group3_2d, 1)] {
+            let image = TiffDecoder::new(Cursor::new(fax_page(compression, &code, options))).unwrap().image().unwrap();
+            assert_eq!(image.data(), &expected, "compression {} options {}", compression, options);
+        }
+    }
+}
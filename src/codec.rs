@@ -0,0 +1,569 @@
+
+use error::{
+    DecodeError,
+    DecodeErrorKind,
+    DecodeResult,
+};
+use byte::{
+    Endian,
+    lzw_compress,
+    lzw_decompress_bounded,
+    packbits_compress,
+};
+use ccitt::{
+    self,
+    Coding,
+};
+use image::{
+    Compression,
+    PhotometricInterpretation,
+    Predictor,
+};
+use jpeg_decoder::{
+    self,
+    ColorTransform,
+};
+use std::collections::HashMap;
+use std::io::{
+    self,
+    Read,
+    Write,
+};
+use flate2::Compression as DeflateLevel;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use std::fmt::{
+    self,
+    Debug,
+};
+use std::sync::Arc;
+
+// Decompresses one strip or tile. `expected_len` is the uncompressed size the
+// decoder will accept; the predictor is passed for information only, since the
+// decoder undoes it after `decode_bytes` returns.
+pub trait DecodeBytes {
+    fn decode_bytes(&mut self, compressed: &[u8], expected_len: usize, predictor: Predictor) -> DecodeResult<Vec<u8>>;
+
+    // `decode_bytes` keeping at most `expected_len` bytes, and counting those past
+    // them, which it returns as well. Producing more than `max_len` in all fails
+    // with `DecodeErrorKind::LimitExceeded`. The decoder calls this one; codecs
+    // that can stop early, so that a small strip can't inflate to gigabytes,
+    // should. By default the strip is decompressed whole and checked after.
+    fn decode_bytes_bounded(&mut self, compressed: &[u8], expected_len: usize, max_len: usize, predictor: Predictor) -> DecodeResult<(Vec<u8>, usize)> {
+        let mut data = self.decode_bytes(compressed, expected_len, predictor)?;
+        check_decompressed(data.len(), max_len)?;
+        let surplus = data.len().saturating_sub(expected_len);
+        data.truncate(expected_len);
+
+        Ok((data, surplus))
+    }
+}
+
+fn check_decompressed(len: usize, max_len: usize) -> DecodeResult<()> {
+    if len > max_len {
+        return Err(DecodeError::from(DecodeErrorKind::LimitExceeded { limit: "decompressed strip or tile size", value: len as u64, max: max_len as u64 }));
+    }
+
+    Ok(())
+}
+
+// Reads `expected_len` bytes of `reader` and counts the rest, giving up once
+// there are more than `max_len` in all.
+fn read_bounded<R: Read>(reader: R, expected_len: usize, max_len: usize) -> DecodeResult<(Vec<u8>, usize)> {
+    let mut reader = reader.take((max_len as u64).saturating_add(1));
+    let mut data = Vec::with_capacity(expected_len.min(max_len));
+    reader.by_ref().take(expected_len as u64).read_to_end(&mut data)?;
+    let surplus = io::copy(&mut reader, &mut io::sink())? as usize;
+    check_decompressed(data.len() + surplus, max_len)?;
+
+    Ok((data, surplus))
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Uncompressed;
+
+impl DecodeBytes for Uncompressed {
+    fn decode_bytes(&mut self, compressed: &[u8], _expected_len: usize, _predictor: Predictor) -> DecodeResult<Vec<u8>> {
+        Ok(compressed.to_vec())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LZWDecoder;
+
+impl DecodeBytes for LZWDecoder {
+    fn decode_bytes(&mut self, compressed: &[u8], _expected_len: usize, _predictor: Predictor) -> DecodeResult<Vec<u8>> {
+        let (data, _) = lzw_decompress_bounded(compressed, usize::MAX, usize::MAX)?;
+        Ok(data)
+    }
+
+    fn decode_bytes_bounded(&mut self, compressed: &[u8], expected_len: usize, max_len: usize, _predictor: Predictor) -> DecodeResult<(Vec<u8>, usize)> {
+        let (data, surplus) = lzw_decompress_bounded(compressed, expected_len, max_len)?;
+        check_decompressed(data.len() + surplus, max_len)?;
+
+        Ok((data, surplus))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PackBitsDecoder;
+
+impl DecodeBytes for PackBitsDecoder {
+    fn decode_bytes(&mut self, compressed: &[u8], expected_len: usize, _predictor: Predictor) -> DecodeResult<Vec<u8>> {
+        let mut uncompressed = Vec::with_capacity(expected_len);
+        let mut bytes = compressed.iter();
+        // a header byte n copies the next n + 1 bytes literally, -n repeats the next
+        // byte 1 + n times, and -128 is a no-op. A run cut short by the end of the
+        // data just ends the strip.
+        while let Some(&header) = bytes.next() {
+            let n = header as i8;
+            if n >= 0 {
+                uncompressed.extend(bytes.by_ref().take(n as usize + 1));
+            } else if n != -128 {
+                match bytes.next() {
+                    Some(&x) => uncompressed.extend(std::iter::repeat_n(x, 1 + (-n) as usize)),
+                    None => break,
+                }
+            }
+        }
+
+        Ok(uncompressed)
+    }
+}
+
+// Both the Adobe (8) and the legacy (32946) codes are zlib streams.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeflateDecoder;
+
+impl DecodeBytes for DeflateDecoder {
+    fn decode_bytes(&mut self, compressed: &[u8], expected_len: usize, _predictor: Predictor) -> DecodeResult<Vec<u8>> {
+        let mut uncompressed = Vec::with_capacity(expected_len);
+        ZlibDecoder::new(compressed).read_to_end(&mut uncompressed)?;
+
+        Ok(uncompressed)
+    }
+
+    fn decode_bytes_bounded(&mut self, compressed: &[u8], expected_len: usize, max_len: usize, _predictor: Predictor) -> DecodeResult<(Vec<u8>, usize)> {
+        read_bounded(ZlibDecoder::new(compressed), expected_len, max_len)
+    }
+}
+
+#[cfg(feature = "zstd")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ZstdDecoder;
+
+#[cfg(feature = "zstd")]
+impl DecodeBytes for ZstdDecoder {
+    fn decode_bytes(&mut self, compressed: &[u8], expected_len: usize, _predictor: Predictor) -> DecodeResult<Vec<u8>> {
+        let mut uncompressed = Vec::with_capacity(expected_len);
+        ::zstd::stream::read::Decoder::new(compressed)?.read_to_end(&mut uncompressed)?;
+
+        Ok(uncompressed)
+    }
+
+    fn decode_bytes_bounded(&mut self, compressed: &[u8], expected_len: usize, max_len: usize, _predictor: Predictor) -> DecodeResult<(Vec<u8>, usize)> {
+        read_bounded(::zstd::stream::read::Decoder::new(compressed)?, expected_len, max_len)
+    }
+}
+
+// New-style JPEG (7). Each strip is a JPEG stream of its own, and the tables it
+// refers to may be stored once per image in JPEGTables.
+#[derive(Debug, Clone)]
+pub struct JpegDecoder {
+    tables: Option<Vec<u8>>,
+    photometric_interpretation: PhotometricInterpretation,
+}
+
+impl JpegDecoder {
+    pub fn new(tables: Option<Vec<u8>>, photometric_interpretation: PhotometricInterpretation) -> JpegDecoder {
+        JpegDecoder {
+            tables,
+            photometric_interpretation,
+        }
+    }
+
+    // The tables stream is SOI, tables, EOI; the strip is SOI, ..., EOI. Splicing
+    // them without the inner EOI/SOI gives one complete stream.
+    fn stream(&self, compressed: &[u8]) -> Vec<u8> {
+        match self.tables {
+            Some(ref tables) if tables.len() >= 4 && compressed.starts_with(&[0xFF, 0xD8]) => {
+                let mut stream = tables[..tables.len() - 2].to_vec();
+                stream.extend_from_slice(&compressed[2..]);
+                stream
+            }
+            _ => compressed.to_vec(),
+        }
+    }
+}
+
+impl DecodeBytes for JpegDecoder {
+    fn decode_bytes(&mut self, compressed: &[u8], expected_len: usize, _predictor: Predictor) -> DecodeResult<Vec<u8>> {
+        let stream = self.stream(compressed);
+        let mut decoder = jpeg_decoder::Decoder::new(&stream[..]);
+        // YCbCr comes out as RGB; everything else is returned as stored.
+        decoder.set_color_transform(match self.photometric_interpretation {
+            PhotometricInterpretation::YCbCr => ColorTransform::YCbCr,
+            PhotometricInterpretation::RGB => ColorTransform::RGB,
+            PhotometricInterpretation::WhiteIsZero | PhotometricInterpretation::BlackIsZero => ColorTransform::Grayscale,
+            _ => ColorTransform::None,
+        });
+        let mut uncompressed = decoder.decode()
+            .map_err(|e| DecodeError::from(DecodeErrorKind::CodecFailed { compression: 7, reason: e.to_string() }))?;
+        // the last strip may be coded at full RowsPerStrip height
+        uncompressed.truncate(expected_len);
+
+        Ok(uncompressed)
+    }
+}
+
+// Bilevel fax coding: Modified Huffman (2), T.4 (3) and T.6 (4). Rows are
+// packed MSB-first with black as 1, whatever the photometric says.
+#[derive(Debug, Clone, Copy)]
+pub struct CCITTDecoder {
+    compression: Compression,
+    width: u32,
+    options: u32,
+}
+
+impl CCITTDecoder {
+    // `options` is T4Options for T.4 and T6Options for T.6.
+    pub fn new(compression: Compression, width: u32, options: u32) -> CCITTDecoder {
+        CCITTDecoder {
+            compression,
+            width,
+            options,
+        }
+    }
+
+    fn failed(&self, reason: &str) -> DecodeError {
+        DecodeError::from(DecodeErrorKind::CodecFailed { compression: self.compression.value(), reason: reason.to_string() })
+    }
+}
+
+impl DecodeBytes for CCITTDecoder {
+    fn decode_bytes(&mut self, compressed: &[u8], expected_len: usize, _predictor: Predictor) -> DecodeResult<Vec<u8>> {
+        let coding = match self.compression {
+            Compression::CCITTRLE => Coding::Huffman,
+            Compression::CCITTFax3 => Coding::Group3,
+            Compression::CCITTFax4 => Coding::Group4,
+            _ => return Err(self.failed("not a CCITT compression")),
+        };
+        // bit 1 of both option tags allows uncompressed mode
+        if self.options & 0b10 != 0 {
+            return Err(self.failed("uncompressed mode is not supported"));
+        }
+
+        let row_bytes = (self.width as usize).div_ceil(8);
+        if row_bytes == 0 {
+            return Ok(vec![]);
+        }
+        let decoder = ccitt::Decoder::new(coding, self.width as usize, self.options & 0b1 != 0);
+        decoder.decode(compressed, expected_len / row_bytes)
+            .map_err(|reason| self.failed(reason))
+    }
+}
+
+// Compresses one strip or tile for the encoder, which writes whatever Compression
+// value the codec declares. The encoder only applies a predictor to the samples
+// beforehand when `uses_predictor` says so.
+pub trait EncodeBytes {
+    fn compression(&self) -> u16;
+    fn encode_bytes(&mut self, uncompressed: &[u8]) -> io::Result<Vec<u8>>;
+
+    // Called before each strip or tile with its width in pixels and the length of
+    // its rows in bytes, for codecs that work row by row.
+    fn start_chunk(&mut self, _width: u32, _row_bytes: usize) {}
+
+    fn uses_predictor(&self) -> bool {
+        false
+    }
+}
+
+impl EncodeBytes for Uncompressed {
+    fn compression(&self) -> u16 {
+        1
+    }
+
+    fn encode_bytes(&mut self, uncompressed: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(uncompressed.to_vec())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LZWEncoder;
+
+impl EncodeBytes for LZWEncoder {
+    fn compression(&self) -> u16 {
+        5
+    }
+
+    fn encode_bytes(&mut self, uncompressed: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(lzw_compress(uncompressed))
+    }
+
+    fn uses_predictor(&self) -> bool {
+        true
+    }
+}
+
+// Adobe Deflate (8), a zlib stream. `level` runs from 0, no compression, to 9,
+// the smallest output; the default is 6.
+#[derive(Debug, Clone, Copy)]
+pub struct DeflateEncoder {
+    level: u32,
+}
+
+impl DeflateEncoder {
+    pub fn new(level: u32) -> DeflateEncoder {
+        DeflateEncoder {
+            level: level.min(9),
+        }
+    }
+
+    pub fn level(&self) -> u32 {
+        self.level
+    }
+}
+
+impl Default for DeflateEncoder {
+    fn default() -> DeflateEncoder {
+        DeflateEncoder::new(6)
+    }
+}
+
+impl EncodeBytes for DeflateEncoder {
+    fn compression(&self) -> u16 {
+        8
+    }
+
+    fn encode_bytes(&mut self, uncompressed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), DeflateLevel::new(self.level));
+        encoder.write_all(uncompressed)?;
+        encoder.finish()
+    }
+
+    fn uses_predictor(&self) -> bool {
+        true
+    }
+}
+
+// The spec has each row packed on its own, so runs never cross rows.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PackBitsEncoder {
+    row_bytes: usize,
+}
+
+impl EncodeBytes for PackBitsEncoder {
+    fn compression(&self) -> u16 {
+        32773
+    }
+
+    fn encode_bytes(&mut self, uncompressed: &[u8]) -> io::Result<Vec<u8>> {
+        let mut compressed = Vec::with_capacity(uncompressed.len() + uncompressed.len() / 128 + 1);
+        let row_bytes = match self.row_bytes {
+            0 => uncompressed.len().max(1),
+            row_bytes => row_bytes,
+        };
+        for row in uncompressed.chunks(row_bytes) {
+            packbits_compress(row, &mut compressed);
+        }
+
+        Ok(compressed)
+    }
+
+    fn start_chunk(&mut self, _width: u32, row_bytes: usize) {
+        self.row_bytes = row_bytes;
+    }
+}
+
+// T.6 (Group 4) for bilevel images, the usual compression of scanned documents.
+// Rows are coded one after another within a strip or tile, each against the one
+// above it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CCITTFax4Encoder {
+    width: u32,
+    row_bytes: usize,
+}
+
+impl EncodeBytes for CCITTFax4Encoder {
+    fn compression(&self) -> u16 {
+        4
+    }
+
+    fn encode_bytes(&mut self, uncompressed: &[u8]) -> io::Result<Vec<u8>> {
+        let width = self.width as usize;
+        if self.row_bytes != width.div_ceil(8) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "CCITT Group 4 only codes images of one 1-bit sample"));
+        }
+        let rows = match self.row_bytes {
+            0 => 0,
+            row_bytes => uncompressed.len() / row_bytes,
+        };
+
+        Ok(ccitt::Encoder::new(width).encode(uncompressed, rows))
+    }
+
+    fn start_chunk(&mut self, width: u32, row_bytes: usize) {
+        self.width = width;
+        self.row_bytes = row_bytes;
+    }
+}
+
+// Undoes horizontal differencing in place. Within each row, every sample after the
+// first pixel was stored as the difference from the same sample of the pixel
+// before it, wrapping at the sample's width.
+pub fn undo_horizontal_predictor(data: &mut [u8], row_bytes: usize, samples: usize, bytes_per_sample: usize, endian: Endian) {
+    let stride = samples * bytes_per_sample;
+    for row in data.chunks_mut(row_bytes) {
+        if bytes_per_sample == 1 {
+            for i in stride..row.len() {
+                row[i] = row[i].wrapping_add(row[i - stride]);
+            }
+            continue;
+        }
+
+        let mask = u64::MAX >> (64 - bytes_per_sample * 8);
+        let mut i = stride;
+        while i + bytes_per_sample <= row.len() {
+            let x = read_sample(&row[i..i + bytes_per_sample], endian);
+            let prev = read_sample(&row[i - stride..i - stride + bytes_per_sample], endian);
+            write_sample(&mut row[i..i + bytes_per_sample], x.wrapping_add(prev) & mask, endian);
+            i += bytes_per_sample;
+        }
+    }
+}
+
+// Stores every sample after the first pixel of a row as the difference from the
+// same sample of the pixel before it, undone by `undo_horizontal_predictor`.
+pub fn apply_horizontal_predictor(data: &mut [u8], row_bytes: usize, samples: usize, bytes_per_sample: usize, endian: Endian) {
+    let stride = samples * bytes_per_sample;
+    for row in data.chunks_mut(row_bytes) {
+        if bytes_per_sample == 1 {
+            for i in (stride..row.len()).rev() {
+                row[i] = row[i].wrapping_sub(row[i - stride]);
+            }
+            continue;
+        }
+
+        let mask = u64::MAX >> (64 - bytes_per_sample * 8);
+        let mut i = (row.len() / bytes_per_sample) * bytes_per_sample;
+        while i >= stride + bytes_per_sample {
+            i -= bytes_per_sample;
+            let x = read_sample(&row[i..i + bytes_per_sample], endian);
+            let prev = read_sample(&row[i - stride..i - stride + bytes_per_sample], endian);
+            write_sample(&mut row[i..i + bytes_per_sample], x.wrapping_sub(prev) & mask, endian);
+        }
+    }
+}
+
+// The floating point predictor of Adobe's TIFF Technical Note 3. Each row is
+// rearranged into planes of the most significant bytes of its samples down to
+// the least, whatever the file's byte order, and then differenced byte by byte
+// across pixels.
+pub fn apply_floating_point_predictor(data: &mut [u8], row_bytes: usize, samples: usize, bytes_per_sample: usize, endian: Endian) {
+    for row in data.chunks_mut(row_bytes) {
+        let values = row.len() / bytes_per_sample;
+        let mut planes = vec![0u8; row.len()];
+        for i in 0..values {
+            for b in 0..bytes_per_sample {
+                planes[plane_index(b, i, values, bytes_per_sample, endian)] = row[i * bytes_per_sample + b];
+            }
+        }
+        for i in (samples..planes.len()).rev() {
+            planes[i] = planes[i].wrapping_sub(planes[i - samples]);
+        }
+        row.copy_from_slice(&planes);
+    }
+}
+
+pub fn undo_floating_point_predictor(data: &mut [u8], row_bytes: usize, samples: usize, bytes_per_sample: usize, endian: Endian) {
+    for row in data.chunks_mut(row_bytes) {
+        for i in samples..row.len() {
+            row[i] = row[i].wrapping_add(row[i - samples]);
+        }
+        let planes = row.to_vec();
+        let values = row.len() / bytes_per_sample;
+        for i in 0..values {
+            for b in 0..bytes_per_sample {
+                row[i * bytes_per_sample + b] = planes[plane_index(b, i, values, bytes_per_sample, endian)];
+            }
+        }
+    }
+}
+
+// Where byte `b` of sample `i`, counted in `endian` order, goes in the planes.
+fn plane_index(b: usize, i: usize, values: usize, bytes_per_sample: usize, endian: Endian) -> usize {
+    let significance = match endian {
+        Endian::Big => b,
+        Endian::Little => bytes_per_sample - 1 - b,
+    };
+    significance * values + i
+}
+
+fn read_sample(bytes: &[u8], endian: Endian) -> u64 {
+    match endian {
+        Endian::Big => bytes.iter().fold(0, |n, x| n << 8 | *x as u64),
+        Endian::Little => bytes.iter().rev().fold(0, |n, x| n << 8 | *x as u64),
+    }
+}
+
+fn write_sample(bytes: &mut [u8], n: u64, endian: Endian) {
+    let len = bytes.len();
+    for (i, x) in bytes.iter_mut().enumerate() {
+        let shift = match endian {
+            Endian::Big => (len - 1 - i) * 8,
+            Endian::Little => i * 8,
+        };
+        *x = (n >> shift) as u8;
+    }
+}
+
+pub type CodecFactory = Arc<dyn Fn() -> Box<dyn DecodeBytes> + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub struct Codecs(HashMap<u16, CodecFactory>);
+
+impl Codecs {
+    pub fn insert(&mut self, compression: u16, factory: CodecFactory) {
+        self.0.insert(compression, factory);
+    }
+
+    pub fn contains(&self, compression: u16) -> bool {
+        self.0.contains_key(&compression)
+    }
+
+    pub fn get(&self, compression: u16) -> Option<Box<dyn DecodeBytes>> {
+        self.0.get(&compression).map(|factory| factory())
+    }
+
+    pub fn compressions(&self) -> Vec<u16> {
+        let mut compressions = self.0.keys().cloned().collect::<Vec<_>>();
+        compressions.sort();
+        compressions
+    }
+}
+
+impl Debug for Codecs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Codecs").field(&self.compressions()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jpeg_tables_are_spliced_into_the_strip() {
+        let tables = vec![0xFF, 0xD8, 0xFF, 0xDB, 1, 2, 0xFF, 0xD9];
+        let decoder = JpegDecoder::new(Some(tables), PhotometricInterpretation::BlackIsZero);
+        // the tables' EOI and the strip's SOI go
+        let strip = [0xFF, 0xD8, 0xFF, 0xC0, 3, 4, 0xFF, 0xD9];
+        assert_eq!(decoder.stream(&strip), vec![0xFF, 0xD8, 0xFF, 0xDB, 1, 2, 0xFF, 0xC0, 3, 4, 0xFF, 0xD9]);
+        // a strip without SOI is left alone
+        assert_eq!(decoder.stream(&strip[2..]), strip[2..].to_vec());
+
+        let decoder = JpegDecoder::new(None, PhotometricInterpretation::BlackIsZero);
+        assert_eq!(decoder.stream(&strip), strip.to_vec());
+    }
+}
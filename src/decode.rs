@@ -1,20 +1,49 @@
 
 use error::{
     DecodeError,
+    DecodeErrorCategory,
     DecodeErrorKind,
     DecodeResult,
+    DecodeWarning,
+    ChunkProblem,
 };
 use byte::{
+    AlignExt,
     Endian,
     EndianReadExt,
+    EndianWriteExt,
     ReadExt,
     SeekExt,
-    LZWReader,
+};
+use header::{
+    FileHeader,
+    read_file_header,
+};
+use codec::{
+    self,
+    Codecs,
+    DecodeBytes,
+    DeflateDecoder,
+    JpegDecoder,
+    CCITTDecoder,
+    LZWDecoder,
+    PackBitsDecoder,
+    Uncompressed,
+};
+#[cfg(feature = "zstd")]
+use codec::ZstdDecoder;
+use gps::{
+    self,
+    Gps,
 };
 use ifd::{
+    self,
     IFD,
     Entry,
     DataType,
+    AnyValues,
+    Rational,
+    SRational,
 };
 use tag::{
     self,
@@ -22,58 +51,279 @@ use tag::{
     AnyTag,
 };
 use std::io::{
+    self,
+    Cursor,
     Read,
     Seek,
+    Write,
 };
+use std::cmp::Reverse;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::mem;
+use std::sync::Arc;
 use image::{
+    self,
     BitsPerSample,
     Image,
     ImageData,
     ImageHeader,
     Compression,
+    ExtraSample,
+    FillOrder,
+    Orientation,
+    PageKind,
     PhotometricInterpretation,
+    PlanarConfiguration,
+    Predictor,
+    Resolution,
+    ResolutionUnit,
+    Sample,
+    SampleFormat,
+    WhitePoint,
 };
 
-macro_rules! read_byte {
-    ($method:ident, $method2:ident, $typestr:ident, $t:ty) => {
-        #[inline]
-        fn $method(&mut self, ifd: &IFD, header: &ImageHeader, buffer_size: usize) -> DecodeResult<ImageData> {
-            let interpretation = header.photometric_interpretation();
-            let compression = header.compression();
-            let offsets = self.get_value(ifd, tag::StripOffsets)?;
-            let strip_byte_counts = self.get_value(ifd, tag::StripByteCounts)?;
-            let endian = self.endian;
-            
-            let mut buffer: Vec<$t> = vec![0; buffer_size];
-            let mut read_size = 0;
-            for (offset, byte_count) in offsets.into_iter().zip(strip_byte_counts.into_iter()) {
-                let offset = offset as usize;
-                let byte_count = byte_count as usize;
-                
-                self.reader.goto(offset as u64)?;
-
-                read_size += match compression {
-                    Compression::No => $method2(
-                        interpretation,
-                        read_size,
-                        buffer_size,
-                        endian,
-                        (&mut self.reader, byte_count),
-                        &mut buffer[read_size..])?,
-
-                    Compression::LZW => $method2(
-                        interpretation,
-                        read_size,
-                        buffer_size,
-                        endian,
-                        LZWReader::new(&mut self.reader, byte_count)?,
-                        &mut buffer[read_size..])?,
-                };
-            }
-            buffer.shrink_to_fit();
+// Bounds on what a file can make the decoder read or allocate, for files from
+// untrusted sources. Every limit but SamplesPerPixel, which is 1024, is off by
+// default; exceeding one fails with `DecodeErrorKind::LimitExceeded`, even in
+// lenient mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    width: Option<u32>,
+    height: Option<u32>,
+    alloc: Option<usize>,
+    ifd_entries: Option<usize>,
+    ifd_chain: Option<usize>,
+    samples_per_pixel: u16,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            width: None,
+            height: None,
+            alloc: None,
+            ifd_entries: None,
+            ifd_chain: None,
+            samples_per_pixel: 1024,
+        }
+    }
+}
+
+impl Limits {
+    pub fn new() -> Limits {
+        Limits::default()
+    }
+
+    pub fn max_dimensions(mut self, width: u32, height: u32) -> Limits {
+        self.width = Some(width);
+        self.height = Some(height);
+        self
+    }
+
+    // The largest buffer, in bytes, for a decoded image, a compressed strip or tile,
+    // or the values of an entry.
+    pub fn max_alloc(mut self, bytes: usize) -> Limits {
+        self.alloc = Some(bytes);
+        self
+    }
+
+    // The most entries a single IFD may declare.
+    pub fn max_ifd_entries(mut self, entries: usize) -> Limits {
+        self.ifd_entries = Some(entries);
+        self
+    }
+
+    // The most IFDs the chain of pages may link.
+    pub fn max_ifd_chain(mut self, ifds: usize) -> Limits {
+        self.ifd_chain = Some(ifds);
+        self
+    }
+
+    // The most samples a pixel may have, 1024 unless set.
+    pub fn max_samples_per_pixel(mut self, samples: u16) -> Limits {
+        self.samples_per_pixel = samples;
+        self
+    }
+}
+
+// Uncompressed strips larger than this, such as the single strip of a file
+// without RowsPerStrip, are read in pieces of about this size. Compressed strips
+// are still decompressed whole, up to `Limits::alloc`.
+const STRIP_CHOP_BYTES: usize = 1 << 20;
+
+// One resolution of a pyramidal image, from `Decoder::levels`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Level {
+    offset: u64,
+    width: u32,
+    height: u32,
+    downsample: f64,
+}
+
+impl Level {
+    // Where its IFD is, for `Decoder::load_ifd_at`.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    // How many full-resolution pixels one of its pixels spans across.
+    pub fn downsample(&self) -> f64 {
+        self.downsample
+    }
+}
+
+// What `Decoder::salvage` recovered from a file: the image of each IFD in the
+// chain, `None` where nothing could be, and every problem met on the way.
+#[derive(Debug)]
+pub struct Salvage {
+    images: Vec<Option<Image>>,
+    problems: Vec<DecodeWarning>,
+}
+
+impl Salvage {
+    pub fn images(&self) -> &[Option<Image>] {
+        &self.images
+    }
+
+    pub fn problems(&self) -> &[DecodeWarning] {
+        &self.problems
+    }
+
+    pub fn into_images(self) -> Vec<Option<Image>> {
+        self.images
+    }
+
+    // Whether the file decoded without a single problem.
+    pub fn is_intact(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// Arrays longer than this are cut short by `Decoder::dump`.
+const DUMP_VALUES: usize = 16;
+
+// Fails if `value` is over `max`.
+fn check_limit<T: Into<u64>>(limit: &'static str, value: u64, max: Option<T>) -> DecodeResult<()> {
+    match max.map(Into::into) {
+        Some(max) if value > max => Err(DecodeError::from(DecodeErrorKind::LimitExceeded { limit, value, max })),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DecoderBuilder {
+    lenient: bool,
+    salvage: bool,
+    limits: Limits,
+    codecs: Codecs,
+    override_builtin_codecs: bool,
+    expand_palette: bool,
+    cmyk_to_rgb: bool,
+    lab_to_rgb: Option<WhitePoint>,
+    apply_orientation: bool,
+    convert_alpha: Option<ExtraSample>,
+}
+
+impl DecoderBuilder {
+    pub fn new() -> DecoderBuilder {
+        DecoderBuilder::default()
+    }
+
+    // In lenient mode recoverable defects are repaired and recorded in
+    // `Decoder::warnings` instead of failing the decode. Unknown Compression and
+    // PhotometricInterpretation values come back as `Unknown`, and malformed
+    // entries fall back to their tag's default.
+    pub fn lenient(mut self, lenient: bool) -> DecoderBuilder {
+        self.lenient = lenient;
+        self
+    }
+
+    // Salvage mode goes beyond lenient mode to get what it can out of a damaged
+    // file: strips and tiles that run past the end of the file are read as far as
+    // they go, and those that can't be decoded are zero-filled, each recorded in
+    // `Decoder::warnings`. Limits still apply. Implies `lenient`.
+    pub fn salvage(mut self, salvage: bool) -> DecoderBuilder {
+        self.salvage = salvage;
+        self
+    }
 
-            Ok(ImageData::$typestr(buffer))
+    pub fn limits(mut self, limits: Limits) -> DecoderBuilder {
+        self.limits = limits;
+        self
+    }
+
+    // Decodes Palette images to 16-bit RGB through their ColorMap instead of
+    // returning the indices.
+    pub fn expand_palette(mut self, expand: bool) -> DecoderBuilder {
+        self.expand_palette = expand;
+        self
+    }
+
+    // Decodes CMYK images to RGB. With the `icc` feature, images with an
+    // ICCProfile are converted through it, see `Image::cmyk_to_rgb_with_profile`;
+    // otherwise, and without one, by `Image::cmyk_to_rgb`.
+    pub fn cmyk_to_rgb(mut self, convert: bool) -> DecoderBuilder {
+        self.cmyk_to_rgb = convert;
+        self
+    }
+
+    // Decodes CIELab images to sRGB, their L*a*b* taken relative to `white`
+    // (usually `WhitePoint::D50`). See `Image::lab_to_rgb`.
+    pub fn lab_to_rgb(mut self, white: WhitePoint) -> DecoderBuilder {
+        self.lab_to_rgb = Some(white);
+        self
+    }
+
+    // Turns and flips whole images the way their Orientation tag says they are
+    // displayed, so `image` returns the top row first. Regions are still taken
+    // from the image as it is stored.
+    pub fn apply_orientation(mut self, apply: bool) -> DecoderBuilder {
+        self.apply_orientation = apply;
+        self
+    }
+
+    // Converts images with an alpha channel to associated or unassociated alpha,
+    // see `Image::convert_alpha`. `ImageHeader::alpha` tells which one a file uses.
+    pub fn convert_alpha(mut self, alpha: ExtraSample) -> DecoderBuilder {
+        self.convert_alpha = Some(alpha);
+        self
+    }
+
+    // Registers a codec for a Compression value the crate doesn't decode itself. The
+    // factory is called once per strip or tile.
+    pub fn register_codec<F>(mut self, compression: u16, factory: F) -> DecoderBuilder
+        where F: Fn() -> Box<dyn DecodeBytes> + Send + Sync + 'static
+    {
+        self.codecs.insert(compression, Arc::new(factory));
+        self
+    }
+
+    // Lets `register_codec` replace a built-in codec. Without it, `build` rejects
+    // registrations for compressions the crate already supports.
+    pub fn override_builtin_codecs(mut self, allow: bool) -> DecoderBuilder {
+        self.override_builtin_codecs = allow;
+        self
+    }
+
+    pub fn build<R: Read + Seek>(self, reader: R) -> DecodeResult<Decoder<R>> {
+        if !self.override_builtin_codecs {
+            for compression in self.codecs.compressions() {
+                if Compression::from_u16(compression).is_ok() {
+                    return Err(DecodeError::from(DecodeErrorKind::BuiltinCodecOverride { compression }));
+                }
+            }
         }
+
+        Decoder::with_builder(reader, self)
     }
 }
 
@@ -81,35 +331,64 @@ macro_rules! read_byte {
 pub struct Decoder<R> {
     reader: R,
     endian: Endian,
-    start: u32,
-    next: u32,
+    start: u64,
+    next: u64,
+    current: u64,
+    index: Option<usize>,
+    lenient: bool,
+    salvage: bool,
+    limits: Limits,
+    warnings: Vec<DecodeWarning>,
+    codecs: Codecs,
+    expand_palette: bool,
+    cmyk_to_rgb: bool,
+    lab_to_rgb: Option<WhitePoint>,
+    apply_orientation: bool,
+    convert_alpha: Option<ExtraSample>,
+    len: u64,
+    header: FileHeader,
+    // IFDs the `Iterator` impl has returned, so a looping chain ends
+    visited: HashSet<u64>,
+    // the IFD `ifd_mut` handed out, and where it was read from
+    edit: Option<(u64, IFD)>,
 }
 
 impl<R> Decoder<R> where R: Read + Seek {
-    pub fn new(mut reader: R) -> DecodeResult<Decoder<R>> {
-        let mut byte_order = [0u8; 2];
-        if let Err(_) = reader.read_exact(&mut byte_order) {
-            return Err(DecodeError::from(DecodeErrorKind::NoByteOrder));
-        }
-        let endian = match &byte_order {
-            b"II" => Endian::Little,
-            b"MM" => Endian::Big,
-            _ => return Err(DecodeError::from(DecodeErrorKind::NoByteOrder)),
-        };
+    pub fn new(reader: R) -> DecodeResult<Decoder<R>> {
+        Decoder::with_builder(reader, DecoderBuilder::new())
+    }
+
+    fn with_builder(mut reader: R, builder: DecoderBuilder) -> DecodeResult<Decoder<R>> {
+        let len = reader.seek(io::SeekFrom::End(0))?;
+        reader.goto(0)?;
 
-        match reader.read_u16(endian) {
-            Ok(x) if x == 42 => {},
-            _ => return Err(DecodeError::from(DecodeErrorKind::NoVersion))
+        let header = read_file_header(&mut reader)?;
+        let endian = header.endian();
+        let start = header.first_ifd_offset();
+        if start >= len {
+            return Err(DecodeError::from(DecodeErrorKind::InvalidIFDAddress { offset: start }));
         }
-        let start = match reader.read_u32(endian) {
-            Ok(x) => x,
-            Err(_) => return Err(DecodeError::from(DecodeErrorKind::NoIFDAddress))
-        };
         let decoder = Decoder {
-            start: start,
+            start,
             next: start,
-            reader: reader,
-            endian: endian,
+            current: start,
+            index: Some(0),
+            reader,
+            endian,
+            lenient: builder.lenient || builder.salvage,
+            salvage: builder.salvage,
+            limits: builder.limits,
+            warnings: vec![],
+            codecs: builder.codecs,
+            expand_palette: builder.expand_palette,
+            cmyk_to_rgb: builder.cmyk_to_rgb,
+            lab_to_rgb: builder.lab_to_rgb,
+            apply_orientation: builder.apply_orientation,
+            convert_alpha: builder.convert_alpha,
+            len,
+            header,
+            visited: HashSet::new(),
+            edit: None,
         };
 
         Ok(decoder)
@@ -119,149 +398,2988 @@ impl<R> Decoder<R> where R: Read + Seek {
         self.collect::<Vec<_>>()
     }
 
+    pub fn ifd_count(&mut self) -> DecodeResult<usize> {
+        Ok(self.ifd_offsets()?.len())
+    }
+
+    // Decodes every page in chain order. The current IFD is left as it is.
+    pub fn images<'a>(&'a mut self) -> Images<'a, R> {
+        let next = self.start;
+        Images {
+            decoder: self,
+            next,
+            visited: HashSet::new(),
+            primary_only: false,
+        }
+    }
+
+    // `images`, skipping thumbnails, pyramid levels and masks (see `PageKind`).
+    pub fn primary_images<'a>(&'a mut self) -> Images<'a, R> {
+        Images {
+            primary_only: true,
+            ..self.images()
+        }
+    }
+
+    // Decodes every image in the chain, keeping going past the ones that fail.
+    // Meant for a decoder in salvage mode, though any decoder will do.
+    pub fn salvage(&mut self) -> Salvage {
+        let results: Vec<_> = self.images().collect();
+        let mut images = Vec::with_capacity(results.len());
+        for (page, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(image) => images.push(Some(image)),
+                Err(e) => {
+                    self.warn(DecodeWarning::PageSkipped { page, reason: e.to_string() });
+                    images.push(None);
+                }
+            }
+        }
+
+        Salvage {
+            images,
+            problems: self.take_warnings(),
+        }
+    }
+
+    // The current IFD, with the changes made through `ifd_mut` if there are any.
     pub fn ifd(&mut self) -> DecodeResult<IFD> {
-        let start = self.start;
-        let (ifd, _) = self.read_ifd(start)?;
+        let current = self.current;
+        match self.edit {
+            Some((offset, ref ifd)) if offset == current => return Ok(ifd.clone()),
+            _ => {},
+        }
+        let (ifd, _) = self.read_ifd(current)?;
         Ok(ifd)
     }
 
-    pub fn endian(&self) -> Endian {
-        self.endian
+    // The current IFD for editing with `IFD::put_value`, `IFD::remove` and the like.
+    // The decoder sees the changes at once; `write_ifd` saves them to the file.
+    // One IFD is edited at a time: editing another drops unsaved changes.
+    pub fn ifd_mut(&mut self) -> DecodeResult<&mut IFD> {
+        let current = self.current;
+        let edit = match self.edit.take() {
+            Some(edit) if edit.0 == current => edit,
+            _ => (current, self.read_ifd(current)?.0),
+        };
+
+        Ok(&mut self.edit.get_or_insert(edit).1)
     }
 
-    fn get_entry<'a, T: TagType>(&mut self, ifd: &'a IFD, tag: T) -> DecodeResult<&'a Entry> {
-        ifd.get(tag).ok_or(DecodeError::from(DecodeErrorKind::CannotFindTheTag{ tag: AnyTag::from(tag) }))
+    // Forgets the changes made through `ifd_mut` that weren't written.
+    pub fn discard_edits(&mut self) {
+        self.edit = None;
     }
-    
-    pub fn get_value<T: TagType>(&mut self, ifd: &IFD, tag: T) -> DecodeResult<T::Value> {
-        let entry = self.get_entry(ifd, tag)?;
-        tag.decode(&mut self.reader, entry.offset(), self.endian, entry.datatype(), entry.count() as usize)
+
+    // Offsets of every IFD in the chain. A chain that links back to an IFD it
+    // already passed is an error.
+    pub fn ifd_offsets(&mut self) -> DecodeResult<Vec<u64>> {
+        let mut offsets = vec![];
+        let mut visited = HashSet::new();
+        let mut next = self.start;
+        while next != 0 {
+            if !visited.insert(next) {
+                return Err(DecodeError::from(DecodeErrorKind::IFDCycle { offset: next }));
+            }
+            offsets.push(next);
+            check_limit("IFD chain length", offsets.len() as u64, self.limits.ifd_chain.map(|x| x as u64))?;
+            next = self.read_next_ifd_address(next)?;
+        }
+
+        Ok(offsets)
     }
 
-    fn read_ifd(&mut self, from: u32) -> DecodeResult<(IFD, u32)>  {
-        self.reader.goto(from as u64)?;
+    // Index of the current IFD in the chain, `None` after `load_ifd_at`.
+    pub fn current_ifd_index(&self) -> Option<usize> {
+        self.index
+    }
 
-        let mut ifd = IFD::new();
-        for _ in 0..self.reader.read_u16(self.endian)? {
-            let (tag, entry) = self.read_entry()?;
-            ifd.insert_anytag(tag, entry);
+    pub fn current_ifd_offset(&self) -> u64 {
+        self.current
+    }
+
+    pub fn change_ifd(&mut self, index: usize) -> DecodeResult<()> {
+        let offsets = self.ifd_offsets()?;
+        let offset = *offsets.get(index)
+            .ok_or(DecodeError::from(DecodeErrorKind::IFDIndexOutOfRange { index, count: offsets.len() }))?;
+        self.current = offset;
+        self.index = Some(index);
+
+        Ok(())
+    }
+
+    // Makes the IFD at `offset` current without walking the chain. The previous
+    // state is kept if no directory can be read there.
+    pub fn load_ifd_at(&mut self, offset: u64) -> DecodeResult<()> {
+        self.read_ifd(offset)?;
+        self.current = offset;
+        self.index = None;
+
+        Ok(())
+    }
+
+    // Reads the IFD at `offset` without making it current, such as the
+    // interoperability IFD an Exif IFD links to.
+    pub fn ifd_at(&mut self, offset: u64) -> DecodeResult<IFD> {
+        self.read_ifd(offset).map(|(ifd, _)| ifd)
+    }
+
+    // Writes every IFD in the chain, and the SubIFDs, Exif and GPS IFDs they link
+    // to, in the manner of tiffinfo: a line for each entry with its tag, datatype,
+    // count and values. Values that can't be read are shown as the error.
+    pub fn dump<W: Write>(&mut self, out: &mut W) -> DecodeResult<()> {
+        let version = if self.header.is_big_tiff() { "BigTIFF" } else { "TIFF" };
+        writeln!(out, "{}, {:?} endian, first IFD at {}", version, self.endian, self.start)?;
+
+        let mut visited = HashSet::new();
+        let mut next = self.start;
+        let mut index = 0;
+        while next != 0 && visited.insert(next) {
+            let (ifd, after) = match self.read_ifd(next) {
+                Ok(ifd) => ifd,
+                Err(e) => {
+                    writeln!(out, "IFD {} at {}: {}", index, next, e)?;
+                    break;
+                }
+            };
+            writeln!(out, "IFD {} at {}, {} entries, next IFD at {}", index, next, ifd.len(), after)?;
+            self.dump_entries(out, &ifd, 1, &mut visited)?;
+            next = after;
+            index += 1;
+        }
+
+        Ok(())
+    }
+
+    fn dump_entries<W: Write>(&mut self, out: &mut W, ifd: &IFD, depth: usize, visited: &mut HashSet<u64>) -> DecodeResult<()> {
+        let indent = "  ".repeat(depth);
+        for (tag, entry) in ifd.iter() {
+            let name = match tag.name() {
+                Some(name) => format!("{} ({})", name, tag.id()),
+                None => format!("Tag {}", tag.id()),
+            };
+            write!(out, "{}{} {:?}[{}]: ", indent, name, entry.datatype(), entry.count())?;
+            let values = self.get_any_values(entry);
+            match values {
+                // strings are shown whole
+                Ok(ref values) if values.len() > DUMP_VALUES && !matches!(*values, AnyValues::Ascii(_)) => writeln!(out, "{}, ...", values.head(DUMP_VALUES))?,
+                Ok(ref values) => writeln!(out, "{}", values)?,
+                Err(ref e) => writeln!(out, "<{}>", e)?,
+            }
+
+            let linked = match (tag, values) {
+                (AnyTag::SubIFDs, Ok(values)) | (AnyTag::ExifIFD, Ok(values)) | (AnyTag::GPSIFD, Ok(values)) => values.offsets(),
+                _ => vec![],
+            };
+            for offset in linked {
+                if !visited.insert(offset) {
+                    continue;
+                }
+                match self.read_ifd(offset) {
+                    Ok((child, _)) => {
+                        writeln!(out, "{}{} IFD at {}, {} entries", indent, tag, offset, child.len())?;
+                        self.dump_entries(out, &child, depth + 1, visited)?;
+                    }
+                    Err(e) => writeln!(out, "{}{} IFD at {}: {}", indent, tag, offset, e)?,
+                }
+            }
         }
 
-        let next = self.reader.read_u32(self.endian)?;
+        Ok(())
+    }
 
-        Ok((ifd, next))
+    // Offsets of the child IFDs the current IFD lists in SubIFDs, such as
+    // reduced-resolution copies or masks. Empty if it has none.
+    pub fn sub_ifds(&mut self) -> DecodeResult<Vec<u64>> {
+        let ifd = self.ifd()?;
+        match ifd.get(tag::SubIFDs) {
+            Some(_) => self.get_value(&ifd, tag::SubIFDs),
+            None => Ok(vec![]),
+        }
     }
-    
-    fn read_entry(&mut self) -> DecodeResult<(AnyTag, Entry)> {
-        let tag = AnyTag::from(self.reader.read_u16(self.endian)?);
-        let datatype = DataType::from(self.reader.read_u16(self.endian)?);
-        let count = self.reader.read_u32(self.endian)?;
-        let offset = self.reader.read_4byte()?;
-        let entry = Entry::new(datatype, count, offset);
 
-        Ok((tag, entry))
+    // Makes the `index`th child of the current IFD current, as `load_ifd_at` does.
+    pub fn load_sub_ifd(&mut self, index: usize) -> DecodeResult<()> {
+        let offsets = self.sub_ifds()?;
+        let offset = *offsets.get(index)
+            .ok_or(DecodeError::from(DecodeErrorKind::IFDIndexOutOfRange { index, count: offsets.len() }))?;
+
+        self.load_ifd_at(offset)
     }
 
-    pub fn header_with(&mut self, ifd: &IFD) -> DecodeResult<ImageHeader> {
-        let width = self.get_value(ifd, tag::ImageWidth)?;
-        let height = self.get_value(ifd, tag::ImageLength)?;
-        let compression = Compression::from_u16(self.get_value(ifd, tag::Compression)?)?;
-        let interpretation = PhotometricInterpretation::from_u16(self.get_value(ifd, tag::PhotometricInterpretation)?)?;
-        let bits_per_sample = BitsPerSample::new(self.get_value(ifd, tag::BitsPerSample)?)?;
-        let header = ImageHeader::new(width, height, compression, interpretation, bits_per_sample)?;
-        
-        Ok(header)
+    // What the current IFD holds, so that thumbnails and masks can be told from the
+    // pages of a document. TransparencyMask images are masks whatever their
+    // NewSubfileType says.
+    pub fn page_kind(&mut self) -> DecodeResult<PageKind> {
+        let ifd = self.ifd()?;
+        self.page_kind_with(&ifd)
     }
-    
-    pub fn header(&mut self) -> DecodeResult<ImageHeader> {
+
+    fn page_kind_with(&mut self, ifd: &IFD) -> DecodeResult<PageKind> {
+        if ifd.get(tag::PhotometricInterpretation).is_some() && self.get_value(ifd, tag::PhotometricInterpretation)? == PhotometricInterpretation::TransparencyMask.value() {
+            return Ok(PageKind::Mask);
+        }
+        if ifd.get(tag::NewSubfileType).is_some() || ifd.get(tag::SubfileType).is_none() {
+            return Ok(PageKind::from_new_subfile_type(self.get_value(ifd, tag::NewSubfileType)?));
+        }
+        let n = self.get_value(ifd, tag::SubfileType)?;
+        match PageKind::from_subfile_type(n) {
+            Err(_) if self.lenient => {
+                self.warn(DecodeWarning::UnknownValue { tag: AnyTag::SubfileType, value: n });
+                Ok(PageKind::Full)
+            }
+            kind => kind,
+        }
+    }
+
+    // The Exif IFD the current IFD points to, whose entries are read with
+    // `get_value` or `get_any_values` like any other.
+    pub fn exif(&mut self) -> DecodeResult<Option<IFD>> {
+        self.linked_ifd(tag::ExifIFD)
+    }
+
+    // XResolution, YResolution and ResolutionUnit of the current IFD, if it has both
+    // resolutions.
+    pub fn resolution(&mut self) -> DecodeResult<Option<Resolution>> {
         let ifd = self.ifd()?;
+        self.resolution_with(&ifd)
+    }
 
-        self.header_with(&ifd)
+    pub fn dpi(&mut self) -> DecodeResult<Option<(f64, f64)>> {
+        Ok(self.resolution()?.and_then(|x| x.dpi()))
     }
-    
-    read_byte!(read_byte_u8, read_byte_detail_u8, U8, u8);
-    read_byte!(read_byte_u16, read_byte_detail_u16, U16, u16);
 
-    pub fn image_with(&mut self, ifd: &IFD) -> DecodeResult<Image> {
-        let header = self.header_with(ifd)?;
-        let width = header.width() as usize;
-        let height = header.height() as usize;
-        let bits_per_sample = header.bits_per_sample();
-        let buffer_size = width * height * header.bits_per_sample().len();
-        let data = match bits_per_sample {
-            BitsPerSample::U8_1 | BitsPerSample::U8_3 | BitsPerSample::U8_4 => self.read_byte_u8(ifd, &header, buffer_size)?,
-            BitsPerSample::U16_1 | BitsPerSample::U16_3 | BitsPerSample::U16_4 => self.read_byte_u16(ifd, &header, buffer_size)?,
+    fn resolution_with(&mut self, ifd: &IFD) -> DecodeResult<Option<Resolution>> {
+        if ifd.get(tag::XResolution).is_none() || ifd.get(tag::YResolution).is_none() {
+            return Ok(None);
+        }
+        let x = self.get_value(ifd, tag::XResolution)?;
+        let y = self.get_value(ifd, tag::YResolution)?;
+        let unit = ResolutionUnit::from_u16(self.get_value(ifd, tag::ResolutionUnit)?)?;
+
+        Ok(Some(Resolution::new(x, y, unit)))
+    }
+
+    // The embedded ICC color profile of the current IFD.
+    pub fn icc_profile(&mut self) -> DecodeResult<Option<Vec<u8>>> {
+        let ifd = self.ifd()?;
+        self.get_optional_value(&ifd, tag::ICCProfile)
+    }
+
+    // The XMP metadata packet of the current IFD, an XML document in UTF-8.
+    pub fn xmp(&mut self) -> DecodeResult<Option<Vec<u8>>> {
+        let ifd = self.ifd()?;
+        self.get_optional_value(&ifd, tag::XMP)
+    }
+
+    // `xmp` as text, with invalid UTF-8 replaced.
+    pub fn xmp_string(&mut self) -> DecodeResult<Option<String>> {
+        Ok(self.xmp()?.map(|x| String::from_utf8_lossy(&x).into_owned()))
+    }
+
+    // The position recorded in the GPS IFD the current IFD points to.
+    pub fn gps(&mut self) -> DecodeResult<Option<Gps>> {
+        let ifd = match self.linked_ifd(tag::GPSIFD)? {
+            Some(ifd) => ifd,
+            None => return Ok(None),
         };
-        
-        Ok(Image::new(header, data))
+        let latitude_ref = self.get_optional_value(&ifd, tag::GPSLatitudeRef)?;
+        let latitude = self.get_optional_value(&ifd, tag::GPSLatitude)?
+            .and_then(|x| gps::degrees(&x, latitude_ref.as_deref()));
+        let longitude_ref = self.get_optional_value(&ifd, tag::GPSLongitudeRef)?;
+        let longitude = self.get_optional_value(&ifd, tag::GPSLongitude)?
+            .and_then(|x| gps::degrees(&x, longitude_ref.as_deref()));
+        let altitude_ref = self.get_optional_value(&ifd, tag::GPSAltitudeRef)?;
+        let altitude = self.get_optional_value(&ifd, tag::GPSAltitude)?
+            .and_then(|x| gps::altitude(x, altitude_ref.as_deref()));
+        let time_stamp = self.get_optional_value(&ifd, tag::GPSTimeStamp)?
+            .and_then(|x| gps::time_stamp(&x));
+        let date_stamp = self.get_optional_value(&ifd, tag::GPSDateStamp)?;
+
+        Ok(Some(Gps::new(latitude, longitude, altitude, time_stamp, date_stamp)))
     }
-    
-    pub fn image(&mut self) -> DecodeResult<Image> {
+
+    fn linked_ifd<T: TagType<Value = u64>>(&mut self, tag: T) -> DecodeResult<Option<IFD>> {
         let ifd = self.ifd()?;
-        self.image_with(&ifd)
+        if ifd.get(tag).is_none() {
+            return Ok(None);
+        }
+        let offset = self.get_value(&ifd, tag)?;
+        let (linked, _) = self.read_ifd(offset)?;
+
+        Ok(Some(linked))
     }
-} 
 
-impl<R> Iterator for Decoder<R> where R: Read + Seek {
-    type Item = IFD;
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
 
-    fn next(&mut self) -> Option<IFD> {
-        let next = self.next;
-        if let Some((ifd, next)) = self.read_ifd(next).ok() {
-            self.next = next;
+    // Hands back the reader, with whatever `write_ifd` wrote to it.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
 
-            Some(ifd)
-        } else {
-            None
+    pub fn file_header(&self) -> FileHeader {
+        self.header
+    }
+
+    pub fn is_lenient(&self) -> bool {
+        self.lenient
+    }
+
+    pub fn is_salvage(&self) -> bool {
+        self.salvage
+    }
+
+    // Whether images come out already turned upright by their Orientation.
+    pub fn applies_orientation(&self) -> bool {
+        self.apply_orientation
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    pub fn warnings(&self) -> &[DecodeWarning] {
+        &self.warnings
+    }
+
+    // Returns the warnings so far and forgets them.
+    pub fn take_warnings(&mut self) -> Vec<DecodeWarning> {
+        mem::take(&mut self.warnings)
+    }
+
+    // Shorthands for the most common values of the current IFD.
+    pub fn width(&mut self) -> DecodeResult<u32> {
+        let ifd = self.ifd()?;
+        self.get_value(&ifd, tag::ImageWidth)
+    }
+
+    pub fn height(&mut self) -> DecodeResult<u32> {
+        let ifd = self.ifd()?;
+        self.get_value(&ifd, tag::ImageLength)
+    }
+
+    pub fn dimensions(&mut self) -> DecodeResult<(u32, u32)> {
+        Ok((self.width()?, self.height()?))
+    }
+
+    // Values with a registered codec come back as `Compression::Unknown`.
+    pub fn compression(&mut self) -> DecodeResult<Compression> {
+        let ifd = self.ifd()?;
+        self.compression_with(&ifd)
+    }
+
+    // A lenient decoder returns values it doesn't know as `Compression::Unknown` too,
+    // which fail only once the image data is read.
+    fn compression_with(&mut self, ifd: &IFD) -> DecodeResult<Compression> {
+        match self.get_value(ifd, tag::Compression)? {
+            n if self.codecs.contains(n) => Ok(Compression::from_u16(n).unwrap_or(Compression::Unknown(n))),
+            n => match Compression::from_u16(n) {
+                Err(_) if self.lenient => {
+                    self.warn(DecodeWarning::UnknownValue { tag: AnyTag::Compression, value: n });
+                    Ok(Compression::Unknown(n))
+                }
+                compression => compression,
+            },
         }
     }
-}
 
-fn read_byte_detail_u16<S>(
-    interpretation: PhotometricInterpretation,
-    read_size: usize,
-    buffer_size: usize,
-    endian: Endian,
-    reader_and_size: (S, usize),
-    buffer: &mut [u16]) -> DecodeResult<usize> where S: Read
-{
-    let mut reader = reader_and_size.0;
-    let compressed_size = reader_and_size.1;
+    // As stored in the file, before any conversion `image` applies.
+    pub fn photometric(&mut self) -> DecodeResult<PhotometricInterpretation> {
+        let ifd = self.ifd()?;
+        self.photometric_with(&ifd)
+    }
+
+    fn photometric_with(&mut self, ifd: &IFD) -> DecodeResult<PhotometricInterpretation> {
+        let n = self.get_value(ifd, tag::PhotometricInterpretation)?;
+        match PhotometricInterpretation::from_u16(n) {
+            Err(_) if self.lenient => {
+                self.warn(DecodeWarning::UnknownValue { tag: AnyTag::PhotometricInterpretation, value: n });
+                Ok(PhotometricInterpretation::Unknown(n))
+            }
+            interpretation => interpretation,
+        }
+    }
+
+    pub fn orientation(&mut self) -> DecodeResult<Orientation> {
+        let ifd = self.ifd()?;
+        self.orientation_with(&ifd)
+    }
+
+    fn orientation_with(&mut self, ifd: &IFD) -> DecodeResult<Orientation> {
+        let n = self.get_value(ifd, tag::Orientation)?;
+        match Orientation::from_u16(n) {
+            Err(_) if self.lenient => {
+                self.warn(DecodeWarning::UnknownValue { tag: AnyTag::Orientation, value: n });
+                Ok(Orientation::TopLeft)
+            }
+            orientation => orientation,
+        }
+    }
 
-    if read_size + compressed_size > buffer_size {
-        return Err(DecodeError::from(DecodeErrorKind::IncorrectBufferSize { calc: buffer_size, sum: read_size + compressed_size }));
+    // Records `warning` once, however often the entry behind it is read.
+    fn warn(&mut self, warning: DecodeWarning) {
+        if !self.warnings.contains(&warning) {
+            self.warnings.push(warning);
+        }
+    }
+
+    fn get_entry<'a, T: TagType>(&mut self, ifd: &'a IFD, tag: T) -> DecodeResult<&'a Entry> {
+        ifd.get(tag).ok_or(DecodeError::from(DecodeErrorKind::CannotFindTheTag{ tag: AnyTag::from(tag) }))
     }
     
-    for data in buffer[..compressed_size/2].iter_mut() {
-        *data = if interpretation == PhotometricInterpretation::BlackIsZero {
-            u16::max_value() - reader.read_u16(endian)?
-        } else {
-            reader.read_u16(endian)?
-        };
+    // The value of `tag` in `ifd`. A missing entry reads as the tag's default, if
+    // TIFF 6.0 gives it one: a reader must assume Compression 1, RowsPerStrip
+    // 2**32-1 or no ExtraSamples when the writer left them out, and most files do.
+    // Tags without a default fail with `CannotFindTheTag`. Use `get_optional_value`
+    // to tell a missing entry from one holding the default.
+    pub fn get_value<T: TagType>(&mut self, ifd: &IFD, tag: T) -> DecodeResult<T::Value> {
+        self.read_value(ifd, tag).map_err(|e| {
+            let e = e.with_tag(AnyTag::from(tag));
+            match ifd.get(tag) {
+                Some(entry) if entry.overflow() && entry.values().is_none() => match entry.value_offset(self.endian) {
+                    Ok(offset) => e.with_offset(offset),
+                    Err(_) => e,
+                },
+                _ => e,
+            }
+        })
     }
 
-    Ok(compressed_size/2)
-}
+    fn read_value<T: TagType>(&mut self, ifd: &IFD, tag: T) -> DecodeResult<T::Value> {
+        match self.get_entry(ifd, tag) {
+            // a malformed entry falls back to the tag's default, if it has one
+            Ok(entry) if self.lenient => {
+                let value = self.decode_entry(entry, tag);
+                match value {
+                    Err(e) => match (e.category(), T::default_value()) {
+                        (DecodeErrorCategory::Tag, Some(value)) => {
+                            self.warn(DecodeWarning::MalformedEntry { tag: AnyTag::from(tag), reason: e.to_string() });
+                            Ok(value)
+                        }
+                        _ => Err(e),
+                    },
+                    value => value,
+                }
+            }
+            Ok(entry) => self.decode_entry(entry, tag),
+            Err(e) => T::default_value().ok_or(e),
+        }
+    }
 
-fn read_byte_detail_u8<S>(
-    interpretation: PhotometricInterpretation, 
-    read_size: usize,
-    buffer_size: usize,
-    _endian: Endian,
-    reader_and_size: (S, usize),
-    buffer: &mut [u8]) -> DecodeResult<usize> where S: Read,
-{
-    let mut reader = reader_and_size.0;
-    let compressed_size = reader_and_size.1;
-    if read_size + compressed_size > buffer_size {
-        return Err(DecodeError::from(DecodeErrorKind::IncorrectBufferSize { calc: buffer_size, sum: read_size + compressed_size }));
-    }
-    let res = reader.read(&mut buffer[..compressed_size])?;
-    if interpretation == PhotometricInterpretation::BlackIsZero {
-        for data in buffer[..compressed_size].iter_mut() {
-            *data = u8::max_value() - *data;
-        }
-    }
-    Ok(res)
-}
+    fn decode_entry<T: TagType>(&mut self, entry: &Entry, tag: T) -> DecodeResult<T::Value> {
+        let count = count(entry)?;
+        let values = match entry.values() {
+            Some(values) => values.to_bytes(self.endian),
+            None if self.lenient => return tag.decode_lenient(&mut self.reader, entry.offset(), self.endian, entry.datatype(), count, &mut self.warnings),
+            None => return tag.decode(&mut self.reader, entry.offset(), self.endian, entry.datatype(), count),
+        };
 
+        // values set through `ifd_mut` are read from memory, as if the file began
+        // with them
+        let field_len = if self.header.is_big_tiff() { 8 } else { 4 };
+        let mut field = vec![0; field_len];
+        if values.len() <= field_len {
+            field[..values.len()].copy_from_slice(&values);
+        }
+        let reader = Cursor::new(&values[..]);
+        if self.lenient {
+            tag.decode_lenient(reader, &field, self.endian, entry.datatype(), count, &mut self.warnings)
+        } else {
+            tag.decode(reader, &field, self.endian, entry.datatype(), count)
+        }
+    }
+
+    // Like `get_value`, but `None` when `ifd` has no such entry, whatever the
+    // tag's default.
+    pub fn get_optional_value<T: TagType>(&mut self, ifd: &IFD, tag: T) -> DecodeResult<Option<T::Value>> {
+        match ifd.get(tag) {
+            Some(_) => self.get_value(ifd, tag).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    // Reads `tag` from every IFD in the chain. A page without the tag gets the tag's
+    // default (or `None` if it has none). A page whose value can't be decoded gets
+    // `None` too, and the failure is recorded in `warnings` instead of aborting.
+    // So does a page whose IFD can't be read, which ends the list as the pages
+    // after it can't be found. The chain is walked once, reading each IFD.
+    pub fn get_value_all_pages<T: TagType>(&mut self, tag: T) -> DecodeResult<Vec<Option<T::Value>>> {
+        let mut values = vec![];
+        let mut visited = HashSet::new();
+        let mut next = self.start;
+        while next != 0 {
+            if !visited.insert(next) {
+                return Err(DecodeError::from(DecodeErrorKind::IFDCycle { offset: next }));
+            }
+            check_limit("IFD chain length", visited.len() as u64, self.limits.ifd_chain.map(|x| x as u64))?;
+            let index = values.len();
+            let skipped = |e: DecodeError| DecodeWarning::SkippedValue { ifd: index, tag: AnyTag::from(tag), reason: e.to_string() };
+            let (ifd, after) = match self.read_ifd(next) {
+                Ok(ifd) => ifd,
+                Err(e) => {
+                    self.warn(skipped(e));
+                    values.push(None);
+                    break;
+                }
+            };
+            let value = match self.get_value(&ifd, tag) {
+                Ok(value) => Some(value),
+                Err(_) if ifd.get(tag).is_none() => None,
+                Err(e) => {
+                    self.warn(skipped(e));
+                    None
+                }
+            };
+            values.push(value);
+            next = after;
+        }
+
+        Ok(values)
+    }
+
+    // Reads an entry's values according to its datatype, for entries that aren't (or
+    // can't be) read through a `TagType`.
+    pub fn get_any_values(&mut self, entry: &Entry) -> DecodeResult<AnyValues> {
+        if let Some(values) = entry.values() {
+            return Ok(values.clone());
+        }
+        let count = count(entry)?;
+        let len = match entry.byte_len() {
+            Some(len) => len,
+            None => return Err(DecodeError::from(DecodeErrorKind::UnsupportedIFDEntry { entry: entry.clone(), reason: "value size overflows".to_string() })),
+        };
+        check_limit("entry size", len as u64, self.limits.alloc.map(|x| x as u64))?;
+        let bytes = if entry.overflow() {
+            let offset = entry.value_offset(self.endian)?;
+            self.reader.goto(offset)?;
+            self.reader.read_bytes(len)?
+        } else {
+            entry.offset()[..len].to_vec()
+        };
+
+        let endian = self.endian;
+        let mut bytes = &bytes[..];
+        let values = match entry.datatype() {
+            DataType::Byte => AnyValues::Byte(bytes.to_vec()),
+            DataType::Undefined => AnyValues::Undefined(bytes.to_vec()),
+            DataType::Ascii => AnyValues::Ascii(bytes.to_vec()),
+            DataType::Short => AnyValues::Short((0..count).map(|_| bytes.read_u16(endian)).collect::<Result<_, _>>()?),
+            DataType::Long => AnyValues::Long((0..count).map(|_| bytes.read_u32(endian)).collect::<Result<_, _>>()?),
+            DataType::IFD => AnyValues::IFD((0..count).map(|_| bytes.read_u32(endian)).collect::<Result<_, _>>()?),
+            DataType::Long8 => AnyValues::Long8((0..count).map(|_| bytes.read_u64(endian)).collect::<Result<_, _>>()?),
+            DataType::SLong8 => AnyValues::SLong8((0..count).map(|_| bytes.read_u64(endian).map(|x| x as i64)).collect::<Result<_, _>>()?),
+            DataType::IFD8 => AnyValues::IFD8((0..count).map(|_| bytes.read_u64(endian)).collect::<Result<_, _>>()?),
+            DataType::Rational => {
+                let mut values = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let numerator = bytes.read_u32(endian)?;
+                    let denominator = bytes.read_u32(endian)?;
+                    values.push(Rational::new(numerator, denominator));
+                }
+                AnyValues::Rational(values)
+            }
+            DataType::SByte => AnyValues::SByte(bytes.iter().map(|&x| x as i8).collect()),
+            DataType::SShort => AnyValues::SShort((0..count).map(|_| bytes.read_u16(endian).map(|x| x as i16)).collect::<Result<_, _>>()?),
+            DataType::SLong => AnyValues::SLong((0..count).map(|_| bytes.read_u32(endian).map(|x| x as i32)).collect::<Result<_, _>>()?),
+            DataType::SRational => {
+                let mut values = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let numerator = bytes.read_u32(endian)? as i32;
+                    let denominator = bytes.read_u32(endian)? as i32;
+                    values.push(SRational::new(numerator, denominator));
+                }
+                AnyValues::SRational(values)
+            }
+            DataType::Float => AnyValues::Float((0..count).map(|_| bytes.read_u32(endian).map(f32::from_bits)).collect::<Result<_, _>>()?),
+            DataType::Double => AnyValues::Double((0..count).map(|_| bytes.read_u64(endian).map(f64::from_bits)).collect::<Result<_, _>>()?),
+            DataType::Unknown(_) => AnyValues::Unknown(entry.offset().to_vec()),
+        };
+
+        Ok(values)
+    }
+
+    fn read_ifd(&mut self, from: u64) -> DecodeResult<(IFD, u64)> {
+        self.read_ifd_entries(from).map_err(|e| e.with_ifd(from))
+    }
+
+    // Classic IFDs count entries with a SHORT and link with a LONG; BigTIFF uses
+    // LONG8 for both.
+    fn read_ifd_entries(&mut self, from: u64) -> DecodeResult<(IFD, u64)>  {
+        if from < self.header.len() || from >= self.len {
+            return Err(DecodeError::from(DecodeErrorKind::InvalidIFDAddress { offset: from }));
+        }
+        self.reader.goto(from)?;
+        let declared = if self.header.is_big_tiff() {
+            self.reader.read_u64(self.endian).map(|count| count as usize)
+        } else {
+            self.reader.read_u16(self.endian).map(|count| count as usize)
+        };
+        let declared = match declared {
+            Ok(count) => count,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(DecodeError::from(DecodeErrorKind::InvalidIFDAddress { offset: from }));
+            }
+            Err(e) => return Err(DecodeError::from(e)),
+        };
+        check_limit("IFD entry count", declared as u64, self.limits.ifd_entries.map(|x| x as u64))?;
+
+        let mut ifd = IFD::new();
+        let mut last = 0;
+        for read in 0..declared {
+            match self.read_entry() {
+                Ok((tag, entry)) => {
+                    // entries must be sorted by tag id; of duplicates the last is kept
+                    if self.lenient && tag.id() < last {
+                        self.warn(DecodeWarning::UnsortedEntries { ifd: from });
+                    }
+                    last = tag.id();
+                    if self.check_entry_offset(tag, &entry)? && ifd.insert_anytag(tag, entry).is_some() && self.lenient {
+                        self.warn(DecodeWarning::DuplicateEntry { ifd: from, tag });
+                    }
+                }
+                Err(e) => return self.truncated_ifd(e, from, declared, read, ifd),
+            }
+        }
+
+        let next = if self.header.is_big_tiff() {
+            self.reader.read_u64(self.endian)
+        } else {
+            self.reader.read_u32(self.endian).map(|next| next as u64)
+        };
+        match next {
+            Ok(next) => Ok((ifd, next)),
+            Err(e) => self.truncated_ifd(DecodeError::from(e), from, declared, declared, ifd),
+        }
+    }
+
+    // Values stored out of line must lie past the header and inside the file. A
+    // lenient decoder drops an entry that doesn't, returning `false`.
+    fn check_entry_offset(&mut self, tag: AnyTag, entry: &Entry) -> DecodeResult<bool> {
+        if !entry.overflow() {
+            return Ok(true);
+        }
+
+        let offset = entry.value_offset(self.endian)?;
+        let len = entry.byte_len().map(|len| len as u64);
+        match len {
+            Some(len) if offset >= self.header.len() && offset.checked_add(len).map(|end| end <= self.len).unwrap_or(false) => return Ok(true),
+            _ => {},
+        }
+
+        let len = len.unwrap_or(u64::MAX);
+        if !self.lenient {
+            return Err(DecodeError::from(DecodeErrorKind::InvalidEntryOffset { tag, offset, len }));
+        }
+        self.warn(DecodeWarning::InvalidEntryOffset { tag, offset, len });
+
+        Ok(false)
+    }
+
+    // The file ended inside the directory at `offset`. A lenient decoder keeps the
+    // entries it could read and ends the chain there.
+    fn truncated_ifd(&mut self, error: DecodeError, offset: u64, declared: usize, read: usize, ifd: IFD) -> DecodeResult<(IFD, u64)> {
+        match *error.kind() {
+            DecodeErrorKind::IO { ref error } if error.kind() == io::ErrorKind::UnexpectedEof => {},
+            _ => return Err(error),
+        }
+        if !self.lenient {
+            return Err(DecodeError::from(DecodeErrorKind::TruncatedIFD { offset, declared, read }));
+        }
+
+        self.warn(DecodeWarning::TruncatedIFD { offset, declared, read });
+
+        Ok((ifd, 0))
+    }
+
+    fn read_next_ifd_address(&mut self, from: u64) -> DecodeResult<u64> {
+        let (_, next) = self.read_ifd(from)?;
+
+        Ok(next)
+    }
+    
+    fn read_entry(&mut self) -> DecodeResult<(AnyTag, Entry)> {
+        let tag = AnyTag::from(self.reader.read_u16(self.endian)?);
+        let datatype = DataType::from(self.reader.read_u16(self.endian)?);
+        let entry = if self.header.is_big_tiff() {
+            let count = self.reader.read_u64(self.endian)?;
+            let mut offset = [0u8; 8];
+            self.reader.read_exact(&mut offset)?;
+            Entry::new_big(datatype, count, offset)
+        } else {
+            let count = self.reader.read_u32(self.endian)?;
+            let offset = self.reader.read_4byte()?;
+            Entry::new(datatype, count, offset)
+        };
+
+        Ok((tag, entry))
+    }
+
+    // Reads every tag that describes the image in `ifd` and checks that they agree
+    // with each other: BitsPerSample with SamplesPerPixel and the
+    // PhotometricInterpretation, and the number of strips or tiles with the layout.
+    pub fn header_with(&mut self, ifd: &IFD) -> DecodeResult<ImageHeader> {
+        let width = self.get_value(ifd, tag::ImageWidth)?;
+        let height = self.get_value(ifd, tag::ImageLength)?;
+        check_limit("image width", width as u64, self.limits.width)?;
+        check_limit("image height", height as u64, self.limits.height)?;
+        let compression = self.compression_with(ifd)?;
+        let mut interpretation = self.photometric_with(ifd)?;
+        // YCbCr is returned as RGB, converted by the JPEG codec or by `image_with`
+        if interpretation == PhotometricInterpretation::YCbCr {
+            interpretation = PhotometricInterpretation::RGB;
+        }
+        let bits_per_sample = self.get_value(ifd, tag::BitsPerSample)?;
+        let samples = match ifd.get(tag::SamplesPerPixel) {
+            Some(_) => self.get_value(ifd, tag::SamplesPerPixel)?,
+            None => bits_per_sample.len() as u16,
+        };
+        check_limit("samples per pixel", samples as u64, Some(self.limits.samples_per_pixel))?;
+        let bits_per_sample = BitsPerSample::new(image::samples_per_pixel(samples, bits_per_sample)?)?;
+        let extra_samples = self.get_value(ifd, tag::ExtraSamples)?
+            .into_iter()
+            .map(|n| match ExtraSample::from_u16(n) {
+                Err(_) if self.lenient => {
+                    self.warn(DecodeWarning::UnknownValue { tag: AnyTag::ExtraSamples, value: n });
+                    Ok(ExtraSample::Unspecified)
+                }
+                sample => sample,
+            })
+            .collect::<DecodeResult<Vec<_>>>()?;
+        if interpretation == PhotometricInterpretation::Palette {
+            match self.validate_palette(ifd, &bits_per_sample) {
+                Ok(()) => {},
+                Err(e) if self.lenient => {
+                    self.warnings.push(DecodeWarning::PaletteAsGray { reason: e.to_string() });
+                    interpretation = PhotometricInterpretation::BlackIsZero;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        // decoded samples take the whole bytes of the widest one
+        let pixel_bytes = bits_per_sample.len() * bits_per_sample.max_bits().div_ceil(8);
+        let image_bytes = checked_mul(checked_mul(width as usize, height as usize, "image size")?, pixel_bytes, "image size")?;
+        check_limit("image size", image_bytes as u64, self.limits.alloc.map(|x| x as u64))?;
+        self.validate_chunks(ifd, width, height, bits_per_sample.len())?;
+        let mut header = ImageHeader::new(width, height, compression, interpretation, bits_per_sample, extra_samples)?;
+        if let Some(resolution) = self.resolution_with(ifd)? {
+            header = header.with_resolution(resolution);
+        }
+        let sample_format = SampleFormat::from_values(&self.get_value(ifd, tag::SampleFormat)?)?;
+        header = header.with_sample_format(sample_format);
+        
+        Ok(header)
+    }
+    
+    // Compares the strips or tiles the image needs with the offsets the IFD gives.
+    // Too few means missing image data, which a lenient decoder zero-fills.
+    fn validate_chunks(&mut self, ifd: &IFD, width: u32, height: u32, samples: usize) -> DecodeResult<()> {
+        let planes = match PlanarConfiguration::from_u16(self.get_value(ifd, tag::PlanarConfiguration)?)? {
+            PlanarConfiguration::Chunky => 1,
+            PlanarConfiguration::Planar => samples,
+        };
+        let (width, height) = (width as usize, height as usize);
+        let (expected, offsets) = if ifd.get(tag::TileWidth).is_some() {
+            let tile_width = self.get_value(ifd, tag::TileWidth)? as usize;
+            let tile_length = self.get_value(ifd, tag::TileLength)? as usize;
+            // read_tiles reports these
+            if tile_width == 0 || tile_length == 0 {
+                return Ok(());
+            }
+            let tiles = checked_mul(width.div_ceil(tile_width), height.div_ceil(tile_length), "tile count")?;
+            (checked_mul(tiles, planes, "tile count")?, ifd.get(tag::TileOffsets))
+        } else {
+            let rows_per_strip = match self.get_value(ifd, tag::RowsPerStrip)? as usize {
+                0 => height.max(1),
+                n => n,
+            };
+            (checked_mul(height.div_ceil(rows_per_strip), planes, "strip count")?, ifd.get(tag::StripOffsets))
+        };
+
+        let actual = match offsets {
+            Some(entry) => entry.count() as usize,
+            None => return Ok(()),
+        };
+        if actual < expected && !self.lenient {
+            return Err(DecodeError::from(DecodeErrorKind::StripCountMismatch { expected, actual }));
+        }
+
+        Ok(())
+    }
+
+    // Checks the strips or tiles of the current image against its size and the
+    // file: their count, the byte counts and the ranges they take.
+    pub fn check_chunks(&mut self) -> DecodeResult<Vec<ChunkProblem>> {
+        let ifd = self.ifd()?;
+        let current = self.current;
+        self.check_chunks_with(&ifd).map_err(|e| e.with_ifd(current))
+    }
+
+    pub fn check_chunks_with(&mut self, ifd: &IFD) -> DecodeResult<Vec<ChunkProblem>> {
+        let width = self.get_value(ifd, tag::ImageWidth)? as usize;
+        let height = self.get_value(ifd, tag::ImageLength)? as usize;
+        let bits_per_sample = self.get_value(ifd, tag::BitsPerSample)?;
+        let samples = match ifd.get(tag::SamplesPerPixel) {
+            Some(_) => self.get_value(ifd, tag::SamplesPerPixel)? as usize,
+            None => bits_per_sample.len(),
+        };
+        // a single BitsPerSample value stands for every sample
+        let bits: Vec<usize> = (0..samples)
+            .map(|i| bits_per_sample.get(i).or(bits_per_sample.last()).map(|&x| x as usize).unwrap_or(1))
+            .collect();
+        let planar = samples > 1 && PlanarConfiguration::from_u16(self.get_value(ifd, tag::PlanarConfiguration)?)? == PlanarConfiguration::Planar;
+        let planes = if planar { samples } else { 1 };
+
+        // the columns and rows of one chunk, and the number of chunks in a plane
+        let tiled = ifd.get(tag::TileWidth).is_some();
+        let (chunk, offsets, byte_counts) = if tiled {
+            let tile_width = self.get_value(ifd, tag::TileWidth)? as usize;
+            let tile_length = self.get_value(ifd, tag::TileLength)? as usize;
+            let chunk = match (tile_width, tile_length) {
+                (0, _) | (_, 0) => None,
+                _ => Some((tile_width, tile_length, width.div_ceil(tile_width).saturating_mul(height.div_ceil(tile_length)))),
+            };
+            let byte_counts = match ifd.get(tag::TileByteCounts) {
+                Some(_) => Some(self.get_value(ifd, tag::TileByteCounts)?),
+                None => None,
+            };
+            (chunk, self.get_value(ifd, tag::TileOffsets)?, byte_counts)
+        } else {
+            let rows_per_strip = match self.get_value(ifd, tag::RowsPerStrip)? as usize {
+                0 => height.max(1),
+                n => n,
+            };
+            let byte_counts = match ifd.get(tag::StripByteCounts) {
+                Some(_) => Some(self.get_value(ifd, tag::StripByteCounts)?),
+                None => None,
+            };
+            (Some((width, rows_per_strip, height.div_ceil(rows_per_strip))), self.get_value(ifd, tag::StripOffsets)?, byte_counts)
+        };
+
+        let mut problems = vec![];
+        if let Some((_, _, per_plane)) = chunk {
+            let expected = per_plane.saturating_mul(planes);
+            if offsets.len() != expected {
+                problems.push(ChunkProblem::CountMismatch { expected, actual: offsets.len() });
+            }
+        }
+        let byte_counts = match byte_counts {
+            Some(byte_counts) => byte_counts,
+            None => {
+                problems.push(ChunkProblem::MissingByteCounts);
+                return Ok(problems);
+            }
+        };
+        if byte_counts.len() != offsets.len() {
+            problems.push(ChunkProblem::ByteCountsMismatch { offsets: offsets.len(), byte_counts: byte_counts.len() });
+        }
+
+        // uncompressed chunks hold exactly their rows; subsampled YCbCr is left out
+        let sized = self.compression_with(ifd)? == Compression::No
+            && self.photometric_with(ifd)? != PhotometricInterpretation::YCbCr;
+        for (index, (&offset, &byte_count)) in offsets.iter().zip(&byte_counts).enumerate() {
+            if !offset.checked_add(byte_count).map(|end| end <= self.len).unwrap_or(false) {
+                problems.push(ChunkProblem::OutOfFile { index, offset, byte_count, len: self.len });
+            }
+            let (columns, rows, per_plane) = match chunk {
+                Some(chunk) if sized && index < chunk.2.saturating_mul(planes) => chunk,
+                _ => continue,
+            };
+            let bits_per_pixel = if planar { bits[index / per_plane] } else { bits.iter().sum() };
+            // the last strip of a plane stops at the bottom of the image
+            let rows = if tiled { rows } else { rows.min(height - index % per_plane * rows) };
+            let expected = (columns.saturating_mul(bits_per_pixel).div_ceil(8) as u64).saturating_mul(rows as u64);
+            if byte_count != expected {
+                problems.push(ChunkProblem::SizeMismatch { index, expected, actual: byte_count });
+            }
+        }
+
+        Ok(problems)
+    }
+
+    fn validate_palette(&mut self, ifd: &IFD, bits_per_sample: &BitsPerSample) -> DecodeResult<()> {
+        if bits_per_sample.len() != 1 {
+            return Err(DecodeError::from(DecodeErrorKind::PaletteSamplesPerPixel { samples_per_pixel: bits_per_sample.len() }));
+        }
+        let bits = bits_per_sample.bits();
+        if bits > 16 {
+            return Err(DecodeError::from(DecodeErrorKind::PaletteBitsPerSample { bits }));
+        }
+        let count = match ifd.get(tag::ColorMap) {
+            Some(entry) => entry.count() as usize,
+            None => return Err(DecodeError::from(DecodeErrorKind::PaletteWithoutColorMap)),
+        };
+        if count != 3 << bits {
+            return Err(DecodeError::from(DecodeErrorKind::PaletteColorMapLength { expected: 3 << bits, actual: count }));
+        }
+
+        Ok(())
+    }
+
+    pub fn header(&mut self) -> DecodeResult<ImageHeader> {
+        let ifd = self.ifd()?;
+        let current = self.current;
+
+        self.header_with(&ifd).map_err(|e| e.with_ifd(current))
+    }
+    
+    // Reads and decompresses the image, stored as strips or as tiles, into rows of
+    // `bits_per_pixel * width` bits padded to a whole byte. Only the rows of
+    // `window` (x, y, width, height) are returned, and tiles entirely left or right
+    // of it are skipped. Planar images are read plane by plane and interleaved
+    // back into pixels.
+    fn read_data(&mut self, ifd: &IFD, header: &ImageHeader, bits_per_pixel: usize, window: (usize, usize, usize, usize)) -> DecodeResult<Vec<u8>> {
+        let grid = Grid::pixels(header, bits_per_pixel, 1).window(window);
+        let mut buffer = vec![0; checked_mul(grid.row_bytes(grid.columns)?, grid.band.1, "image size")?];
+        self.read_data_into(ifd, header, bits_per_pixel, window, &mut buffer)?;
+
+        Ok(buffer)
+    }
+
+    // `read_data` into `buffer`, which must hold exactly the rows of `window`. Rows
+    // missing from the file are left as they are.
+    fn read_data_into(&mut self, ifd: &IFD, header: &ImageHeader, bits_per_pixel: usize, window: (usize, usize, usize, usize), buffer: &mut [u8]) -> DecodeResult<()> {
+        let planar_configuration = PlanarConfiguration::from_u16(self.get_value(ifd, tag::PlanarConfiguration)?)?;
+        let samples = header.bits_per_sample().len();
+        let grid = Grid::pixels(header, bits_per_pixel, samples).window(window);
+        if planar_configuration == PlanarConfiguration::Chunky || samples == 1 {
+            return self.read_plane_into(ifd, header, &grid, 0, buffer);
+        }
+
+        let sample_bits = bits_per_pixel / samples;
+        if !sample_bits.is_multiple_of(8) {
+            return Err(DecodeError::from(DecodeErrorKind::UnsupportedBitsPerSample { values: header.bits_per_sample().values() }));
+        }
+        let sample_bytes = sample_bits / 8;
+        let grid = Grid::pixels(header, sample_bits, 1).window(window);
+        for plane in 0..samples {
+            let data = self.read_plane(ifd, header, &grid, plane)?;
+            for (i, x) in data.chunks(sample_bytes).enumerate() {
+                let start = (i * samples + plane) * sample_bytes;
+                buffer[start..start + sample_bytes].copy_from_slice(x);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_plane(&mut self, ifd: &IFD, header: &ImageHeader, grid: &Grid, plane: usize) -> DecodeResult<Vec<u8>> {
+        let mut buffer = vec![0; checked_mul(grid.row_bytes(grid.columns)?, grid.band.1, "image size")?];
+        self.read_plane_into(ifd, header, grid, plane, &mut buffer)?;
+
+        Ok(buffer)
+    }
+
+    fn read_plane_into(&mut self, ifd: &IFD, header: &ImageHeader, grid: &Grid, plane: usize, buffer: &mut [u8]) -> DecodeResult<()> {
+        if ifd.get(tag::TileWidth).is_some() {
+            self.read_tiles(ifd, header, grid, plane, buffer)
+        } else {
+            self.read_strips(ifd, header, grid, plane, buffer)
+        }
+    }
+
+    // Reads and decompresses the strips of `plane` that overlap the grid's band
+    // into `buffer`, `row_bytes` for each row of the band.
+    fn read_strips(&mut self, ifd: &IFD, header: &ImageHeader, grid: &Grid, plane: usize, buffer: &mut [u8]) -> DecodeResult<()> {
+        let compression = header.compression();
+        let offsets = self.get_value(ifd, tag::StripOffsets)?;
+        let fill_order = FillOrder::from_u16(self.get_value(ifd, tag::FillOrder)?)?;
+        let predictor = Predictor::from_u16(self.get_value(ifd, tag::Predictor)?)?;
+        let height = grid.rows;
+        let row_bytes = grid.row_bytes(grid.columns)?;
+        let rows_per_strip = match self.get_value(ifd, tag::RowsPerStrip)? as usize {
+            0 => height,
+            n => n.div_ceil(grid.unit.1).min(height),
+        };
+
+        // the strips of each plane follow those of the one before
+        let strips = height.div_ceil(rows_per_strip.max(1));
+        let first = plane * strips;
+        let strip_byte_counts = match ifd.get(tag::StripByteCounts) {
+            // uncompressed strips hold exactly their rows
+            None if self.lenient && compression == Compression::No => {
+                self.warn(DecodeWarning::ReconstructedByteCounts { count: offsets.len() });
+                (0..offsets.len())
+                    .map(|strip| (rows_per_strip.min(height.saturating_sub(strip % strips.max(1) * rows_per_strip)) * row_bytes) as u64)
+                    .collect()
+            }
+            _ => self.get_value(ifd, tag::StripByteCounts)?,
+        };
+
+        let mut codec = self.codec(ifd, compression)?;
+        let max_len = self.limits.alloc.unwrap_or(usize::MAX);
+        let uncompressed = compression == Compression::No && !self.codecs.contains(compression.value());
+        let (band_top, band_rows) = grid.band;
+        for (strip, (offset, byte_count)) in offsets.into_iter().zip(strip_byte_counts).enumerate().skip(first) {
+            let top = (strip - first).saturating_mul(rows_per_strip);
+            let rows = rows_per_strip.min(height.saturating_sub(top));
+            if rows == 0 || top >= band_top + band_rows {
+                break;
+            }
+            if top + rows <= band_top {
+                continue;
+            }
+
+            let expected = rows * row_bytes;
+            if uncompressed && expected > STRIP_CHOP_BYTES && byte_count == expected as u64 {
+                let (from, to) = (top.max(band_top), (top + rows).min(band_top + band_rows));
+                let rows_at = (offset + ((from - top) * row_bytes) as u64, to - from);
+                let into = &mut buffer[(from - band_top) * row_bytes..(to - band_top) * row_bytes];
+                self.read_rows(rows_at, row_bytes, fill_order, predictor, grid.samples, header, into)
+                    .map_err(|e| e.with_tag(AnyTag::StripOffsets).with_offset(offset))?;
+                continue;
+            }
+            let data = self.read_chunk(offset, byte_count, fill_order)
+                .and_then(|raw| codec.decode_bytes_bounded(&raw, expected, max_len, predictor))
+                .and_then(|(mut data, surplus)| self.fit_strip(strip, &mut data, surplus, expected).map(|()| data))
+                .and_then(|mut data| self.unpredict(predictor, &mut data, row_bytes, grid.samples, header).map(|()| data))
+                .or_else(|e| self.salvage_chunk(strip, expected, e))
+                .map_err(|e| e.with_tag(AnyTag::StripOffsets).with_offset(offset))?;
+
+            // the rows the strip and the band share
+            let (from, to) = (top.max(band_top), (top + rows).min(band_top + band_rows));
+            buffer[(from - band_top) * row_bytes..(to - band_top) * row_bytes].copy_from_slice(&data[(from - top) * row_bytes..(to - top) * row_bytes]);
+        }
+
+        Ok(())
+    }
+
+    // Reads and decompresses every tile that overlaps the grid's band and span
+    // into place.
+    // Tiles are stored left to right, top to bottom, and always hold TileWidth x
+    // TileLength pixels; the parts that hang over the right and bottom edges are
+    // dropped.
+    fn read_tiles(&mut self, ifd: &IFD, header: &ImageHeader, grid: &Grid, plane: usize, buffer: &mut [u8]) -> DecodeResult<()> {
+        let compression = header.compression();
+        let tile_width = match self.get_value(ifd, tag::TileWidth)? as usize {
+            0 => return Err(DecodeError::from(DecodeErrorKind::UnsupportedData { tag: AnyTag::TileWidth, data: 0 })),
+            n => n.div_ceil(grid.unit.0),
+        };
+        let tile_length = match self.get_value(ifd, tag::TileLength)? as usize {
+            0 => return Err(DecodeError::from(DecodeErrorKind::UnsupportedData { tag: AnyTag::TileLength, data: 0 })),
+            n => n.div_ceil(grid.unit.1),
+        };
+        let offsets = self.get_value(ifd, tag::TileOffsets)?;
+        let tile_byte_counts = self.get_value(ifd, tag::TileByteCounts)?;
+        let fill_order = FillOrder::from_u16(self.get_value(ifd, tag::FillOrder)?)?;
+        let predictor = Predictor::from_u16(self.get_value(ifd, tag::Predictor)?)?;
+        let (width, height) = (grid.columns, grid.rows);
+        let row_bytes = grid.row_bytes(width)?;
+        let tile_row_bytes = grid.row_bytes(tile_width)?;
+        let tiles_across = width.div_ceil(tile_width);
+        let tiles = checked_mul(tiles_across, height.div_ceil(tile_length), "tile count")?;
+        let expected = checked_mul(tile_row_bytes, tile_length, "tile size")?;
+
+        let mut codec = self.codec(ifd, compression)?;
+        let max_len = self.limits.alloc.unwrap_or(usize::MAX);
+        let (band_top, band_rows) = grid.band;
+        let (span_left, span_columns) = grid.span;
+        let first = checked_mul(plane, tiles, "tile index")?;
+        for (tile, (offset, byte_count)) in offsets.into_iter().zip(tile_byte_counts).enumerate().skip(first).take(tiles) {
+            let top = ((tile - first) / tiles_across) * tile_length;
+            if top >= band_top + band_rows || top + tile_length <= band_top {
+                continue;
+            }
+            let column = ((tile - first) % tiles_across) * tile_width;
+            if column >= span_left + span_columns || column + tile_width <= span_left {
+                continue;
+            }
+
+            let data = self.read_chunk(offset, byte_count, fill_order)
+                .and_then(|raw| codec.decode_bytes_bounded(&raw, expected, max_len, predictor))
+                .and_then(|(mut data, surplus)| self.fit_strip(tile, &mut data, surplus, expected).map(|()| data))
+                .and_then(|mut data| self.unpredict(predictor, &mut data, tile_row_bytes, grid.samples, header).map(|()| data))
+                .or_else(|e| self.salvage_chunk(tile, expected, e))
+                .map_err(|e| e.with_tag(AnyTag::TileOffsets).with_offset(offset))?;
+
+            // TileWidth is a multiple of 16, so tiles start on a byte boundary
+            let left = column * grid.bits / 8;
+            let len = tile_row_bytes.min(row_bytes - left);
+            for (y, row) in (top..height).zip(data.chunks(tile_row_bytes)) {
+                if y < band_top || y >= band_top + band_rows {
+                    continue;
+                }
+                let start = (y - band_top) * row_bytes + left;
+                buffer[start..start + len].copy_from_slice(&row[..len]);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Undoes the differencing a predictor applied to the rows of a strip or tile.
+    fn unpredict(&self, predictor: Predictor, data: &mut [u8], row_bytes: usize, samples: usize, header: &ImageHeader) -> DecodeResult<()> {
+        let bits_per_sample = header.bits_per_sample();
+        match predictor {
+            Predictor::No => Ok(()),
+            Predictor::Horizontal if bits_per_sample.is_uniform() && [8, 16, 32, 64].contains(&bits_per_sample.bits()) => {
+                codec::undo_horizontal_predictor(data, row_bytes, samples, bits_per_sample.bits() / 8, self.endian);
+                Ok(())
+            }
+            Predictor::FloatingPoint if header.sample_format() == SampleFormat::Float && bits_per_sample.is_uniform() && [16, 32, 64].contains(&bits_per_sample.bits()) => {
+                codec::undo_floating_point_predictor(data, row_bytes, samples, bits_per_sample.bits() / 8, self.endian);
+                Ok(())
+            }
+            _ => Err(DecodeError::from(DecodeErrorKind::UnsupportedPredictor { predictor: predictor.value(), bits_per_sample: bits_per_sample.values() })),
+        }
+    }
+
+    // Reads `rows` uncompressed rows of `row_bytes` bytes at `offset` into `buffer`
+    // a few at a time, so that a huge strip is never held whole, and neither are
+    // the rows of it outside the band.
+    #[allow(clippy::too_many_arguments)]
+    fn read_rows(&mut self, (offset, rows): (u64, usize), row_bytes: usize, fill_order: FillOrder, predictor: Predictor, samples: usize, header: &ImageHeader, buffer: &mut [u8]) -> DecodeResult<()> {
+        let chunk_rows = (STRIP_CHOP_BYTES / row_bytes.max(1)).max(1);
+        for (i, into) in buffer.chunks_mut(chunk_rows * row_bytes).enumerate().take(rows.div_ceil(chunk_rows)) {
+            let mut data = self.read_chunk(offset + (i * chunk_rows * row_bytes) as u64, into.len() as u64, fill_order)?;
+            // only a salvaging decoder reads fewer bytes than asked
+            data.resize(into.len(), 0);
+            self.unpredict(predictor, &mut data, row_bytes, samples, header)?;
+            into.copy_from_slice(&data);
+        }
+
+        Ok(())
+    }
+
+    fn read_chunk(&mut self, offset: u64, byte_count: u64, fill_order: FillOrder) -> DecodeResult<Vec<u8>> {
+        check_limit("strip or tile size", byte_count, self.limits.alloc.map(|x| x as u64))?;
+        let mut byte_count = byte_count;
+        if !offset.checked_add(byte_count).map(|end| end <= self.len).unwrap_or(false) {
+            if !self.salvage {
+                return Err(DecodeError::from(DecodeErrorKind::InvalidChunkOffset { offset, byte_count }));
+            }
+            let read = self.len.saturating_sub(offset);
+            self.warn(DecodeWarning::ChunkTruncated { offset, byte_count, read });
+            byte_count = read;
+        }
+        if byte_count == 0 {
+            return Ok(vec![]);
+        }
+        self.reader.goto(offset)?;
+        let mut raw = self.reader.read_bytes(byte_count as usize)?;
+        // FillOrder 2 stores each byte of the bitstream reversed, whatever the codec.
+        if fill_order == FillOrder::LowToHigh {
+            for x in raw.iter_mut() {
+                *x = x.reverse_bits();
+            }
+        }
+
+        Ok(raw)
+    }
+
+    fn codec(&mut self, ifd: &IFD, compression: Compression) -> DecodeResult<Box<dyn DecodeBytes>> {
+        if let Some(codec) = self.codecs.get(compression.value()) {
+            return Ok(codec);
+        }
+
+        match compression {
+            Compression::No => Ok(Box::new(Uncompressed)),
+            Compression::CCITTRLE | Compression::CCITTFax3 | Compression::CCITTFax4 => {
+                let width = match ifd.get(tag::TileWidth) {
+                    Some(_) => self.get_value(ifd, tag::TileWidth)?,
+                    None => self.get_value(ifd, tag::ImageWidth)?,
+                };
+                let options = match compression {
+                    Compression::CCITTFax3 => self.get_value(ifd, tag::T4Options)?,
+                    Compression::CCITTFax4 => self.get_value(ifd, tag::T6Options)?,
+                    _ => 0,
+                };
+
+                Ok(Box::new(CCITTDecoder::new(compression, width, options)))
+            }
+            Compression::LZW => Ok(Box::new(LZWDecoder)),
+            Compression::PackBits => Ok(Box::new(PackBitsDecoder)),
+            Compression::Deflate | Compression::OldDeflate => Ok(Box::new(DeflateDecoder)),
+            Compression::JPEG => {
+                let tables = match ifd.get(tag::JPEGTables) {
+                    Some(_) => Some(self.get_value(ifd, tag::JPEGTables)?),
+                    None => None,
+                };
+                let interpretation = self.photometric_with(ifd)?;
+
+                Ok(Box::new(JpegDecoder::new(tables, interpretation)))
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Ok(Box::new(ZstdDecoder)),
+            Compression::Unknown(n) => Err(DecodeError::from(DecodeErrorKind::UnsupportedData { tag: AnyTag::Compression, data: n as u32 })),
+        }
+    }
+
+    // A strip or tile that decompressed to more than its rows, `surplus` bytes the
+    // codec didn't keep, is truncated, one that came up short is zero-filled. Both
+    // are errors unless the decoder is lenient.
+    fn fit_strip(&mut self, strip: usize, data: &mut Vec<u8>, surplus: usize, expected: usize) -> DecodeResult<()> {
+        let actual = data.len() + surplus;
+        if actual == expected {
+            return Ok(());
+        }
+        if !self.lenient {
+            return Err(DecodeError::from(DecodeErrorKind::StripLengthMismatch { strip, expected, actual }));
+        }
+
+        let warning = if actual > expected {
+            DecodeWarning::StripTruncated { strip, surplus: actual - expected }
+        } else {
+            DecodeWarning::StripZeroFilled { strip, missing: expected - actual }
+        };
+        self.warnings.push(warning);
+        data.resize(expected, 0);
+
+        Ok(())
+    }
+
+    // A salvaging decoder zero-fills the strip or tile `chunk` that failed with
+    // `e`, unless a limit was hit.
+    fn salvage_chunk(&mut self, chunk: usize, expected: usize, e: DecodeError) -> DecodeResult<Vec<u8>> {
+        if !self.salvage || e.kind().category() == DecodeErrorCategory::Limit {
+            return Err(e);
+        }
+        self.warn(DecodeWarning::ChunkSkipped { chunk, reason: e.to_string() });
+
+        Ok(vec![0; expected])
+    }
+
+    pub fn image_with(&mut self, ifd: &IFD) -> DecodeResult<Image> {
+        let image = self.region_with(ifd, None)?;
+        let image = self.converted(ifd, image)?;
+        if !self.apply_orientation {
+            return Ok(image);
+        }
+        match self.orientation_with(ifd)? {
+            Orientation::TopLeft => Ok(image),
+            orientation => Ok(image.oriented(orientation)),
+        }
+    }
+
+    // The resolutions of the current image, largest first: the image itself, then
+    // its reduced-resolution copies, whether they are SubIFDs of it (as in OME-TIFF)
+    // or follow it in the chain (as in Cloud Optimized GeoTIFF).
+    pub fn levels(&mut self) -> DecodeResult<Vec<Level>> {
+        let (ifd, mut next) = self.read_ifd(self.current)?;
+        let width = self.get_value(&ifd, tag::ImageWidth)?;
+        let current = self.current;
+        let mut levels = vec![self.level_with(current, &ifd, width)?];
+
+        let sub_ifds = self.sub_ifds()?;
+        if !sub_ifds.is_empty() {
+            next = 0;
+        }
+        for offset in sub_ifds {
+            let (ifd, _) = self.read_ifd(offset)?;
+            if self.page_kind_with(&ifd)? == PageKind::ReducedResolution {
+                levels.push(self.level_with(offset, &ifd, width)?);
+            }
+        }
+        // overviews and their masks, up to the next page
+        let mut visited = HashSet::new();
+        while next != 0 && visited.insert(next) {
+            let (ifd, after) = self.read_ifd(next)?;
+            match self.page_kind_with(&ifd)? {
+                PageKind::ReducedResolution => levels.push(self.level_with(next, &ifd, width)?),
+                PageKind::Mask => {},
+                PageKind::Full | PageKind::Page => break,
+            }
+            next = after;
+        }
+        levels.sort_by_key(|x| Reverse(x.width));
+
+        Ok(levels)
+    }
+
+    // The level at `offset`, of an image `full_width` pixels wide.
+    fn level_with(&mut self, offset: u64, ifd: &IFD, full_width: u32) -> DecodeResult<Level> {
+        let width = self.get_value(ifd, tag::ImageWidth)?;
+        Ok(Level {
+            offset,
+            width,
+            height: self.get_value(ifd, tag::ImageLength)?,
+            downsample: full_width as f64 / width.max(1) as f64,
+        })
+    }
+
+    // Decodes the `width` x `height` pixels of `level` (an index into `levels`)
+    // whose top left corner is at (`x`, `y`) of the full-resolution image, as
+    // OpenSlide does. The current IFD is left as it is. Like `read_region`, the
+    // region is not turned by `apply_orientation`.
+    pub fn read_region_at_level(&mut self, level: usize, x: u32, y: u32, width: u32, height: u32) -> DecodeResult<Image> {
+        let levels = self.levels()?;
+        let level = *levels.get(level)
+            .ok_or(DecodeError::from(DecodeErrorKind::IFDIndexOutOfRange { index: level, count: levels.len() }))?;
+        let (ifd, _) = self.read_ifd(level.offset)?;
+        let x = (x as f64 / level.downsample) as usize;
+        let y = (y as f64 / level.downsample) as usize;
+        let region = (x, y, width as usize, height as usize);
+        self.region_with(&ifd, Some(region))
+            .and_then(|image| self.converted(&ifd, image))
+            .map_err(|e| e.with_ifd(level.offset))
+    }
+
+    // Decodes the `width` x `height` pixels at (`x`, `y`) of the current image,
+    // reading only the strips or tiles that overlap them. The coordinates are those
+    // of the image as it is stored: `apply_orientation` turns whole images only,
+    // so a region of a file with an Orientation other than TopLeft comes out
+    // unturned.
+    pub fn read_region(&mut self, x: u32, y: u32, width: u32, height: u32) -> DecodeResult<Image> {
+        let ifd = self.ifd()?;
+        let current = self.current;
+        let region = (x as usize, y as usize, width as usize, height as usize);
+        self.region_with(&ifd, Some(region))
+            .and_then(|image| self.converted(&ifd, image))
+            .map_err(|e| e.with_ifd(current))
+    }
+
+    // Applies the `cmyk_to_rgb`, `lab_to_rgb` and `convert_alpha` options.
+    fn converted(&mut self, ifd: &IFD, image: Image) -> DecodeResult<Image> {
+        let image = if self.cmyk_to_rgb { self.cmyk_converted(ifd, image)? } else { image };
+        let image = match self.lab_to_rgb {
+            Some(white) => image.lab_to_rgb(white),
+            None => image,
+        };
+        match self.convert_alpha {
+            Some(alpha) => Ok(image.convert_alpha(alpha)),
+            None => Ok(image),
+        }
+    }
+
+    #[cfg(feature = "icc")]
+    fn cmyk_converted(&mut self, ifd: &IFD, image: Image) -> DecodeResult<Image> {
+        if image.header().photometric_interpretation() != PhotometricInterpretation::CMYK {
+            return Ok(image);
+        }
+        match self.get_optional_value(ifd, tag::ICCProfile)? {
+            Some(profile) => image.cmyk_to_rgb_with_profile(&profile),
+            None => Ok(image.cmyk_to_rgb()),
+        }
+    }
+
+    #[cfg(not(feature = "icc"))]
+    fn cmyk_converted(&mut self, _: &IFD, image: Image) -> DecodeResult<Image> {
+        Ok(image.cmyk_to_rgb())
+    }
+
+    // `region` is (x, y, width, height), the whole image if `None`.
+    fn region_with(&mut self, ifd: &IFD, region: Option<(usize, usize, usize, usize)>) -> DecodeResult<Image> {
+        let header = self.header_with(ifd)?;
+        let width = header.width() as usize;
+        let height = header.height() as usize;
+        let region = match region {
+            Some((x, y, w, h)) if x.saturating_add(w) > width || y.saturating_add(h) > height => {
+                return Err(DecodeError::from(DecodeErrorKind::RegionOutOfBounds { x: x as u32, y: y as u32, width: w as u32, height: h as u32, image_width: width as u32, image_height: height as u32 }));
+            }
+            Some(region) => region,
+            None => (0, 0, width, height),
+        };
+        let (x, _, region_width, region_height) = region;
+        let bits_per_sample = header.bits_per_sample().clone();
+        let samples = bits_per_sample.len();
+        if header.photometric_interpretation() == PhotometricInterpretation::TransparencyMask {
+            let packed = self.read_data(ifd, &header, 1, region)?;
+            let data = ImageData::Mask(image::unpack_mask(&packed, width, region_height));
+            let data = image::crop(data, width, 1, (x, 0, region_width, region_height));
+
+            return Ok(Image::new(header.with_dimensions(region_width as u32, region_height as u32), data));
+        }
+
+        let stored = self.photometric_with(ifd)?;
+        if stored == PhotometricInterpretation::YCbCr && header.compression() != Compression::JPEG {
+            return self.ycbcr_image(ifd, header, region);
+        }
+
+        let bits = bits_per_sample.bits();
+        let endian = self.endian;
+        let sample_format = match header.sample_format() {
+            SampleFormat::Void => SampleFormat::Unsigned,
+            sample_format => sample_format,
+        };
+        let data = match (sample_format, bits) {
+            (SampleFormat::Unsigned, _) if !bits_per_sample.is_uniform() => self.mixed_samples(ifd, &header, region)?,
+            (_, _) if !bits_per_sample.is_uniform() => return Err(DecodeError::from(DecodeErrorKind::UnsupportedBitsPerSample { values: bits_per_sample.values() })),
+            (SampleFormat::Unsigned, 1) | (SampleFormat::Unsigned, 2) | (SampleFormat::Unsigned, 4) => {
+                let packed = self.read_data(ifd, &header, samples * bits, region)?;
+                ImageData::U8(image::unpack_samples(&packed, width * samples, region_height, bits))
+            }
+            (SampleFormat::Unsigned, 8) => ImageData::U8(self.read_data(ifd, &header, samples * 8, region)?),
+            (SampleFormat::Unsigned, 16) => ImageData::U16(u16_samples(&self.read_data(ifd, &header, samples * 16, region)?, endian)),
+            (SampleFormat::Signed, 16) => ImageData::I16(u16_samples(&self.read_data(ifd, &header, samples * 16, region)?, endian).into_iter().map(|x| x as i16).collect()),
+            (SampleFormat::Signed, 32) => ImageData::I32(u32_samples(&self.read_data(ifd, &header, samples * 32, region)?, endian).into_iter().map(|x| x as i32).collect()),
+            (SampleFormat::Float, 32) => ImageData::F32(u32_samples(&self.read_data(ifd, &header, samples * 32, region)?, endian).into_iter().map(f32::from_bits).collect()),
+            (SampleFormat::Float, 64) => ImageData::F64(u64_samples(&self.read_data(ifd, &header, samples * 64, region)?, endian).into_iter().map(f64::from_bits).collect()),
+            (SampleFormat::Unsigned, _) => return Err(DecodeError::from(DecodeErrorKind::UnsupportedBitsPerSample { values: bits_per_sample.values() })),
+            (sample_format, _) => return Err(DecodeError::from(DecodeErrorKind::UnsupportedSampleFormat { sample_format: sample_format.value(), bits_per_sample: bits_per_sample.values() })),
+        };
+        let mut data = image::crop(data, width, samples, (x, 0, region_width, region_height));
+        let header = header.with_dimensions(region_width as u32, region_height as u32);
+
+        // WhiteIsZero is normalized to black-is-zero. Only the gray sample is
+        // inverted, an alpha channel that follows it is left as it is. Signed
+        // samples swap their minimum and maximum, floats have no fixed range and
+        // are left alone.
+        if header.photometric_interpretation() == PhotometricInterpretation::WhiteIsZero {
+            image::invert_gray(&mut data, bits_per_sample.values()[0], samples);
+        }
+
+        if self.expand_palette && header.photometric_interpretation() == PhotometricInterpretation::Palette {
+            return self.expand_palette(ifd, &header, &data);
+        }
+        
+        Ok(Image::new(header, data))
+    }
+    
+    // Reads unsigned samples of different sizes, such as 8-bit RGB with a 1-bit
+    // alpha channel. Each keeps its own range, in bytes if none is wider than 8
+    // bits and in `u16`s otherwise.
+    fn mixed_samples(&mut self, ifd: &IFD, header: &ImageHeader, region: (usize, usize, usize, usize)) -> DecodeResult<ImageData> {
+        let values = header.bits_per_sample().values();
+        if header.bits_per_sample().max_bits() > 16 {
+            return Err(DecodeError::from(DecodeErrorKind::UnsupportedBitsPerSample { values }));
+        }
+        let samples = values.len();
+        let width = header.width() as usize;
+        let (_, _, _, region_height) = region;
+        let unpacked = match PlanarConfiguration::from_u16(self.get_value(ifd, tag::PlanarConfiguration)?)? {
+            PlanarConfiguration::Chunky => {
+                let packed = self.read_data(ifd, header, values.iter().map(|&x| x as usize).sum(), region)?;
+                image::unpack_fields(&packed, &values, width * samples, region_height)
+            }
+            PlanarConfiguration::Planar => {
+                let mut unpacked = vec![0; width * samples * region_height];
+                for (plane, &bits) in values.iter().enumerate() {
+                    let grid = Grid::pixels(header, bits as usize, 1).window(region);
+                    let data = self.read_plane(ifd, header, &grid, plane)?;
+                    for (i, x) in image::unpack_fields(&data, &[bits], width, region_height).into_iter().enumerate() {
+                        unpacked[i * samples + plane] = x;
+                    }
+                }
+                unpacked
+            }
+        };
+
+        if header.bits_per_sample().max_bits() <= 8 {
+            Ok(ImageData::U8(unpacked.into_iter().map(|x| x as u8).collect()))
+        } else {
+            Ok(ImageData::U16(unpacked))
+        }
+    }
+
+    // Reads 8-bit YCbCr, upsampling it if YCbCrSubSampling says it was
+    // subsampled, and converts it to RGB.
+    fn ycbcr_image(&mut self, ifd: &IFD, header: ImageHeader, region: (usize, usize, usize, usize)) -> DecodeResult<Image> {
+        let bits_per_sample = header.bits_per_sample();
+        if !bits_per_sample.is_uniform() || bits_per_sample.bits() != 8 || header.sample_format() != SampleFormat::Unsigned {
+            return Err(DecodeError::from(DecodeErrorKind::UnsupportedBitsPerSample { values: bits_per_sample.values() }));
+        }
+        let samples = header.samples_per_pixel();
+        let (width, height) = (header.width() as usize, header.height() as usize);
+        let subsampling = self.get_value(ifd, tag::YCbCrSubSampling)?;
+        let (h, v) = match subsampling[..] {
+            [h, v] if [1, 2, 4].contains(&h) && [1, 2, 4].contains(&v) => (h as usize, v as usize),
+            _ => return Err(DecodeError::from(DecodeErrorKind::UnsupportedMultipleData { tag: AnyTag::YCbCrSubSampling, data: subsampling.iter().map(|x| *x as u32).collect() })),
+        };
+
+        let (x, y, region_width, region_height) = region;
+        let (top, data) = if (h, v) == (1, 1) {
+            (y, self.read_data(ifd, &header, samples * 8, region)?)
+        } else {
+            // blocks hold exactly Y, Cb and Cr, and always interleave them
+            if samples != 3 {
+                return Err(DecodeError::from(DecodeErrorKind::UnsupportedData { tag: AnyTag::SamplesPerPixel, data: samples as u32 }));
+            }
+            if PlanarConfiguration::from_u16(self.get_value(ifd, tag::PlanarConfiguration)?)? == PlanarConfiguration::Planar {
+                return Err(DecodeError::from(DecodeErrorKind::UnsupportedData { tag: AnyTag::PlanarConfiguration, data: 2 }));
+            }
+            // whole rows of blocks, from the one holding row `y`
+            let (first, last) = (y / v, (y + region_height).div_ceil(v));
+            let grid = Grid {
+                columns: width.div_ceil(h),
+                rows: height.div_ceil(v),
+                bits: (h * v + 2) * 8,
+                samples: h * v + 2,
+                unit: (h, v),
+                span: (x / h, (x + region_width).div_ceil(h) - x / h),
+                band: (first, last - first),
+            };
+            let units = self.read_plane(ifd, &header, &grid, 0)?;
+            (first * v, image::upsample_ycbcr(&units, width, (last * v).min(height) - first * v, h, v))
+        };
+        let mut data = image::crop_samples(data, width, samples, (x, y - top, region_width, region_height));
+        let header = header.with_dimensions(region_width as u32, region_height as u32);
+
+        let coefficients = self.get_value(ifd, tag::YCbCrCoefficients)?;
+        let reference = match ifd.get(tag::ReferenceBlackWhite) {
+            Some(_) => self.get_value(ifd, tag::ReferenceBlackWhite)?,
+            None => [0, 255, 128, 255, 128, 255].iter().map(|x| Rational::new(*x, 1)).collect(),
+        };
+        let (luma, reference) = match (rational_array(&coefficients), rational_array(&reference)) {
+            (Some(luma), Some(reference)) => (luma, reference),
+            (None, _) => return Err(DecodeError::from(DecodeErrorKind::UnsupportedData { tag: AnyTag::YCbCrCoefficients, data: coefficients.len() as u32 })),
+            (_, None) => return Err(DecodeError::from(DecodeErrorKind::UnsupportedData { tag: AnyTag::ReferenceBlackWhite, data: reference.len() as u32 })),
+        };
+        image::ycbcr_to_rgb(&mut data, samples, luma, reference);
+
+        Ok(Image::new(header, ImageData::U8(data)))
+    }
+
+    // Looks every index up in ColorMap, which holds all the red values, then all
+    // the green and then all the blue, 2^BitsPerSample of each.
+    fn expand_palette(&mut self, ifd: &IFD, header: &ImageHeader, data: &ImageData) -> DecodeResult<Image> {
+        let color_map = self.get_value(ifd, tag::ColorMap)?;
+        let colors = color_map.len() / 3;
+        let indices: Vec<usize> = match *data {
+            ImageData::U8(ref data) => data.iter().map(|x| *x as usize).collect(),
+            ImageData::U16(ref data) => data.iter().map(|x| *x as usize).collect(),
+            _ => return Err(DecodeError::from(DecodeErrorKind::PaletteBitsPerSample { bits: header.bits_per_sample().bits() })),
+        };
+        let mut rgb = Vec::with_capacity(indices.len() * 3);
+        for index in indices {
+            rgb.push(color_map[index]);
+            rgb.push(color_map[colors + index]);
+            rgb.push(color_map[colors * 2 + index]);
+        }
+
+        let mut rgb_header = ImageHeader::new(header.width(), header.height(), header.compression(), PhotometricInterpretation::RGB, BitsPerSample::U16_3, vec![])?;
+        if let Some(resolution) = header.resolution() {
+            rgb_header = rgb_header.with_resolution(resolution);
+        }
+
+        Ok(Image::new(rgb_header, ImageData::U16(rgb)))
+    }
+
+    pub fn image(&mut self) -> DecodeResult<Image> {
+        let ifd = self.ifd()?;
+        let current = self.current;
+        self.image_with(&ifd).map_err(|e| e.with_ifd(current))
+    }
+
+    // The samples of the current image converted to `T`, one of `u8`, `u16` or
+    // `f32`, whatever its bit depth and sample format. See `Sample`. Palette
+    // indices are scaled like any other sample, so palette images should be read
+    // with `expand_palette`.
+    pub fn read_image_as<T: Sample>(&mut self) -> DecodeResult<Vec<T>> {
+        Ok(self.image()?.samples())
+    }
+
+    // The size in bytes of the buffer `read_image_into` needs for the current image.
+    pub fn image_buffer_size(&mut self) -> DecodeResult<usize> {
+        let ifd = self.ifd()?;
+        let header = self.header_with(&ifd)?;
+        self.buffer_size(&header)
+    }
+
+    // Decodes the current image into `buffer`, which must be exactly
+    // `image_buffer_size` bytes, and returns its header. The samples are those
+    // `image` would return, each in native byte order. Images that are stored the
+    // way they are returned are decoded straight into `buffer`.
+    pub fn read_image_into(&mut self, buffer: &mut [u8]) -> DecodeResult<ImageHeader> {
+        let ifd = self.ifd()?;
+        let current = self.current;
+        self.image_into(&ifd, buffer).map_err(|e| e.with_ifd(current))
+    }
+
+    fn buffer_size(&self, header: &ImageHeader) -> DecodeResult<usize> {
+        let pixel_bytes = match header.photometric_interpretation() {
+            PhotometricInterpretation::Palette if self.expand_palette => 3 * 2,
+            PhotometricInterpretation::CMYK if self.cmyk_to_rgb => (header.bits_per_sample().len() - 1) * header.bits_per_sample().max_bits().div_ceil(8),
+            PhotometricInterpretation::TransparencyMask => 1,
+            _ => header.bits_per_sample().len() * header.bits_per_sample().max_bits().div_ceil(8),
+        };
+        let pixels = checked_mul(header.width() as usize, header.height() as usize, "image size")?;
+
+        checked_mul(pixels, pixel_bytes, "image size")
+    }
+
+    fn image_into(&mut self, ifd: &IFD, buffer: &mut [u8]) -> DecodeResult<ImageHeader> {
+        let header = self.header_with(ifd)?;
+        let size = self.buffer_size(&header)?;
+        if buffer.len() != size {
+            return Err(DecodeError::from(DecodeErrorKind::IncorrectBufferSize { calc: size, sum: buffer.len() }));
+        }
+
+        if !self.is_direct(ifd, &header)? {
+            let image = self.image_with(ifd)?;
+            image::write_native(image.data(), buffer);
+            return Ok(image.header().clone());
+        }
+
+        // strips or tiles the file doesn't have stay zero, as in `image`
+        for x in buffer.iter_mut() {
+            *x = 0;
+        }
+        let bits_per_sample = header.bits_per_sample();
+        let bits = bits_per_sample.bits();
+        let samples = bits_per_sample.len();
+        self.read_data_into(ifd, &header, samples * bits, (0, 0, header.width() as usize, header.height() as usize), buffer)?;
+        let sample_bytes = bits / 8;
+        if sample_bytes > 1 && self.endian != Endian::native() {
+            buffer.chunks_mut(sample_bytes).for_each(|x| x.reverse());
+        }
+        // integer samples of the full bit depth are inverted by flipping their bits
+        if header.photometric_interpretation() == PhotometricInterpretation::WhiteIsZero && header.sample_format() != SampleFormat::Float {
+            for sample in buffer.chunks_mut(sample_bytes).step_by(samples) {
+                sample.iter_mut().for_each(|x| *x = !*x);
+            }
+        }
+
+        Ok(header)
+    }
+
+    // Whether the samples `image` returns are the decompressed ones as they are,
+    // but for their byte order and the inversion of WhiteIsZero.
+    fn is_direct(&mut self, ifd: &IFD, header: &ImageHeader) -> DecodeResult<bool> {
+        let bits_per_sample = header.bits_per_sample();
+        let ycbcr = self.photometric_with(ifd)? == PhotometricInterpretation::YCbCr && header.compression() != Compression::JPEG;
+        let whole_bytes = matches!(
+            (header.sample_format(), bits_per_sample.bits()),
+            (SampleFormat::Unsigned, 8) | (SampleFormat::Void, 8)
+                | (SampleFormat::Unsigned, 16) | (SampleFormat::Void, 16) | (SampleFormat::Signed, 16)
+                | (SampleFormat::Signed, 32) | (SampleFormat::Float, 32) | (SampleFormat::Float, 64)
+        );
+        let turned = self.apply_orientation && self.orientation_with(ifd)? != Orientation::TopLeft;
+        let converted = match (self.convert_alpha, header.alpha()) {
+            (Some(alpha), Some(current)) => alpha != ExtraSample::Unspecified && alpha != current,
+            _ => false,
+        };
+        let direct = bits_per_sample.is_uniform() && whole_bytes && !ycbcr && !turned && !converted
+            && match header.photometric_interpretation() {
+                PhotometricInterpretation::Palette => !self.expand_palette,
+                PhotometricInterpretation::CMYK => !self.cmyk_to_rgb,
+                PhotometricInterpretation::CIELab => self.lab_to_rgb.is_none(),
+                PhotometricInterpretation::TransparencyMask => false,
+                _ => true,
+            };
+
+        Ok(direct)
+    }
+
+    // Looks for a TransparencyMask IFD (NewSubfileType bit 2) with the same
+    // dimensions as `page`, preferring the pages that follow it.
+    pub fn find_mask_for(&mut self, page: usize) -> DecodeResult<Option<usize>> {
+        let offsets = self.ifd_offsets()?;
+        let (ifd, _) = match offsets.get(page) {
+            Some(offset) => self.read_ifd(*offset)?,
+            None => return Err(DecodeError::from(DecodeErrorKind::IFDIndexOutOfRange { index: page, count: offsets.len() })),
+        };
+        let width = self.get_value(&ifd, tag::ImageWidth)?;
+        let height = self.get_value(&ifd, tag::ImageLength)?;
+
+        let candidates = (page + 1..offsets.len()).chain(0..page);
+        for index in candidates {
+            let (ifd, _) = self.read_ifd(offsets[index])?;
+            let is_mask = self.get_value(&ifd, tag::NewSubfileType).map(|x| x & 4 != 0).unwrap_or(false)
+                && self.get_value(&ifd, tag::PhotometricInterpretation).ok() == Some(4);
+            if is_mask
+                && self.get_value(&ifd, tag::ImageWidth).ok() == Some(width)
+                && self.get_value(&ifd, tag::ImageLength).ok() == Some(height)
+            {
+                return Ok(Some(index));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Decodes the current page and attaches its transparency mask, if the file has one.
+    pub fn image_with_mask(&mut self) -> DecodeResult<Image> {
+        let image = self.image()?;
+        let page = match self.current_ifd_index() {
+            Some(page) => page,
+            None => return Ok(image),
+        };
+        match self.find_mask_for(page)? {
+            Some(index) => {
+                let offsets = self.ifd_offsets()?;
+                let (ifd, _) = self.read_ifd(offsets[index])?;
+                let mask = self.image_with(&ifd)?;
+
+                Ok(image.with_mask(mask))
+            }
+            None => Ok(image),
+        }
+    }
+} 
+
+impl<R> Decoder<R> where R: Read + Write + Seek {
+    // Saves the changes made through `ifd_mut` to the current IFD. The new IFD and
+    // the values that don't fit in it are appended to the file, and the header or
+    // the previous IFD of the chain is pointed at it; the old one is left behind
+    // unreferenced, its values in place for the entries that still use them. For an
+    // IFD outside the chain, such as an Exif IFD, the parent's entry has to be
+    // pointed at the offset returned, which becomes the current IFD.
+    pub fn write_ifd(&mut self) -> DecodeResult<u64> {
+        let (old, ifd) = match self.edit.take() {
+            Some(edit) if edit.0 == self.current => edit,
+            edit => {
+                self.edit = edit;
+                return Ok(self.current);
+            }
+        };
+        let (_, next) = self.read_ifd(old)?;
+        let big_tiff = self.header.is_big_tiff();
+        let field_len = if big_tiff { 8 } else { 4 };
+
+        self.reader.seek(io::SeekFrom::End(0))?;
+        let mut entries = Vec::with_capacity(ifd.len());
+        for (tag, entry) in ifd.iter() {
+            let field = match entry.values() {
+                Some(values) => {
+                    let mut bytes = values.to_bytes(self.endian);
+                    if bytes.len() > field_len {
+                        let at = self.reader.align_to_word()?;
+                        self.reader.write_all(&bytes)?;
+                        bytes = self.offset_field(at)?;
+                    }
+                    bytes.resize(field_len, 0);
+                    bytes
+                }
+                None => entry.offset().to_vec(),
+            };
+            entries.push((tag, entry.datatype(), entry.count(), field));
+        }
+
+        let at = self.reader.align_to_word()?;
+        let count = entries.len() as u64;
+        if big_tiff {
+            self.reader.write_u64(count, self.endian)?;
+        } else {
+            self.reader.write_u16(count as u16, self.endian)?;
+        }
+        for (tag, datatype, count, field) in entries {
+            self.reader.write_u16(tag.id(), self.endian)?;
+            self.reader.write_u16(datatype.value(), self.endian)?;
+            if big_tiff {
+                self.reader.write_u64(count, self.endian)?;
+            } else {
+                self.reader.write_u32(count as u32, self.endian)?;
+            }
+            self.reader.write_all(&field)?;
+        }
+        let field = self.offset_field(next)?;
+        self.reader.write_all(&field)?;
+        self.len = self.reader.stream_position()?;
+
+        self.link_ifd(old, at)?;
+        self.reader.flush()?;
+        self.current = at;
+
+        Ok(at)
+    }
+
+    // Points whatever links to the IFD at `old` in the chain at `new` instead.
+    fn link_ifd(&mut self, old: u64, new: u64) -> DecodeResult<()> {
+        let big_tiff = self.header.is_big_tiff();
+        let pointer = if old == self.start {
+            self.start = new;
+            self.header = self.header.with_first_ifd_offset(new);
+            Some(if big_tiff { 8 } else { 4 })
+        } else {
+            let mut visited = HashSet::new();
+            let mut at = self.start;
+            let mut pointer = None;
+            while at != 0 && visited.insert(at) {
+                let (_, next) = self.read_ifd(at)?;
+                if next == old {
+                    pointer = Some(self.next_ifd_pointer(at)?);
+                    break;
+                }
+                at = next;
+            }
+            pointer
+        };
+        if self.next == old {
+            self.next = new;
+        }
+
+        if let Some(pointer) = pointer {
+            let field = self.offset_field(new)?;
+            self.reader.goto(pointer)?;
+            self.reader.write_all(&field)?;
+        }
+
+        Ok(())
+    }
+
+    // Where the IFD at `offset` keeps the offset of the next one: after the
+    // count and the entries.
+    pub fn next_ifd_pointer(&mut self, offset: u64) -> DecodeResult<u64> {
+        let (ifd, _) = self.read_ifd(offset)?;
+        let big_tiff = self.header.is_big_tiff();
+        self.reader.goto(offset)?;
+        let declared = if big_tiff { self.reader.read_u64(self.endian)? } else { self.reader.read_u16(self.endian)? as u64 };
+        let (count_len, entry_len) = if big_tiff { (8, 20) } else { (2, 12) };
+
+        Ok(offset + count_len + declared.max(ifd.len() as u64) * entry_len)
+    }
+
+    // `offset` as a value field, which classic TIFF limits to 32 bits.
+    fn offset_field(&self, offset: u64) -> DecodeResult<Vec<u8>> {
+        if self.header.is_big_tiff() {
+            return Ok(ifd::endian_bytes(offset, 8, self.endian));
+        }
+        check_limit("classic TIFF offset", offset, Some(u32::MAX))?;
+
+        Ok(ifd::endian_bytes(offset, 4, self.endian))
+    }
+}
+
+impl<'a> Decoder<Cursor<&'a [u8]>> {
+    // Decodes a file already in memory, such as a memory map. The methods below
+    // return parts of `data` without copying them.
+    pub fn from_slice(data: &'a [u8]) -> DecodeResult<Decoder<Cursor<&'a [u8]>>> {
+        Decoder::new(Cursor::new(data))
+    }
+
+    // The strips or tiles of the current image as they are stored, compressed or
+    // not.
+    pub fn chunk_slices(&mut self) -> DecodeResult<Vec<&'a [u8]>> {
+        let ifd = self.ifd()?;
+        let (offsets, byte_counts) = if ifd.get(tag::TileWidth).is_some() {
+            (self.get_value(&ifd, tag::TileOffsets)?, self.get_value(&ifd, tag::TileByteCounts)?)
+        } else {
+            (self.get_value(&ifd, tag::StripOffsets)?, self.get_value(&ifd, tag::StripByteCounts)?)
+        };
+        let data: &'a [u8] = self.reader.get_ref();
+        offsets.into_iter().zip(byte_counts).map(|(offset, byte_count)| {
+            offset.checked_add(byte_count)
+                .filter(|&end| end <= data.len() as u64)
+                .map(|end| &data[offset as usize..end as usize])
+                .ok_or(DecodeError::from(DecodeErrorKind::InvalidChunkOffset { offset, byte_count }))
+        }).collect()
+    }
+
+    // The samples of the current image as `read_image_into` writes them. They are
+    // borrowed from the file when it stores them that way: uncompressed, without a
+    // predictor, in contiguous strips of whole rows, in native byte order and not
+    // WhiteIsZero. Anything else is decoded into a new buffer.
+    pub fn image_bytes(&mut self) -> DecodeResult<Cow<'a, [u8]>> {
+        let ifd = self.ifd()?;
+        let current = self.current;
+        match self.stored_samples(&ifd).map_err(|e| e.with_ifd(current))? {
+            Some(samples) => Ok(Cow::Borrowed(samples)),
+            None => {
+                let mut buffer = vec![0; self.image_buffer_size()?];
+                self.read_image_into(&mut buffer)?;
+                Ok(Cow::Owned(buffer))
+            }
+        }
+    }
+
+    fn stored_samples(&mut self, ifd: &IFD) -> DecodeResult<Option<&'a [u8]>> {
+        let header = self.header_with(ifd)?;
+        let bits_per_sample = header.bits_per_sample();
+        let sample_bytes = bits_per_sample.bits() / 8;
+        let samples = bits_per_sample.len();
+        let stored = self.is_direct(ifd, &header)?
+            && header.compression() == Compression::No
+            && Predictor::from_u16(self.get_value(ifd, tag::Predictor)?)? == Predictor::No
+            && FillOrder::from_u16(self.get_value(ifd, tag::FillOrder)?)? == FillOrder::HighToLow
+            && (sample_bytes == 1 || self.endian == Endian::native())
+            && (header.photometric_interpretation() != PhotometricInterpretation::WhiteIsZero || header.sample_format() == SampleFormat::Float)
+            && (samples == 1 || PlanarConfiguration::from_u16(self.get_value(ifd, tag::PlanarConfiguration)?)? == PlanarConfiguration::Chunky)
+            && ifd.get(tag::TileWidth).is_none()
+            && ifd.get(tag::StripByteCounts).is_some();
+        if !stored {
+            return Ok(None);
+        }
+
+        let height = header.height() as usize;
+        let row_bytes = checked_mul(header.width() as usize, samples * sample_bytes, "row size")?;
+        let size = checked_mul(row_bytes, height, "image size")?;
+        let rows_per_strip = match self.get_value(ifd, tag::RowsPerStrip)? as usize {
+            0 => height,
+            n => n.min(height),
+        };
+        let strip_bytes = checked_mul(rows_per_strip, row_bytes, "strip size")? as u64;
+        let offsets = self.get_value(ifd, tag::StripOffsets)?;
+        let byte_counts = self.get_value(ifd, tag::StripByteCounts)?;
+        let start = match offsets.first() {
+            Some(&start) => start,
+            None => return Ok(None),
+        };
+        // each strip but the last holds exactly its rows and the next follows it
+        let mut end = start;
+        for (i, (&offset, &byte_count)) in offsets.iter().zip(&byte_counts).enumerate() {
+            if offset != end || (i + 1 < offsets.len() && byte_count != strip_bytes) {
+                return Ok(None);
+            }
+            end = offset.saturating_add(byte_count);
+        }
+
+        let data: &'a [u8] = self.reader.get_ref();
+        match start.checked_add(size as u64) {
+            Some(stop) if stop <= end && stop <= data.len() as u64 => Ok(Some(&data[start as usize..stop as usize])),
+            _ => Ok(None),
+        }
+    }
+}
+
+impl<R> Iterator for Decoder<R> where R: Read + Seek {
+    type Item = IFD;
+
+    fn next(&mut self) -> Option<IFD> {
+        let next = self.next;
+        if !self.visited.insert(next) {
+            return None;
+        }
+        if let Ok((ifd, next)) = self.read_ifd(next) {
+            self.next = next;
+
+            Some(ifd)
+        } else {
+            None
+        }
+    }
+}
+
+// How a plane is laid out: `columns` x `rows` units of `bits` bits, each made of
+// `samples` samples. A unit is normally one pixel, but subsampled YCbCr stores a
+// block of `unit` pixels (across, down) as one. Only the rows of `band` (first
+// row, row count) are read, and of those only the tiles that reach into the
+// columns of `span` (first column, column count).
+struct Grid {
+    columns: usize,
+    rows: usize,
+    bits: usize,
+    samples: usize,
+    unit: (usize, usize),
+    span: (usize, usize),
+    band: (usize, usize),
+}
+
+impl Grid {
+    fn pixels(header: &ImageHeader, bits_per_pixel: usize, samples: usize) -> Grid {
+        Grid {
+            columns: header.width() as usize,
+            rows: header.height() as usize,
+            bits: bits_per_pixel,
+            samples,
+            unit: (1, 1),
+            span: (0, header.width() as usize),
+            band: (0, header.height() as usize),
+        }
+    }
+
+    fn window(mut self, (x, y, width, height): (usize, usize, usize, usize)) -> Grid {
+        self.span = (x, width);
+        self.band = (y, height);
+        self
+    }
+
+    // Rows are padded to a whole byte.
+    fn row_bytes(&self, columns: usize) -> DecodeResult<usize> {
+        Ok(checked_mul(columns, self.bits, "row size")?.div_ceil(8))
+    }
+}
+
+// Iterator over the decoded pages of a file, from `Decoder::images`. It stops
+// after the first IFD that can't be read, or that the chain already passed.
+pub struct Images<'a, R: 'a> {
+    decoder: &'a mut Decoder<R>,
+    next: u64,
+    visited: HashSet<u64>,
+    primary_only: bool,
+}
+
+impl<'a, R> Iterator for Images<'a, R> where R: Read + Seek {
+    type Item = DecodeResult<Image>;
+
+    fn next(&mut self) -> Option<DecodeResult<Image>> {
+        while self.next != 0 {
+            let ifd = if self.visited.insert(self.next) {
+                check_limit("IFD chain length", self.visited.len() as u64, self.decoder.limits.ifd_chain.map(|x| x as u64))
+                    .and_then(|()| self.decoder.read_ifd(self.next))
+            } else {
+                Err(DecodeError::from(DecodeErrorKind::IFDCycle { offset: self.next }))
+            };
+            let (ifd, next) = match ifd {
+                Ok(ifd) => ifd,
+                Err(e) => {
+                    self.next = 0;
+                    return Some(Err(e));
+                }
+            };
+            let offset = self.next;
+            self.next = next;
+            if self.primary_only {
+                match self.decoder.page_kind_with(&ifd) {
+                    Ok(kind) if !kind.is_primary() => continue,
+                    Ok(_) => {},
+                    Err(e) => return Some(Err(e.with_ifd(offset))),
+                }
+            }
+
+            return Some(self.decoder.image_with(&ifd).map_err(|e| e.with_ifd(offset)));
+        }
+
+        None
+    }
+}
+
+// Sizes and offsets computed from values in the file, which a corrupt or hostile
+// file can make overflow. `what` names the quantity for the error.
+fn checked_mul(a: usize, b: usize, what: &'static str) -> DecodeResult<usize> {
+    a.checked_mul(b).ok_or_else(|| DecodeError::from(DecodeErrorKind::CorruptOffset { what }))
+}
+
+// An entry's count as a usize, for entries whose count doesn't fit.
+fn count(entry: &Entry) -> DecodeResult<usize> {
+    if entry.count() > usize::MAX as u64 {
+        return Err(DecodeError::from(DecodeErrorKind::UnsupportedIFDEntry { entry: entry.clone(), reason: "count overflows".to_string() }));
+    }
+
+    Ok(entry.count() as usize)
+}
+
+// Exactly `N` rationals with non-zero denominators, as floats.
+fn rational_array<const N: usize>(values: &[Rational]) -> Option<[f64; N]> {
+    if values.len() != N {
+        return None;
+    }
+    let mut array = [0.; N];
+    for (x, value) in array.iter_mut().zip(values) {
+        *x = value.to_f64()?;
+    }
+
+    Some(array)
+}
+
+fn u16_samples(bytes: &[u8], endian: Endian) -> Vec<u16> {
+    bytes.chunks(2)
+        .map(|mut x| x.read_u16(endian).unwrap_or(0))
+        .collect()
+}
+
+fn u32_samples(bytes: &[u8], endian: Endian) -> Vec<u32> {
+    bytes.chunks(4)
+        .map(|mut x| x.read_u32(endian).unwrap_or(0))
+        .collect()
+}
+
+fn u64_samples(bytes: &[u8], endian: Endian) -> Vec<u64> {
+    bytes.chunks(8)
+        .map(|mut x| x.read_u64(endian).unwrap_or(0))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec::DecodeBytes;
+    use flate2::write::ZlibEncoder;
+    use std::io::{
+        Cursor,
+        Write,
+    };
+    use testutil::{
+        Page,
+        TiffBuilder,
+        Value,
+    };
+
+    // A `width` x `height` page of 8-bit samples in one uncompressed strip.
+    fn page(width: u32, height: u32, photometric: u16, samples: u16, bits: Vec<u16>, pixels: Vec<u8>) -> Page {
+        Page::gray8(width, height, pixels)
+            .tag(258, Value::Short(bits))
+            .tag(262, Value::Short(vec![photometric]))
+            .tag(277, Value::Short(vec![samples]))
+    }
+
+    fn decode(page: Page) -> DecodeResult<Image> {
+        let file = TiffBuilder::new(Endian::Little).page(page).build();
+        Decoder::new(Cursor::new(file))?.image()
+    }
+
+    #[test]
+    fn six_channel_image() {
+        let pixels = (0..4 * 3 * 6).map(|x| x as u8 * 3).collect::<Vec<_>>();
+        let image = decode(page(4, 3, 1, 6, vec![8], pixels.clone())).unwrap();
+        assert_eq!(image.header().samples_per_pixel(), 6);
+        assert_eq!(image.header().bits_per_sample().values(), vec![8; 6]);
+        assert_eq!(image.data(), &ImageData::U8(pixels));
+    }
+
+    #[test]
+    fn samples_per_pixel_is_checked() {
+        // RGB needs three samples
+        assert!(decode(page(2, 2, 2, 2, vec![8, 8], vec![0; 8])).is_err());
+        match *decode(page(2, 2, 1, 0, vec![8], vec![0; 4])).unwrap_err().kind() {
+            DecodeErrorKind::UnsupportedData { data: 0, .. } => {}
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        match *decode(page(1, 1, 1, 1025, vec![8], vec![0; 1025])).unwrap_err().kind() {
+            DecodeErrorKind::LimitExceeded { limit: "samples per pixel", value: 1025, max: 1024 } => {}
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        let file = TiffBuilder::new(Endian::Little).page(page(1, 1, 1, 6, vec![8], vec![0; 6])).build();
+        let limits = Limits::new().max_samples_per_pixel(4);
+        match *DecoderBuilder::new().limits(limits).build(Cursor::new(file)).unwrap().image().unwrap_err().kind() {
+            DecodeErrorKind::LimitExceeded { limit: "samples per pixel", value: 6, max: 4 } => {}
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        match *decode(page(2, 2, 2, 3, vec![8, 8], vec![0; 12])).unwrap_err().kind() {
+            DecodeErrorKind::InconsistentSamplesPerPixel { samples_per_pixel: 3, ref bits_per_sample } => assert_eq!(bits_per_sample, &[8, 8]),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn bits_per_sample_cn() {
+        let bits = BitsPerSample::new([8; 6]).unwrap();
+        assert_eq!(bits, BitsPerSample::CN(vec![8; 6]));
+        assert!(bits.is_uniform());
+        assert_eq!((bits.len(), bits.max_bits()), (6, 8));
+
+        let pixels = (0..3 * 2 * 6).map(|x| 255 - x as u8).collect::<Vec<_>>();
+        let image = decode(page(3, 2, 1, 6, vec![8; 6], pixels.clone())).unwrap();
+        assert_eq!(image.header().bits_per_sample(), &bits);
+        assert_eq!(image.data(), &ImageData::U8(pixels));
+
+        // mixed depths decode as long as none is wider than 16 bits
+        let bits = BitsPerSample::new([8, 32]).unwrap();
+        assert!(!bits.is_uniform());
+        assert_eq!(bits.max_bits(), 32);
+        match *decode(page(2, 2, 1, 2, vec![8, 32], vec![0; 20])).unwrap_err().kind() {
+            DecodeErrorKind::UnsupportedBitsPerSample { ref values } => assert_eq!(values, &[8, 32]),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn malformed_ascii_needs_a_lenient_decoder() {
+        let description = Value::Raw { datatype: 2, count: 28, bytes: b"Scanned 600 dpi, lamp 40\xB0C\0\0\x20".to_vec() };
+        let page = Page::gray8(1, 1, vec![0]).tag(270, description);
+        let file = TiffBuilder::new(Endian::Little).page(page).build();
+
+        let mut decoder = Decoder::new(Cursor::new(file.clone())).unwrap();
+        let ifd = decoder.ifd().unwrap();
+        assert!(decoder.get_value(&ifd, tag::ImageDescription).is_err());
+
+        let mut decoder = DecoderBuilder::new().lenient(true).build(Cursor::new(file)).unwrap();
+        let ifd = decoder.ifd().unwrap();
+        assert_eq!(decoder.get_value(&ifd, tag::ImageDescription).unwrap(), "Scanned 600 dpi, lamp 40\u{B0}C");
+        assert_eq!(decoder.warnings().len(), 2);
+    }
+
+    fn three_pages() -> Vec<u8> {
+        TiffBuilder::new(Endian::Little)
+            .page(Page::gray8(1, 1, vec![0]))
+            .page(Page::gray8(2, 1, vec![0; 2]))
+            .page(Page::gray8(3, 1, vec![0; 3]))
+            .build()
+    }
+
+    #[test]
+    fn load_ifd_at_an_offset() {
+        let mut decoder = Decoder::new(Cursor::new(three_pages())).unwrap();
+        let offsets = decoder.ifd_offsets().unwrap();
+        assert_eq!(decoder.current_ifd_index(), Some(0));
+        assert_eq!(decoder.current_ifd_offset(), offsets[0]);
+
+        decoder.load_ifd_at(offsets[1]).unwrap();
+        assert_eq!(decoder.current_ifd_index(), None);
+        assert_eq!(decoder.current_ifd_offset(), offsets[1]);
+        let ifd = decoder.ifd().unwrap();
+        assert_eq!(decoder.get_value(&ifd, tag::ImageWidth).unwrap(), 2);
+
+        // past the end of the file: nothing changes
+        assert!(decoder.load_ifd_at(1 << 20).is_err());
+        assert_eq!(decoder.current_ifd_offset(), offsets[1]);
+
+        decoder.change_ifd(2).unwrap();
+        assert_eq!(decoder.current_ifd_index(), Some(2));
+        assert_eq!(decoder.current_ifd_offset(), offsets[2]);
+    }
+
+    #[test]
+    fn value_of_every_page() {
+        let file = TiffBuilder::new(Endian::Little)
+            .page(Page::gray8(1, 1, vec![0]).tag(270, Value::Ascii("first".to_string())))
+            .page(Page::gray8(1, 1, vec![0]).tag(274, Value::Short(vec![6])))
+            .page(Page::gray8(1, 1, vec![0]).tag(270, Value::Raw { datatype: 2, count: 3, bytes: b"bad".to_vec() }))
+            .build();
+        let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+        decoder.change_ifd(1).unwrap();
+
+        // missing without a default, then undecodable
+        let descriptions = decoder.get_value_all_pages(tag::ImageDescription).unwrap();
+        assert_eq!(descriptions, vec![Some("first".to_string()), None, None]);
+        match decoder.warnings() {
+            [DecodeWarning::SkippedValue { ifd: 2, .. }] => {}
+            warnings => panic!("unexpected warnings {:?}", warnings),
+        }
+
+        // missing with a default
+        assert_eq!(decoder.get_value_all_pages(tag::Orientation).unwrap(), vec![Some(1), Some(6), Some(1)]);
+        assert_eq!(decoder.current_ifd_index(), Some(1));
+    }
+
+    #[test]
+    fn value_of_every_page_past_an_unreadable_ifd() {
+        let file = TiffBuilder::new(Endian::Little)
+            .page(Page::gray8(1, 1, vec![0]).tag(274, Value::Short(vec![6])))
+            // its description points into the header, so the IFD can't be read
+            .page(Page::gray8(1, 1, vec![0]).tag(270, Value::Ascii("description".to_string())).offset(270, 2))
+            .page(Page::gray8(1, 1, vec![0]).tag(274, Value::Short(vec![3])))
+            .build();
+        let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+        // the pages after it can't be found
+        assert_eq!(decoder.get_value_all_pages(tag::Orientation).unwrap(), vec![Some(6), None]);
+        // the same failure again is only recorded once
+        assert_eq!(decoder.get_value_all_pages(tag::Orientation).unwrap(), vec![Some(6), None]);
+        match decoder.warnings() {
+            [DecodeWarning::SkippedValue { ifd: 1, tag: AnyTag::Orientation, .. }] => {}
+            warnings => panic!("unexpected warnings {:?}", warnings),
+        }
+    }
+
+    // A 2x1 palette page of `bits`-bit indices and a ColorMap of `colors` entries.
+    fn palette_page(bits: Vec<u16>, colors: Option<usize>) -> Page {
+        let page = Page::gray8(2, 1, vec![0x12])
+            .tag(258, Value::Short(bits))
+            .tag(262, Value::Short(vec![3]));
+        match colors {
+            Some(colors) => page.tag(320, Value::Short((0..colors as u16).collect())),
+            None => page,
+        }
+    }
+
+    fn palette_error(page: Page) -> DecodeError {
+        let file = TiffBuilder::new(Endian::Little).page(page).build();
+        Decoder::new(Cursor::new(file)).unwrap().header().unwrap_err()
+    }
+
+    #[test]
+    fn palette_prerequisites() {
+        let file = TiffBuilder::new(Endian::Little).page(palette_page(vec![4], Some(48))).build();
+        let image = Decoder::new(Cursor::new(file)).unwrap().image().unwrap();
+        assert_eq!(image.header().photometric_interpretation(), PhotometricInterpretation::Palette);
+        assert_eq!(image.data(), &ImageData::U8(vec![1, 2]));
+
+        match *palette_error(palette_page(vec![8, 8], Some(768)).tag(277, Value::Short(vec![2]))).kind() {
+            DecodeErrorKind::PaletteSamplesPerPixel { samples_per_pixel: 2 } => {}
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        match *palette_error(palette_page(vec![32], None)).kind() {
+            DecodeErrorKind::PaletteBitsPerSample { bits: 32 } => {}
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        match *palette_error(palette_page(vec![4], None)).kind() {
+            DecodeErrorKind::PaletteWithoutColorMap => {}
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        match *palette_error(palette_page(vec![4], Some(47))).kind() {
+            DecodeErrorKind::PaletteColorMapLength { expected: 48, actual: 47 } => {}
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn lenient_palette_falls_back_to_gray() {
+        let file = TiffBuilder::new(Endian::Little).page(palette_page(vec![4], None)).build();
+        let mut decoder = DecoderBuilder::new().lenient(true).build(Cursor::new(file)).unwrap();
+        let header = decoder.header().unwrap();
+        assert_eq!(header.photometric_interpretation(), PhotometricInterpretation::BlackIsZero);
+        match decoder.warnings() {
+            [DecodeWarning::PaletteAsGray { .. }] => {}
+            warnings => panic!("unexpected warnings {:?}", warnings),
+        }
+    }
+
+    // A 1-bit TransparencyMask page, one byte per row.
+    fn mask_page(width: u32, rows: Vec<u8>) -> Page {
+        let height = rows.len() as u32;
+        Page::gray8(width, height, rows)
+            .tag(254, Value::Long(vec![4]))
+            .tag(258, Value::Short(vec![1]))
+            .tag(262, Value::Short(vec![4]))
+    }
+
+    #[test]
+    fn transparency_mask_pairs_with_its_image() {
+        let file = TiffBuilder::new(Endian::Little)
+            .page(Page::gray8(4, 2, vec![9; 8]))
+            .page(mask_page(3, vec![0xE0, 0xE0]))
+            .page(mask_page(4, vec![0xA0, 0x50]))
+            .build();
+        let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+        assert_eq!(decoder.find_mask_for(0).unwrap(), Some(2));
+        assert_eq!(decoder.find_mask_for(1).unwrap(), None);
+
+        let image = decoder.image_with_mask().unwrap();
+        assert_eq!(image.data(), &ImageData::U8(vec![9; 8]));
+        let mask = image.mask().unwrap();
+        assert_eq!(mask.header().photometric_interpretation(), PhotometricInterpretation::TransparencyMask);
+        assert_eq!(mask.data(), &ImageData::Mask(vec![255, 0, 255, 0, 0, 255, 0, 255]));
+    }
+
+    #[test]
+    fn transparency_mask_must_be_one_bit() {
+        let page = mask_page(1, vec![0]).tag(258, Value::Short(vec![8]));
+        assert!(decode(page).is_err());
+    }
+
+    fn reversed(bytes: &[u8]) -> Vec<u8> {
+        bytes.iter().map(|x| x.reverse_bits()).collect()
+    }
+
+    #[test]
+    fn fill_order_reverses_compressed_strips() {
+        // 16x4 bilevel rows in a Group 4 stream
+        let packed = [0xF0, 0x0F, 0x3C, 0x3C, 0x00, 0xFF, 0xAA, 0x55];
+        let g4 = ::ccitt::Encoder::new(16).encode(&packed, 4);
+        let bilevel = |strip: Vec<u8>, fill_order: u16| Page::gray8(16, 4, strip)
+            .tag(258, Value::Short(vec![1]))
+            .tag(259, Value::Short(vec![4]))
+            .tag(266, Value::Short(vec![fill_order]));
+        let bits = packed.iter().flat_map(|x| (0..8).rev().map(move |i| x >> i & 1)).collect::<Vec<_>>();
+        assert_eq!(decode(bilevel(g4.clone(), 1)).unwrap().data(), &ImageData::U8(bits.clone()));
+        assert_eq!(decode(bilevel(reversed(&g4), 2)).unwrap().data(), &ImageData::U8(bits));
+
+        let pixels = (0..64).map(|x| (x * 5 % 7) as u8).collect::<Vec<_>>();
+        let lzw = ::byte::lzw_compress(&pixels);
+        let page = Page::gray8(8, 8, reversed(&lzw)).tag(259, Value::Short(vec![5])).tag(266, Value::Short(vec![2]));
+        assert_eq!(decode(page).unwrap().data(), &ImageData::U8(pixels));
+    }
+
+    // A 4x5 LZW page in strips of 3 rows, whose last strip holds `last` instead of
+    // its 2 rows.
+    fn doctored_lzw(last: &[u8]) -> Vec<u8> {
+        let page = Page::gray8(4, 5, vec![])
+            .tag(259, Value::Short(vec![5]))
+            .tag(278, Value::Long(vec![3]))
+            .strips(vec![::byte::lzw_compress(&[1; 12]), ::byte::lzw_compress(last)]);
+        TiffBuilder::new(Endian::Little).page(page).build()
+    }
+
+    #[test]
+    fn strip_with_surplus_rows() {
+        // padded out to a whole strip of 3 rows
+        let file = doctored_lzw(&[2; 12]);
+        let e = Decoder::new(Cursor::new(file.clone())).unwrap().image().unwrap_err();
+        match *e.kind() {
+            DecodeErrorKind::StripLengthMismatch { strip: 1, expected: 8, actual: 12 } => {}
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        assert!(e.to_string().contains("12 bytes, but 8"), "{}", e);
+
+        let mut decoder = DecoderBuilder::new().lenient(true).build(Cursor::new(file)).unwrap();
+        let expected = [vec![1; 12], vec![2; 8]].concat();
+        assert_eq!(decoder.image().unwrap().data(), &ImageData::U8(expected));
+        assert_eq!(decoder.warnings(), &[DecodeWarning::StripTruncated { strip: 1, surplus: 4 }][..]);
+    }
+
+    #[test]
+    fn strip_with_missing_rows() {
+        let file = doctored_lzw(&[2; 5]);
+        match *Decoder::new(Cursor::new(file.clone())).unwrap().image().unwrap_err().kind() {
+            DecodeErrorKind::StripLengthMismatch { strip: 1, expected: 8, actual: 5 } => {}
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+
+        let mut decoder = DecoderBuilder::new().lenient(true).build(Cursor::new(file)).unwrap();
+        let expected = [vec![1; 12], vec![2; 5], vec![0; 3]].concat();
+        assert_eq!(decoder.image().unwrap().data(), &ImageData::U8(expected));
+        assert_eq!(decoder.warnings(), &[DecodeWarning::StripZeroFilled { strip: 1, missing: 3 }][..]);
+    }
+
+    // XORs every byte with 0x5A, both ways
+    struct Xor;
+
+    impl DecodeBytes for Xor {
+        fn decode_bytes(&mut self, compressed: &[u8], _expected_len: usize, _predictor: Predictor) -> DecodeResult<Vec<u8>> {
+            Ok(compressed.iter().map(|x| x ^ 0x5A).collect())
+        }
+    }
+
+    #[test]
+    fn registered_codec() {
+        let pixels = (0..12).collect::<Vec<u8>>();
+        let xored = pixels.iter().map(|x| x ^ 0x5A).collect();
+        let file = TiffBuilder::new(Endian::Little).page(Page::gray8(4, 3, xored).tag(259, Value::Short(vec![65000]))).build();
+        assert!(Decoder::new(Cursor::new(file.clone())).unwrap().image().is_err());
+
+        let mut decoder = DecoderBuilder::new().register_codec(65000, || Box::new(Xor)).build(Cursor::new(file)).unwrap();
+        assert_eq!(decoder.image().unwrap().data(), &ImageData::U8(pixels.clone()));
+
+        // built-ins are only replaced on request
+        match *DecoderBuilder::new().register_codec(5, || Box::new(Xor)).build(Cursor::new(vec![])).unwrap_err().kind() {
+            DecodeErrorKind::BuiltinCodecOverride { compression: 5 } => {}
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        let xored = pixels.iter().map(|x| x ^ 0x5A).collect();
+        let file = TiffBuilder::new(Endian::Little).page(Page::gray8(4, 3, xored).tag(259, Value::Short(vec![5]))).build();
+        let builder = DecoderBuilder::new().register_codec(5, || Box::new(Xor)).override_builtin_codecs(true);
+        assert_eq!(builder.build(Cursor::new(file)).unwrap().image().unwrap().data(), &ImageData::U8(pixels));
+    }
+
+    // The first IFD of `file`, and the warnings reading it raised.
+    fn first_ifd(file: Vec<u8>, lenient: bool) -> DecodeResult<(IFD, Vec<DecodeWarning>)> {
+        let mut decoder = DecoderBuilder::new().lenient(lenient).build(Cursor::new(file))?;
+        let ifd = decoder.ifd()?;
+        Ok((ifd, decoder.warnings().to_vec()))
+    }
+
+    #[test]
+    fn first_ifd_offset_inside_the_header() {
+        for &(big_tiff, offset) in &[(false, 0), (false, 4), (true, 0), (true, 12)] {
+            let mut builder = TiffBuilder::new(Endian::Little).page(Page::gray8(1, 1, vec![0])).first_ifd_offset(offset);
+            if big_tiff {
+                builder = builder.big_tiff();
+            }
+            for &lenient in &[false, true] {
+                match *first_ifd(builder.build(), lenient).unwrap_err().kind() {
+                    DecodeErrorKind::InvalidIFDAddress { offset: at } if at == offset as u64 => {}
+                    ref kind => panic!("unexpected error {:?}", kind),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ifd_cut_short_by_the_end_of_the_file() {
+        // 8 entries; the file ends 5 bytes into the fourth
+        let file = TiffBuilder::new(Endian::Little).page(Page::gray8(2, 1, vec![0; 2])).build();
+        let ifd = u32::from_le_bytes([file[4], file[5], file[6], file[7]]) as usize;
+        let truncated = file[..ifd + 2 + 12 * 3 + 5].to_vec();
+        match *first_ifd(truncated.clone(), false).unwrap_err().kind() {
+            DecodeErrorKind::TruncatedIFD { offset, declared: 8, read: 3 } if offset == ifd as u64 => {}
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+
+        let (entries, warnings) = first_ifd(truncated, true).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(warnings, vec![DecodeWarning::TruncatedIFD { offset: ifd as u64, declared: 8, read: 3 }]);
+
+        // an entry count of 40 with only the 8 entries and the next-IFD pointer after it
+        let mut overcounted = file.clone();
+        overcounted[ifd..ifd + 2].copy_from_slice(&40u16.to_le_bytes());
+        match *first_ifd(overcounted.clone(), false).unwrap_err().kind() {
+            DecodeErrorKind::TruncatedIFD { declared: 40, read: 8, .. } => {}
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        let (entries, warnings) = first_ifd(overcounted, true).unwrap();
+        assert_eq!(entries.len(), 8);
+        assert_eq!(warnings, vec![DecodeWarning::TruncatedIFD { offset: ifd as u64, declared: 40, read: 8 }]);
+    }
+
+    #[test]
+    fn value_offsets_outside_the_file_or_inside_the_header() {
+        // "description" is 12 bytes with its NUL, too long for the entry itself
+        let described = |offset| Page::gray8(1, 1, vec![0]).tag(270, Value::Ascii("description".to_string())).offset(270, offset);
+        let len = TiffBuilder::new(Endian::Little).page(described(0)).build().len() as u32;
+        let cases = vec![
+            (TiffBuilder::new(Endian::Little).page(described(2)), 2),
+            (TiffBuilder::new(Endian::Little).page(described(len + 100)), len + 100),
+            // starts inside the file, ends past it
+            (TiffBuilder::new(Endian::Little).page(described(len - 6)), len - 6),
+            (TiffBuilder::new(Endian::Big).big_tiff().page(described(10)), 10),
+        ];
+
+        for (builder, at) in cases {
+            match *first_ifd(builder.build(), false).unwrap_err().kind() {
+                DecodeErrorKind::InvalidEntryOffset { tag: AnyTag::ImageDescription, offset, len: 12 } if offset == at as u64 => {}
+                ref kind => panic!("unexpected error {:?}", kind),
+            }
+
+            let (ifd, warnings) = first_ifd(builder.build(), true).unwrap();
+            assert!(ifd.get(tag::ImageDescription).is_none());
+            assert!(ifd.get(tag::ImageWidth).is_some());
+            assert_eq!(warnings, vec![DecodeWarning::InvalidEntryOffset { tag: AnyTag::ImageDescription, offset: at as u64, len: 12 }]);
+        }
+    }
+
+    // 16 MiB of zeros, deflated to a few KB
+    fn deflate_bomb() -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(vec![], ::flate2::Compression::best());
+        encoder.write_all(&vec![0; 16 << 20]).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn bomb_file() -> Vec<u8> {
+        let page = Page::gray8(64, 64, deflate_bomb()).tag(259, Value::Short(vec![8]));
+        TiffBuilder::new(Endian::Little).page(page).build()
+    }
+
+    #[test]
+    fn accessors_read_the_current_ifd() {
+        let file = TiffBuilder::new(Endian::Big)
+            .page(Page::gray8(3, 2, vec![0; 6]))
+            .page(Page::gray8(1, 4, vec![0; 4]).tag(259, Value::Short(vec![8])).tag(262, Value::Short(vec![0])))
+            .page(Page::new().tag(257, Value::Short(vec![5])).tag(262, Value::Short(vec![2])))
+            .build();
+        let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+        assert_eq!(decoder.width().unwrap(), 3);
+        assert_eq!(decoder.height().unwrap(), 2);
+        assert_eq!(decoder.dimensions().unwrap(), (3, 2));
+        assert_eq!(decoder.compression().unwrap(), Compression::No);
+        assert_eq!(decoder.photometric().unwrap(), PhotometricInterpretation::BlackIsZero);
+
+        decoder.change_ifd(1).unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (1, 4));
+        assert_eq!(decoder.compression().unwrap(), Compression::Deflate);
+        assert_eq!(decoder.photometric().unwrap(), PhotometricInterpretation::WhiteIsZero);
+
+        // no width, and Compression falls back to its default
+        decoder.change_ifd(2).unwrap();
+        match *decoder.width().unwrap_err().kind() {
+            DecodeErrorKind::CannotFindTheTag { tag: AnyTag::ImageWidth } => {}
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+        assert_eq!(decoder.height().unwrap(), 5);
+        assert_eq!(decoder.compression().unwrap(), Compression::No);
+        assert_eq!(decoder.photometric().unwrap(), PhotometricInterpretation::RGB);
+    }
+
+    #[test]
+    fn accessors_with_unknown_values() {
+        let page = Page::gray8(1, 1, vec![0]).tag(259, Value::Short(vec![60000])).tag(262, Value::Short(vec![60001]));
+        let file = TiffBuilder::new(Endian::Little).page(page).build();
+        let mut decoder = Decoder::new(Cursor::new(file.clone())).unwrap();
+        assert!(decoder.compression().is_err());
+        assert!(decoder.photometric().is_err());
+
+        let mut decoder = DecoderBuilder::new().lenient(true).build(Cursor::new(file)).unwrap();
+        assert_eq!(decoder.compression().unwrap(), Compression::Unknown(60000));
+        assert_eq!(decoder.photometric().unwrap(), PhotometricInterpretation::Unknown(60001));
+        assert_eq!(decoder.warnings(), &[
+            DecodeWarning::UnknownValue { tag: AnyTag::Compression, value: 60000 },
+            DecodeWarning::UnknownValue { tag: AnyTag::PhotometricInterpretation, value: 60001 },
+        ][..]);
+    }
+
+    #[test]
+    fn missing_tags_read_as_their_default() {
+        let page = Page::new()
+            .tag(256, Value::Long(vec![2]))
+            .tag(257, Value::Long(vec![2]))
+            .tag(258, Value::Short(vec![8]))
+            .tag(262, Value::Short(vec![1]))
+            .strips(vec![vec![0; 4]]);
+        let file = TiffBuilder::new(Endian::Little).page(page).build();
+        let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+        let ifd = decoder.ifd().unwrap();
+
+        assert_eq!(decoder.get_value(&ifd, tag::Compression).unwrap(), 1);
+        assert_eq!(decoder.get_optional_value(&ifd, tag::Compression).unwrap(), None);
+        assert_eq!(decoder.get_value(&ifd, tag::ExtraSamples).unwrap(), Vec::<u16>::new());
+        match *decoder.get_value(&ifd, tag::ImageDescription).unwrap_err().kind() {
+            DecodeErrorKind::CannotFindTheTag { .. } => {}
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn deflate_bomb_hits_the_alloc_limit() {
+        let limits = Limits::new().max_alloc(1 << 20);
+        let mut decoder = DecoderBuilder::new().limits(limits).lenient(true).build(Cursor::new(bomb_file())).unwrap();
+        match *decoder.image().unwrap_err().kind() {
+            DecodeErrorKind::LimitExceeded { max, .. } => assert_eq!(max, 1 << 20),
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn deflate_bomb_keeps_only_the_strip() {
+        let (data, surplus) = DeflateDecoder.decode_bytes_bounded(&deflate_bomb(), 4096, usize::MAX, Predictor::No).unwrap();
+        assert_eq!(data.len(), 4096);
+        assert_eq!(surplus, (16 << 20) - 4096);
+
+        let mut decoder = DecoderBuilder::new().lenient(true).build(Cursor::new(bomb_file())).unwrap();
+        assert_eq!(decoder.image().unwrap().data(), &ImageData::U8(vec![0; 4096]));
+        assert_eq!(decoder.warnings(), &[DecodeWarning::StripTruncated { strip: 0, surplus: (16 << 20) - 4096 }][..]);
+    }
+
+    // A 3x1 8-bit strip of 0, 100, 255 at offset 8, then an IFD without Compression.
+    fn gray_strip_file(photometric: u16) -> Vec<u8> {
+        let mut file = b"II\x2a\x00\x0c\x00\x00\x00".to_vec();
+        file.extend_from_slice(&[0, 100, 255, 0]);
+        let entries = [(256, 3, 3), (257, 3, 1), (258, 3, 8), (262, 3, photometric as u32), (273, 4, 8), (279, 4, 3)];
+        file.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        for &(tag, datatype, value) in &entries {
+            file.extend_from_slice(&(tag as u16).to_le_bytes());
+            file.extend_from_slice(&(datatype as u16).to_le_bytes());
+            file.extend_from_slice(&1u32.to_le_bytes());
+            file.extend_from_slice(&value.to_le_bytes());
+        }
+        file.extend_from_slice(&0u32.to_le_bytes());
+        file
+    }
+
+    fn gray_strip(photometric: u16) -> Vec<u8> {
+        let image = Decoder::new(Cursor::new(gray_strip_file(photometric))).unwrap().image().unwrap();
+        assert_eq!(image.header().compression(), Compression::No);
+        match *image.data() {
+            ImageData::U8(ref data) => data.clone(),
+            ref data => panic!("unexpected data {:?}", data),
+        }
+    }
+
+    #[test]
+    fn absent_compression_reads_as_uncompressed() {
+        assert_eq!(gray_strip(1), [0, 100, 255]);
+    }
+
+    #[test]
+    fn white_is_zero_is_inverted() {
+        assert_eq!(gray_strip(0), [255, 155, 0]);
+    }
+
+    // JPEGTables for 8-bit gray: quantization of 8 for DC and 1 for the rest,
+    // the standard luminance DC codes, and an AC table of nothing but EOB.
+    fn jpeg_tables() -> Vec<u8> {
+        let mut tables = vec![0xFF, 0xD8];
+        tables.extend_from_slice(&[0xFF, 0xDB, 0, 67, 0, 8]);
+        tables.extend_from_slice(&[1; 63]);
+        tables.extend_from_slice(&[0xFF, 0xC4, 0, 31, 0x00, 0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0]);
+        tables.extend(0..12);
+        tables.extend_from_slice(&[0xFF, 0xC4, 0, 20, 0x10, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        tables.extend_from_slice(&[0xFF, 0xD9]);
+        tables
+    }
+
+    // An abbreviated gray JPEG of `width` x `height` whose 8x8 blocks, in raster
+    // order, are flat at 128 plus `levels`, relying on `jpeg_tables`.
+    fn jpeg_strip(width: u16, height: u16, levels: &[i32]) -> Vec<u8> {
+        let mut strip = vec![0xFF, 0xD8];
+        let [w0, w1] = width.to_be_bytes();
+        let [h0, h1] = height.to_be_bytes();
+        strip.extend_from_slice(&[0xFF, 0xC0, 0, 11, 8, h0, h1, w0, w1, 1, 1, 0x11, 0]);
+        strip.extend_from_slice(&[0xFF, 0xDA, 0, 8, 1, 1, 0x00, 0, 63, 0]);
+
+        let dc_codes = ["00", "010", "011", "100", "101", "110", "1110", "11110", "111110"];
+        let mut bits = String::new();
+        let mut previous = 0;
+        for &level in levels {
+            let diff = level - previous;
+            previous = level;
+            let category = 32 - diff.unsigned_abs().leading_zeros() as usize;
+            bits.push_str(dc_codes[category]);
+            if category > 0 {
+                let extra = if diff > 0 { diff } else { diff + (1 << category) - 1 };
+                bits.push_str(&format!("{:0width$b}", extra, width = category));
+            }
+            // EOB
+            bits.push('0');
+        }
+        while !bits.len().is_multiple_of(8) {
+            bits.push('1');
+        }
+        for byte in bits.as_bytes().chunks(8) {
+            let byte = u8::from_str_radix(std::str::from_utf8(byte).unwrap(), 2).unwrap();
+            strip.push(byte);
+            if byte == 0xFF {
+                strip.push(0);
+            }
+        }
+        strip.extend_from_slice(&[0xFF, 0xD9]);
+        strip
+    }
+
+    #[test]
+    fn jpeg_strips_with_shared_tables() {
+        // 16x24 in strips of 16 rows; the last strip is coded 16 rows high too
+        let strips = vec![jpeg_strip(16, 16, &[-64, -32, 0, 32]), jpeg_strip(16, 16, &[64, 96, 10, 10])];
+        let page = |tables: Option<Vec<u8>>| {
+            let page = Page::new()
+                .tag(256, Value::Long(vec![16]))
+                .tag(257, Value::Long(vec![24]))
+                .tag(258, Value::Short(vec![8]))
+                .tag(259, Value::Short(vec![7]))
+                .tag(262, Value::Short(vec![1]))
+                .tag(278, Value::Long(vec![16]))
+                .strips(strips.clone());
+            match tables {
+                Some(tables) => page.tag(347, Value::Raw { datatype: 7, count: tables.len() as u32, bytes: tables }),
+                None => page,
+            }
+        };
+
+        let file = TiffBuilder::new(Endian::Little).page(page(Some(jpeg_tables()))).build();
+        let image = Decoder::new(Cursor::new(file)).unwrap().image().unwrap();
+        let mut expected = vec![];
+        for &(left, right, rows) in &[(64, 96, 8), (128, 160, 8), (192, 224, 8)] {
+            for _ in 0..rows {
+                expected.extend(vec![left; 8]);
+                expected.extend(vec![right; 8]);
+            }
+        }
+        assert_eq!(image.data(), &ImageData::U8(expected));
+
+        // the strips can't be decoded on their own
+        let file = TiffBuilder::new(Endian::Little).page(page(None)).build();
+        match *Decoder::new(Cursor::new(file)).unwrap().image().unwrap_err().kind() {
+            DecodeErrorKind::CodecFailed { compression: 7, .. } => {}
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    // Cuts a plane of `width` x `height` samples of `bytes` bytes into strips of
+    // `rows` rows, or into tiles of `tile` x `tile` padded with zeros.
+    fn chunks(plane: &[u8], width: usize, height: usize, bytes: usize, rows: usize, tile: Option<usize>) -> Vec<Vec<u8>> {
+        let row = width * bytes;
+        let tile = match tile {
+            Some(tile) => tile,
+            None => return plane.chunks(rows * row).map(|x| x.to_vec()).collect(),
+        };
+        let mut tiles = vec![];
+        for top in (0..height).step_by(tile) {
+            for left in (0..width).step_by(tile) {
+                let mut data = vec![0; tile * tile * bytes];
+                for y in top..(top + tile).min(height) {
+                    let from = &plane[y * row + left * bytes..y * row + (left + tile).min(width) * bytes];
+                    let at = (y - top) * tile * bytes;
+                    data[at..at + from.len()].copy_from_slice(from);
+                }
+                tiles.push(data);
+            }
+        }
+        tiles
+    }
+
+    #[test]
+    fn planar_rgb() {
+        let (width, height) = (21, 19);
+        for &bits in &[8u16, 16] {
+            let bytes = bits as usize / 8;
+            // the R, G and B planes one after another, stored little-endian
+            let planes = (0..3).map(|plane| {
+                (0..width * height).flat_map(|i| ((i * 3 + plane) as u16).wrapping_mul(97).to_le_bytes()[..bytes].to_vec()).collect::<Vec<_>>()
+            }).collect::<Vec<_>>();
+            let expected = match bits {
+                8 => ImageData::U8((0..width * height * 3).map(|x| (x as u16).wrapping_mul(97) as u8).collect()),
+                _ => ImageData::U16((0..width * height * 3).map(|x| (x as u16).wrapping_mul(97)).collect()),
+            };
+
+            for &tile in &[None, Some(16)] {
+                // plane by plane, the chunks of each plane in the usual order
+                let data = planes.iter().flat_map(|plane| chunks(plane, width, height, bytes, 4, tile)).collect::<Vec<_>>();
+                let page = Page::new()
+                    .tag(256, Value::Long(vec![width as u32]))
+                    .tag(257, Value::Long(vec![height as u32]))
+                    .tag(258, Value::Short(vec![bits; 3]))
+                    .tag(262, Value::Short(vec![2]))
+                    .tag(277, Value::Short(vec![3]))
+                    .tag(284, Value::Short(vec![2]));
+                let page = match tile {
+                    Some(tile) => page.tag(322, Value::Long(vec![tile as u32])).tag(323, Value::Long(vec![tile as u32])).tiles(data),
+                    None => page.tag(278, Value::Long(vec![4])).strips(data),
+                };
+                let file = TiffBuilder::new(Endian::Little).page(page).build();
+                let image = Decoder::new(Cursor::new(file)).unwrap().image().unwrap();
+                assert_eq!(image.data(), &expected, "{} bits, tiles {:?}", bits, tile);
+            }
+        }
+    }
+
+    // Packs `values` of `bits` bits each into rows of `width`, each row starting
+    // on a byte boundary.
+    fn pack(values: &[u8], width: usize, bits: usize) -> Vec<u8> {
+        let mut packed = vec![];
+        for row in values.chunks(width) {
+            let mut bytes = vec![0u8; (width * bits).div_ceil(8)];
+            for (i, &value) in row.iter().enumerate() {
+                let at = i * bits;
+                bytes[at / 8] |= value << (8 - bits - at % 8);
+            }
+            packed.extend(bytes);
+        }
+        packed
+    }
+
+    #[test]
+    fn region_matches_crop() {
+        let (width, height) = (37, 29);
+        let regions = [(0, 0, 37, 29), (0, 0, 1, 1), (36, 28, 1, 1), (3, 5, 20, 17), (15, 7, 18, 10), (5, 0, 9, 29), (0, 13, 37, 4)];
+        for &bits in &[1usize, 2, 4, 8] {
+            let values = (0..width * height).map(|i| ((i * 7 + i / width) % (1 << bits)) as u8).collect::<Vec<_>>();
+            for &tile in &[None, Some(16)] {
+                let data = match tile {
+                    // sub-byte rows are packed per strip row, and per tile row for tiles
+                    None => pack(&values, width, bits).chunks(5 * (width * bits).div_ceil(8)).map(|x| x.to_vec()).collect(),
+                    Some(tile) => chunks(&values, width, height, 1, 0, Some(tile)).iter().map(|x| pack(x, tile, bits)).collect(),
+                };
+                let page = Page::new()
+                    .tag(256, Value::Long(vec![width as u32]))
+                    .tag(257, Value::Long(vec![height as u32]))
+                    .tag(258, Value::Short(vec![bits as u16]))
+                    .tag(262, Value::Short(vec![1]));
+                let page = match tile {
+                    Some(tile) => page.tag(322, Value::Long(vec![tile as u32])).tag(323, Value::Long(vec![tile as u32])).tiles(data),
+                    None => page.tag(278, Value::Long(vec![5])).strips(data),
+                };
+                let file = TiffBuilder::new(Endian::Little).page(page).build();
+                let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+                let image = decoder.image().unwrap();
+                assert_eq!(image.data(), &ImageData::U8(values.clone()), "{} bits, tiles {:?}", bits, tile);
+
+                for &(x, y, w, h) in &regions {
+                    let region = decoder.read_region(x as u32, y as u32, w as u32, h as u32).unwrap();
+                    assert_eq!((region.header().width(), region.header().height()), (w as u32, h as u32));
+                    let expected = image::crop(image.data().clone(), width, 1, (x, y, w, h));
+                    assert_eq!(region.data(), &expected, "{} bits, tiles {:?}, region {:?}", bits, tile, (x, y, w, h));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn region_ignores_orientation() {
+        let pixels = (0..48).collect::<Vec<u8>>();
+        // BottomRight: the stored image is displayed turned by 180 degrees
+        let page = Page::gray8(8, 6, pixels.clone()).tag(274, Value::Short(vec![3]));
+        let file = TiffBuilder::new(Endian::Little).page(page).build();
+        let mut decoder = DecoderBuilder::new().apply_orientation(true).build(Cursor::new(file)).unwrap();
+        let image = decoder.image().unwrap();
+        assert_eq!(image.data(), &ImageData::U8(pixels.iter().rev().cloned().collect()));
+
+        let region = decoder.read_region(1, 2, 3, 2).unwrap();
+        assert_eq!(region.data(), &ImageData::U8(vec![17, 18, 19, 25, 26, 27]));
+    }
+
+    #[test]
+    fn edited_ifds_are_written() {
+        let file = TiffBuilder::new(Endian::Little)
+            .page(Page::gray8(4, 2, (0..8).collect()).tag(305, Value::Ascii("scanner".to_string())))
+            .page(Page::gray8(3, 3, (10..19).collect()))
+            .build();
+        let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+        let offsets = decoder.ifd_offsets().unwrap();
+
+        // the second page gets a description too long for its entry and loses nothing
+        decoder.change_ifd(1).unwrap();
+        decoder.ifd_mut().unwrap().put_value(tag::ImageDescription, "the second page".to_string());
+        let ifd = decoder.ifd().unwrap();
+        assert_eq!(decoder.get_value(&ifd, tag::ImageDescription).unwrap(), "the second page");
+        let second = decoder.write_ifd().unwrap();
+        assert!(second > offsets[1]);
+        // the first page, which the header points to, loses its Software
+        decoder.change_ifd(0).unwrap();
+        decoder.ifd_mut().unwrap().remove(tag::Software);
+        let first = decoder.write_ifd().unwrap();
+        assert!(first > second);
+
+        let mut decoder = Decoder::new(decoder.into_inner()).unwrap();
+        assert_eq!(decoder.ifd_offsets().unwrap(), vec![first, second]);
+        let ifd = decoder.ifd().unwrap();
+        assert!(ifd.get(tag::Software).is_none());
+        assert_eq!(decoder.image().unwrap().data(), &ImageData::U8((0..8).collect()));
+        decoder.change_ifd(1).unwrap();
+        let ifd = decoder.ifd().unwrap();
+        assert_eq!(decoder.get_value(&ifd, tag::ImageDescription).unwrap(), "the second page");
+        assert_eq!(decoder.image().unwrap().data(), &ImageData::U8((10..19).collect()));
+    }
+
+    #[test]
+    fn discarded_edits_are_not_written() {
+        let file = TiffBuilder::new(Endian::Little).page(Page::gray8(4, 2, (0..8).collect())).build();
+        let len = file.len();
+        let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+        let offset = decoder.current_ifd_offset();
+        decoder.ifd_mut().unwrap().put_value(tag::Software, "editor".to_string());
+        decoder.discard_edits();
+        assert!(decoder.ifd().unwrap().get(tag::Software).is_none());
+
+        // with nothing to save the file is left alone
+        assert_eq!(decoder.write_ifd().unwrap(), offset);
+        let file = decoder.into_inner().into_inner();
+        assert_eq!(file.len(), len);
+        let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+        assert!(decoder.ifd().unwrap().get(tag::Software).is_none());
+    }
+
+    // A file in memory that remembers the longest read asked of it.
+    struct LongestRead {
+        file: Cursor<Vec<u8>>,
+        longest: usize,
+    }
+
+    impl Read for LongestRead {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.file.read(buf)?;
+            self.longest = self.longest.max(n);
+            Ok(n)
+        }
+    }
+
+    impl Seek for LongestRead {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    #[test]
+    fn large_strips_are_read_in_pieces() {
+        // one uncompressed strip of 2.2 MiB, without RowsPerStrip
+        let (width, height) = (1500, 1540);
+        let pixels = (0..width * height).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let page = Page::new()
+            .tag(256, Value::Long(vec![width as u32]))
+            .tag(257, Value::Long(vec![height as u32]))
+            .tag(258, Value::Short(vec![8]))
+            .tag(262, Value::Short(vec![1]))
+            .strips(vec![pixels.clone()]);
+        let file = TiffBuilder::new(Endian::Little).page(page).build();
+
+        let mut decoder = Decoder::new(LongestRead { file: Cursor::new(file.clone()), longest: 0 }).unwrap();
+        assert_eq!(decoder.image().unwrap().data(), &ImageData::U8(pixels.clone()));
+        assert!(decoder.into_inner().longest <= STRIP_CHOP_BYTES);
+
+        // a band across two pieces reads only the rows it needs
+        let mut decoder = Decoder::new(LongestRead { file: Cursor::new(file), longest: 0 }).unwrap();
+        let region = decoder.read_region(10, 690, 20, 20).unwrap();
+        assert_eq!(region.data(), &image::crop(ImageData::U8(pixels), width, 1, (10, 690, 20, 20)));
+        assert!(decoder.into_inner().longest <= 20 * width);
+    }
+
+    #[test]
+    fn subsampled_ycbcr() {
+        // 4:2:0 and 4:2:2 with a width and height that cut the last blocks short
+        for &(width, height, h, v) in &[(5usize, 3usize, 2usize, 2usize), (7, 3, 2, 1)] {
+            let unit_len = h * v + 2;
+            let (columns, rows) = (width.div_ceil(h), height.div_ceil(v));
+            // luma ramps; the chroma of block 1 is red's, the others' neutral
+            let units = (0..columns * rows).flat_map(|n| {
+                let mut unit = (0..h * v).map(|i| (n * 20 + i * 5) as u8).collect::<Vec<_>>();
+                unit.extend_from_slice(if n == 1 { &[85, 255] } else { &[128, 128] });
+                unit
+            }).collect::<Vec<_>>();
+            let page = page(width as u32, height as u32, 6, 3, vec![8; 3], vec![])
+                .tag(278, Value::Long(vec![v as u32]))
+                .tag(530, Value::Short(vec![h as u16, v as u16]))
+                .strips(units.chunks(columns * unit_len).map(|x| x.to_vec()).collect());
+            let file = TiffBuilder::new(Endian::Little).page(page).build();
+            let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+            let image = decoder.image().unwrap();
+
+            let mut expected = image::upsample_ycbcr(&units, width, height, h, v);
+            image::ycbcr_to_rgb(&mut expected, 3, [0.299, 0.587, 0.114], [0., 255., 128., 255., 128., 255.]);
+            assert_eq!(image.data(), &ImageData::U8(expected.clone()), "{}x{}", h, v);
+            // block 0 starts black, block 1 is red chroma over a luma of 20
+            assert_eq!(&expected[..3], &[0, 0, 0]);
+            assert_eq!(&expected[h * 3..h * 3 + 3], &[198, 0, 0]);
+
+            // regions that start and end inside blocks, the edge ones included
+            for &(x, y, w, rh) in &[(1, 1, 3, 2), (width - 2, height - 2, 2, 2), (width - 1, 0, 1, height)] {
+                let region = decoder.read_region(x as u32, y as u32, w as u32, rh as u32).unwrap();
+                let crop = image::crop(ImageData::U8(expected.clone()), width, 3, (x, y, w, rh));
+                assert_eq!(region.data(), &crop, "{}x{} region {:?}", h, v, (x, y, w, rh));
+            }
+        }
+    }
+
+    #[test]
+    fn cmyk_decodes_to_rgb() {
+        let inks = vec![255, 0, 0, 0, 0, 255, 255, 0, 128, 64, 0, 64];
+        let file = TiffBuilder::new(Endian::Little).page(page(3, 1, 5, 4, vec![8; 4], inks.clone())).build();
+        let mut decoder = Decoder::new(Cursor::new(file.clone())).unwrap();
+        assert_eq!(decoder.image().unwrap().data(), &ImageData::U8(inks));
+
+        let rgb = vec![0, 255, 255, 255, 0, 0, 95, 143, 191];
+        let mut decoder = DecoderBuilder::new().cmyk_to_rgb(true).build(Cursor::new(file)).unwrap();
+        let image = decoder.image().unwrap();
+        assert_eq!(image.header().photometric_interpretation(), PhotometricInterpretation::RGB);
+        assert_eq!(image.data(), &ImageData::U8(rgb.clone()));
+        let mut buffer = vec![0; decoder.image_buffer_size().unwrap()];
+        decoder.read_image_into(&mut buffer).unwrap();
+        assert_eq!(buffer, rgb);
+    }
+}
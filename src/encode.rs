@@ -0,0 +1,2821 @@
+use error::{
+    EncodeError,
+    EncodeErrorKind,
+    EncodeResult,
+};
+use byte::{
+    AlignExt,
+    Endian,
+    EndianWriteExt,
+};
+use codec::{
+    self,
+    EncodeBytes,
+    Uncompressed,
+};
+use decode::Decoder;
+use unseekable::Unseekable;
+use ifd::{
+    self,
+    AnyValues,
+    DataType,
+    IFD,
+    Rational,
+};
+use image::{
+    self,
+    Image,
+    ImageData,
+    ImageHeader,
+    PhotometricInterpretation,
+    Predictor,
+    Resolution,
+    SampleFormat,
+};
+use tag::{
+    self,
+    AnyTag,
+    IntoAnyValues,
+    TagType,
+};
+use std::collections::{
+    BTreeMap,
+    HashSet,
+    VecDeque,
+};
+use std::convert::TryFrom;
+use std::mem;
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
+use std::fmt::{
+    self,
+    Debug,
+};
+use std::io::{
+    self,
+    Cursor,
+    Read,
+    SeekFrom,
+    Seek,
+    Write,
+};
+
+// libtiff's default, small enough for readers to hold a strip at a time
+const DEFAULT_STRIP_BYTES: usize = 8192;
+const DEFAULT_TILE_SIZE: u32 = 256;
+// leaves room for compression gone wrong and the IFDs
+const BIG_TIFF_THRESHOLD: u64 = 0xF000_0000;
+
+pub struct EncoderBuilder {
+    endian: Endian,
+    big_tiff: Option<bool>,
+    codec: Box<dyn EncodeBytes>,
+    predictor: Predictor,
+    layout: Layout,
+    icc_profile: Option<Vec<u8>>,
+    xmp: Option<Vec<u8>>,
+    software: Option<String>,
+    image_description: Option<String>,
+    date_time: Option<String>,
+    auto_date_time: bool,
+    resolution: Option<Resolution>,
+    overview_placement: OverviewPlacement,
+    edge_padding: EdgePadding,
+    auto_pyramid: Option<usize>,
+}
+
+impl Default for EncoderBuilder {
+    fn default() -> EncoderBuilder {
+        EncoderBuilder {
+            endian: Endian::native(),
+            big_tiff: None,
+            codec: Box::new(Uncompressed),
+            predictor: Predictor::No,
+            layout: Layout::Bytes(DEFAULT_STRIP_BYTES),
+            icc_profile: None,
+            xmp: None,
+            software: None,
+            image_description: None,
+            date_time: None,
+            auto_date_time: true,
+            resolution: None,
+            overview_placement: OverviewPlacement::Chained,
+            edge_padding: EdgePadding::Zero,
+            auto_pyramid: None,
+        }
+    }
+}
+
+impl EncoderBuilder {
+    pub fn new() -> EncoderBuilder {
+        EncoderBuilder::default()
+    }
+
+    // The byte order of the file, the machine's own unless set: `Endian::Little`
+    // writes an II header, `Endian::Big` an MM one for readers that need
+    // Motorola order. Samples and values follow it alike.
+    pub fn endian(mut self, endian: Endian) -> EncoderBuilder {
+        self.endian = endian;
+        self
+    }
+
+    // Writes a BigTIFF, with 64-bit offsets, or a classic TIFF, which can't
+    // address past 4 GiB. Unless set, the first page decides: a BigTIFF is
+    // written if its samples alone come close to 4 GiB. Later pages aren't known
+    // yet then, so a file of many pages that only add up to that fails on the
+    // first offset past 4 GiB; set this for those. `encode_cog` and
+    // `build_sequential` count every page they are given.
+    pub fn big_tiff(mut self, big_tiff: bool) -> EncoderBuilder {
+        self.big_tiff = Some(big_tiff);
+        self
+    }
+
+    // Compresses strips with `codec` and writes the Compression value it declares.
+    pub fn with_codec(mut self, codec: Box<dyn EncodeBytes>) -> EncoderBuilder {
+        self.codec = codec;
+        self
+    }
+
+    // Applies `predictor` to the samples of codecs that take one, such as LZW and
+    // Deflate, and is ignored for the others. Horizontal differencing suits 8 and
+    // 16-bit samples, the floating point predictor float samples.
+    pub fn predictor(mut self, predictor: Predictor) -> EncoderBuilder {
+        self.predictor = predictor;
+        self
+    }
+
+    // Splits pages into strips of `rows` rows, the last of which may be shorter.
+    // `u32::MAX` writes every page as a single strip.
+    pub fn rows_per_strip(mut self, rows: u32) -> EncoderBuilder {
+        self.layout = Layout::Rows(rows.max(1));
+        self
+    }
+
+    // Splits pages into strips of as many rows as fit in `bytes` before
+    // compression, at least one. The default is 8 KiB.
+    pub fn strip_bytes(mut self, bytes: usize) -> EncoderBuilder {
+        self.layout = Layout::Bytes(bytes);
+        self
+    }
+
+    // Writes pages as 256x256 tiles instead of strips.
+    pub fn tiled(self) -> EncoderBuilder {
+        self.tile_size(DEFAULT_TILE_SIZE, DEFAULT_TILE_SIZE)
+    }
+
+    // Writes pages as tiles of `width` by `height` pixels, both multiples of 16.
+    // Tiles at the right and bottom edges are padded as `edge_padding` says.
+    pub fn tile_size(mut self, width: u32, height: u32) -> EncoderBuilder {
+        self.layout = Layout::Tiles(width, height);
+        self
+    }
+
+    // What fills the parts of tiles past the right and bottom edges of a page,
+    // zeros unless set. Readers drop it either way.
+    pub fn edge_padding(mut self, padding: EdgePadding) -> EncoderBuilder {
+        self.edge_padding = padding;
+        self
+    }
+
+    // Embeds an ICC color profile in every page.
+    pub fn icc_profile(mut self, profile: Vec<u8>) -> EncoderBuilder {
+        self.icc_profile = Some(profile);
+        self
+    }
+
+    // Embeds an XMP packet in every page.
+    pub fn xmp<T: Into<Vec<u8>>>(mut self, packet: T) -> EncoderBuilder {
+        self.xmp = Some(packet.into());
+        self
+    }
+
+    // Names the program that wrote the file in every page.
+    pub fn software<T: Into<String>>(mut self, software: T) -> EncoderBuilder {
+        self.software = Some(software.into());
+        self
+    }
+
+    // Describes the subject of every page.
+    pub fn image_description<T: Into<String>>(mut self, description: T) -> EncoderBuilder {
+        self.image_description = Some(description.into());
+        self
+    }
+
+    // Dates every page, in TIFF's "YYYY:MM:DD HH:MM:SS" format. Building the
+    // encoder fails for a date in any other shape.
+    pub fn date_time<T: Into<String>>(mut self, date_time: T) -> EncoderBuilder {
+        self.date_time = Some(date_time.into());
+        self
+    }
+
+    // Dates every page with the current time, in UTC as TIFF has no time zones.
+    pub fn date_time_now(self) -> EncoderBuilder {
+        self.date_time(format_date_time(now()))
+    }
+
+    // Pages not dated with `date_time` get the time the encoder was built, unless
+    // they carry a DateTime of their own, such as one `copy_image` copied. On by
+    // default; turn it off for files that must come out the same every time.
+    pub fn auto_date_time(mut self, auto: bool) -> EncoderBuilder {
+        self.auto_date_time = auto;
+        self
+    }
+
+    // The resolution of pages whose headers don't have one.
+    pub fn resolution(mut self, resolution: Resolution) -> EncoderBuilder {
+        self.resolution = Some(resolution);
+        self
+    }
+
+    // Where `Encoder::add_overviews` and `auto_pyramid` put reduced-resolution
+    // pages, after their page in the chain unless set.
+    pub fn overview_placement(mut self, placement: OverviewPlacement) -> EncoderBuilder {
+        self.overview_placement = placement;
+        self
+    }
+
+    // Follows every page given to `Encoder::append_image` with its reduced-resolution
+    // levels, each half the size of the one before (rounding up) by a 2x2 box
+    // filter, down to the first whose width and height are both below
+    // `min_dimension`. They're written like overviews, where `overview_placement`
+    // says, compressed and tiled like the page. Only pages of 8 and 16-bit
+    // unsigned samples get levels, Palette pages none.
+    pub fn auto_pyramid(mut self, min_dimension: usize) -> EncoderBuilder {
+        self.auto_pyramid = Some(min_dimension);
+        self
+    }
+
+    pub fn build<W: Write + Seek>(self, writer: W) -> EncodeResult<Encoder<W>> {
+        Encoder::with_builder(writer, self)
+    }
+
+    // Builds an encoder for a writer that can't seek. Everything written since
+    // the last linked IFD is held in memory until the encoder is done with it, so
+    // each page, compressed, is held whole with its SubIFDs until the next page
+    // starts or `finish`. A page given row by row is held the same way, and a COG
+    // whole until `finish`. `build_sequential` holds only the IFDs, for pages
+    // known up front.
+    pub fn build_unseekable<W: Write>(self, writer: W) -> EncodeResult<Encoder<Unseekable<W>>> {
+        let mut encoder = Encoder::with_builder(Unseekable::new(writer), self)?;
+        encoder.release = Some(Unseekable::release);
+
+        Ok(encoder)
+    }
+
+    // Builds an encoder that writes `pages` to a writer that can't seek, laid out
+    // before anything is written; see `SequentialEncoder` for its rules.
+    pub fn build_sequential<W: Write>(self, writer: W, pages: Vec<PlannedPage>) -> EncodeResult<SequentialEncoder<W>> {
+        SequentialEncoder::plan(writer, self, pages)
+    }
+
+    // Builds an encoder that adds pages to the end of the TIFF in `file`. The
+    // file's byte order and BigTIFF choice win over the builder's.
+    pub fn build_append<F: Read + Write + Seek>(self, file: F) -> EncodeResult<Encoder<F>> {
+        Encoder::append_with_builder(file, self)
+    }
+}
+
+impl Debug for EncoderBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EncoderBuilder")
+            .field("endian", &self.endian)
+            .field("big_tiff", &self.big_tiff)
+            .field("compression", &self.codec.compression())
+            .field("predictor", &self.predictor)
+            .field("layout", &self.layout)
+            .field("icc_profile", &self.icc_profile.as_ref().map(|x| x.len()))
+            .field("xmp", &self.xmp.as_ref().map(|x| x.len()))
+            .field("software", &self.software)
+            .field("image_description", &self.image_description)
+            .field("date_time", &self.date_time)
+            .field("auto_date_time", &self.auto_date_time)
+            .field("resolution", &self.resolution)
+            .field("overview_placement", &self.overview_placement)
+            .field("edge_padding", &self.edge_padding)
+            .field("auto_pyramid", &self.auto_pyramid)
+            .finish()
+    }
+}
+
+// Writes TIFF files page by page, each laid out as strips, out-of-line values and
+// then the IFD. A page's IFD waits for the next page or `finish`, so that the
+// SubIFDs written in between can be listed in it, and its offset is then patched
+// into the header or into the previous page's next-IFD pointer.
+pub struct Encoder<W> {
+    writer: W,
+    endian: Endian,
+    // `None` until the first page decides
+    big_tiff_choice: Option<bool>,
+    big_tiff: bool,
+    // the samples of a first page over this make a BigTIFF, see `choose_big_tiff`
+    big_tiff_threshold: u64,
+    codec: Box<dyn EncodeBytes>,
+    predictor: Predictor,
+    layout: Layout,
+    icc_profile: Option<Vec<u8>>,
+    xmp: Option<Vec<u8>>,
+    software: Option<String>,
+    image_description: Option<String>,
+    date_time: Option<String>,
+    // the DateTime of pages that have none
+    auto_date_time: Option<String>,
+    resolution: Option<Resolution>,
+    overview_placement: OverviewPlacement,
+    edge_padding: EdgePadding,
+    auto_pyramid: Option<usize>,
+    // entries for the next page, set with `put_value` and `put_any`
+    extra: Directory,
+    // the page being given row by row
+    stream: Option<Stream>,
+    // the last page, whose IFD waits for the SubIFDs that may follow it
+    parent: Option<Parent>,
+    // where the offset of the next page's IFD goes
+    next_pointer: u64,
+    pages: usize,
+    // tells the writer that nothing before an offset will be written again
+    release: Option<fn(&mut W, u64) -> io::Result<()>>,
+}
+
+impl<W> Encoder<W> where W: Write + Seek {
+    pub fn new(writer: W) -> EncodeResult<Encoder<W>> {
+        Encoder::with_builder(writer, EncoderBuilder::new())
+    }
+
+    fn with_builder(mut writer: W, builder: EncoderBuilder) -> EncodeResult<Encoder<W>> {
+        builder.check()?;
+        let big_tiff = builder.big_tiff.unwrap_or(false);
+        let next_pointer = write_header(&mut writer, builder.endian, big_tiff)?;
+
+        Ok(Encoder::resume(writer, builder, next_pointer, 0))
+    }
+
+    // An encoder that goes on after `pages` pages, linking the next one at
+    // `next_pointer`.
+    fn resume(writer: W, builder: EncoderBuilder, next_pointer: u64, pages: usize) -> Encoder<W> {
+        let big_tiff = builder.big_tiff.unwrap_or(false);
+        let auto_date_time = match builder.date_time {
+            None if builder.auto_date_time => Some(format_date_time(now())),
+            _ => None,
+        };
+        Encoder {
+            writer,
+            endian: builder.endian,
+            big_tiff_choice: builder.big_tiff,
+            big_tiff,
+            big_tiff_threshold: BIG_TIFF_THRESHOLD,
+            codec: builder.codec,
+            predictor: builder.predictor,
+            layout: builder.layout,
+            icc_profile: builder.icc_profile,
+            xmp: builder.xmp,
+            software: builder.software,
+            image_description: builder.image_description,
+            date_time: builder.date_time,
+            auto_date_time,
+            resolution: builder.resolution,
+            overview_placement: builder.overview_placement,
+            edge_padding: builder.edge_padding,
+            auto_pyramid: builder.auto_pyramid,
+            extra: Directory::default(),
+            stream: None,
+            parent: None,
+            next_pointer,
+            pages,
+            release: None,
+        }
+    }
+
+}
+
+impl<W> Encoder<W> where W: Read + Write + Seek {
+    // Adds pages to the end of the TIFF in `file`, chained after its last IFD.
+    // What's already in the file is left as it is; `pages` counts its pages too.
+    pub fn open_append(file: W) -> EncodeResult<Encoder<W>> {
+        Encoder::append_with_builder(file, EncoderBuilder::new())
+    }
+
+    fn append_with_builder(file: W, builder: EncoderBuilder) -> EncodeResult<Encoder<W>> {
+        builder.check()?;
+        let mut decoder = Decoder::new(file)?;
+        let header = decoder.file_header();
+        let offsets = decoder.ifd_offsets()?;
+        let next_pointer = match offsets.last() {
+            Some(&last) => decoder.next_ifd_pointer(last)?,
+            None => if header.is_big_tiff() { 8 } else { 4 },
+        };
+        let mut file = decoder.into_inner();
+        file.seek(SeekFrom::End(0))?;
+
+        let builder = EncoderBuilder {
+            endian: header.endian(),
+            big_tiff: Some(header.is_big_tiff()),
+            ..builder
+        };
+
+        Ok(Encoder::resume(file, builder, next_pointer, offsets.len()))
+    }
+}
+
+impl<W> Encoder<W> where W: Write + Seek {
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    pub fn is_big_tiff(&self) -> bool {
+        self.big_tiff
+    }
+
+    pub fn pages(&self) -> usize {
+        self.pages
+    }
+
+    // Writes `image` as the file's only page and hands back the writer.
+    pub fn encode(mut self, image: &Image) -> EncodeResult<W> {
+        self.append_image(image)?;
+        self.finish()
+    }
+
+    // Writes `image` and its `overviews` as a Cloud Optimized GeoTIFF and hands
+    // back the writer. Every IFD comes right after the header, so a reader learns
+    // the whole layout from the first bytes; the tiles follow from the smallest
+    // overview to the full image, each level in row-major order. Pages are tiled
+    // with the builder's tile size, 256x256 unless set. Overviews must each be
+    // smaller than the level before and get NewSubfileType 1; entries given to
+    // `put_value` and `put_any` go to the full image.
+    pub fn encode_cog(mut self, image: &Image, overviews: &[Image]) -> EncodeResult<W> {
+        if self.pages != 0 || self.stream.is_some() {
+            return Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration { reason: "a COG can't follow other pages".to_string() }));
+        }
+        let levels = Some(image).into_iter().chain(overviews).collect::<Vec<_>>();
+        for pair in levels.windows(2) {
+            let (larger, smaller) = (pair[0].header(), pair[1].header());
+            if smaller.width() > larger.width() || smaller.height() > larger.height() || (smaller.width(), smaller.height()) == (larger.width(), larger.height()) {
+                return Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration {
+                    reason: format!("overview of {}x{} pixels after a level of {}x{}", smaller.width(), smaller.height(), larger.width(), larger.height()),
+                }));
+            }
+        }
+        let layout = match self.layout {
+            Layout::Tiles(..) => self.layout,
+            _ => Layout::Tiles(DEFAULT_TILE_SIZE, DEFAULT_TILE_SIZE),
+        };
+        self.check_conflicts(image.header(), &self.extra)?;
+        self.choose_big_tiff(levels.iter().map(|x| image_bytes(x.header())).sum())?;
+        let extra = mem::take(&mut self.extra);
+        extra.check(self.big_tiff)?;
+        let overview = Directory::overview();
+
+        let mut chunks = Vec::with_capacity(levels.len());
+        for level in &levels {
+            chunks.push(self.compress(level, layout)?);
+        }
+        let directories = (0..levels.len()).map(|i| if i == 0 { &extra } else { &overview }).collect::<Vec<_>>();
+        let headers = levels.iter().map(|x| x.header()).collect::<Vec<_>>();
+        let layouts = chunks.iter().map(|x| (x.chunking, x.byte_counts())).collect::<Vec<_>>();
+        let order = (0..levels.len()).rev().collect::<Vec<_>>();
+        self.write_ifds_first(&headers, &layouts, &directories, &order)?;
+        for level in chunks.iter().rev() {
+            for data in &level.data {
+                self.writer.write_all(data)?;
+            }
+        }
+
+        self.finish()
+    }
+
+    // Writes and links the IFDs of pages whose strips or tiles, of the sizes in
+    // `layouts`, all come after them, page by page in `order`. The IFDs are
+    // written twice: first with zero offsets to learn where the chunks start,
+    // then over themselves, the same size, with the real ones. Leaves the writer
+    // where the chunks start.
+    fn write_ifds_first(&mut self, headers: &[&ImageHeader], layouts: &[(Chunking, Vec<u64>)], directories: &[&Directory], order: &[usize]) -> EncodeResult<()> {
+        let start = self.writer.stream_position()?;
+        let zeros = layouts.iter().map(|x| vec![0; x.1.len()]).collect::<Vec<_>>();
+        self.write_ifds(headers, layouts, directories, &zeros)?;
+        self.writer.align_to_word()?;
+        let mut position = self.writer.stream_position()?;
+        let mut offsets = vec![vec![]; headers.len()];
+        for &i in order {
+            for &byte_count in &layouts[i].1 {
+                offsets[i].push(position);
+                position += byte_count;
+            }
+        }
+        if !self.big_tiff && position > u32::MAX as u64 {
+            return Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration {
+                reason: format!("offset {} doesn't fit in a classic TIFF, see EncoderBuilder::big_tiff", position),
+            }));
+        }
+        self.writer.seek(SeekFrom::Start(start))?;
+        let ifds = self.write_ifds(headers, layouts, directories, &offsets)?;
+
+        let mut at = self.next_pointer;
+        for (ifd, next_pointer) in ifds {
+            self.patch_offset(at, ifd)?;
+            at = next_pointer;
+        }
+        self.next_pointer = at;
+        self.pages += headers.len();
+
+        Ok(())
+    }
+
+    fn write_ifds(&mut self, headers: &[&ImageHeader], layouts: &[(Chunking, Vec<u64>)], directories: &[&Directory], offsets: &[Vec<u64>]) -> EncodeResult<Vec<(u64, u64)>> {
+        let mut ifds = Vec::with_capacity(headers.len());
+        for i in 0..headers.len() {
+            let mut fields = self.page_fields(headers[i], layouts[i].0, &offsets[i], &layouts[i].1);
+            self.write_page_directory(directories[i], &mut fields)?;
+            ifds.push(self.write_ifd(fields)?);
+        }
+
+        Ok(ifds)
+    }
+
+    // Sets `tag` for the next page written, replacing any value it was given
+    // before. The datatype is the narrowest the tag allows, see `IntoAnyValues`.
+    // Setting a tag the encoder writes for the page itself, like ImageWidth or
+    // StripOffsets, fails it with `TagConflict`.
+    pub fn put_value<T: TagType>(&mut self, tag: T, value: T::Value) -> Option<AnyValues> where T::Value: IntoAnyValues {
+        self.put_any(AnyTag::from(tag), ifd::tag_values(tag, value))
+    }
+
+    // Sets `tag` for the next page to `values` of whatever datatype they are.
+    // Values of up to 4 bytes are stored in the entry, longer ones before the IFD.
+    pub fn put_any(&mut self, tag: AnyTag, values: AnyValues) -> Option<AnyValues> {
+        self.extra.values.insert(tag.id(), values)
+    }
+
+    // Writes `image` as the next page, linked from the previous one, with the
+    // entries given to `put_value` and `put_any` since the last page, and the
+    // levels `EncoderBuilder::auto_pyramid` asks for.
+    pub fn append_image(&mut self, image: &Image) -> EncodeResult<()> {
+        self.check_conflicts(image.header(), &self.extra)?;
+        let extra = mem::take(&mut self.extra);
+        self.append_image_with(image, &extra)?;
+        self.append_pyramid(image)
+    }
+
+    fn append_pyramid(&mut self, image: &Image) -> EncodeResult<()> {
+        let min_dimension = match self.auto_pyramid {
+            Some(min_dimension) => min_dimension.min(u32::MAX as usize) as u32,
+            None => return Ok(()),
+        };
+        let header = image.header();
+        let filterable = match *image.data() {
+            ImageData::U8(_) => header.bits_per_sample().values().iter().all(|&x| x == 8),
+            ImageData::U16(_) => true,
+            _ => false,
+        };
+        if !filterable || header.photometric_interpretation() == PhotometricInterpretation::Palette {
+            return Ok(());
+        }
+        let overview = Directory::overview();
+        for level in image.pyramid(min_dimension) {
+            match self.overview_placement {
+                OverviewPlacement::Chained => self.append_image_with(&level, &overview)?,
+                OverviewPlacement::SubIFDs => self.append_sub_image_with(&level, &overview)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    // Writes `image` as a child of the last page, in that page's SubIFDs rather
+    // than the chain of pages, with bit 0 of its NewSubfileType set to mark it
+    // reduced-resolution. A page can have any number of them, in the order they're
+    // written. It gets the entries given to `put_value` and `put_any` since the
+    // last page.
+    pub fn append_sub_image(&mut self, image: &Image) -> EncodeResult<()> {
+        self.check_conflicts(image.header(), &self.extra)?;
+        let extra = mem::take(&mut self.extra);
+        self.append_sub_image_with(image, &extra)
+    }
+
+    fn append_sub_image_with(&mut self, image: &Image, extra: &Directory) -> EncodeResult<()> {
+        self.check_no_stream()?;
+        match self.parent {
+            Some(ref parent) if parent.fields.entries.contains_key(&tag::SubIFDs.id()) => {
+                return Err(EncodeError::from(EncodeErrorKind::TagConflict { tag: AnyTag::SubIFDs }));
+            }
+            Some(_) => {},
+            None => return Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration { reason: "a SubIFD needs a page before it to belong to".to_string() })),
+        }
+        let mut fields = self.write_image(image, extra)?;
+        fields.add_subfile_type(1);
+        let (ifd, _) = self.write_ifd(fields)?;
+        if let Some(ref mut parent) = self.parent {
+            parent.sub_ifds.push(ifd);
+        }
+
+        Ok(())
+    }
+
+    // Adds a reduced-resolution page of `image` for each of `factors`, every one
+    // `image` shrunk that many times with a box filter and marked with
+    // NewSubfileType 1. They go where the builder's `overview_placement` says, as
+    // pages of their own or as SubIFDs of the last page. Factors must be at least
+    // 2. Entries given to `put_value` and `put_any` are kept for the next full
+    // page.
+    pub fn add_overviews(&mut self, image: &Image, factors: &[u32]) -> EncodeResult<()> {
+        if let Some(factor) = factors.iter().find(|&&x| x < 2) {
+            return Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration { reason: format!("overview factor {}, it must be at least 2", factor) }));
+        }
+        let overview = Directory::overview();
+        for &factor in factors {
+            let reduced = image.downsample_by(factor);
+            match self.overview_placement {
+                OverviewPlacement::Chained => self.append_image_with(&reduced, &overview)?,
+                OverviewPlacement::SubIFDs => self.append_sub_image_with(&reduced, &overview)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    // Starts a page of `header`'s size whose samples are given with `write_rows`
+    // rather than all at once, so it never has to be in memory whole. It gets the
+    // entries given to `put_value` and `put_any` since the last page.
+    pub fn begin_image(&mut self, header: ImageHeader) -> EncodeResult<()> {
+        self.check_no_stream()?;
+        self.check_conflicts(&header, &self.extra)?;
+        self.write_parent()?;
+        if self.pages == 0 {
+            self.choose_big_tiff(image_bytes(&header))?;
+        }
+        let extra = mem::take(&mut self.extra);
+        extra.check(self.big_tiff)?;
+        extra.check_palette(&header)?;
+        let row_bytes = row_bytes(&header);
+        let chunking = self.chunking(&header)?;
+        self.writer.align_to_word()?;
+
+        let stream = Stream {
+            header,
+            extra,
+            row_bytes,
+            chunking,
+            buffer: vec![],
+            written: 0,
+            offsets: vec![],
+            byte_counts: vec![],
+        };
+        if stream.total_bytes() == 0 {
+            return self.end_stream(stream);
+        }
+        self.stream = Some(stream);
+
+        Ok(())
+    }
+
+    // Adds rows to the page started with `begin_image`, their samples as stored in
+    // the file: 16-bit ones in the encoder's byte order and masks packed to one
+    // bit per pixel, each row starting on a byte. A row may be split across calls.
+    // Strips or tiles are written as soon as their rows are in, and the page's IFD
+    // with its last row.
+    pub fn write_rows(&mut self, rows: &[u8]) -> EncodeResult<()> {
+        let mut stream = match self.stream.take() {
+            Some(stream) => stream,
+            None => return Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration { reason: "no page was begun with begin_image".to_string() })),
+        };
+        let given = stream.written + stream.buffer.len() + rows.len();
+        if given > stream.total_bytes() {
+            let reason = format!("{} bytes of rows given for a page of {}", given, stream.total_bytes());
+            self.stream = Some(stream);
+            return Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration { reason }));
+        }
+        stream.buffer.extend_from_slice(rows);
+
+        let band_bytes = stream.chunking.rows as usize * stream.row_bytes;
+        let mut start = 0;
+        while stream.buffer.len() - start >= band_bytes || (given == stream.total_bytes() && start < stream.buffer.len()) {
+            let end = (start + band_bytes).min(stream.buffer.len());
+            let band = stream.buffer[start..end].to_vec();
+            self.write_band(&mut stream, &band)?;
+            start = end;
+        }
+        stream.buffer.drain(..start);
+
+        if stream.written == stream.total_bytes() {
+            return self.end_stream(stream);
+        }
+        self.stream = Some(stream);
+
+        Ok(())
+    }
+
+    // How a page of `header`'s size is cut into chunks with the builder's layout.
+    fn chunking(&self, header: &ImageHeader) -> EncodeResult<Chunking> {
+        let predictor = self.page_predictor(header)?;
+        Ok(self.chunking_with(header, predictor, self.codec.compression()))
+    }
+
+    fn chunking_with(&self, header: &ImageHeader, predictor: Predictor, compression: u16) -> Chunking {
+        match self.layout {
+            Layout::Tiles(width, height) => Chunking { tile_size: Some((width, height)), rows: height, predictor, compression },
+            _ => Chunking { tile_size: None, rows: self.strip_rows(header.height(), row_bytes(header)), predictor, compression },
+        }
+    }
+
+    // Fails if `directory` sets a tag the encoder writes for a page of `header`.
+    fn check_conflicts(&self, header: &ImageHeader, directory: &Directory) -> EncodeResult<()> {
+        let derived = self.page_fields(header, self.chunking(header)?, &[], &[]);
+        match directory.values.keys().chain(directory.links.keys()).find(|x| derived.entries.contains_key(x)) {
+            Some(&tag) => Err(EncodeError::from(EncodeErrorKind::TagConflict { tag: AnyTag::from(tag) })),
+            None => Ok(()),
+        }
+    }
+
+    // Compresses and writes rows of the streamed page, a strip's worth or a row of
+    // tiles, or what's left of one at the bottom.
+    fn write_band(&mut self, stream: &mut Stream, pixels: &[u8]) -> EncodeResult<()> {
+        let rows = match stream.row_bytes {
+            0 => 0,
+            row_bytes => pixels.len() / row_bytes,
+        };
+        let header = stream.header.clone().with_dimensions(stream.header.width(), rows as u32);
+        let layout = self.layout;
+        let chunks = self.compress_pixels(&header, pixels, layout)?;
+        for data in &chunks.data {
+            stream.offsets.push(self.offset_here()?);
+            stream.byte_counts.push(data.len() as u64);
+            self.writer.write_all(data)?;
+        }
+        stream.written += pixels.len();
+
+        Ok(())
+    }
+
+    fn end_stream(&mut self, mut stream: Stream) -> EncodeResult<()> {
+        if stream.offsets.is_empty() {
+            self.write_band(&mut stream, &[])?;
+        }
+        let mut fields = self.page_fields(&stream.header, stream.chunking, &stream.offsets, &stream.byte_counts);
+        self.write_page_directory(&stream.extra, &mut fields)?;
+        self.add_page(fields);
+
+        Ok(())
+    }
+
+    fn check_no_stream(&self) -> EncodeResult<()> {
+        match self.stream {
+            Some(ref stream) => Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration {
+                reason: format!("the page begun with begin_image has {} of its {} rows", (stream.written + stream.buffer.len()) / stream.row_bytes, stream.header.height()),
+            })),
+            None => Ok(()),
+        }
+    }
+
+    // Writes `image`, a Palette image whose samples index `palette`, as the next
+    // page with the ColorMap for it. Indices are as they are, also for images of
+    // fewer than 8 bits, and colors have 16 bits a channel. ColorMap holds a color
+    // for every index BitsPerSample allows; those past `palette` are black.
+    pub fn append_palette_image(&mut self, image: &Image, palette: &[[u16; 3]]) -> EncodeResult<()> {
+        let header = image.header();
+        let bits_per_sample = header.bits_per_sample();
+        if header.photometric_interpretation() != PhotometricInterpretation::Palette || header.samples_per_pixel() != 1 || bits_per_sample.bits() > 8 {
+            return Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration {
+                reason: format!("a palette needs a Palette image of one sample of up to 8 bits, not {:?} with BitsPerSample {:?}", header.photometric_interpretation(), bits_per_sample.values()),
+            }));
+        }
+        let colors = 1 << bits_per_sample.bits();
+        if palette.len() > colors {
+            return Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration {
+                reason: format!("{} colors for {}-bit indices", palette.len(), bits_per_sample.bits()),
+            }));
+        }
+        if let ImageData::U8(ref data) = *image.data() {
+            if let Some(index) = data.iter().find(|&&x| x as usize >= palette.len()) {
+                return Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration {
+                    reason: format!("palette index {} past its {} colors", index, palette.len()),
+                }));
+            }
+        }
+        if self.extra.values.contains_key(&tag::ColorMap.id()) {
+            return Err(EncodeError::from(EncodeErrorKind::TagConflict { tag: AnyTag::ColorMap }));
+        }
+        self.check_conflicts(header, &self.extra)?;
+
+        let mut color_map = vec![0u16; colors * 3];
+        for (i, color) in palette.iter().enumerate() {
+            for (c, value) in color.iter().enumerate() {
+                color_map[c * colors + i] = *value;
+            }
+        }
+        let mut extra = mem::take(&mut self.extra);
+        extra.values.insert(tag::ColorMap.id(), AnyValues::Short(color_map));
+        self.append_image_with(image, &extra)
+    }
+
+    // Writes the RGB `image` as a Palette page of at most `max_colors` colors, see
+    // `Image::quantize`.
+    pub fn append_quantized(&mut self, image: &Image, max_colors: usize) -> EncodeResult<()> {
+        match image.quantize(max_colors) {
+            Some((indices, palette)) => self.append_palette_image(&indices, &palette),
+            None => Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration {
+                reason: format!("only RGB images of 8 or 16-bit samples can be quantized, not {:?} with BitsPerSample {:?}", image.header().photometric_interpretation(), image.header().bits_per_sample().values()),
+            })),
+        }
+    }
+
+    fn append_image_with(&mut self, image: &Image, extra: &Directory) -> EncodeResult<()> {
+        self.check_no_stream()?;
+        self.write_parent()?;
+        let fields = self.write_image(image, extra)?;
+        self.add_page(fields);
+
+        Ok(())
+    }
+
+    // Makes the page of `fields` the last one, its IFD written once no more
+    // SubIFDs can follow.
+    fn add_page(&mut self, fields: Fields) {
+        self.parent = Some(Parent { fields, sub_ifds: vec![] });
+        self.pages += 1;
+    }
+
+    // Writes the IFD of the last page, with its SubIFDs, and links it.
+    fn write_parent(&mut self) -> EncodeResult<()> {
+        let mut parent = match self.parent.take() {
+            Some(parent) => parent,
+            None => return Ok(()),
+        };
+        if !parent.sub_ifds.is_empty() {
+            let sub_ifds = match self.big_tiff {
+                true => AnyValues::IFD8(parent.sub_ifds),
+                false => AnyValues::IFD(parent.sub_ifds.iter().map(|&x| x as u32).collect()),
+            };
+            parent.fields.any(tag::SubIFDs.id(), &sub_ifds);
+        }
+        let ifd = self.write_ifd(parent.fields)?;
+        self.link_page(ifd)
+    }
+
+    // Points the previous page, or the header, at the IFD just written.
+    fn link_page(&mut self, (ifd, next_pointer): (u64, u64)) -> EncodeResult<()> {
+        let at = self.next_pointer;
+        self.patch_offset(at, ifd)?;
+        self.next_pointer = next_pointer;
+        if let Some(release) = self.release {
+            release(&mut self.writer, next_pointer)?;
+        }
+
+        Ok(())
+    }
+
+    // Hands back the writer once every page is written. A TIFF needs at least one.
+    pub fn finish(mut self) -> EncodeResult<W> {
+        self.check_no_stream()?;
+        self.write_parent()?;
+        if self.pages == 0 {
+            return Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration { reason: "no image was written".to_string() }));
+        }
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    // Writes the strips or tiles of `image` and the values of its IFD but not the
+    // IFD itself.
+    fn write_image(&mut self, image: &Image, extra: &Directory) -> EncodeResult<Fields> {
+        if self.pages == 0 {
+            self.choose_big_tiff(image_bytes(image.header()))?;
+        }
+        extra.check(self.big_tiff)?;
+        extra.check_palette(image.header())?;
+        let layout = self.layout;
+        let chunks = self.compress(image, layout)?;
+
+        let mut offsets = Vec::with_capacity(chunks.data.len());
+        self.writer.align_to_word()?;
+        for data in &chunks.data {
+            offsets.push(self.offset_here()?);
+            self.writer.write_all(data)?;
+        }
+        let mut fields = self.page_fields(image.header(), chunks.chunking, &offsets, &chunks.byte_counts());
+        self.write_page_directory(extra, &mut fields)?;
+
+        Ok(fields)
+    }
+
+    // Switches to BigTIFF before the first page if the builder left it open and
+    // `bytes` of samples wouldn't leave room in a classic TIFF.
+    fn choose_big_tiff(&mut self, bytes: u64) -> EncodeResult<()> {
+        if self.big_tiff_choice.is_none() && bytes > self.big_tiff_threshold {
+            self.next_pointer = write_header(&mut self.writer, self.endian, true)?;
+            self.big_tiff = true;
+        }
+
+        Ok(())
+    }
+
+    // Compresses the strips or tiles of `image` without writing them.
+    fn compress(&mut self, image: &Image, layout: Layout) -> EncodeResult<Chunks> {
+        let pixels = self.pixel_bytes(image)?;
+        self.compress_pixels(image.header(), &pixels, layout)
+    }
+
+    fn compress_pixels(&mut self, header: &ImageHeader, pixels: &[u8], layout: Layout) -> EncodeResult<Chunks> {
+        let predictor = self.page_predictor(header)?;
+        match layout {
+            Layout::Tiles(width, height) => self.compress_tiles(header, pixels, predictor, width, height),
+            _ => self.compress_strips(header, pixels, predictor),
+        }
+    }
+
+    fn compress_strips(&mut self, header: &ImageHeader, pixels: &[u8], predictor: Predictor) -> EncodeResult<Chunks> {
+        let row_bytes = match header.height() {
+            0 => 0,
+            height => pixels.len() / height as usize,
+        };
+        let rows = self.strip_rows(header.height(), row_bytes);
+        let strips = match rows as usize * row_bytes {
+            0 => vec![pixels],
+            len => pixels.chunks(len).collect(),
+        };
+
+        let mut data = Vec::with_capacity(strips.len());
+        for strip in strips {
+            data.push(self.encode_chunk(header, predictor, strip, header.width(), row_bytes)?);
+        }
+
+        Ok(Chunks { data, chunking: Chunking { tile_size: None, rows, predictor, compression: self.codec.compression() } })
+    }
+
+    // Tiles go row by row. Their widths are multiples of 16 pixels, so each starts
+    // on a byte even in a bilevel image. Edge tiles are padded row by row, then
+    // below the page's last row.
+    fn compress_tiles(&mut self, header: &ImageHeader, pixels: &[u8], predictor: Predictor, tile_width: u32, tile_height: u32) -> EncodeResult<Chunks> {
+        let (width, height) = (header.width() as usize, header.height() as usize);
+        let bits_per_pixel = header.bits_per_sample().values().iter().map(|&x| x as usize).sum::<usize>();
+        let row_bytes = match height {
+            0 => 0,
+            height => pixels.len() / height,
+        };
+        let (tile_width, tile_height) = (tile_width as usize, tile_height as usize);
+        let tile_row_bytes = tile_width * bits_per_pixel / 8;
+        let across = width.div_ceil(tile_width);
+        let down = height.div_ceil(tile_height);
+
+        let mut data = Vec::with_capacity(across * down);
+        let mut tile = vec![0u8; tile_row_bytes * tile_height];
+        for ty in 0..down {
+            for tx in 0..across {
+                let start = tx * tile_row_bytes;
+                let len = tile_row_bytes.min(row_bytes - start);
+                let columns = tile_width.min(width - tx * tile_width);
+                let rows = tile_height.min(height - ty * tile_height);
+                for (y, tile_row) in tile.chunks_mut(tile_row_bytes).enumerate().take(rows) {
+                    let row = ty * tile_height + y;
+                    tile_row.fill(0);
+                    tile_row[..len].copy_from_slice(&pixels[row * row_bytes + start..][..len]);
+                    if self.edge_padding == EdgePadding::ReplicateEdge {
+                        replicate_pixel(tile_row, columns, bits_per_pixel);
+                    }
+                }
+                match self.edge_padding {
+                    EdgePadding::Zero => tile[rows * tile_row_bytes..].fill(0),
+                    EdgePadding::ReplicateEdge => {
+                        let (filled, padding) = tile.split_at_mut(rows * tile_row_bytes);
+                        let last = &filled[filled.len() - tile_row_bytes..];
+                        padding.chunks_mut(tile_row_bytes).for_each(|x| x.copy_from_slice(last));
+                    }
+                }
+                data.push(self.encode_chunk(header, predictor, &tile, tile_width as u32, tile_row_bytes)?);
+            }
+        }
+
+        let tile_size = (tile_width as u32, tile_height as u32);
+        Ok(Chunks { data, chunking: Chunking { tile_size: Some(tile_size), rows: tile_size.1, predictor, compression: self.codec.compression() } })
+    }
+
+    // The entries the encoder derives for a page whose chunks start at `offsets`.
+    fn page_fields(&self, header: &ImageHeader, chunking: Chunking, offsets: &[u64], byte_counts: &[u64]) -> Fields {
+        let mut fields = Fields::new(self.endian, self.big_tiff);
+        match chunking.tile_size {
+            Some((width, height)) => {
+                fields.short_or_long(tag::TileWidth, &[width]);
+                fields.short_or_long(tag::TileLength, &[height]);
+                fields.offsets(tag::TileOffsets, offsets);
+                fields.short_or_long_or_long8(tag::TileByteCounts, byte_counts);
+            }
+            None => {
+                fields.offsets(tag::StripOffsets, offsets);
+                fields.short_or_long(tag::RowsPerStrip, &[chunking.rows]);
+                fields.short_or_long_or_long8(tag::StripByteCounts, byte_counts);
+            }
+        }
+        if chunking.predictor != Predictor::No {
+            fields.short(tag::Predictor, &[chunking.predictor.value()]);
+        }
+
+        let interpretation = header.photometric_interpretation();
+        if interpretation == PhotometricInterpretation::TransparencyMask {
+            fields.short_or_long(tag::NewSubfileType, &[4]);
+        }
+        fields.short_or_long(tag::ImageWidth, &[header.width()]);
+        fields.short_or_long(tag::ImageLength, &[header.height()]);
+        fields.short(tag::BitsPerSample, &header.bits_per_sample().values());
+        fields.short(tag::Compression, &[chunking.compression]);
+        fields.short(tag::PhotometricInterpretation, &[interpretation.value()]);
+        fields.short(tag::SamplesPerPixel, &[header.samples_per_pixel() as u16]);
+        if header.sample_format() != SampleFormat::Unsigned {
+            fields.short(tag::SampleFormat, &vec![header.sample_format().value(); header.samples_per_pixel()]);
+        }
+        if let Some(resolution) = header.resolution().or(self.resolution) {
+            fields.rational(tag::XResolution, resolution.x());
+            fields.rational(tag::YResolution, resolution.y());
+            fields.short(tag::ResolutionUnit, &[resolution.unit().value()]);
+        }
+        if !header.extra_samples().is_empty() {
+            let extra_samples = header.extra_samples().iter().map(|x| x.value()).collect::<Vec<_>>();
+            fields.short(tag::ExtraSamples, &extra_samples);
+        }
+        if let Some(ref packet) = self.xmp {
+            fields.byte(tag::XMP, packet);
+        }
+        if let Some(ref profile) = self.icc_profile {
+            fields.undefined(tag::ICCProfile, profile);
+        }
+        if let Some(ref software) = self.software {
+            fields.any(tag::Software.id(), &ifd::tag_values(tag::Software, software.clone()));
+        }
+        if let Some(ref description) = self.image_description {
+            fields.any(tag::ImageDescription.id(), &ifd::tag_values(tag::ImageDescription, description.clone()));
+        }
+        if let Some(ref date_time) = self.date_time {
+            fields.any(tag::DateTime.id(), &ifd::tag_values(tag::DateTime, date_time.clone()));
+        }
+
+        fields
+    }
+
+    // The predictor for a page: none for codecs that don't take one, and an error
+    // if the samples don't suit the one asked for.
+    fn page_predictor(&self, header: &ImageHeader) -> EncodeResult<Predictor> {
+        if !self.codec.uses_predictor() {
+            return Ok(Predictor::No);
+        }
+        let bits_per_sample = header.bits_per_sample();
+        let suits = match self.predictor {
+            Predictor::No => true,
+            Predictor::Horizontal => bits_per_sample.is_uniform() && [8, 16, 32, 64].contains(&bits_per_sample.bits()),
+            Predictor::FloatingPoint => header.sample_format() == SampleFormat::Float && bits_per_sample.is_uniform() && [16, 32, 64].contains(&bits_per_sample.bits()),
+        };
+        if !suits {
+            return Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration {
+                reason: format!("Predictor {:?} doesn't suit {:?} samples of {:?} bits", self.predictor, header.sample_format(), bits_per_sample.values()),
+            }));
+        }
+
+        Ok(self.predictor)
+    }
+
+    // Compresses one strip or tile of rows `row_bytes` long, applying `predictor`
+    // to a copy of it first.
+    fn encode_chunk(&mut self, header: &ImageHeader, predictor: Predictor, chunk: &[u8], width: u32, row_bytes: usize) -> EncodeResult<Vec<u8>> {
+        self.codec.start_chunk(width, row_bytes);
+        let samples = header.samples_per_pixel();
+        let bytes_per_sample = header.bits_per_sample().bits() / 8;
+        let encoded = match predictor {
+            Predictor::No => self.codec.encode_bytes(chunk)?,
+            Predictor::Horizontal => {
+                let mut chunk = chunk.to_vec();
+                codec::apply_horizontal_predictor(&mut chunk, row_bytes, samples, bytes_per_sample, self.endian);
+                self.codec.encode_bytes(&chunk)?
+            }
+            Predictor::FloatingPoint => {
+                let mut chunk = chunk.to_vec();
+                codec::apply_floating_point_predictor(&mut chunk, row_bytes, samples, bytes_per_sample, self.endian);
+                self.codec.encode_bytes(&chunk)?
+            }
+        };
+
+        Ok(encoded)
+    }
+
+    // Adds the entries of `directory` the encoder hasn't set itself, writing the
+    // IFDs it links to first.
+    // `write_directory` for a page, which is then dated if it isn't yet.
+    fn write_page_directory(&mut self, directory: &Directory, fields: &mut Fields) -> EncodeResult<()> {
+        self.write_directory(directory, fields)?;
+        if let Some(ref date_time) = self.auto_date_time {
+            fields.any(tag::DateTime.id(), &ifd::tag_values(tag::DateTime, date_time.clone()));
+        }
+
+        Ok(())
+    }
+
+    fn write_directory(&mut self, directory: &Directory, fields: &mut Fields) -> EncodeResult<()> {
+        for (tag, linked) in &directory.links {
+            let mut linked_fields = Fields::new(self.endian, self.big_tiff);
+            self.write_directory(linked, &mut linked_fields)?;
+            let (offset, _) = self.write_ifd(linked_fields)?;
+            let link = match self.big_tiff {
+                true => AnyValues::IFD8(vec![offset]),
+                false => AnyValues::Long(vec![offset as u32]),
+            };
+            fields.any(*tag, &link);
+        }
+        for (tag, values) in &directory.values {
+            fields.any(*tag, values);
+        }
+
+        Ok(())
+    }
+
+    // Rows in each strip of an image `height` rows high whose rows are
+    // `row_bytes` long uncompressed.
+    fn strip_rows(&self, height: u32, row_bytes: usize) -> u32 {
+        let rows = match self.layout {
+            Layout::Rows(rows) => rows,
+            Layout::Bytes(bytes) => match row_bytes {
+                0 => height,
+                row_bytes => (bytes / row_bytes).min(u32::MAX as usize) as u32,
+            },
+            Layout::Tiles(..) => height,
+        };
+
+        rows.max(1).min(height.max(1))
+    }
+
+    // The samples as stored in the file: samples of 16 bits and more in the file's
+    // byte order and masks packed to one bit per pixel. Signed and float samples
+    // need the SampleFormat of the header to say so.
+    fn pixel_bytes(&self, image: &Image) -> EncodeResult<Vec<u8>> {
+        let header = image.header();
+        let bits_per_sample = header.bits_per_sample();
+        let (width, height) = (header.width(), header.height());
+        let samples = header.samples_per_pixel();
+        let expected = width as usize * height as usize * samples;
+        let actual = image.data().len();
+        if actual != expected {
+            return Err(EncodeError::from(EncodeErrorKind::InvalidImageDimensions { width, height, samples, expected, actual }));
+        }
+
+        let uniform = |bits: usize| bits_per_sample.is_uniform() && bits_per_sample.bits() == bits;
+        let format = header.sample_format();
+        // the decoder reads WhiteIsZero as black-is-zero, so it's turned back here
+        let inverted;
+        let data = if header.photometric_interpretation() == PhotometricInterpretation::WhiteIsZero {
+            let mut data = image.data().clone();
+            image::invert_gray(&mut data, bits_per_sample.values()[0], samples);
+            inverted = data;
+            &inverted
+        } else {
+            image.data()
+        };
+        match *data {
+            ImageData::U8(ref data) if uniform(8) => Ok(data.clone()),
+            ImageData::U16(ref data) if uniform(16) => Ok(sample_bytes(data, 2, |x| x as u64, self.endian)),
+            ImageData::I16(ref data) if uniform(16) && format == SampleFormat::Signed => Ok(sample_bytes(data, 2, |x| x as u16 as u64, self.endian)),
+            ImageData::I32(ref data) if uniform(32) && format == SampleFormat::Signed => Ok(sample_bytes(data, 4, |x| x as u32 as u64, self.endian)),
+            ImageData::F32(ref data) if uniform(32) && format == SampleFormat::Float => Ok(sample_bytes(data, 4, |x| x.to_bits() as u64, self.endian)),
+            ImageData::F64(ref data) if uniform(64) && format == SampleFormat::Float => Ok(sample_bytes(data, 8, |x| x.to_bits(), self.endian)),
+            ImageData::Mask(ref data) if bits_per_sample.values() == [1] => Ok(image::pack_mask(data, width as usize)),
+            // palette indices and gray levels, as raw values like the decoder reads them
+            ImageData::U8(ref data) if bits_per_sample.len() == 1 && [1, 2, 4].contains(&bits_per_sample.bits()) => {
+                let bits = bits_per_sample.bits();
+                match data.iter().find(|&&x| x as usize >= 1 << bits) {
+                    Some(value) => Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration {
+                        reason: format!("sample value {} doesn't fit in {} bits", value, bits),
+                    })),
+                    None => Ok(image::pack_samples(data, width as usize, bits)),
+                }
+            }
+            ref data => Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration {
+                reason: format!("{} samples don't match BitsPerSample {:?} and SampleFormat {:?}", data.sample_type(), bits_per_sample.values(), format),
+            })),
+        }
+    }
+
+    // Writes values that don't fit in their entry, then the IFD itself in tag order.
+    // Returns where the IFD and its next-IFD pointer are. BigTIFF entries have
+    // 8-byte counts and value fields, and the IFD an 8-byte entry count.
+    fn write_ifd(&mut self, fields: Fields) -> EncodeResult<(u64, u64)> {
+        let field_size = self.offset_size();
+        let mut entries = Vec::with_capacity(fields.entries.len());
+        for (tag, (datatype, count, bytes)) in fields.entries {
+            let field = if bytes.len() > field_size {
+                self.writer.align_to_word()?;
+                let offset = self.offset_here()?;
+                self.writer.write_all(&bytes)?;
+                ifd::endian_bytes(offset, field_size, self.endian)
+            } else {
+                let mut field = bytes;
+                field.resize(field_size, 0);
+                field
+            };
+            entries.push((tag, datatype, count, field));
+        }
+
+        self.writer.align_to_word()?;
+        let offset = self.offset_here()?;
+        if self.big_tiff {
+            self.writer.write_u64(entries.len() as u64, self.endian)?;
+        } else {
+            self.writer.write_u16(entries.len() as u16, self.endian)?;
+        }
+        for (tag, datatype, count, field) in entries {
+            self.writer.write_u16(tag, self.endian)?;
+            self.writer.write_u16(datatype.value(), self.endian)?;
+            if self.big_tiff {
+                self.writer.write_u64(count, self.endian)?;
+            } else {
+                self.writer.write_u32(count as u32, self.endian)?;
+            }
+            self.writer.write_all(&field)?;
+        }
+        let next_pointer = self.writer.stream_position()?;
+        self.write_offset(0)?;
+
+        Ok((offset, next_pointer))
+    }
+
+    fn patch_offset(&mut self, at: u64, offset: u64) -> EncodeResult<()> {
+        self.writer.seek(SeekFrom::Start(at))?;
+        self.write_offset(offset)?;
+        self.writer.seek(SeekFrom::End(0))?;
+
+        Ok(())
+    }
+
+    fn write_offset(&mut self, offset: u64) -> EncodeResult<()> {
+        if self.big_tiff {
+            self.writer.write_u64(offset, self.endian)?;
+        } else {
+            self.writer.write_u32(offset as u32, self.endian)?;
+        }
+
+        Ok(())
+    }
+
+    fn offset_size(&self) -> usize {
+        if self.big_tiff { 8 } else { 4 }
+    }
+
+    // The current position as an offset. Classic TIFF addresses everything with
+    // 32 bits.
+    fn offset_here(&mut self) -> EncodeResult<u64> {
+        let position = self.writer.stream_position()?;
+        if !self.big_tiff && position > u32::MAX as u64 {
+            return Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration {
+                reason: format!("offset {} doesn't fit in a classic TIFF, see EncoderBuilder::big_tiff", position),
+            }));
+        }
+
+        Ok(position)
+    }
+}
+
+// A page for `EncoderBuilder::build_sequential`, whose size is known before
+// anything is written.
+#[derive(Debug)]
+pub struct PlannedPage {
+    header: ImageHeader,
+    data: PlannedData,
+    extra: Directory,
+}
+
+#[derive(Debug)]
+enum PlannedData {
+    Image(Image),
+    // the size of every strip or tile, given later
+    Compressed(Vec<u64>),
+}
+
+impl PlannedPage {
+    // A page compressed with the builder's codec while the file is planned.
+    pub fn image(image: Image) -> PlannedPage {
+        PlannedPage {
+            header: image.header().clone(),
+            data: PlannedData::Image(image),
+            extra: Directory::default(),
+        }
+    }
+
+    // A page whose strips or tiles are given to `SequentialEncoder::write_chunk`
+    // already compressed, `byte_counts` long each. They're cut as the builder's
+    // layout cuts a page of `header`'s size, and compressed as the Compression of
+    // `header` says, without a predictor.
+    pub fn compressed(header: ImageHeader, byte_counts: Vec<u64>) -> PlannedPage {
+        PlannedPage {
+            header,
+            data: PlannedData::Compressed(byte_counts),
+            extra: Directory::default(),
+        }
+    }
+
+    // Sets `tag` for the page, as `Encoder::put_value` does for the next page.
+    pub fn put_value<T: TagType>(&mut self, tag: T, value: T::Value) -> Option<AnyValues> where T::Value: IntoAnyValues {
+        self.put_any(AnyTag::from(tag), ifd::tag_values(tag, value))
+    }
+
+    pub fn put_any(&mut self, tag: AnyTag, values: AnyValues) -> Option<AnyValues> {
+        self.extra.values.insert(tag.id(), values)
+    }
+}
+
+// Writes a TIFF to a writer that can't seek, like the body of an HTTP response,
+// strictly in order. Every page is planned before anything is written, so the
+// header and all the IFDs go first and the strips and tiles follow page by page,
+// each where the IFDs already said. Its rules:
+//
+// - Pages are all given to `EncoderBuilder::build_sequential`; none can be added
+//   later.
+// - Pages given with `PlannedPage::image` are compressed in memory while the
+//   file is planned, and held until their turn comes.
+// - Pages given with `PlannedPage::compressed` name the size of each of their
+//   strips or tiles, as many as the builder's layout cuts the page into. Their
+//   data is given to `write_chunk` in file order, page by page, strips top to
+//   bottom and tiles row by row, each exactly its planned size.
+// - `finish` needs every planned strip and tile written.
+//
+// Breaking one fails with `EncodeErrorKind::SequentialRule`. Nothing is written
+// past what was planned.
+pub struct SequentialEncoder<W> {
+    writer: W,
+    chunks: VecDeque<PlannedChunk>,
+}
+
+enum PlannedChunk {
+    Data(Vec<u8>),
+    // the size of a chunk `write_chunk` gives
+    Size(u64),
+}
+
+impl<W> SequentialEncoder<W> where W: Write {
+    // Lays the file out with an encoder writing to memory, which only has to
+    // hold the header and the IFDs, then writes them.
+    fn plan(mut writer: W, builder: EncoderBuilder, pages: Vec<PlannedPage>) -> EncodeResult<SequentialEncoder<W>> {
+        if pages.is_empty() {
+            return Err(EncodeError::from(EncodeErrorKind::SequentialRule { reason: "no pages were planned".to_string() }));
+        }
+        let mut planner = Encoder::with_builder(Cursor::new(vec![]), builder)?;
+        planner.choose_big_tiff(pages.iter().map(|x| image_bytes(&x.header)).sum())?;
+
+        let mut layouts = Vec::with_capacity(pages.len());
+        let mut chunks = VecDeque::new();
+        for (i, page) in pages.iter().enumerate() {
+            planner.check_conflicts(&page.header, &page.extra)?;
+            page.extra.check(planner.big_tiff)?;
+            page.extra.check_palette(&page.header)?;
+            match page.data {
+                PlannedData::Image(ref image) => {
+                    let layout = planner.layout;
+                    let compressed = planner.compress(image, layout)?;
+                    layouts.push((compressed.chunking, compressed.byte_counts()));
+                    chunks.extend(compressed.data.into_iter().map(PlannedChunk::Data));
+                }
+                PlannedData::Compressed(ref byte_counts) => {
+                    let chunking = planner.chunking_with(&page.header, Predictor::No, page.header.compression().value());
+                    let expected = chunk_count(&page.header, chunking);
+                    if byte_counts.len() != expected {
+                        return Err(EncodeError::from(EncodeErrorKind::SequentialRule {
+                            reason: format!("page {} has {} byte counts for its {} strips or tiles", i, byte_counts.len(), expected),
+                        }));
+                    }
+                    layouts.push((chunking, byte_counts.clone()));
+                    chunks.extend(byte_counts.iter().map(|&x| PlannedChunk::Size(x)));
+                }
+            }
+        }
+
+        let headers = pages.iter().map(|x| &x.header).collect::<Vec<_>>();
+        let directories = pages.iter().map(|x| &x.extra).collect::<Vec<_>>();
+        let order = (0..pages.len()).collect::<Vec<_>>();
+        planner.write_ifds_first(&headers, &layouts, &directories, &order)?;
+        writer.write_all(planner.writer.get_ref())?;
+
+        let mut encoder = SequentialEncoder {
+            writer,
+            chunks,
+        };
+        encoder.write_planned()?;
+
+        Ok(encoder)
+    }
+
+    // Writes the next strip or tile of the pages given with
+    // `PlannedPage::compressed`, then those of the following pages given as
+    // images, up to the next one it needs.
+    pub fn write_chunk(&mut self, data: &[u8]) -> EncodeResult<()> {
+        match self.chunks.pop_front() {
+            Some(PlannedChunk::Size(size)) if size == data.len() as u64 => self.writer.write_all(data)?,
+            Some(chunk) => {
+                let reason = match chunk {
+                    PlannedChunk::Size(size) => format!("a chunk of {} bytes where {} were planned", data.len(), size),
+                    PlannedChunk::Data(_) => "a chunk given for a page planned as an image".to_string(),
+                };
+                self.chunks.push_front(chunk);
+                return Err(EncodeError::from(EncodeErrorKind::SequentialRule { reason }));
+            }
+            None => return Err(EncodeError::from(EncodeErrorKind::SequentialRule { reason: "a chunk given after every planned one".to_string() })),
+        }
+
+        self.write_planned()
+    }
+
+    // Strips and tiles still to be given to `write_chunk`.
+    pub fn chunks_left(&self) -> usize {
+        self.chunks.iter().filter(|x| matches!(**x, PlannedChunk::Size(_))).count()
+    }
+
+    // Hands back the writer once every planned strip and tile is written.
+    pub fn finish(mut self) -> EncodeResult<W> {
+        if !self.chunks.is_empty() {
+            return Err(EncodeError::from(EncodeErrorKind::SequentialRule {
+                reason: format!("{} planned strips or tiles weren't written", self.chunks.len()),
+            }));
+        }
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    fn write_planned(&mut self) -> EncodeResult<()> {
+        loop {
+            match self.chunks.pop_front() {
+                Some(PlannedChunk::Data(data)) => self.writer.write_all(&data)?,
+                Some(chunk) => {
+                    self.chunks.push_front(chunk);
+                    return Ok(());
+                }
+                None => return Ok(()),
+            }
+        }
+    }
+}
+
+impl<W> Debug for SequentialEncoder<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SequentialEncoder")
+            .field("chunks_left", &self.chunks.len())
+            .finish()
+    }
+}
+
+// Strips or tiles in a page of `header`'s size.
+fn chunk_count(header: &ImageHeader, chunking: Chunking) -> usize {
+    let (width, height) = (header.width() as usize, header.height() as usize);
+    match chunking.tile_size {
+        Some((tile_width, tile_height)) => width.div_ceil(tile_width as usize) * height.div_ceil(tile_height as usize),
+        None => height.div_ceil(chunking.rows as usize).max(1),
+    }
+}
+
+// Writes the file header from the start of `writer`, leaving it right after it.
+// Returns where the first IFD's offset goes.
+fn write_header<W: Write + Seek>(writer: &mut W, endian: Endian, big_tiff: bool) -> EncodeResult<u64> {
+    writer.seek(SeekFrom::Start(0))?;
+    match endian {
+        Endian::Big => writer.write_all(b"MM")?,
+        Endian::Little => writer.write_all(b"II")?,
+    }
+    if big_tiff {
+        writer.write_u16(43, endian)?;
+        writer.write_u16(8, endian)?;
+        writer.write_u16(0, endian)?;
+        writer.write_u64(0, endian)?;
+        Ok(8)
+    } else {
+        writer.write_u16(42, endian)?;
+        writer.write_u32(0, endian)?;
+        Ok(4)
+    }
+}
+
+// What fills the parts of edge tiles past the page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgePadding {
+    Zero,
+    // the last pixel of each row across, then the last row down. It leaves no dark
+    // seams in readers that show the padding, and with the horizontal predictor
+    // rows end in differences of zero rather than a jump to black.
+    ReplicateEdge,
+}
+
+// Where reduced-resolution pages go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverviewPlacement {
+    // after their full-resolution page in the chain of pages, as GDAL writes them
+    Chained,
+    // in the SubIFDs of their full-resolution page, as Photoshop and slide
+    // scanners write them
+    SubIFDs,
+}
+
+// How pages are split into strips or tiles.
+#[derive(Debug, Clone, Copy)]
+enum Layout {
+    // strips of so many rows
+    Rows(u32),
+    // strips of about so many uncompressed bytes
+    Bytes(usize),
+    // tiles of width by height pixels
+    Tiles(u32, u32),
+}
+
+impl Layout {
+    fn check(&self) -> EncodeResult<()> {
+        if let Layout::Tiles(width, height) = *self {
+            if width == 0 || height == 0 || width % 16 != 0 || height % 16 != 0 {
+                return Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration {
+                    reason: format!("tiles of {}x{} pixels, TIFF needs multiples of 16", width, height),
+                }));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// The compressed strips or tiles of a page, in the order they're written.
+struct Chunks {
+    data: Vec<Vec<u8>>,
+    chunking: Chunking,
+}
+
+impl Chunks {
+    fn byte_counts(&self) -> Vec<u64> {
+        self.data.iter().map(|x| x.len() as u64).collect()
+    }
+}
+
+// A page given with `Encoder::write_rows`, written as its rows come in.
+struct Stream {
+    header: ImageHeader,
+    extra: Directory,
+    row_bytes: usize,
+    chunking: Chunking,
+    // rows given but not yet written
+    buffer: Vec<u8>,
+    // bytes of rows written
+    written: usize,
+    offsets: Vec<u64>,
+    byte_counts: Vec<u64>,
+}
+
+impl Stream {
+    fn total_bytes(&self) -> usize {
+        self.header.height() as usize * self.row_bytes
+    }
+}
+
+// A page written but for its IFD, and the SubIFDs written for it so far.
+struct Parent {
+    fields: Fields,
+    sub_ifds: Vec<u64>,
+}
+
+// How a page is cut into chunks, for the entries that describe them.
+#[derive(Debug, Clone, Copy)]
+struct Chunking {
+    tile_size: Option<(u32, u32)>,
+    // rows per strip, or the tile length
+    rows: u32,
+    predictor: Predictor,
+    compression: u16,
+}
+
+impl EncoderBuilder {
+    fn check(&self) -> EncodeResult<()> {
+        self.layout.check()?;
+        match self.date_time {
+            Some(ref date_time) if !is_date_time(date_time) => Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration {
+                reason: format!("DateTime {:?}, TIFF needs \"YYYY:MM:DD HH:MM:SS\"", date_time),
+            })),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Whether `date_time` is in TIFF's "YYYY:MM:DD HH:MM:SS" format, with a month,
+// day and time that can be.
+fn is_date_time(date_time: &str) -> bool {
+    let bytes = date_time.as_bytes();
+    let shape = b"0000:00:00 00:00:00";
+    if bytes.len() != shape.len() || !bytes.iter().zip(shape).all(|(&x, &y)| if y == b'0' { x.is_ascii_digit() } else { x == y }) {
+        return false;
+    }
+    let field = |at: usize| date_time[at..at + 2].parse::<u32>().unwrap_or(0);
+    (1..=12).contains(&field(5)) && (1..=31).contains(&field(8)) && field(11) < 24 && field(14) < 60 && field(17) < 60
+}
+
+// Seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0)
+}
+
+// `seconds` since the Unix epoch as TIFF's "YYYY:MM:DD HH:MM:SS", in UTC.
+fn format_date_time(seconds: u64) -> String {
+    let (days, time) = (seconds / 86400, seconds % 86400);
+    // the civil date of a day count, with years starting in March so that leap
+    // days come last
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}:{:02}:{:02} {:02}:{:02}:{:02}", year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
+
+// Fills `row` past its first `pixels` pixels of `bits_per_pixel` bits with copies
+// of the last of them.
+fn replicate_pixel(row: &mut [u8], pixels: usize, bits_per_pixel: usize) {
+    let total = row.len() * 8 / bits_per_pixel;
+    if bits_per_pixel.is_multiple_of(8) {
+        let size = bits_per_pixel / 8;
+        let (filled, padding) = row.split_at_mut(pixels * size);
+        let last = &filled[filled.len() - size..];
+        padding.chunks_mut(size).for_each(|x| x.copy_from_slice(last));
+        return;
+    }
+    let bit = |row: &[u8], i: usize| row[i / 8] >> (7 - i % 8) & 1;
+    let last = (pixels - 1) * bits_per_pixel;
+    for pixel in pixels..total {
+        for i in 0..bits_per_pixel {
+            let value = bit(row, last + i);
+            let at = pixel * bits_per_pixel + i;
+            row[at / 8] = row[at / 8] & !(0x80 >> (at % 8)) | value << (7 - at % 8);
+        }
+    }
+}
+
+// Bytes in a row of an image as stored, each starting on a byte.
+fn row_bytes(header: &ImageHeader) -> usize {
+    let bits_per_pixel = header.bits_per_sample().values().iter().map(|&x| x as usize).sum::<usize>();
+    (header.width() as usize * bits_per_pixel).div_ceil(8)
+}
+
+// Bytes of samples in an image, uncompressed.
+fn image_bytes(header: &ImageHeader) -> u64 {
+    let bits_per_pixel = header.bits_per_sample().values().iter().map(|&x| x as u64).sum::<u64>();
+    header.width() as u64 * header.height() as u64 * bits_per_pixel / 8
+}
+
+// Tags describing how the pixels are stored, which the encoder writes for its
+// own layout, or pointing at data outside the IFD that isn't copied along.
+const LAYOUT_TAGS: &[u16] = &[
+    256, 257, 258, 259, 262, 266, 273, 277, 278, 279, 284, 288, 289, 292, 293,
+    317, 322, 323, 324, 325, 330, 338, 339, 347, 513, 514, 515, 517, 518, 519, 520, 521,
+];
+
+// Entries linking to IFDs that are copied along with the image.
+const LINK_TAGS: &[u16] = &[34665, 34853, 40965];
+
+// Writes the current image of `decoder` as the next page of `encoder`, with every
+// other tag of its IFD, private and unknown ones included, so a file can be
+// recompressed without losing its metadata. The Exif, GPS and interoperability
+// IFDs are copied as well; SubIFDs are not. Tags describing how the pixels are
+// stored are replaced by the encoder's own, and so are XMP and ICC profiles
+// given to its builder and tags set with `put_value`.
+pub fn copy_image<R: Read + Seek, W: Write + Seek>(decoder: &mut Decoder<R>, encoder: &mut Encoder<W>) -> EncodeResult<()> {
+    let image = decoder.image()?;
+    let ifd = decoder.ifd()?;
+    encoder.check_conflicts(image.header(), &encoder.extra)?;
+
+    // the decoder may have changed what some tags describe
+    let photometric = image.header().photometric_interpretation();
+    let mut skip = LAYOUT_TAGS.to_vec();
+    if photometric != PhotometricInterpretation::Palette {
+        skip.push(tag::ColorMap.id());
+    }
+    if photometric != PhotometricInterpretation::YCbCr {
+        skip.extend_from_slice(&[529, 530, 531, 532]);
+    }
+    if decoder.applies_orientation() {
+        skip.push(tag::Orientation.id());
+    }
+
+    let mut visited = HashSet::new();
+    visited.insert(decoder.current_ifd_offset());
+    let mut directory = copy_directory(decoder, &ifd, &skip, &mut visited)?;
+    // what was set with `put_value` replaces what was copied
+    directory.values.extend(mem::take(&mut encoder.extra).values);
+
+    encoder.append_image_with(&image, &directory)
+}
+
+fn copy_directory<R: Read + Seek>(decoder: &mut Decoder<R>, ifd: &IFD, skip: &[u16], visited: &mut HashSet<u64>) -> EncodeResult<Directory> {
+    let mut directory = Directory::default();
+    for (tag, entry) in ifd.iter() {
+        let id = tag.id();
+        if skip.contains(&id) {
+            continue;
+        }
+        let values = decoder.get_any_values(entry)?;
+        if LINK_TAGS.contains(&id) {
+            let offset = match values.offsets().first() {
+                Some(&offset) => offset,
+                None => continue,
+            };
+            if visited.insert(offset) {
+                let linked = decoder.ifd_at(offset)?;
+                directory.links.insert(id, copy_directory(decoder, &linked, &[], visited)?);
+            }
+            continue;
+        }
+        if let AnyValues::Unknown(_) = values {
+            return Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration {
+                reason: format!("tag ({}) has values of unknown datatype {}", AnyTag::from(id), entry.datatype().value()),
+            }));
+        }
+        directory.values.insert(id, values);
+    }
+
+    Ok(directory)
+}
+
+// BigTIFF's 64-bit values as the 32-bit ones of a classic TIFF, `None` if they
+// don't fit. Other values are kept.
+fn narrow(values: &AnyValues) -> Option<AnyValues> {
+    fn narrow<T: Copy, U: TryFrom<T>>(v: &[T]) -> Option<Vec<U>> {
+        v.iter().map(|&x| U::try_from(x).ok()).collect()
+    }
+
+    match *values {
+        AnyValues::Long8(ref v) => narrow(v).map(AnyValues::Long),
+        AnyValues::IFD8(ref v) => narrow(v).map(AnyValues::IFD),
+        AnyValues::SLong8(ref v) => narrow(v).map(AnyValues::SLong),
+        ref values => Some(values.clone()),
+    }
+}
+
+// The entries of an IFD to write besides those the encoder derives from the
+// image, and the IFDs they link to by tag.
+#[derive(Debug, Clone, Default)]
+struct Directory {
+    values: BTreeMap<u16, AnyValues>,
+    links: BTreeMap<u16, Directory>,
+}
+
+impl Directory {
+    // Marks a reduced-resolution page.
+    fn overview() -> Directory {
+        let mut directory = Directory::default();
+        directory.values.insert(tag::NewSubfileType.id(), ifd::tag_values(tag::NewSubfileType, 1));
+        directory
+    }
+
+    // Fails before anything is written if a classic TIFF can't hold the values.
+    fn check(&self, big_tiff: bool) -> EncodeResult<()> {
+        for (tag, values) in &self.values {
+            if !big_tiff && narrow(values).is_none() {
+                return Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration {
+                    reason: format!("tag ({}) has {:?} values too large for a classic TIFF", AnyTag::from(*tag), values.datatype()),
+                }));
+            }
+        }
+
+        self.links.values().try_for_each(|x| x.check(big_tiff))
+    }
+
+    // Palette pages are unreadable without their colors.
+    fn check_palette(&self, header: &ImageHeader) -> EncodeResult<()> {
+        if header.photometric_interpretation() == PhotometricInterpretation::Palette && !self.values.contains_key(&tag::ColorMap.id()) {
+            return Err(EncodeError::from(EncodeErrorKind::UnsupportedConfiguration {
+                reason: "a Palette page needs a ColorMap, see Encoder::append_palette_image".to_string(),
+            }));
+        }
+
+        Ok(())
+    }
+}
+
+
+// The entries of one IFD as datatype, count and value bytes, ordered by tag.
+struct Fields {
+    entries: BTreeMap<u16, (DataType, u64, Vec<u8>)>,
+    endian: Endian,
+    big_tiff: bool,
+}
+
+impl Fields {
+    fn new(endian: Endian, big_tiff: bool) -> Fields {
+        Fields {
+            entries: BTreeMap::new(),
+            endian,
+            big_tiff,
+        }
+    }
+
+    fn short<T: TagType>(&mut self, tag: T, values: &[u16]) {
+        let mut bytes = Vec::with_capacity(values.len() * 2);
+        for x in values {
+            bytes.extend_from_slice(&u16_bytes(*x, self.endian));
+        }
+        self.entries.insert(tag.id(), (DataType::Short, values.len() as u64, bytes));
+    }
+
+    fn short_or_long<T: TagType>(&mut self, tag: T, values: &[u32]) {
+        match DataType::short_or_long(values) {
+            DataType::Short => self.short(tag, &values.iter().map(|x| *x as u16).collect::<Vec<_>>()),
+            _ => self.long(tag, values),
+        }
+    }
+
+    fn long<T: TagType>(&mut self, tag: T, values: &[u32]) {
+        let mut bytes = Vec::with_capacity(values.len() * 4);
+        for x in values {
+            bytes.extend_from_slice(&u32_bytes(*x, self.endian));
+        }
+        self.entries.insert(tag.id(), (DataType::Long, values.len() as u64, bytes));
+    }
+
+    // Byte counts, LONG8 only if a value needs it.
+    fn short_or_long_or_long8<T: TagType>(&mut self, tag: T, values: &[u64]) {
+        match values.iter().map(|&x| u32::try_from(x)).collect::<Result<Vec<_>, _>>() {
+            Ok(values) => self.short_or_long(tag, &values),
+            Err(_) => self.long8(tag, values),
+        }
+    }
+
+    // Offsets, LONG or LONG8 by the kind of file whatever their size, so that
+    // an IFD written with placeholder offsets keeps its size once they're known.
+    fn offsets<T: TagType>(&mut self, tag: T, values: &[u64]) {
+        match self.big_tiff {
+            true => self.long8(tag, values),
+            false => self.long(tag, &values.iter().map(|&x| x as u32).collect::<Vec<_>>()),
+        }
+    }
+
+    fn long8<T: TagType>(&mut self, tag: T, values: &[u64]) {
+        let values = AnyValues::Long8(values.to_vec());
+        self.entries.insert(tag.id(), (DataType::Long8, values.len() as u64, values.to_bytes(self.endian)));
+    }
+
+    fn byte<T: TagType>(&mut self, tag: T, bytes: &[u8]) {
+        self.entries.insert(tag.id(), (DataType::Byte, bytes.len() as u64, bytes.to_vec()));
+    }
+
+    fn undefined<T: TagType>(&mut self, tag: T, bytes: &[u8]) {
+        self.entries.insert(tag.id(), (DataType::Undefined, bytes.len() as u64, bytes.to_vec()));
+    }
+
+    // Keeps an entry already set for `tag`. A classic TIFF gets 64-bit values
+    // narrowed, which `Directory::check` made sure they can be.
+    fn any(&mut self, tag: u16, values: &AnyValues) {
+        if self.entries.contains_key(&tag) {
+            return;
+        }
+        let values = match self.big_tiff {
+            true => values.clone(),
+            false => narrow(values).unwrap_or_else(|| values.clone()),
+        };
+        let bytes = values.to_bytes(self.endian);
+        self.entries.insert(tag, (values.datatype(), values.len() as u64, bytes));
+    }
+
+    // Sets `bits` of NewSubfileType, keeping those already set.
+    fn add_subfile_type(&mut self, bits: u32) {
+        let old = match self.entries.get(&tag::NewSubfileType.id()) {
+            Some(&(DataType::Short, _, ref bytes)) => u16_value([bytes[0], bytes[1]], self.endian) as u32,
+            Some(&(DataType::Long, _, ref bytes)) => u32_value([bytes[0], bytes[1], bytes[2], bytes[3]], self.endian),
+            _ => 0,
+        };
+        self.short_or_long(tag::NewSubfileType, &[old | bits]);
+    }
+
+    fn rational<T: TagType>(&mut self, tag: T, value: Rational) {
+        let mut bytes = Vec::with_capacity(8);
+        bytes.extend_from_slice(&u32_bytes(value.numerator, self.endian));
+        bytes.extend_from_slice(&u32_bytes(value.denominator, self.endian));
+        self.entries.insert(tag.id(), (DataType::Rational, 1, bytes));
+    }
+}
+
+// Each of `data` as `size` bytes in `endian` order, by way of `bits`.
+fn sample_bytes<T: Copy, F: Fn(T) -> u64>(data: &[T], size: usize, bits: F, endian: Endian) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len() * size);
+    for x in data {
+        let x = bits(*x);
+        match endian {
+            Endian::Big => bytes.extend_from_slice(&x.to_be_bytes()[8 - size..]),
+            Endian::Little => bytes.extend_from_slice(&x.to_le_bytes()[..size]),
+        }
+    }
+
+    bytes
+}
+
+fn u16_bytes(n: u16, endian: Endian) -> [u8; 2] {
+    match endian {
+        Endian::Big => n.to_be_bytes(),
+        Endian::Little => n.to_le_bytes(),
+    }
+}
+
+fn u32_bytes(n: u32, endian: Endian) -> [u8; 4] {
+    match endian {
+        Endian::Big => n.to_be_bytes(),
+        Endian::Little => n.to_le_bytes(),
+    }
+}
+
+fn u16_value(bytes: [u8; 2], endian: Endian) -> u16 {
+    match endian {
+        Endian::Big => u16::from_be_bytes(bytes),
+        Endian::Little => u16::from_le_bytes(bytes),
+    }
+}
+
+fn u32_value(bytes: [u8; 4], endian: Endian) -> u32 {
+    match endian {
+        Endian::Big => u32::from_be_bytes(bytes),
+        Endian::Little => u32::from_le_bytes(bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::BitsPerSample;
+    use image::Compression;
+    use image::ExtraSample;
+    use decode::DecoderBuilder;
+    use byte::EndianReadExt;
+    use std::io::Cursor;
+    use testutil::{
+        Page,
+        TiffBuilder,
+        Value,
+    };
+
+    fn gray(width: u32, height: u32, seed: u8) -> Image {
+        let header = ImageHeader::new(width, height, Compression::No, PhotometricInterpretation::BlackIsZero, BitsPerSample::new([8]).unwrap(), vec![]).unwrap();
+        let data = (0..width * height).map(|i| (i as u8).wrapping_mul(7).wrapping_add(seed)).collect();
+        Image::new(header, ImageData::U8(data))
+    }
+
+    fn encode_pyramid(builder: EncoderBuilder) -> Vec<u8> {
+        let mut encoder = builder.build(Cursor::new(vec![])).unwrap();
+        encoder.append_image(&gray(40, 30, 0)).unwrap();
+        encoder.append_sub_image(&gray(20, 15, 1)).unwrap();
+        encoder.append_sub_image(&gray(10, 8, 2)).unwrap();
+        encoder.append_image(&gray(9, 9, 3)).unwrap();
+        encoder.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn sub_images_go_into_sub_ifds() {
+        for &big_tiff in &[false, true] {
+            let file = encode_pyramid(EncoderBuilder::new().big_tiff(big_tiff));
+            let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+            assert_eq!(decoder.ifd_count().unwrap(), 2);
+
+            assert_eq!(decoder.image().unwrap().data(), gray(40, 30, 0).data());
+            let sub_ifds = decoder.sub_ifds().unwrap();
+            assert_eq!(sub_ifds.len(), 2);
+            for (i, &(width, height)) in [(20, 15), (10, 8)].iter().enumerate() {
+                decoder.load_ifd_at(sub_ifds[i]).unwrap();
+                let ifd = decoder.ifd().unwrap();
+                assert_eq!(decoder.get_value(&ifd, tag::NewSubfileType).unwrap(), 1);
+                assert_eq!(decoder.image().unwrap().data(), gray(width, height, i as u8 + 1).data());
+            }
+
+            decoder.change_ifd(1).unwrap();
+            assert!(decoder.sub_ifds().unwrap().is_empty());
+            assert_eq!(decoder.image().unwrap().data(), gray(9, 9, 3).data());
+        }
+    }
+
+    #[test]
+    fn sub_image_keeps_its_subfile_type() {
+        let mut encoder = Encoder::new(Cursor::new(vec![])).unwrap();
+        encoder.append_image(&gray(8, 8, 0)).unwrap();
+        encoder.put_value(tag::NewSubfileType, 2);
+        encoder.append_sub_image(&gray(4, 4, 0)).unwrap();
+        let mut decoder = Decoder::new(Cursor::new(encoder.finish().unwrap().into_inner())).unwrap();
+        decoder.load_sub_ifd(0).unwrap();
+        let ifd = decoder.ifd().unwrap();
+        assert_eq!(decoder.get_value(&ifd, tag::NewSubfileType).unwrap(), 3);
+    }
+
+    #[test]
+    fn sub_image_needs_a_page() {
+        let mut encoder = Encoder::new(Cursor::new(vec![])).unwrap();
+        assert!(encoder.append_sub_image(&gray(4, 4, 0)).is_err());
+    }
+
+    #[test]
+    fn overviews_as_sub_ifds() {
+        let image = gray(32, 32, 0);
+        let mut encoder = EncoderBuilder::new().overview_placement(OverviewPlacement::SubIFDs).build(Cursor::new(vec![])).unwrap();
+        encoder.append_image(&image).unwrap();
+        encoder.add_overviews(&image, &[2, 4]).unwrap();
+        let mut decoder = Decoder::new(Cursor::new(encoder.finish().unwrap().into_inner())).unwrap();
+        assert_eq!(decoder.ifd_count().unwrap(), 1);
+        assert_eq!(decoder.sub_ifds().unwrap().len(), 2);
+        decoder.load_sub_ifd(1).unwrap();
+        assert_eq!(decoder.image().unwrap().data(), image.downsample_by(4).data());
+    }
+
+    fn image(width: u32, height: u32, interpretation: PhotometricInterpretation, bits: &[u16], data: ImageData) -> Image {
+        let header = ImageHeader::new(width, height, Compression::No, interpretation, BitsPerSample::new(bits).unwrap(), vec![]).unwrap();
+        Image::new(header, data)
+    }
+
+    fn gradient(width: u32, height: u32) -> Image {
+        let mut data = Vec::with_capacity((width * height * 3) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                data.extend_from_slice(&[(x * 255 / width) as u8, (y * 255 / height) as u8, ((x + y) * 127 / (width + height)) as u8]);
+            }
+        }
+        image(width, height, PhotometricInterpretation::RGB, &[8, 8, 8], ImageData::U8(data))
+    }
+
+    // hands the bytes back as they are, declaring them uncompressed
+    struct PassThrough;
+
+    impl EncodeBytes for PassThrough {
+        fn compression(&self) -> u16 {
+            1
+        }
+
+        fn encode_bytes(&mut self, uncompressed: &[u8]) -> io::Result<Vec<u8>> {
+            Ok(uncompressed.to_vec())
+        }
+    }
+
+    #[test]
+    fn pass_through_codec_matches_uncompressed() {
+        let image = gradient(33, 17);
+        let plain = Encoder::new(Cursor::new(vec![])).unwrap().encode(&image).unwrap().into_inner();
+        // it doesn't ask for the predictor, so none is applied or recorded
+        let builder = EncoderBuilder::new().with_codec(Box::new(PassThrough)).predictor(Predictor::Horizontal);
+        let passed = builder.build(Cursor::new(vec![])).unwrap().encode(&image).unwrap().into_inner();
+        assert_eq!(passed, plain);
+
+        let builder = EncoderBuilder::new().with_codec(Box::new(codec::LZWEncoder)).predictor(Predictor::Horizontal);
+        let lzw = builder.build(Cursor::new(vec![])).unwrap().encode(&image).unwrap().into_inner();
+        let mut decoder = Decoder::new(Cursor::new(lzw)).unwrap();
+        let ifd = decoder.ifd().unwrap();
+        assert_eq!(decoder.get_value(&ifd, tag::Compression).unwrap(), 5);
+        assert_eq!(decoder.get_value(&ifd, tag::Predictor).unwrap(), 2);
+        assert_eq!(decoder.image().unwrap().data(), image.data());
+    }
+
+    // An entry as stored, with the position of its value or offset field.
+    struct RawEntry {
+        tag: u16,
+        datatype: u16,
+        count: usize,
+        field: usize,
+    }
+
+    // Reads IFDs straight from the bytes of a file, without the decoder.
+    struct RawFile<'a> {
+        file: &'a [u8],
+        endian: Endian,
+        big_tiff: bool,
+    }
+
+    impl<'a> RawFile<'a> {
+        fn new(file: &'a [u8]) -> RawFile<'a> {
+            let endian = if &file[..2] == b"II" { Endian::Little } else { Endian::Big };
+            let big_tiff = (&file[2..]).read_u16(endian).unwrap() == 43;
+            RawFile { file, endian, big_tiff }
+        }
+
+        fn read(&self, at: usize, size: usize) -> usize {
+            let mut bytes = &self.file[at..at + size];
+            match size {
+                1 => bytes[0] as usize,
+                2 => bytes.read_u16(self.endian).unwrap() as usize,
+                4 => bytes.read_u32(self.endian).unwrap() as usize,
+                _ => bytes.read_u64(self.endian).unwrap() as usize,
+            }
+        }
+
+        fn offset_size(&self) -> usize {
+            if self.big_tiff { 8 } else { 4 }
+        }
+
+        fn first_ifd(&self) -> usize {
+            self.read(if self.big_tiff { 8 } else { 4 }, self.offset_size())
+        }
+
+        // The entries of the IFD at `at`, and the offset of the next one.
+        fn ifd(&self, at: usize) -> (Vec<RawEntry>, usize) {
+            let (count_size, entry_size) = if self.big_tiff { (8, 20) } else { (2, 12) };
+            let count = self.read(at, count_size);
+            let entries = (0..count).map(|i| {
+                let entry = at + count_size + i * entry_size;
+                let count_at = entry + 4;
+                RawEntry {
+                    tag: self.read(entry, 2) as u16,
+                    datatype: self.read(entry + 2, 2) as u16,
+                    count: self.read(count_at, self.offset_size()),
+                    field: count_at + self.offset_size(),
+                }
+            }).collect();
+
+            (entries, self.read(at + count_size + count * entry_size, self.offset_size()))
+        }
+
+        fn value_size(entry: &RawEntry) -> usize {
+            match entry.datatype {
+                1 | 2 | 6 | 7 => 1,
+                3 | 8 => 2,
+                4 | 9 | 11 | 13 => 4,
+                _ => 8,
+            }
+        }
+
+        // Where the values of `entry` are, and whether they are out of line.
+        fn values_at(&self, entry: &RawEntry) -> (usize, bool) {
+            match entry.count * RawFile::value_size(entry) > self.offset_size() {
+                true => (self.read(entry.field, self.offset_size()), true),
+                false => (entry.field, false),
+            }
+        }
+
+        fn unsigned_values(&self, entry: &RawEntry) -> Vec<usize> {
+            let (at, _) = self.values_at(entry);
+            let size = RawFile::value_size(entry);
+            (0..entry.count).map(|i| self.read(at + i * size, size)).collect()
+        }
+
+        fn chain(&self) -> Vec<Vec<RawEntry>> {
+            let mut ifds = vec![];
+            let mut next = self.first_ifd();
+            while next != 0 {
+                let (entries, after) = self.ifd(next);
+                ifds.push(entries);
+                next = after;
+            }
+
+            ifds
+        }
+    }
+
+    // The tag id and datatype of the entries of each IFD in the chain, as they
+    // appear in the file.
+    fn raw_entries(file: &[u8]) -> Vec<Vec<(u16, u16)>> {
+        RawFile::new(file).chain().into_iter()
+            .map(|ifd| ifd.into_iter().map(|entry| (entry.tag, entry.datatype)).collect())
+            .collect()
+    }
+
+    fn raw_tag_ids(file: &[u8]) -> Vec<Vec<u16>> {
+        raw_entries(file).into_iter().map(|ifd| ifd.into_iter().map(|(tag, _)| tag).collect()).collect()
+    }
+
+    fn encode_out_of_order(big_tiff: bool) -> Vec<u8> {
+        let mut encoder = EncoderBuilder::new().big_tiff(big_tiff).build(Cursor::new(vec![])).unwrap();
+        encoder.put_any(AnyTag::Unknown(65001), AnyValues::Short(vec![1]));
+        encoder.put_value(tag::Copyright, "nobody".to_string());
+        encoder.put_value(tag::Artist, "someone".to_string());
+        encoder.put_value(tag::ImageDescription, "out of order".to_string());
+        encoder.append_image(&gradient(9, 5)).unwrap();
+        encoder.put_value(tag::Software, "test".to_string());
+        encoder.append_image(&gray(4, 4, 0)).unwrap();
+        encoder.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn entries_are_sorted_by_tag() {
+        for &big_tiff in &[false, true] {
+            let ifds = raw_tag_ids(&encode_out_of_order(big_tiff));
+            assert_eq!(ifds.len(), 2);
+            for ids in &ifds {
+                assert!(ids.windows(2).all(|x| x[0] < x[1]), "{:?}", ids);
+            }
+            assert!(ifds[0].ends_with(&[315, 33432, 65001]), "{:?}", ifds[0]);
+        }
+    }
+
+    #[test]
+    fn output_is_reproducible() {
+        for &big_tiff in &[false, true] {
+            assert_eq!(encode_out_of_order(big_tiff), encode_out_of_order(big_tiff));
+        }
+    }
+
+    #[test]
+    fn dimensions_use_the_smallest_datatype() {
+        assert_eq!(DataType::short_or_long(&[1, 65535]).value(), 3);
+        assert_eq!(DataType::short_or_long(&[1, 65536]).value(), 4);
+
+        let datatypes = |file: &[u8]| raw_entries(file)[0].iter().cloned().collect::<BTreeMap<_, _>>();
+        let small = Encoder::new(Cursor::new(vec![])).unwrap().encode(&gray(9, 5, 0)).unwrap().into_inner();
+        let small = datatypes(&small);
+        for tag in &[256, 257, 278, 279] {
+            assert_eq!(small[tag], 3, "tag {}", tag);
+        }
+
+        let image = gray(70_000, 1, 0);
+        let file = Encoder::new(Cursor::new(vec![])).unwrap().encode(&image).unwrap().into_inner();
+        let large = datatypes(&file);
+        assert_eq!((large[&256], large[&257], large[&279]), (4, 3, 4));
+        let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+        let ifd = decoder.ifd().unwrap();
+        assert_eq!(decoder.get_value(&ifd, tag::ImageWidth).unwrap(), 70_000);
+        assert_eq!(decoder.get_value(&ifd, tag::ImageLength).unwrap(), 1);
+        assert_eq!(decoder.image().unwrap().data(), image.data());
+    }
+
+    // Every IFD, out-of-line value, strip, tile and SubIFD offset in `file`, from
+    // the IFD at `at` and those it links to.
+    fn all_offsets(raw: &RawFile, at: usize, offsets: &mut Vec<(String, usize)>) {
+        let mut next = at;
+        while next != 0 {
+            offsets.push(("IFD".to_string(), next));
+            let (entries, after) = raw.ifd(next);
+            for entry in &entries {
+                let (values, out_of_line) = raw.values_at(entry);
+                if out_of_line {
+                    offsets.push((format!("values of {}", entry.tag), values));
+                }
+                match entry.tag {
+                    273 | 324 => offsets.extend(raw.unsigned_values(entry).into_iter().map(|x| (format!("chunk of {}", entry.tag), x))),
+                    330 => for sub_ifd in raw.unsigned_values(entry) {
+                        all_offsets(raw, sub_ifd, offsets);
+                    },
+                    _ => {}
+                }
+            }
+            next = after;
+        }
+    }
+
+    #[test]
+    fn everything_is_word_aligned() {
+        for &big_tiff in &[false, true] {
+            let builder = || EncoderBuilder::new().big_tiff(big_tiff);
+            let mut encoder = builder().build(Cursor::new(vec![])).unwrap();
+            // a NUL-terminated value of 5 bytes, and chunks of 9 or 15
+            encoder.put_value(tag::ImageDescription, "odd!".to_string());
+            encoder.append_image(&gray(3, 3, 0)).unwrap();
+            encoder.append_sub_image(&gray(3, 5, 0)).unwrap();
+            encoder.put_value(tag::Artist, "abc".to_string());
+            encoder.append_image(&gradient(5, 1)).unwrap();
+            let file = encoder.finish().unwrap().into_inner();
+
+            let mut encoder = builder().tile_size(16, 16).build_append(Cursor::new(file)).unwrap();
+            encoder.put_value(tag::Software, "seven!".to_string());
+            encoder.append_image(&gray(17, 3, 0)).unwrap();
+            let file = encoder.finish().unwrap().into_inner();
+
+            let raw = RawFile::new(&file);
+            let mut offsets = vec![];
+            all_offsets(&raw, raw.first_ifd(), &mut offsets);
+            assert_eq!(offsets.iter().filter(|(what, _)| what == "IFD").count(), 4);
+            for (what, offset) in offsets {
+                assert!(offset % 2 == 0, "{} at {}, big_tiff {}", what, offset, big_tiff);
+            }
+        }
+    }
+
+    #[test]
+    fn gray_alpha_round_trips() {
+        let (width, height) = (7, 5);
+        let u8s = ImageData::U8((0..width * height * 2).map(|x| (x * 37) as u8).collect());
+        let u16s = ImageData::U16((0..width * height * 2).map(|x| (x * 1877) as u16).collect());
+        for &(bits, ref data) in &[([8, 8], u8s), ([16, 16], u16s)] {
+            for &interpretation in &[PhotometricInterpretation::BlackIsZero, PhotometricInterpretation::WhiteIsZero] {
+                let header = ImageHeader::new(width, height, Compression::No, interpretation, BitsPerSample::new(bits).unwrap(), vec![ExtraSample::UnassociatedAlpha]).unwrap();
+                let image = Image::new(header, data.clone());
+                let file = Encoder::new(Cursor::new(vec![])).unwrap().encode(&image).unwrap().into_inner();
+                let mut decoder = Decoder::new(Cursor::new(file.clone())).unwrap();
+                let ifd = decoder.ifd().unwrap();
+                assert_eq!(decoder.get_value(&ifd, tag::ExtraSamples).unwrap(), vec![2]);
+
+                // WhiteIsZero inverts the gray sample in the file, never the alpha
+                if let ImageData::U8(ref data) = *data {
+                    let offset = decoder.get_value(&ifd, tag::StripOffsets).unwrap()[0] as usize;
+                    let stored = &file[offset..offset + data.len()];
+                    for (i, (&x, &y)) in stored.iter().zip(data).enumerate() {
+                        let inverted = i % 2 == 0 && interpretation == PhotometricInterpretation::WhiteIsZero;
+                        assert_eq!(x, if inverted { !y } else { y });
+                    }
+                }
+
+                let decoded = decoder.image().unwrap();
+                assert_eq!(decoded.header().extra_samples(), &[ExtraSample::UnassociatedAlpha][..]);
+                assert_eq!(decoded.header().bits_per_sample().len(), 2);
+                assert_eq!(decoded.data(), data, "{:?} {:?}", bits, interpretation);
+            }
+        }
+    }
+
+    #[test]
+    fn multi_band_round_trips() {
+        let (width, height) = (19, 13);
+        let u8s = |samples: u32| ImageData::U8((0..width * height * samples).map(|x| (x * 31 + x / 7) as u8).collect());
+        let u16s = |samples: u32| ImageData::U16((0..width * height * samples).map(|x| (x * 1543 + x / 5) as u16).collect());
+        let images = vec![
+            (PhotometricInterpretation::CMYK, vec![8; 4], vec![], u8s(4)),
+            (PhotometricInterpretation::CMYK, vec![16; 4], vec![], u16s(4)),
+            (PhotometricInterpretation::CMYK, vec![8; 5], vec![ExtraSample::UnassociatedAlpha], u8s(5)),
+            (PhotometricInterpretation::BlackIsZero, vec![8; 4], vec![ExtraSample::Unspecified; 3], u8s(4)),
+            (PhotometricInterpretation::BlackIsZero, vec![16; 6], vec![ExtraSample::Unspecified; 5], u16s(6)),
+        ];
+        for (interpretation, bits, extra_samples, data) in images {
+            let header = ImageHeader::new(width, height, Compression::No, interpretation, BitsPerSample::new(bits.clone()).unwrap(), extra_samples.clone()).unwrap();
+            let image = Image::new(header, data);
+            for &predictor in &[Predictor::No, Predictor::Horizontal] {
+                for &tiled in &[false, true] {
+                    let builder = EncoderBuilder::new().with_codec(Box::new(codec::LZWEncoder)).predictor(predictor);
+                    let builder = if tiled { builder.tile_size(16, 16) } else { builder.rows_per_strip(4) };
+                    let file = builder.build(Cursor::new(vec![])).unwrap().encode(&image).unwrap().into_inner();
+
+                    let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+                    let ifd = decoder.ifd().unwrap();
+                    assert_eq!(decoder.get_value(&ifd, tag::SamplesPerPixel).unwrap(), bits.len() as u16);
+                    assert_eq!(ifd.get(tag::TileWidth).is_some(), tiled);
+                    let decoded = decoder.image().unwrap();
+                    let case = format!("{:?} {:?} {:?} tiled {}", interpretation, bits, predictor, tiled);
+                    assert_eq!(decoded.header().photometric_interpretation(), interpretation, "{}", case);
+                    assert_eq!(decoded.header().extra_samples(), &extra_samples[..], "{}", case);
+                    assert_eq!(decoded.data(), image.data(), "{}", case);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn edge_padding_round_trips() {
+        let images = vec![
+            gradient(37, 21),
+            image(19, 33, PhotometricInterpretation::BlackIsZero, &[16], ImageData::U16((0..19 * 33).map(|x| x * 97).collect())),
+            image(21, 17, PhotometricInterpretation::BlackIsZero, &[4], ImageData::U8((0..21 * 17).map(|x| (x % 16) as u8).collect())),
+            image(23, 19, PhotometricInterpretation::TransparencyMask, &[1], ImageData::Mask((0..23 * 19).map(|x| if x % 3 == 0 { 255 } else { 0 }).collect())),
+        ];
+        for padding in [EdgePadding::Zero, EdgePadding::ReplicateEdge].iter().cloned() {
+            for image in &images {
+                let predictor = if image.header().bits_per_sample().bits() >= 8 { Predictor::Horizontal } else { Predictor::No };
+                let builder = EncoderBuilder::new().with_codec(Box::new(codec::LZWEncoder)).predictor(predictor).tile_size(16, 16).edge_padding(padding);
+                let file = builder.build(Cursor::new(vec![])).unwrap().encode(image).unwrap().into_inner();
+                let decoded = Decoder::new(Cursor::new(file)).unwrap().image().unwrap();
+                assert_eq!(decoded.data(), image.data(), "{:?} {:?}", padding, image.header().bits_per_sample());
+            }
+        }
+    }
+
+    #[test]
+    fn replicate_pixel_fills_the_rest_of_a_row() {
+        let mut row = vec![1, 2, 3, 4, 5, 6, 0, 0, 0, 0, 0, 0];
+        replicate_pixel(&mut row, 2, 24);
+        assert_eq!(row, [1, 2, 3, 4, 5, 6, 4, 5, 6, 4, 5, 6]);
+
+        // three 4-bit pixels, the third 0xC
+        let mut row = vec![0xAB, 0xC0, 0, 0];
+        replicate_pixel(&mut row, 3, 4);
+        assert_eq!(row, [0xAB, 0xCC, 0xCC, 0xCC]);
+
+        // five 1-bit pixels, the fifth set
+        let mut row = vec![0b0000_1000, 0];
+        replicate_pixel(&mut row, 5, 1);
+        assert_eq!(row, [0b0000_1111, 0xFF]);
+    }
+
+    #[test]
+    fn replicated_edges_compress_better() {
+        // 72 columns of padding on the right of every row of tiles
+        let image = gradient(200, 128);
+        let size = |padding| {
+            let builder = EncoderBuilder::new().with_codec(Box::new(codec::LZWEncoder)).predictor(Predictor::Horizontal).tile_size(128, 128).edge_padding(padding);
+            builder.build(Cursor::new(vec![])).unwrap().encode(&image).unwrap().into_inner().len()
+        };
+        assert!(size(EdgePadding::ReplicateEdge) < size(EdgePadding::Zero));
+    }
+
+    // a writer that can't seek
+    #[derive(Debug)]
+    struct WriteOnly(Vec<u8>);
+
+    impl Write for WriteOnly {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn sequential_pages() -> (Vec<PlannedPage>, Image) {
+        let raw = gray(16, 10, 5);
+        let mut compressed = PlannedPage::compressed(raw.header().clone(), vec![64, 64, 32]);
+        compressed.put_value(tag::Software, "planned".to_string());
+        let pages = vec![PlannedPage::image(gradient(30, 20)), compressed, PlannedPage::image(gray(7, 5, 9))];
+        (pages, raw)
+    }
+
+    #[test]
+    fn sequential_pages_round_trip() {
+        let builder = EncoderBuilder::new().with_codec(Box::new(codec::LZWEncoder)).rows_per_strip(4);
+        let (pages, raw) = sequential_pages();
+        let mut encoder = builder.build_sequential(WriteOnly(vec![]), pages).unwrap();
+        assert_eq!(encoder.chunks_left(), 3);
+        // the raw page's strips, uncompressed as its header says
+        let pixels = match *raw.data() {
+            ImageData::U8(ref data) => data.clone(),
+            _ => unreachable!(),
+        };
+        for strip in pixels.chunks(64) {
+            encoder.write_chunk(strip).unwrap();
+        }
+        let file = encoder.finish().unwrap().0;
+
+        let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+        let images = decoder.images().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(images.len(), 3);
+        assert_eq!(images[0].data(), gradient(30, 20).data());
+        assert_eq!(images[1].data(), raw.data());
+        assert_eq!(images[2].data(), gray(7, 5, 9).data());
+        decoder.change_ifd(1).unwrap();
+        let ifd = decoder.ifd().unwrap();
+        assert_eq!(decoder.get_value(&ifd, tag::Compression).unwrap(), 1);
+        assert_eq!(decoder.get_value(&ifd, tag::Software).unwrap(), "planned");
+        decoder.change_ifd(0).unwrap();
+        let ifd = decoder.ifd().unwrap();
+        assert_eq!(decoder.get_value(&ifd, tag::Compression).unwrap(), 5);
+    }
+
+    #[test]
+    fn sequential_rules_are_enforced() {
+        let is_rule = |e: EncodeError| matches!(*e.kind(), EncodeErrorKind::SequentialRule { .. });
+
+        assert!(is_rule(EncoderBuilder::new().build_sequential(WriteOnly(vec![]), vec![]).unwrap_err()));
+
+        let raw = gray(16, 10, 5);
+        let pages = vec![PlannedPage::compressed(raw.header().clone(), vec![64, 96])];
+        assert!(is_rule(EncoderBuilder::new().rows_per_strip(4).build_sequential(WriteOnly(vec![]), pages).unwrap_err()));
+
+        let (pages, _) = sequential_pages();
+        let mut encoder = EncoderBuilder::new().rows_per_strip(4).build_sequential(WriteOnly(vec![]), pages).unwrap();
+        assert!(is_rule(encoder.write_chunk(&[0; 63]).unwrap_err()));
+        encoder.write_chunk(&[0; 64]).unwrap();
+        assert!(is_rule(encoder.finish().unwrap_err()));
+
+        let mut encoder = EncoderBuilder::new().build_sequential(WriteOnly(vec![]), vec![PlannedPage::image(gray(4, 4, 0))]).unwrap();
+        assert!(is_rule(encoder.write_chunk(&[0; 16]).unwrap_err()));
+        assert!(encoder.finish().unwrap().0.len() > 16);
+    }
+
+    // a 2x2 box filter written out, to check the encoder's levels against
+    fn reference_half(data: &[u32], width: usize, height: usize, samples: usize) -> Vec<u32> {
+        let (half_width, half_height) = (width.div_ceil(2), height.div_ceil(2));
+        let mut out = vec![];
+        for y in 0..half_height {
+            for x in 0..half_width {
+                for s in 0..samples {
+                    let mut sum = 0;
+                    let mut n = 0;
+                    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter().cloned() {
+                        if 2 * x + dx < width && 2 * y + dy < height {
+                            sum += data[((2 * y + dy) * width + 2 * x + dx) * samples + s];
+                            n += 1;
+                        }
+                    }
+                    out.push((sum as f64 / n as f64).round() as u32);
+                }
+            }
+        }
+
+        out
+    }
+
+    fn samples_u32(data: &ImageData) -> Vec<u32> {
+        match *data {
+            ImageData::U8(ref data) => data.iter().map(|&x| x as u32).collect(),
+            ImageData::U16(ref data) => data.iter().map(|&x| x as u32).collect(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn auto_pyramid_levels() {
+        let rgb = gradient(37, 23);
+        let gray_alpha = {
+            let header = ImageHeader::new(45, 19, Compression::No, PhotometricInterpretation::BlackIsZero, BitsPerSample::new([16, 16]).unwrap(), vec![::image::ExtraSample::UnassociatedAlpha]).unwrap();
+            Image::new(header, ImageData::U16((0..45 * 19 * 2).map(|x| (x * 331 % 65536) as u16).collect()))
+        };
+        for placement in [OverviewPlacement::Chained, OverviewPlacement::SubIFDs].iter().cloned() {
+            for image in &[&rgb, &gray_alpha] {
+                let builder = EncoderBuilder::new().with_codec(Box::new(codec::DeflateEncoder::default())).tile_size(16, 16).auto_pyramid(5).overview_placement(placement);
+                let file = builder.build(Cursor::new(vec![])).unwrap().encode(image).unwrap().into_inner();
+                let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+                let levels = match placement {
+                    OverviewPlacement::Chained => decoder.ifd_offsets().unwrap(),
+                    OverviewPlacement::SubIFDs => {
+                        let mut offsets = vec![decoder.current_ifd_offset()];
+                        offsets.extend(decoder.sub_ifds().unwrap());
+                        offsets
+                    }
+                };
+
+                let (width, height) = (image.header().width() as usize, image.header().height() as usize);
+                let samples = image.header().samples_per_pixel();
+                let mut reference = samples_u32(image.data());
+                // down to the first level below 5 pixels both ways
+                let mut expected_levels = 1;
+                while (width.div_ceil(1 << (expected_levels - 1)) >= 5) || (height.div_ceil(1 << (expected_levels - 1)) >= 5) {
+                    expected_levels += 1;
+                }
+                assert_eq!(levels.len(), expected_levels);
+                for (k, &offset) in levels.iter().enumerate() {
+                    decoder.load_ifd_at(offset).unwrap();
+                    let ifd = decoder.ifd().unwrap();
+                    let level = decoder.image().unwrap();
+                    let (level_width, level_height) = (width.div_ceil(1 << k), height.div_ceil(1 << k));
+                    assert_eq!((level.header().width() as usize, level.header().height() as usize), (level_width, level_height));
+                    assert_eq!(level.header().samples_per_pixel(), samples);
+                    assert_eq!(decoder.get_value(&ifd, tag::TileWidth).unwrap(), 16);
+                    assert_eq!(decoder.get_value(&ifd, tag::Compression).unwrap(), 8);
+                    if k > 0 {
+                        assert_eq!(decoder.get_value(&ifd, tag::NewSubfileType).unwrap(), 1);
+                        reference = reference_half(&reference, width.div_ceil(1 << (k - 1)), height.div_ceil(1 << (k - 1)), samples);
+                    }
+                    assert_eq!(samples_u32(level.data()), reference, "level {}", k);
+                }
+            }
+        }
+    }
+
+    fn palette_image(bits: u16, data: Vec<u8>) -> Image {
+        image(data.len() as u32, 1, PhotometricInterpretation::Palette, &[bits], ImageData::U8(data))
+    }
+
+    fn configuration_error(result: EncodeResult<()>) -> String {
+        match *result.unwrap_err().kind() {
+            EncodeErrorKind::UnsupportedConfiguration { ref reason } => reason.clone(),
+            ref kind => panic!("{:?}", kind),
+        }
+    }
+
+    #[test]
+    fn color_map_is_channel_major() {
+        let palette = [[1, 2, 3], [4, 5, 6], [7, 8, 9]];
+        let mut encoder = Encoder::new(Cursor::new(vec![])).unwrap();
+        encoder.append_palette_image(&palette_image(2, vec![0, 1, 2, 1]), &palette).unwrap();
+        let file = encoder.finish().unwrap().into_inner();
+        let mut decoder = Decoder::new(Cursor::new(file.clone())).unwrap();
+        let ifd = decoder.ifd().unwrap();
+        // 3 x 2^2 values: the reds, then the greens, then the blues, unused colors black
+        assert_eq!(decoder.get_value(&ifd, tag::ColorMap).unwrap(), vec![1, 4, 7, 0, 2, 5, 8, 0, 3, 6, 9, 0]);
+        assert_eq!(decoder.image().unwrap().data(), &ImageData::U8(vec![0, 1, 2, 1]));
+        let mut decoder = DecoderBuilder::new().expand_palette(true).build(Cursor::new(file)).unwrap();
+        assert_eq!(decoder.image().unwrap().data(), &ImageData::U16(vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 4, 5, 6]));
+    }
+
+    #[test]
+    fn palette_errors() {
+        let mut encoder = Encoder::new(Cursor::new(vec![])).unwrap();
+        let reason = configuration_error(encoder.append_palette_image(&palette_image(1, vec![0, 1, 2]), &[[0; 3], [1; 3]]));
+        assert_eq!(reason, "palette index 2 past its 2 colors");
+        let reason = configuration_error(encoder.append_palette_image(&palette_image(1, vec![0]), &[[0; 3]; 3]));
+        assert_eq!(reason, "3 colors for 1-bit indices");
+        assert!(configuration_error(encoder.append_palette_image(&gray(2, 2, 0), &[[0; 3]])).starts_with("a palette needs a Palette image"));
+        assert!(configuration_error(encoder.append_quantized(&gray(2, 2, 0), 16)).starts_with("only RGB images"));
+        // no pixels, no colors
+        encoder.append_quantized(&image(0, 0, PhotometricInterpretation::RGB, &[8, 8, 8], ImageData::U8(vec![])), 16).unwrap();
+    }
+
+    #[test]
+    fn quantized_round_trip() {
+        let image = gradient(16, 16);
+        let mut encoder = Encoder::new(Cursor::new(vec![])).unwrap();
+        encoder.append_quantized(&image, 8).unwrap();
+        let mut decoder = Decoder::new(Cursor::new(encoder.finish().unwrap().into_inner())).unwrap();
+        let ifd = decoder.ifd().unwrap();
+        assert_eq!(decoder.get_value(&ifd, tag::BitsPerSample).unwrap(), vec![4]);
+        assert_eq!(decoder.get_value(&ifd, tag::ColorMap).unwrap().len(), 3 << 4);
+        assert_eq!(decoder.image().unwrap().data(), image.quantize(8).unwrap().0.data());
+    }
+
+    fn bilevel(width: u32, height: u32) -> Image {
+        let data = (0..width * height).map(|i| {
+            let (x, y) = (i % width, i / width);
+            // blocks, a diagonal and a long run, so every mode shows up
+            (((x / 5 + y / 3) % 2 == 0) || x == y || (y == 7 && x > 2)) as u8
+        }).collect();
+        image(width, height, PhotometricInterpretation::WhiteIsZero, &[1], ImageData::U8(data))
+    }
+
+    #[test]
+    fn ccitt_fax4_round_trips() {
+        let image = bilevel(37, 21);
+        for builder in [EncoderBuilder::new().strip_bytes(5 * 8), EncoderBuilder::new().tile_size(16, 16)] {
+            let mut encoder = builder.with_codec(Box::new(codec::CCITTFax4Encoder::default())).build(Cursor::new(vec![])).unwrap();
+            encoder.append_image(&image).unwrap();
+            let mut decoder = Decoder::new(Cursor::new(encoder.finish().unwrap().into_inner())).unwrap();
+            let ifd = decoder.ifd().unwrap();
+            assert_eq!(decoder.get_value(&ifd, tag::Compression).unwrap(), 4);
+            assert_eq!(decoder.image().unwrap().data(), image.data());
+        }
+    }
+
+    #[test]
+    fn ccitt_fax4_needs_one_bit() {
+        let mut encoder = EncoderBuilder::new().with_codec(Box::new(codec::CCITTFax4Encoder::default())).build(Cursor::new(vec![])).unwrap();
+        match *encoder.append_image(&gray(8, 8, 0)).unwrap_err().kind() {
+            EncodeErrorKind::IO { ref error } => assert_eq!(error.kind(), io::ErrorKind::InvalidInput),
+            ref kind => panic!("{:?}", kind),
+        }
+    }
+
+    #[test]
+    fn cog_layout() {
+        let image = gray(70, 50, 0);
+        let overviews = [image.downsample_by(2), image.downsample_by(4)];
+        for &big_tiff in &[false, true] {
+            let encoder = EncoderBuilder::new().big_tiff(big_tiff).tile_size(16, 16).build(Cursor::new(vec![])).unwrap();
+            let file = encoder.encode_cog(&image, &overviews).unwrap().into_inner();
+            let raw = RawFile::new(&file);
+
+            // the full image heads the chain, then the overviews from the largest
+            let ifds = raw.chain();
+            let widths = ifds.iter().map(|ifd| raw.unsigned_values(ifd.iter().find(|x| x.tag == 256).unwrap())[0]).collect::<Vec<_>>();
+            assert_eq!(widths, vec![70, 35, 18]);
+
+            // every IFD and value comes before the first tile
+            let mut offsets = vec![];
+            all_offsets(&raw, raw.first_ifd(), &mut offsets);
+            let first_tile = offsets.iter().filter(|x| x.0 == "chunk of 324").map(|x| x.1).min().unwrap();
+            for (what, offset) in offsets.iter().filter(|x| x.0 != "chunk of 324") {
+                assert!(*offset < first_tile, "{} at {} after the first tile at {}", what, offset, first_tile);
+            }
+
+            // the tiles of the smallest level come first
+            let tiles = ifds.iter().map(|ifd| raw.unsigned_values(ifd.iter().find(|x| x.tag == 324).unwrap())).collect::<Vec<_>>();
+            assert_eq!(tiles.iter().map(|x| x.len()).collect::<Vec<_>>(), vec![20, 6, 2]);
+            for pair in tiles.windows(2) {
+                assert!(pair[1].iter().max() < pair[0].iter().min());
+            }
+            assert_eq!(tiles[2][0], first_tile);
+
+            let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+            assert_eq!(decoder.image().unwrap().data(), image.data());
+            decoder.change_ifd(2).unwrap();
+            let ifd = decoder.ifd().unwrap();
+            assert_eq!(decoder.get_value(&ifd, tag::NewSubfileType).unwrap(), 1);
+            assert_eq!(decoder.image().unwrap().data(), overviews[1].data());
+        }
+    }
+
+    #[test]
+    fn cog_overviews_must_shrink() {
+        let image = gray(40, 30, 0);
+        // as large, wider, and taller but narrower than the level before it
+        for &(width, height) in &[(40, 30), (41, 10), (20, 31)] {
+            let result = Encoder::new(Cursor::new(vec![])).unwrap().encode_cog(&image, &[gray(width, height, 1)]);
+            let reason = format!("overview of {}x{} pixels after a level of 40x30", width, height);
+            assert_eq!(configuration_error(result.map(|_| ())), reason);
+            let result = Encoder::new(Cursor::new(vec![])).unwrap().encode_cog(&image, &[gray(30, 20, 1), gray(width / 2 + 15, height / 2 + 10, 1)]);
+            let reason = format!("overview of {}x{} pixels after a level of 30x20", width / 2 + 15, height / 2 + 10);
+            assert_eq!(configuration_error(result.map(|_| ())), reason);
+        }
+
+        let mut encoder = Encoder::new(Cursor::new(vec![])).unwrap();
+        encoder.append_image(&image).unwrap();
+        assert!(configuration_error(encoder.encode_cog(&image, &[]).map(|_| ())).contains("can't follow other pages"));
+    }
+
+    // The values of entry `id` of `ifd`.
+    fn any_values<R: Read + Seek>(decoder: &mut Decoder<R>, ifd: &IFD, id: u16) -> Option<AnyValues> {
+        let entry = ifd.iter().find(|x| x.0.id() == id)?.1.clone();
+        Some(decoder.get_any_values(&entry).unwrap())
+    }
+
+    // A 6x5 page with a private tag, an Exif IFD and a GPS IFD, which lie before
+    // it outside the chain.
+    fn with_metadata() -> Vec<u8> {
+        let exif = Page::new()
+            .tag(33434, Value::Rational(vec![(1, 250)]))
+            .tag(65001, Value::Ascii("private in Exif".to_string()));
+        let gps = Page::new()
+            .tag(0, Value::Byte(vec![2, 3, 0, 0]))
+            .tag(1, Value::Ascii("S".to_string()))
+            .tag(2, Value::Rational(vec![(35, 1), (30, 1), (0, 1)]))
+            .tag(29, Value::Ascii("2024:02:29".to_string()));
+        let page = |links: Option<(u32, u32)>| {
+            let page = Page::gray8(6, 5, (0..30).collect())
+                .tag(305, Value::Ascii("scanner".to_string()))
+                .tag(306, Value::Ascii("2001:02:03 04:05:06".to_string()))
+                .tag(65000, Value::Ascii("private".to_string()))
+                .tag(34665, Value::Long(vec![0]))
+                .tag(34853, Value::Long(vec![0]));
+            match links {
+                Some((exif, gps)) => page.offset(34665, exif).offset(34853, gps),
+                None => page,
+            }
+        };
+        let builder = |links| TiffBuilder::new(Endian::Little).page(exif.clone()).page(gps.clone()).page(page(links));
+
+        // the links don't change the layout, so the chain of a first build says
+        // where each IFD goes
+        let offsets = Decoder::new(Cursor::new(builder(None).build())).unwrap().ifd_offsets().unwrap();
+        builder(Some((offsets[0] as u32, offsets[1] as u32))).first_ifd_offset(offsets[2] as u32).build()
+    }
+
+    #[test]
+    fn copy_image_keeps_metadata() {
+        let mut decoder = Decoder::new(Cursor::new(with_metadata())).unwrap();
+        assert_eq!(decoder.ifd_count().unwrap(), 1);
+        let mut encoder = EncoderBuilder::new().rows_per_strip(2).software("rustiff").build(Cursor::new(vec![])).unwrap();
+        copy_image(&mut decoder, &mut encoder).unwrap();
+        let file = encoder.finish().unwrap().into_inner();
+
+        let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+        assert_eq!(decoder.image().unwrap().data(), &ImageData::U8((0..30).collect()));
+        let ifd = decoder.ifd().unwrap();
+        assert_eq!(any_values(&mut decoder, &ifd, 65000), Some(AnyValues::Ascii(b"private\0".to_vec())));
+        // the builder's Software replaces the copied one
+        assert_eq!(decoder.get_value(&ifd, tag::Software).unwrap(), "rustiff");
+        // and the copied DateTime the one the encoder would date it with
+        assert_eq!(decoder.get_value(&ifd, tag::DateTime).unwrap(), "2001:02:03 04:05:06");
+        // the strips are the encoder's
+        assert_eq!(decoder.get_value(&ifd, tag::RowsPerStrip).unwrap(), 2);
+        assert_eq!(decoder.get_value(&ifd, tag::StripOffsets).unwrap().len(), 3);
+
+        let exif = decoder.exif().unwrap().unwrap();
+        assert_eq!(exif.len(), 2);
+        assert_eq!(any_values(&mut decoder, &exif, 33434), Some(AnyValues::Rational(vec![Rational::new(1, 250)])));
+        assert_eq!(any_values(&mut decoder, &exif, 65001), Some(AnyValues::Ascii(b"private in Exif\0".to_vec())));
+        let gps = decoder.gps().unwrap().unwrap();
+        assert_eq!(gps.latitude(), Some(-35.5));
+        assert_eq!(gps.date_stamp(), Some("2024:02:29"));
+    }
+
+    #[test]
+    fn date_time_format() {
+        assert_eq!(format_date_time(0), "1970:01:01 00:00:00");
+        assert_eq!(format_date_time(951782400), "2000:02:29 00:00:00");
+        assert_eq!(format_date_time(1709251199), "2024:02:29 23:59:59");
+        assert_eq!(format_date_time(1709251200), "2024:03:01 00:00:00");
+        assert_eq!(format_date_time(4107542400), "2100:03:01 00:00:00");
+        assert_eq!(format_date_time(1735689599), "2024:12:31 23:59:59");
+
+        assert!(is_date_time("2024:02:29 23:59:59"));
+        for &date_time in &["2024-02-29 23:59:59", "2024:02:29", "2024:13:01 00:00:00", "2024:00:01 00:00:00", "2024:01:01 24:00:00", " 2024:01:01 00:00:0"] {
+            assert!(!is_date_time(date_time), "{}", date_time);
+            let result = EncoderBuilder::new().date_time(date_time).build(Cursor::new(vec![])).map(|_| ());
+            assert!(configuration_error(result).contains("YYYY:MM:DD HH:MM:SS"));
+        }
+    }
+
+    #[test]
+    fn date_time_is_filled_in() {
+        let date_time = |builder: EncoderBuilder, put: Option<&str>| {
+            let mut encoder = builder.build(Cursor::new(vec![])).unwrap();
+            if let Some(put) = put {
+                encoder.put_value(tag::DateTime, put.to_string());
+            }
+            encoder.append_image(&gray(4, 4, 0)).unwrap();
+            let mut decoder = Decoder::new(Cursor::new(encoder.finish().unwrap().into_inner())).unwrap();
+            let ifd = decoder.ifd().unwrap();
+            decoder.get_optional_value(&ifd, tag::DateTime).unwrap()
+        };
+
+        let before = format_date_time(now());
+        let filled = date_time(EncoderBuilder::new(), None).unwrap();
+        assert!(is_date_time(&filled) && filled >= before && filled <= format_date_time(now()), "{}", filled);
+        let given = "2000:02:29 12:00:00";
+        assert_eq!(date_time(EncoderBuilder::new().date_time(given), None).as_deref(), Some(given));
+        assert_eq!(date_time(EncoderBuilder::new(), Some(given)).as_deref(), Some(given));
+        assert_eq!(date_time(EncoderBuilder::new().auto_date_time(false), None), None);
+    }
+
+    #[test]
+    fn big_tiff_is_chosen_by_the_first_page() {
+        let encode = |builder: EncoderBuilder, pages: &[(u32, u32)]| {
+            let mut encoder = builder.build(Cursor::new(vec![])).unwrap();
+            encoder.big_tiff_threshold = 1000;
+            for (i, &(width, height)) in pages.iter().enumerate() {
+                encoder.append_image(&gray(width, height, i as u8)).unwrap();
+            }
+            let mut decoder = Decoder::new(Cursor::new(encoder.finish().unwrap().into_inner())).unwrap();
+            assert_eq!(decoder.ifd_count().unwrap(), pages.len());
+            decoder.file_header().is_big_tiff()
+        };
+
+        // 1200 bytes of samples pass the threshold, 100 don't
+        assert!(encode(EncoderBuilder::new(), &[(40, 30)]));
+        assert!(encode(EncoderBuilder::new(), &[(40, 30), (10, 10)]));
+        assert!(!encode(EncoderBuilder::new(), &[(10, 10)]));
+        // the pages after the first aren't counted
+        assert!(!encode(EncoderBuilder::new(), &[(10, 10), (40, 30)]));
+        // nor is anything when the builder says
+        assert!(!encode(EncoderBuilder::new().big_tiff(false), &[(40, 30)]));
+        assert!(encode(EncoderBuilder::new().big_tiff(true), &[(10, 10)]));
+
+        // a COG counts all its levels
+        let image = gray(30, 20, 0);
+        let overviews = [image.downsample_by(2), image.downsample_by(4)];
+        let mut encoder = Encoder::new(Cursor::new(vec![])).unwrap();
+        encoder.big_tiff_threshold = 700;
+        let file = encoder.encode_cog(&image, &overviews).unwrap().into_inner();
+        assert!(Decoder::new(Cursor::new(file)).unwrap().file_header().is_big_tiff());
+    }
+}
@@ -1,3 +1,5 @@
+// failure's derive puts its impls inside a const item next to the type.
+#![allow(non_local_definitions)]
 
 use ifd::{
     Entry,
@@ -23,10 +25,29 @@ use failure::{
 
 pub type DecodeResult<T> = ::std::result::Result<T, DecodeError>;
 
+// Broad classes of `DecodeErrorKind`, so callers can react to a failure without
+// matching every variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DecodeErrorCategory {
+    // the file header or the IFD chain is malformed
+    Header,
+    IO,
+    // an entry is missing or can't be read as the tag's type
+    Tag,
+    // values were read but are invalid or inconsistent with each other
+    Value,
+    // the file exceeds a configured limit
+    Limit,
+    // valid TIFF that this crate doesn't decode
+    Unsupported,
+}
+
 #[derive(Debug, Fail)]
+#[non_exhaustive]
 pub enum DecodeErrorKind {
     #[fail(display = "IO Error: {:?}", error)]
-    IO { error: io::Error },
+    IO { #[fail(cause)] error: io::Error },
 
     #[fail(display = "Incorrect header: No Byte Order")]
     NoByteOrder,
@@ -40,6 +61,21 @@ pub enum DecodeErrorKind {
     #[fail(display = "No Image address")]
     NoImage,
 
+    #[fail(display = "Invalid IFD address: {}", offset)]
+    InvalidIFDAddress { offset: u64 },
+
+    #[fail(display = "IFD chain links back to the IFD at {}", offset)]
+    IFDCycle { offset: u64 },
+
+    #[fail(display = "IFD at {} declares {} entries, but the file ends after {}", offset, declared, read)]
+    TruncatedIFD { offset: u64, declared: usize, read: usize },
+
+    #[fail(display = "Tag ({}) has {} bytes of values at offset {}, outside the file or inside the header", tag, len, offset)]
+    InvalidEntryOffset { tag: AnyTag, offset: u64, len: u64 },
+
+    #[fail(display = "IFD index {} is out of range ({} IFDs)", index, count)]
+    IFDIndexOutOfRange { index: usize, count: usize },
+
     #[fail(display = "Can't find the tag ({:?})", tag)]
     CannotFindTheTag { tag: AnyTag },
 
@@ -52,6 +88,18 @@ pub enum DecodeErrorKind {
     #[fail(display = "Tag ({:?}) does not support data: ({:?})", tag, data)]
     UnsupportedData { tag: AnyTag, data: u32 },
 
+    #[fail(display = "SamplesPerPixel ({}) does not match BitsPerSample ({:?})", samples_per_pixel, bits_per_sample)]
+    InconsistentSamplesPerPixel { samples_per_pixel: u16, bits_per_sample: Vec<u16> },
+
+    #[fail(display = "Unsupported sample layout: BitsPerSample {:?}", values)]
+    UnsupportedBitsPerSample { values: Vec<u16> },
+
+    #[fail(display = "SampleFormat {} is not supported with BitsPerSample {:?}", sample_format, bits_per_sample)]
+    UnsupportedSampleFormat { sample_format: u16, bits_per_sample: Vec<u16> },
+
+    #[fail(display = "Predictor {} is not supported with BitsPerSample {:?}", predictor, bits_per_sample)]
+    UnsupportedPredictor { predictor: u16, bits_per_sample: Vec<u16> },
+
     #[fail(display = "Calculated from width and height: {}, sum: {}", calc, sum)]
     IncorrectBufferSize { calc: usize, sum: usize },
 
@@ -66,16 +114,232 @@ pub enum DecodeErrorKind {
 
     #[fail(display = "Tag ({:?}) doesn't support this datatype/count : {:?}/{}", tag, datatype, count)]
     NoSupportDataType { tag: AnyTag, datatype: DataType, count: usize },
+
+    #[fail(display = "Tag ({:?}) has a malformed ASCII value: {}", tag, reason)]
+    InvalidAscii { tag: AnyTag, reason: String },
+
+    #[fail(display = "Strip {} decompressed to {} bytes, but {} bytes were expected", strip, actual, expected)]
+    StripLengthMismatch { strip: usize, expected: usize, actual: usize },
+
+    #[fail(display = "The image needs {} strips or tiles, but only {} are given", expected, actual)]
+    StripCountMismatch { expected: usize, actual: usize },
+
+    #[fail(display = "Corrupt file: the {} overflows", what)]
+    CorruptOffset { what: &'static str },
+
+    #[fail(display = "Strip or tile of {} bytes at offset {} lies outside the file", byte_count, offset)]
+    InvalidChunkOffset { offset: u64, byte_count: u64 },
+
+    #[fail(display = "Compression {} failed to decode: {}", compression, reason)]
+    CodecFailed { compression: u16, reason: String },
+
+    #[fail(display = "A codec was registered for built-in compression {} without allowing overrides", compression)]
+    BuiltinCodecOverride { compression: u16 },
+
+    #[fail(display = "{} of {} exceeds the limit of {}", limit, value, max)]
+    LimitExceeded { limit: &'static str, value: u64, max: u64 },
+
+    #[fail(display = "Region {}x{} at ({}, {}) lies outside the {}x{} image", width, height, x, y, image_width, image_height)]
+    RegionOutOfBounds { x: u32, y: u32, width: u32, height: u32, image_width: u32, image_height: u32 },
+
+    #[fail(display = "Palette image has no ColorMap")]
+    PaletteWithoutColorMap,
+
+    #[fail(display = "Palette image requires SamplesPerPixel 1, but got {}", samples_per_pixel)]
+    PaletteSamplesPerPixel { samples_per_pixel: usize },
+
+    #[fail(display = "Palette image has unsupported BitsPerSample {}", bits)]
+    PaletteBitsPerSample { bits: usize },
+
+    #[fail(display = "Palette ColorMap requires {} values, but got {}", expected, actual)]
+    PaletteColorMapLength { expected: usize, actual: usize },
+
+    #[fail(display = "The image crate has no color type for {}", reason)]
+    UnsupportedColorType { reason: String },
+
+    #[fail(display = "Requested {} samples, but the image has {} samples", requested, actual)]
+    SampleTypeMismatch { requested: &'static str, actual: &'static str },
+
+    #[fail(display = "Cannot convert through the ICC profile: {}", reason)]
+    InvalidColorProfile { reason: String },
+}
+
+impl DecodeErrorKind {
+    pub fn category(&self) -> DecodeErrorCategory {
+        match *self {
+            DecodeErrorKind::IO { .. } => DecodeErrorCategory::IO,
+            DecodeErrorKind::NoByteOrder
+            | DecodeErrorKind::NoVersion
+            | DecodeErrorKind::NoIFDAddress
+            | DecodeErrorKind::NoImage
+            | DecodeErrorKind::InvalidIFDAddress { .. }
+            | DecodeErrorKind::IFDCycle { .. }
+            | DecodeErrorKind::TruncatedIFD { .. }
+            | DecodeErrorKind::InvalidEntryOffset { .. }
+            | DecodeErrorKind::IFDIndexOutOfRange { .. } => DecodeErrorCategory::Header,
+            DecodeErrorKind::CannotFindTheTag { .. }
+            | DecodeErrorKind::UnsupportedIFDEntry { .. }
+            | DecodeErrorKind::NoData { .. }
+            | DecodeErrorKind::ExtraData { .. }
+            | DecodeErrorKind::NoSupportDataType { .. }
+            | DecodeErrorKind::InvalidAscii { .. } => DecodeErrorCategory::Tag,
+            DecodeErrorKind::InconsistentSamplesPerPixel { .. }
+            | DecodeErrorKind::IncorrectBufferSize { .. }
+            | DecodeErrorKind::IncompatibleData { .. }
+            | DecodeErrorKind::StripLengthMismatch { .. }
+            | DecodeErrorKind::StripCountMismatch { .. }
+            | DecodeErrorKind::InvalidChunkOffset { .. }
+            | DecodeErrorKind::CorruptOffset { .. }
+            | DecodeErrorKind::RegionOutOfBounds { .. }
+            | DecodeErrorKind::BuiltinCodecOverride { .. }
+            | DecodeErrorKind::CodecFailed { .. }
+            | DecodeErrorKind::PaletteWithoutColorMap
+            | DecodeErrorKind::PaletteSamplesPerPixel { .. }
+            | DecodeErrorKind::PaletteBitsPerSample { .. }
+            | DecodeErrorKind::PaletteColorMapLength { .. }
+            | DecodeErrorKind::InvalidColorProfile { .. } => DecodeErrorCategory::Value,
+            DecodeErrorKind::UnsupportedMultipleData { .. }
+            | DecodeErrorKind::UnsupportedData { .. }
+            | DecodeErrorKind::UnsupportedBitsPerSample { .. }
+            | DecodeErrorKind::UnsupportedPredictor { .. }
+            | DecodeErrorKind::UnsupportedSampleFormat { .. }
+            | DecodeErrorKind::UnsupportedColorType { .. }
+            | DecodeErrorKind::SampleTypeMismatch { .. } => DecodeErrorCategory::Unsupported,
+            DecodeErrorKind::LimitExceeded { .. } => DecodeErrorCategory::Limit,
+        }
+    }
+}
+
+// Problems a lenient decoder worked around instead of failing.
+#[derive(Debug, Clone, PartialEq, Fail)]
+pub enum DecodeWarning {
+    #[fail(display = "Tag ({}) has an ASCII value without NUL terminator", tag)]
+    AsciiMissingNul { tag: AnyTag },
+
+    #[fail(display = "Tag ({}) has {} byte(s) of padding after its ASCII value", tag, bytes)]
+    AsciiPadding { tag: AnyTag, bytes: usize },
+
+    #[fail(display = "Tag ({}) has non-ASCII bytes, read as {}", tag, encoding)]
+    AsciiNonAscii { tag: AnyTag, encoding: &'static str },
+
+    #[fail(display = "Tag ({}) has the unknown value {}", tag, value)]
+    UnknownValue { tag: AnyTag, value: u16 },
+
+    #[fail(display = "Tag ({}) is malformed and its default was used instead: {}", tag, reason)]
+    MalformedEntry { tag: AnyTag, reason: String },
+
+    #[fail(display = "Tag ({}) in IFD {} was skipped: {}", tag, ifd, reason)]
+    SkippedValue { ifd: usize, tag: AnyTag, reason: String },
+
+    #[fail(display = "Strip {} decompressed to {} surplus bytes, which were dropped", strip, surplus)]
+    StripTruncated { strip: usize, surplus: usize },
+
+    #[fail(display = "Strip {} was {} bytes short and was zero-filled", strip, missing)]
+    StripZeroFilled { strip: usize, missing: usize },
+
+    #[fail(display = "IFD at {} declares {} entries, but only {} could be read", offset, declared, read)]
+    TruncatedIFD { offset: u64, declared: usize, read: usize },
+
+    #[fail(display = "The entries of the IFD at {} are not sorted by tag", ifd)]
+    UnsortedEntries { ifd: u64 },
+
+    #[fail(display = "Tag ({}) appears more than once in the IFD at {}, the last entry was used", tag, ifd)]
+    DuplicateEntry { ifd: u64, tag: AnyTag },
+
+    #[fail(display = "Tag ({}) was dropped: {} bytes of values at offset {} lie outside the file or inside the header", tag, len, offset)]
+    InvalidEntryOffset { tag: AnyTag, offset: u64, len: u64 },
+
+    #[fail(display = "Palette image decoded as grayscale: {}", reason)]
+    PaletteAsGray { reason: String },
+
+    #[fail(display = "StripByteCounts is missing, {} byte count(s) were computed from the image size", count)]
+    ReconstructedByteCounts { count: usize },
+
+    #[fail(display = "Strip or tile of {} bytes at offset {} was cut to the {} bytes the file holds", byte_count, offset, read)]
+    ChunkTruncated { offset: u64, byte_count: u64, read: u64 },
+
+    #[fail(display = "Strip or tile {} could not be decoded and was zero-filled: {}", chunk, reason)]
+    ChunkSkipped { chunk: usize, reason: String },
+
+    #[fail(display = "Page {} could not be decoded: {}", page, reason)]
+    PageSkipped { page: usize, reason: String },
+}
+
+// Inconsistencies between the strips or tiles of an image and the file, found by
+// `Decoder::check_chunks`. Indices count the chunks of all planes in file order.
+#[derive(Debug, Clone, PartialEq, Fail)]
+pub enum ChunkProblem {
+    #[fail(display = "The image needs {} strips or tiles, but {} are given", expected, actual)]
+    CountMismatch { expected: usize, actual: usize },
+
+    #[fail(display = "There are {} offsets but {} byte counts", offsets, byte_counts)]
+    ByteCountsMismatch { offsets: usize, byte_counts: usize },
+
+    #[fail(display = "The byte counts are missing")]
+    MissingByteCounts,
+
+    #[fail(display = "Chunk {} ({} bytes at offset {}) goes past the end of the file ({} bytes)", index, byte_count, offset, len)]
+    OutOfFile { index: usize, offset: u64, byte_count: u64, len: u64 },
+
+    #[fail(display = "Uncompressed chunk {} holds {} bytes, but its rows take {}", index, actual, expected)]
+    SizeMismatch { index: usize, expected: u64, actual: u64 },
+}
+
+// Where in the file a decode failed, as far as the decoder knew: the IFD, the tag
+// whose entry was being read, and the offset of the bytes that were being read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Position {
+    ifd: Option<u64>,
+    tag: Option<AnyTag>,
+    offset: Option<u64>,
+}
+
+impl Position {
+    // Offset of the IFD.
+    pub fn ifd(&self) -> Option<u64> {
+        self.ifd
+    }
+
+    pub fn tag(&self) -> Option<AnyTag> {
+        self.tag
+    }
+
+    pub fn offset(&self) -> Option<u64> {
+        self.offset
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        *self == Position::default()
+    }
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some(ifd) = self.ifd {
+            parts.push(format!("IFD at {}", ifd));
+        }
+        if let Some(tag) = self.tag {
+            parts.push(format!("tag {}", tag));
+        }
+        if let Some(offset) = self.offset {
+            parts.push(format!("offset {}", offset));
+        }
+
+        write!(f, "{}", parts.join(", "))
+    }
 }
 
 #[derive(Debug)]
 pub struct DecodeError {
     inner: Context<DecodeErrorKind>,
+    // boxed to keep `DecodeResult` small
+    position: Box<Position>,
 }
 
 impl Fail for DecodeError {
-    fn cause(&self) -> Option<&Fail> {
-        self.inner.cause()
+    fn cause(&self) -> Option<&dyn Fail> {
+        self.kind().cause()
     }
 
     fn backtrace(&self) -> Option<&Backtrace> {
@@ -85,18 +349,48 @@ impl Fail for DecodeError {
 
 impl Display for DecodeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        Display::fmt(&self.inner, f)
+        Display::fmt(&self.inner, f)?;
+        if !self.position.is_unknown() {
+            write!(f, " ({})", self.position)?;
+        }
+
+        Ok(())
     }
 }
 
 impl DecodeError {
     fn new(kind: DecodeErrorKind) -> DecodeError {
-        DecodeError { inner: Context::new(kind) }
+        DecodeError { inner: Context::new(kind), position: Box::default() }
     }
     
     pub fn kind(&self) -> &DecodeErrorKind {
         self.inner.get_context()
     }
+
+    pub fn category(&self) -> DecodeErrorCategory {
+        self.kind().category()
+    }
+
+    pub fn position(&self) -> Position {
+        *self.position
+    }
+
+    // These record where the failure happened. Each keeps a position that is
+    // already set, so the innermost, most precise one wins as the error travels up.
+    pub fn with_ifd(mut self, offset: u64) -> DecodeError {
+        self.position.ifd = self.position.ifd.or(Some(offset));
+        self
+    }
+
+    pub fn with_tag(mut self, tag: AnyTag) -> DecodeError {
+        self.position.tag = self.position.tag.or(Some(tag));
+        self
+    }
+
+    pub fn with_offset(mut self, offset: u64) -> DecodeError {
+        self.position.offset = self.position.offset.or(Some(offset));
+        self
+    }
 }
 
 impl From<io::Error> for DecodeError {
@@ -128,7 +422,227 @@ impl From<ImageHeaderError> for DecodeError {
 
 impl From<DecodeErrorKind> for DecodeError {
     fn from(kind: DecodeErrorKind) -> DecodeError {
-        DecodeError { inner: Context::new(kind) }
+        DecodeError::new(kind)
+    }
+}
+
+
+pub type EncodeResult<T> = ::std::result::Result<T, EncodeError>;
+
+#[derive(Debug, Fail)]
+#[non_exhaustive]
+pub enum EncodeErrorKind {
+    #[fail(display = "IO Error: {:?}", error)]
+    IO { #[fail(cause)] error: io::Error },
+
+    #[fail(display = "Image data has {} values, but {}x{} pixels with {} samples need {}", actual, width, height, samples, expected)]
+    InvalidImageDimensions { width: u32, height: u32, samples: usize, expected: usize, actual: usize },
+
+    #[fail(display = "Unsupported configuration: {}", reason)]
+    UnsupportedConfiguration { reason: String },
+
+    #[fail(display = "Tag ({}) is written by the encoder and can't be set", tag)]
+    TagConflict { tag: AnyTag },
+
+    // a `SequentialEncoder` was given something it didn't plan for
+    #[fail(display = "Sequential encoding: {}", reason)]
+    SequentialRule { reason: String },
+
+    // reading the image being copied failed; boxed to keep `EncodeError` small
+    #[fail(display = "Decode Error: {}", error)]
+    Decode { error: Box<DecodeError> },
+}
+
+#[derive(Debug)]
+pub struct EncodeError {
+    inner: Context<EncodeErrorKind>,
+}
+
+impl Fail for EncodeError {
+    fn cause(&self) -> Option<&dyn Fail> {
+        self.kind().cause()
+    }
+
+    fn backtrace(&self) -> Option<&Backtrace> {
+        self.inner.backtrace()
     }
 }
 
+impl Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Display::fmt(&self.inner, f)
+    }
+}
+
+impl EncodeError {
+    pub fn kind(&self) -> &EncodeErrorKind {
+        self.inner.get_context()
+    }
+
+    // Whether the writer failed, rather than the encoder refusing what it was given.
+    pub fn is_io_error(&self) -> bool {
+        matches!(*self.kind(), EncodeErrorKind::IO { .. })
+    }
+}
+
+impl From<io::Error> for EncodeError {
+    fn from(err: io::Error) -> EncodeError {
+        EncodeError::from(EncodeErrorKind::IO { error: err })
+    }
+}
+
+impl From<DecodeError> for EncodeError {
+    fn from(err: DecodeError) -> EncodeError {
+        EncodeError::from(EncodeErrorKind::Decode { error: Box::new(err) })
+    }
+}
+
+impl From<EncodeErrorKind> for EncodeError {
+    fn from(kind: EncodeErrorKind) -> EncodeError {
+        EncodeError { inner: Context::new(kind) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    // One of each kind, with its category and message.
+    fn every_kind() -> Vec<(DecodeErrorKind, DecodeErrorCategory, &'static str)> {
+        use self::DecodeErrorCategory::*;
+        use self::DecodeErrorKind::*;
+
+        let io = io::Error::new(io::ErrorKind::UnexpectedEof, "eof");
+        let entry = Entry::new(DataType::Short, 1, [0; 4]);
+        vec![
+            (DecodeErrorKind::IO { error: io }, DecodeErrorCategory::IO, "IO Error: Custom { kind: UnexpectedEof, error: \"eof\" }"),
+            (NoByteOrder, Header, "Incorrect header: No Byte Order"),
+            (NoVersion, Header, "Incorrect header: No Version"),
+            (NoIFDAddress, Header, "Incorrect header: No IFD address"),
+            (NoImage, Header, "No Image address"),
+            (InvalidIFDAddress { offset: 4 }, Header, "Invalid IFD address: 4"),
+            (IFDCycle { offset: 8 }, Header, "IFD chain links back to the IFD at 8"),
+            (TruncatedIFD { offset: 8, declared: 40, read: 10 }, Header, "IFD at 8 declares 40 entries, but the file ends after 10"),
+            (InvalidEntryOffset { tag: AnyTag::Artist, offset: 2, len: 6 }, Header, "Tag (Artist) has 6 bytes of values at offset 2, outside the file or inside the header"),
+            (IFDIndexOutOfRange { index: 3, count: 2 }, Header, "IFD index 3 is out of range (2 IFDs)"),
+            (CannotFindTheTag { tag: AnyTag::ImageWidth }, Tag, "Can't find the tag (ImageWidth)"),
+            (UnsupportedIFDEntry { entry, reason: "why".to_string() }, Tag, "Unsupported IFD Entry ("),
+            (UnsupportedMultipleData { tag: AnyTag::BitsPerSample, data: vec![0] }, Unsupported, "Tag (BitsPerSample) does not support data: ([0])"),
+            (UnsupportedData { tag: AnyTag::Compression, data: 9 }, Unsupported, "Tag (Compression) does not support data: (9)"),
+            (InconsistentSamplesPerPixel { samples_per_pixel: 3, bits_per_sample: vec![8, 8] }, Value, "SamplesPerPixel (3) does not match BitsPerSample ([8, 8])"),
+            (UnsupportedBitsPerSample { values: vec![8, 32] }, Unsupported, "Unsupported sample layout: BitsPerSample [8, 32]"),
+            (UnsupportedSampleFormat { sample_format: 3, bits_per_sample: vec![8] }, Unsupported, "SampleFormat 3 is not supported with BitsPerSample [8]"),
+            (UnsupportedPredictor { predictor: 3, bits_per_sample: vec![8] }, Unsupported, "Predictor 3 is not supported with BitsPerSample [8]"),
+            (IncorrectBufferSize { calc: 4, sum: 3 }, Value, "Calculated from width and height: 4, sum: 3"),
+            (IncompatibleData { photometric_interpretation: PhotometricInterpretation::RGB, bits_per_sample: BitsPerSample::U8_1 }, Value, "Incompatible Data (RGB/U8_1"),
+            (NoData { tag: AnyTag::ImageWidth }, Tag, "Tag (ImageWidth) requires data, but you dont got any data"),
+            (ExtraData { tag: AnyTag::ImageWidth, data: vec![1, 2] }, Tag, "Tag (ImageWidth) requires only one value, but you got extra data: [1, 2]."),
+            (NoSupportDataType { tag: AnyTag::ImageWidth, datatype: DataType::Ascii, count: 2 }, Tag, "Tag (ImageWidth) doesn't support this datatype/count : Ascii/2"),
+            (InvalidAscii { tag: AnyTag::Artist, reason: "missing NUL terminator".to_string() }, Tag, "Tag (Artist) has a malformed ASCII value: missing NUL terminator"),
+            (StripLengthMismatch { strip: 1, expected: 8, actual: 12 }, Value, "Strip 1 decompressed to 12 bytes, but 8 bytes were expected"),
+            (StripCountMismatch { expected: 2, actual: 1 }, Value, "The image needs 2 strips or tiles, but only 1 are given"),
+            (CorruptOffset { what: "strip end" }, Value, "Corrupt file: the strip end overflows"),
+            (InvalidChunkOffset { offset: 100, byte_count: 9 }, Value, "Strip or tile of 9 bytes at offset 100 lies outside the file"),
+            (CodecFailed { compression: 4, reason: "bad code".to_string() }, Value, "Compression 4 failed to decode: bad code"),
+            (BuiltinCodecOverride { compression: 5 }, Value, "A codec was registered for built-in compression 5 without allowing overrides"),
+            (LimitExceeded { limit: "image width", value: 9, max: 8 }, Limit, "image width of 9 exceeds the limit of 8"),
+            (RegionOutOfBounds { x: 1, y: 2, width: 3, height: 4, image_width: 2, image_height: 2 }, Value, "Region 3x4 at (1, 2) lies outside the 2x2 image"),
+            (PaletteWithoutColorMap, Value, "Palette image has no ColorMap"),
+            (PaletteSamplesPerPixel { samples_per_pixel: 2 }, Value, "Palette image requires SamplesPerPixel 1, but got 2"),
+            (PaletteBitsPerSample { bits: 32 }, Value, "Palette image has unsupported BitsPerSample 32"),
+            (PaletteColorMapLength { expected: 48, actual: 47 }, Value, "Palette ColorMap requires 48 values, but got 47"),
+            (UnsupportedColorType { reason: "5 samples".to_string() }, Unsupported, "The image crate has no color type for 5 samples"),
+            (SampleTypeMismatch { requested: "u8", actual: "u16" }, Unsupported, "Requested u8 samples, but the image has u16 samples"),
+            (InvalidColorProfile { reason: "empty".to_string() }, Value, "Cannot convert through the ICC profile: empty"),
+        ]
+    }
+
+    // Fails to compile when a kind is added, until `every_kind` covers it too.
+    fn variant(kind: &DecodeErrorKind) -> &'static str {
+        use self::DecodeErrorKind::*;
+
+        match *kind {
+            IO { .. } => "IO",
+            NoByteOrder => "NoByteOrder",
+            NoVersion => "NoVersion",
+            NoIFDAddress => "NoIFDAddress",
+            NoImage => "NoImage",
+            InvalidIFDAddress { .. } => "InvalidIFDAddress",
+            IFDCycle { .. } => "IFDCycle",
+            TruncatedIFD { .. } => "TruncatedIFD",
+            InvalidEntryOffset { .. } => "InvalidEntryOffset",
+            IFDIndexOutOfRange { .. } => "IFDIndexOutOfRange",
+            CannotFindTheTag { .. } => "CannotFindTheTag",
+            UnsupportedIFDEntry { .. } => "UnsupportedIFDEntry",
+            UnsupportedMultipleData { .. } => "UnsupportedMultipleData",
+            UnsupportedData { .. } => "UnsupportedData",
+            InconsistentSamplesPerPixel { .. } => "InconsistentSamplesPerPixel",
+            UnsupportedBitsPerSample { .. } => "UnsupportedBitsPerSample",
+            UnsupportedSampleFormat { .. } => "UnsupportedSampleFormat",
+            UnsupportedPredictor { .. } => "UnsupportedPredictor",
+            IncorrectBufferSize { .. } => "IncorrectBufferSize",
+            IncompatibleData { .. } => "IncompatibleData",
+            NoData { .. } => "NoData",
+            ExtraData { .. } => "ExtraData",
+            NoSupportDataType { .. } => "NoSupportDataType",
+            InvalidAscii { .. } => "InvalidAscii",
+            StripLengthMismatch { .. } => "StripLengthMismatch",
+            StripCountMismatch { .. } => "StripCountMismatch",
+            CorruptOffset { .. } => "CorruptOffset",
+            InvalidChunkOffset { .. } => "InvalidChunkOffset",
+            CodecFailed { .. } => "CodecFailed",
+            BuiltinCodecOverride { .. } => "BuiltinCodecOverride",
+            LimitExceeded { .. } => "LimitExceeded",
+            RegionOutOfBounds { .. } => "RegionOutOfBounds",
+            PaletteWithoutColorMap => "PaletteWithoutColorMap",
+            PaletteSamplesPerPixel { .. } => "PaletteSamplesPerPixel",
+            PaletteBitsPerSample { .. } => "PaletteBitsPerSample",
+            PaletteColorMapLength { .. } => "PaletteColorMapLength",
+            UnsupportedColorType { .. } => "UnsupportedColorType",
+            SampleTypeMismatch { .. } => "SampleTypeMismatch",
+            InvalidColorProfile { .. } => "InvalidColorProfile",
+        }
+    }
+
+    #[test]
+    fn every_kind_has_a_category_and_message() {
+        let kinds = every_kind();
+        let variants = kinds.iter().map(|(kind, _, _)| variant(kind)).collect::<HashSet<_>>();
+        assert_eq!(variants.len(), kinds.len());
+        assert_eq!(variants.len(), 39);
+
+        for (kind, category, message) in kinds {
+            let name = variant(&kind);
+            let error = DecodeError::from(kind);
+            assert_eq!(error.category(), category, "{}", name);
+            // An entry formats as its raw fields, so only the prefix is pinned.
+            if message.ends_with('(') {
+                assert!(error.to_string().starts_with(message), "{}", name);
+            } else {
+                assert_eq!(error.to_string(), message, "{}", name);
+            }
+        }
+    }
+
+    #[test]
+    fn io_errors_keep_their_cause() {
+        let error = DecodeError::from(io::Error::new(io::ErrorKind::UnexpectedEof, "eof"));
+        assert_eq!(error.category(), DecodeErrorCategory::IO);
+        let cause = error.cause().and_then(|cause| cause.downcast_ref::<io::Error>()).unwrap();
+        assert_eq!(cause.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(DecodeError::from(DecodeErrorKind::NoImage).cause().is_none());
+    }
+
+    #[test]
+    fn position_is_kept_from_the_innermost_context() {
+        let error = DecodeError::from(DecodeErrorKind::NoData { tag: AnyTag::Artist })
+            .with_offset(20)
+            .with_tag(AnyTag::Artist)
+            .with_ifd(8)
+            .with_ifd(100)
+            .with_offset(30);
+        assert_eq!((error.position().ifd(), error.position().tag(), error.position().offset()), (Some(8), Some(AnyTag::Artist), Some(20)));
+        assert_eq!(error.to_string(), "Tag (Artist) requires data, but you dont got any data (IFD at 8, tag Artist, offset 20)");
+    }
+}
@@ -0,0 +1,79 @@
+use ifd::Rational;
+
+// Where and when an image was taken, from its GPS IFD. Anything the file
+// doesn't record, or records with a zero denominator, is `None`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gps {
+    latitude: Option<f64>,
+    longitude: Option<f64>,
+    altitude: Option<f64>,
+    time_stamp: Option<(f64, f64, f64)>,
+    date_stamp: Option<String>,
+}
+
+impl Gps {
+    pub fn new(latitude: Option<f64>, longitude: Option<f64>, altitude: Option<f64>, time_stamp: Option<(f64, f64, f64)>, date_stamp: Option<String>) -> Gps {
+        Gps {
+            latitude,
+            longitude,
+            altitude,
+            time_stamp,
+            date_stamp,
+        }
+    }
+
+    // Degrees, negative south of the equator.
+    pub fn latitude(&self) -> Option<f64> {
+        self.latitude
+    }
+
+    // Degrees, negative west of Greenwich.
+    pub fn longitude(&self) -> Option<f64> {
+        self.longitude
+    }
+
+    // Meters, negative below sea level.
+    pub fn altitude(&self) -> Option<f64> {
+        self.altitude
+    }
+
+    // Hours, minutes and seconds, in UTC.
+    pub fn time_stamp(&self) -> Option<(f64, f64, f64)> {
+        self.time_stamp
+    }
+
+    // "YYYY:MM:DD", in UTC.
+    pub fn date_stamp(&self) -> Option<&str> {
+        self.date_stamp.as_deref()
+    }
+}
+
+// Degrees, minutes and seconds as one signed value. `reference` is the matching
+// GPS*Ref value; "S" and "W" make it negative.
+pub fn degrees(dms: &[Rational], reference: Option<&str>) -> Option<f64> {
+    let (degrees, minutes, seconds) = match *dms {
+        [d, m, s] => (d.to_f64()?, m.to_f64()?, s.to_f64()?),
+        _ => return None,
+    };
+    let value = degrees + minutes / 60. + seconds / 3600.;
+    match reference {
+        Some("S") | Some("W") => Some(-value),
+        _ => Some(value),
+    }
+}
+
+// GPSAltitudeRef 1 means below sea level.
+pub fn altitude(altitude: Rational, reference: Option<&[u8]>) -> Option<f64> {
+    let value = altitude.to_f64()?;
+    match reference {
+        Some([1]) => Some(-value),
+        _ => Some(value),
+    }
+}
+
+pub fn time_stamp(hms: &[Rational]) -> Option<(f64, f64, f64)> {
+    match *hms {
+        [h, m, s] => Some((h.to_f64()?, m.to_f64()?, s.to_f64()?)),
+        _ => None,
+    }
+}
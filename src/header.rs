@@ -0,0 +1,184 @@
+
+use error::{
+    DecodeError,
+    DecodeErrorKind,
+    DecodeResult,
+};
+use byte::{
+    Endian,
+    EndianReadExt,
+    SeekExt,
+};
+use std::io::{
+    Read,
+    Seek,
+};
+
+// The first bytes of a TIFF file: byte order, version (42, or 43 for BigTIFF) and
+// where the first IFD is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FileHeader {
+    endian: Endian,
+    version: u16,
+    first_ifd_offset: u64,
+}
+
+impl FileHeader {
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn is_big_tiff(&self) -> bool {
+        self.version == 43
+    }
+
+    pub fn first_ifd_offset(&self) -> u64 {
+        self.first_ifd_offset
+    }
+
+    // The header of a file whose first IFD was moved to `offset`.
+    pub fn with_first_ifd_offset(mut self, offset: u64) -> FileHeader {
+        self.first_ifd_offset = offset;
+        self
+    }
+
+    // Size of the header itself; IFD and value offsets must point past it.
+    pub fn len(&self) -> u64 {
+        if self.is_big_tiff() { 16 } else { 8 }
+    }
+
+    // Never, every header has its byte order, magic number and first IFD offset.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+}
+
+// Reads the header from the current position.
+pub fn read_file_header<R: Read>(mut reader: R) -> DecodeResult<FileHeader> {
+    let mut byte_order = [0u8; 2];
+    if reader.read_exact(&mut byte_order).is_err() {
+        return Err(DecodeError::from(DecodeErrorKind::NoByteOrder));
+    }
+    let endian = match &byte_order {
+        b"II" => Endian::Little,
+        b"MM" => Endian::Big,
+        _ => return Err(DecodeError::from(DecodeErrorKind::NoByteOrder)),
+    };
+
+    let version = match reader.read_u16(endian) {
+        Ok(x @ 42) | Ok(x @ 43) => x,
+        _ => return Err(DecodeError::from(DecodeErrorKind::NoVersion)),
+    };
+    let first_ifd_offset = if version == 43 {
+        // BigTIFF: offset size (always 8), a reserved zero, then a 64-bit offset
+        match (reader.read_u16(endian), reader.read_u16(endian)) {
+            (Ok(8), Ok(0)) => {},
+            _ => return Err(DecodeError::from(DecodeErrorKind::NoVersion)),
+        }
+        reader.read_u64(endian)
+    } else {
+        reader.read_u32(endian).map(|x| x as u64)
+    };
+    let first_ifd_offset = match first_ifd_offset {
+        Ok(x) => x,
+        Err(_) => return Err(DecodeError::from(DecodeErrorKind::NoIFDAddress)),
+    };
+
+    let header = FileHeader {
+        endian,
+        version,
+        first_ifd_offset,
+    };
+    // 0 would mean no image at all, and anything smaller points into the header
+    if first_ifd_offset < header.len() {
+        return Err(DecodeError::from(DecodeErrorKind::InvalidIFDAddress { offset: first_ifd_offset }));
+    }
+
+    Ok(header)
+}
+
+// Reads only the header, for telling TIFF apart from other formats cheaply. The
+// reader is left where it was, whether or not it holds a TIFF.
+pub fn sniff<R: Read + Seek>(mut reader: R) -> DecodeResult<FileHeader> {
+    let start = reader.stream_position()?;
+    let header = read_file_header(&mut reader);
+    reader.goto(start)?;
+
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decode::Decoder;
+    use std::io::Cursor;
+    use testutil::{
+        Page,
+        TiffBuilder,
+    };
+
+    // Kinds aren't comparable, their messages are.
+    fn sniffed_error(bytes: &[u8]) -> String {
+        sniff(Cursor::new(bytes)).unwrap_err().to_string()
+    }
+
+    fn message(kind: DecodeErrorKind) -> String {
+        DecodeError::from(kind).to_string()
+    }
+
+    #[test]
+    fn both_byte_orders_and_versions() {
+        let cases: &[(&[u8], Endian, u16, u64)] = &[
+            (b"II\x2a\x00\x08\x00\x00\x00", Endian::Little, 42, 8),
+            (b"MM\x00\x2a\x00\x00\x01\x00", Endian::Big, 42, 256),
+            (b"II\x2b\x00\x08\x00\x00\x00\x10\x00\x00\x00\x00\x00\x00\x00", Endian::Little, 43, 16),
+            (b"MM\x00\x2b\x00\x08\x00\x00\x00\x00\x00\x01\x00\x00\x00\x00", Endian::Big, 43, 1 << 32),
+        ];
+        for &(bytes, endian, version, offset) in cases {
+            let header = sniff(Cursor::new(bytes)).unwrap();
+            assert_eq!(header.endian(), endian);
+            assert_eq!(header.version(), version);
+            assert_eq!(header.is_big_tiff(), version == 43);
+            assert_eq!(header.first_ifd_offset(), offset);
+            assert_eq!(header.len(), bytes.len() as u64);
+        }
+    }
+
+    #[test]
+    fn malformed_headers() {
+        assert_eq!(sniffed_error(b""), message(DecodeErrorKind::NoByteOrder));
+        assert_eq!(sniffed_error(b"IM\x2a\x00\x08\x00\x00\x00"), message(DecodeErrorKind::NoByteOrder));
+        assert_eq!(sniffed_error(b"II\x00\x2a\x08\x00\x00\x00"), message(DecodeErrorKind::NoVersion));
+        assert_eq!(sniffed_error(b"II\x2a"), message(DecodeErrorKind::NoVersion));
+        assert_eq!(sniffed_error(b"II\x2b\x00\x04\x00\x00\x00\x10\x00\x00\x00"), message(DecodeErrorKind::NoVersion));
+        assert_eq!(sniffed_error(b"II\x2a\x00\x08\x00"), message(DecodeErrorKind::NoIFDAddress));
+        assert_eq!(sniffed_error(b"II\x2b\x00\x08\x00\x00\x00\x10\x00\x00\x00"), message(DecodeErrorKind::NoIFDAddress));
+        assert_eq!(sniffed_error(b"II\x2a\x00\x00\x00\x00\x00"), message(DecodeErrorKind::InvalidIFDAddress { offset: 0 }));
+        assert_eq!(sniffed_error(b"II\x2b\x00\x08\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00\x00"), message(DecodeErrorKind::InvalidIFDAddress { offset: 8 }));
+    }
+
+    #[test]
+    fn sniff_leaves_the_reader_where_it_was() {
+        for bytes in &[&b"...II\x2a\x00\x08\x00\x00\x00"[..], &b"...GIF89a"[..]] {
+            let mut reader = Cursor::new(bytes);
+            reader.set_position(3);
+            let _ = sniff(&mut reader);
+            assert_eq!(reader.position(), 3);
+        }
+    }
+
+    #[test]
+    fn decoder_reports_the_sniffed_header() {
+        for &endian in &[Endian::Little, Endian::Big] {
+            let file = TiffBuilder::new(endian).big_tiff().page(Page::gray8(1, 1, vec![0])).build();
+            let header = sniff(Cursor::new(&file)).unwrap();
+            let decoder = Decoder::new(Cursor::new(file)).unwrap();
+            assert_eq!(decoder.file_header(), header);
+            assert_eq!(decoder.endian(), endian);
+        }
+    }
+}
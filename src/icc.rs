@@ -0,0 +1,62 @@
+use moxcms::{
+    CmsError,
+    ColorProfile,
+    Layout,
+    TransformExecutor,
+    TransformOptions,
+};
+
+use error::{
+    DecodeError,
+    DecodeErrorKind,
+    DecodeResult,
+};
+use image::ImageData;
+
+// CMYK `data`, pixels of `samples` samples with the four inks first, converted
+// to sRGB through the ICC profile `profile`. `None` for samples other than
+// 8-bit, 16-bit and `f32`.
+pub fn cmyk_to_rgb(data: &ImageData, samples: usize, profile: &[u8]) -> DecodeResult<Option<ImageData>> {
+    let source = ColorProfile::new_from_slice(profile).map_err(profile_error)?;
+    let srgb = ColorProfile::new_srgb();
+    let options = TransformOptions::default();
+    let data = match *data {
+        ImageData::U8(ref data) => {
+            let transform = source.create_transform_8bit(Layout::Rgba, &srgb, Layout::Rgb, options).map_err(profile_error)?;
+            ImageData::U8(transform_inks(&*transform, data, samples)?)
+        }
+        ImageData::U16(ref data) => {
+            let transform = source.create_transform_16bit(Layout::Rgba, &srgb, Layout::Rgb, options).map_err(profile_error)?;
+            ImageData::U16(transform_inks(&*transform, data, samples)?)
+        }
+        ImageData::F32(ref data) => {
+            let transform = source.create_transform_f32(Layout::Rgba, &srgb, Layout::Rgb, options).map_err(profile_error)?;
+            ImageData::F32(transform_inks(&*transform, data, samples)?)
+        }
+        _ => return Ok(None),
+    };
+
+    Ok(Some(data))
+}
+
+// Transforms the four inks at the start of each pixel, keeping the samples after
+// them.
+fn transform_inks<T: Copy + Default>(transform: &(dyn TransformExecutor<T> + Send + Sync), data: &[T], samples: usize) -> DecodeResult<Vec<T>> {
+    let inks = data.chunks(samples).flat_map(|x| x[..4].iter().cloned()).collect::<Vec<_>>();
+    let mut rgb = vec![T::default(); inks.len() / 4 * 3];
+    transform.transform(&inks, &mut rgb).map_err(profile_error)?;
+    if samples == 4 {
+        return Ok(rgb);
+    }
+
+    let mut pixels = Vec::with_capacity(data.len() / samples * (samples - 1));
+    for (pixel, color) in data.chunks(samples).zip(rgb.chunks(3)) {
+        pixels.extend_from_slice(color);
+        pixels.extend_from_slice(&pixel[4..]);
+    }
+    Ok(pixels)
+}
+
+fn profile_error(error: CmsError) -> DecodeError {
+    DecodeError::from(DecodeErrorKind::InvalidColorProfile { reason: error.to_string() })
+}
@@ -1,20 +1,42 @@
+// failure's derive puts its impls inside a const item next to the type.
+#![allow(non_local_definitions)]
 
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::collections::btree_map;
 use std::fmt::{
     self,
     Display,
 };
+use std::io;
 use tag::{
     TagType,
     AnyTag,
+    IntoAnyValues,
+};
+use byte::{
+    Endian,
+    EndianReadExt,
 };
 
 #[derive(Debug, Clone, Copy)]
 pub enum DataType {
     Byte,
+    Ascii,
     Short,
     Long,
     Rational,
+    SByte,
+    Undefined,
+    SShort,
+    SLong,
+    SRational,
+    Float,
+    Double,
+    IFD,
+    // BigTIFF only
+    Long8,
+    SLong8,
+    IFD8,
     Unknown(u16),
 }
 
@@ -22,41 +44,426 @@ impl From<u16> for DataType {
     fn from(n: u16) -> DataType {
         match n {
             1 => DataType::Byte,
+            2 => DataType::Ascii,
             3 => DataType::Short,
             4 => DataType::Long,
             5 => DataType::Rational,
+            6 => DataType::SByte,
+            7 => DataType::Undefined,
+            8 => DataType::SShort,
+            9 => DataType::SLong,
+            10 => DataType::SRational,
+            11 => DataType::Float,
+            12 => DataType::Double,
+            13 => DataType::IFD,
+            16 => DataType::Long8,
+            17 => DataType::SLong8,
+            18 => DataType::IFD8,
             n => DataType::Unknown(n),
         }
     }
 }
 
+impl DataType {
+    pub fn value(&self) -> u16 {
+        match *self {
+            DataType::Byte => 1,
+            DataType::Ascii => 2,
+            DataType::Short => 3,
+            DataType::Long => 4,
+            DataType::Rational => 5,
+            DataType::SByte => 6,
+            DataType::Undefined => 7,
+            DataType::SShort => 8,
+            DataType::SLong => 9,
+            DataType::SRational => 10,
+            DataType::Float => 11,
+            DataType::Double => 12,
+            DataType::IFD => 13,
+            DataType::Long8 => 16,
+            DataType::SLong8 => 17,
+            DataType::IFD8 => 18,
+            DataType::Unknown(n) => n,
+        }
+    }
+
+    // Size of a single value. Unknown types have no size we could rely on, so their
+    // values are never treated as stored out of line.
+    pub fn size_in_bytes(&self) -> usize {
+        match *self {
+            DataType::Byte | DataType::Ascii | DataType::SByte | DataType::Undefined => 1,
+            DataType::Short | DataType::SShort => 2,
+            DataType::Long | DataType::SLong | DataType::Float | DataType::IFD => 4,
+            DataType::Rational | DataType::SRational | DataType::Double | DataType::Long8 | DataType::SLong8 | DataType::IFD8 => 8,
+            DataType::Unknown(_) => 0,
+        }
+    }
+
+    // For tags the spec allows as either SHORT or LONG. The choice covers every value
+    // of the tag at once, so a single large value makes the whole array LONG.
+    pub fn short_or_long(values: &[u32]) -> DataType {
+        if values.iter().all(|x| *x <= u16::MAX as u32) {
+            DataType::Short
+        } else {
+            DataType::Long
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rational {
+    pub numerator: u32,
+    pub denominator: u32,
+}
+
+impl Rational {
+    pub fn new(numerator: u32, denominator: u32) -> Rational {
+        Rational {
+            numerator,
+            denominator,
+        }
+    }
+
+    // The closest rational with a power-of-ten denominator up to 10000, `None`
+    // for negative values or ones too large for a u32 numerator.
+    pub fn from_f64(x: f64) -> Option<Rational> {
+        if !(0. ..=u32::MAX as f64).contains(&x) {
+            return None;
+        }
+        let mut denominator = 1u32;
+        while denominator < 10000 && (x * denominator as f64).fract().abs() > 1e-9 && x * (denominator * 10) as f64 <= u32::MAX as f64 {
+            denominator *= 10;
+        }
+
+        Some(Rational::new((x * denominator as f64).round() as u32, denominator))
+    }
+
+    // `None` for a zero denominator rather than an infinity or NaN.
+    pub fn to_f64(&self) -> Option<f64> {
+        match self.denominator {
+            0 => None,
+            d => Some(self.numerator as f64 / d as f64),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SRational {
+    pub numerator: i32,
+    pub denominator: i32,
+}
+
+impl SRational {
+    pub fn new(numerator: i32, denominator: i32) -> SRational {
+        SRational {
+            numerator,
+            denominator,
+        }
+    }
+
+    // `None` for a zero denominator rather than an infinity or NaN.
+    pub fn to_f64(&self) -> Option<f64> {
+        match self.denominator {
+            0 => None,
+            d => Some(self.numerator as f64 / d as f64),
+        }
+    }
+}
+
+// The values of an entry read as its own datatype, whatever tag it belongs to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnyValues {
+    Byte(Vec<u8>),
+    Ascii(Vec<u8>),
+    Undefined(Vec<u8>),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    Rational(Vec<Rational>),
+    SByte(Vec<i8>),
+    SShort(Vec<i16>),
+    SLong(Vec<i32>),
+    SRational(Vec<SRational>),
+    Float(Vec<f32>),
+    Double(Vec<f64>),
+    IFD(Vec<u32>),
+    Long8(Vec<u64>),
+    SLong8(Vec<i64>),
+    IFD8(Vec<u64>),
+    // the raw offset field of an entry whose datatype is unknown
+    Unknown(Vec<u8>),
+}
+
+impl AnyValues {
+    pub fn len(&self) -> usize {
+        match *self {
+            AnyValues::Byte(ref v) | AnyValues::Ascii(ref v) | AnyValues::Undefined(ref v) | AnyValues::Unknown(ref v) => v.len(),
+            AnyValues::Short(ref v) => v.len(),
+            AnyValues::Long(ref v) | AnyValues::IFD(ref v) => v.len(),
+            AnyValues::Rational(ref v) => v.len(),
+            AnyValues::SByte(ref v) => v.len(),
+            AnyValues::SShort(ref v) => v.len(),
+            AnyValues::SLong(ref v) => v.len(),
+            AnyValues::SRational(ref v) => v.len(),
+            AnyValues::Float(ref v) => v.len(),
+            AnyValues::Double(ref v) => v.len(),
+            AnyValues::Long8(ref v) | AnyValues::IFD8(ref v) => v.len(),
+            AnyValues::SLong8(ref v) => v.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The first `n` values, or all of them if there are fewer.
+    pub fn head(&self, n: usize) -> AnyValues {
+        fn head<T: Clone>(v: &[T], n: usize) -> Vec<T> {
+            v[..n.min(v.len())].to_vec()
+        }
+
+        match *self {
+            AnyValues::Byte(ref v) => AnyValues::Byte(head(v, n)),
+            AnyValues::Ascii(ref v) => AnyValues::Ascii(head(v, n)),
+            AnyValues::Undefined(ref v) => AnyValues::Undefined(head(v, n)),
+            AnyValues::Short(ref v) => AnyValues::Short(head(v, n)),
+            AnyValues::Long(ref v) => AnyValues::Long(head(v, n)),
+            AnyValues::Rational(ref v) => AnyValues::Rational(head(v, n)),
+            AnyValues::SByte(ref v) => AnyValues::SByte(head(v, n)),
+            AnyValues::SShort(ref v) => AnyValues::SShort(head(v, n)),
+            AnyValues::SLong(ref v) => AnyValues::SLong(head(v, n)),
+            AnyValues::SRational(ref v) => AnyValues::SRational(head(v, n)),
+            AnyValues::Float(ref v) => AnyValues::Float(head(v, n)),
+            AnyValues::Double(ref v) => AnyValues::Double(head(v, n)),
+            AnyValues::IFD(ref v) => AnyValues::IFD(head(v, n)),
+            AnyValues::Long8(ref v) => AnyValues::Long8(head(v, n)),
+            AnyValues::SLong8(ref v) => AnyValues::SLong8(head(v, n)),
+            AnyValues::IFD8(ref v) => AnyValues::IFD8(head(v, n)),
+            AnyValues::Unknown(ref v) => AnyValues::Unknown(head(v, n)),
+        }
+    }
+
+    // The datatype the values are written as. Unknown values, whose datatype
+    // wasn't kept, are written as UNDEFINED bytes.
+    pub fn datatype(&self) -> DataType {
+        match *self {
+            AnyValues::Byte(_) => DataType::Byte,
+            AnyValues::Ascii(_) => DataType::Ascii,
+            AnyValues::Undefined(_) | AnyValues::Unknown(_) => DataType::Undefined,
+            AnyValues::Short(_) => DataType::Short,
+            AnyValues::Long(_) => DataType::Long,
+            AnyValues::Rational(_) => DataType::Rational,
+            AnyValues::SByte(_) => DataType::SByte,
+            AnyValues::SShort(_) => DataType::SShort,
+            AnyValues::SLong(_) => DataType::SLong,
+            AnyValues::SRational(_) => DataType::SRational,
+            AnyValues::Float(_) => DataType::Float,
+            AnyValues::Double(_) => DataType::Double,
+            AnyValues::IFD(_) => DataType::IFD,
+            AnyValues::Long8(_) => DataType::Long8,
+            AnyValues::SLong8(_) => DataType::SLong8,
+            AnyValues::IFD8(_) => DataType::IFD8,
+        }
+    }
+
+    // The values as they are stored in a file in `endian` byte order.
+    pub fn to_bytes(&self, endian: Endian) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len() * self.datatype().size_in_bytes());
+        match *self {
+            AnyValues::Byte(ref v) | AnyValues::Ascii(ref v) | AnyValues::Undefined(ref v) | AnyValues::Unknown(ref v) => bytes.extend_from_slice(v),
+            AnyValues::Short(ref v) => for x in v {
+                bytes.extend_from_slice(&endian_bytes(*x as u64, 2, endian));
+            },
+            AnyValues::Long(ref v) | AnyValues::IFD(ref v) => for x in v {
+                bytes.extend_from_slice(&endian_bytes(*x as u64, 4, endian));
+            },
+            AnyValues::Rational(ref v) => for x in v {
+                bytes.extend_from_slice(&endian_bytes(x.numerator as u64, 4, endian));
+                bytes.extend_from_slice(&endian_bytes(x.denominator as u64, 4, endian));
+            },
+            AnyValues::SByte(ref v) => bytes.extend(v.iter().map(|&x| x as u8)),
+            AnyValues::SShort(ref v) => for x in v {
+                bytes.extend_from_slice(&endian_bytes(*x as u16 as u64, 2, endian));
+            },
+            AnyValues::SLong(ref v) => for x in v {
+                bytes.extend_from_slice(&endian_bytes(*x as u32 as u64, 4, endian));
+            },
+            AnyValues::SRational(ref v) => for x in v {
+                bytes.extend_from_slice(&endian_bytes(x.numerator as u32 as u64, 4, endian));
+                bytes.extend_from_slice(&endian_bytes(x.denominator as u32 as u64, 4, endian));
+            },
+            AnyValues::Float(ref v) => for x in v {
+                bytes.extend_from_slice(&endian_bytes(x.to_bits() as u64, 4, endian));
+            },
+            AnyValues::Double(ref v) => for x in v {
+                bytes.extend_from_slice(&endian_bytes(x.to_bits(), 8, endian));
+            },
+            AnyValues::Long8(ref v) | AnyValues::IFD8(ref v) => for x in v {
+                bytes.extend_from_slice(&endian_bytes(*x, 8, endian));
+            },
+            AnyValues::SLong8(ref v) => for x in v {
+                bytes.extend_from_slice(&endian_bytes(*x as u64, 8, endian));
+            },
+        }
+
+        bytes
+    }
+
+    // The values as offsets, for entries that link to other IFDs.
+    pub fn offsets(&self) -> Vec<u64> {
+        match *self {
+            AnyValues::Long(ref v) | AnyValues::IFD(ref v) => v.iter().map(|&x| x as u64).collect(),
+            AnyValues::Long8(ref v) | AnyValues::IFD8(ref v) => v.clone(),
+            _ => vec![],
+        }
+    }
+}
+
+impl Display for AnyValues {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn list<T: Display>(f: &mut fmt::Formatter, values: &[T]) -> fmt::Result {
+            for (i, x) in values.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{}", x)?;
+            }
+            Ok(())
+        }
+
+        match *self {
+            AnyValues::Ascii(ref v) => {
+                let end = v.iter().position(|x| *x == 0).unwrap_or(v.len());
+                write!(f, "{:?}", String::from_utf8_lossy(&v[..end]))
+            }
+            AnyValues::Byte(ref v) | AnyValues::Undefined(ref v) | AnyValues::Unknown(ref v) => list(f, v),
+            AnyValues::Short(ref v) => list(f, v),
+            AnyValues::Long(ref v) | AnyValues::IFD(ref v) => list(f, v),
+            AnyValues::Rational(ref v) => list(f, v),
+            AnyValues::SByte(ref v) => list(f, v),
+            AnyValues::SShort(ref v) => list(f, v),
+            AnyValues::SLong(ref v) => list(f, v),
+            AnyValues::SRational(ref v) => list(f, v),
+            AnyValues::Float(ref v) => list(f, v),
+            AnyValues::Double(ref v) => list(f, v),
+            AnyValues::Long8(ref v) | AnyValues::IFD8(ref v) => list(f, v),
+            AnyValues::SLong8(ref v) => list(f, v),
+        }
+    }
+}
+
+// `value` as the values of `tag` are written.
+pub fn tag_values<T: TagType>(tag: T, value: T::Value) -> AnyValues where T::Value: IntoAnyValues {
+    match (AnyTag::from(tag), value.into_any_values()) {
+        // the spec types these as UNDEFINED rather than BYTE
+        (AnyTag::JPEGTables, AnyValues::Byte(v)) | (AnyTag::ICCProfile, AnyValues::Byte(v)) => AnyValues::Undefined(v),
+        (_, values) => values,
+    }
+}
+
+// The low `size` bytes of `n` in `endian` byte order.
+pub fn endian_bytes(n: u64, size: usize, endian: Endian) -> Vec<u8> {
+    match endian {
+        Endian::Big => n.to_be_bytes()[8 - size..].to_vec(),
+        Endian::Little => n.to_le_bytes()[..size].to_vec(),
+    }
+}
+
+impl Display for Rational {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.numerator, self.denominator)
+    }
+}
+
+impl Display for SRational {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.numerator, self.denominator)
+    }
+}
+
+// `offset` is the raw value field: 4 bytes in TIFF, 8 in BigTIFF. Values that fit
+// are stored in it directly, otherwise it holds their offset. Entries made with
+// `from_values` hold their values instead, until the IFD is written.
 #[derive(Debug, Clone, Fail)]
 pub struct Entry {
     datatype: DataType,
-    count: u32,
-    offset: [u8; 4],
+    count: u64,
+    offset: Vec<u8>,
+    // boxed to keep `Entry`, and the errors that hold one, small
+    values: Option<Box<AnyValues>>,
 }
 
 impl Entry {
     pub fn new(datatype: DataType, count: u32, offset: [u8; 4]) -> Entry {
         Entry {
-            datatype: datatype,
-            count: count,
-            offset: offset,
+            datatype,
+            count: count as u64,
+            offset: offset.to_vec(),
+            values: None,
         }
     }
 
+    pub fn new_big(datatype: DataType, count: u64, offset: [u8; 8]) -> Entry {
+        Entry {
+            datatype,
+            count,
+            offset: offset.to_vec(),
+            values: None,
+        }
+    }
+
+    // An entry for values that aren't in a file yet. Its value field is empty.
+    pub fn from_values(values: AnyValues) -> Entry {
+        Entry {
+            datatype: values.datatype(),
+            count: values.len() as u64,
+            offset: vec![],
+            values: Some(Box::new(values)),
+        }
+    }
+
+    // The values of an entry made with `from_values`.
+    pub fn values(&self) -> Option<&AnyValues> {
+        self.values.as_deref()
+    }
+
     pub fn datatype(&self) -> DataType {
         self.datatype
     }
 
-    pub fn count(&self) -> u32 {
+    pub fn count(&self) -> u64 {
         self.count
     }
 
     pub fn offset(&self) -> &[u8] {
         &self.offset
     }
+
+    // Where the values are, for an entry whose values don't fit in `offset`.
+    pub fn value_offset(&self, endian: Endian) -> io::Result<u64> {
+        read_offset(&self.offset, endian)
+    }
+
+    // Total size of the values, `None` if it doesn't fit in a usize.
+    pub fn byte_len(&self) -> Option<usize> {
+        if self.count > usize::MAX as u64 {
+            return None;
+        }
+        (self.count as usize).checked_mul(self.datatype.size_in_bytes())
+    }
+
+    // Whether the values live elsewhere in the file and `offset` points at them.
+    pub fn overflow(&self) -> bool {
+        self.byte_len().map(|len| len > self.offset.len()).unwrap_or(true)
+    }
+}
+
+// Reads an offset field, 4 or 8 bytes wide depending on the file.
+pub fn read_offset(mut field: &[u8], endian: Endian) -> io::Result<u64> {
+    if field.len() >= 8 {
+        field.read_u64(endian)
+    } else {
+        field.read_u32(endian).map(|x| x as u64)
+    }
 }
 
 impl Display for Entry {
@@ -65,12 +472,14 @@ impl Display for Entry {
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct IFD(HashMap<u16, Entry>);
+// Entries are kept ordered by tag id, which is the order the spec requires them
+// to be written in.
+#[derive(Debug, Clone, Default)]
+pub struct IFD(BTreeMap<u16, Entry>);
 
 impl IFD {
     pub fn new() -> IFD {
-        IFD(HashMap::new())
+        IFD(BTreeMap::new())
     }
 
     pub fn insert<T: TagType>(&mut self, k: T, v: Entry) -> Option<Entry> {
@@ -80,10 +489,95 @@ impl IFD {
     pub fn insert_anytag(&mut self, k: AnyTag, v: Entry) -> Option<Entry> {
         self.0.insert(k.id(), v)
     }
+
+    // Sets `tag` to `value`, replacing any entry it had. The datatype is the
+    // narrowest the tag allows for the value, see `IntoAnyValues`.
+    pub fn put_value<T: TagType>(&mut self, tag: T, value: T::Value) -> Option<Entry> where T::Value: IntoAnyValues {
+        self.put_any(AnyTag::from(tag), tag_values(tag, value))
+    }
+
+    // Sets `tag` to `values` of whatever datatype they are.
+    pub fn put_any(&mut self, tag: AnyTag, values: AnyValues) -> Option<Entry> {
+        self.insert_anytag(tag, Entry::from_values(values))
+    }
+
+    pub fn remove<T: TagType>(&mut self, k: T) -> Option<Entry> {
+        self.0.remove(&k.id())
+    }
+
+    pub fn remove_anytag(&mut self, k: AnyTag) -> Option<Entry> {
+        self.0.remove(&k.id())
+    }
     
     #[inline]
     pub fn get<T: TagType>(&self, k: T) -> Option<&Entry> {
         self.0.get(&k.id())
     }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter<'a>(&'a self) -> Iter<'a> {
+        Iter(self.0.iter())
+    }
+}
+
+pub struct Iter<'a>(btree_map::Iter<'a, u16, Entry>);
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (AnyTag, &'a Entry);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(id, entry)| (AnyTag::from(*id), entry))
+    }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every datatype with the size of one value
+    const SIZES: &[(u16, usize)] = &[
+        (1, 1), (2, 1), (3, 2), (4, 4), (5, 8), (6, 1), (7, 1), (8, 2), (9, 4),
+        (10, 8), (11, 4), (12, 8), (13, 4), (16, 8), (17, 8), (18, 8), (99, 0),
+    ];
+
+    #[test]
+    fn size_in_bytes() {
+        for &(datatype, size) in SIZES {
+            let datatype = DataType::from(datatype);
+            assert_eq!(datatype.size_in_bytes(), size, "{:?}", datatype);
+        }
+    }
+
+    #[test]
+    fn overflow() {
+        for &(datatype, size) in SIZES {
+            for &count in &[0, 1, 2, u32::MAX] {
+                let entry = Entry::new(DataType::from(datatype), count, [0; 4]);
+                let expected = count as usize * size > 4;
+                assert_eq!(entry.overflow(), expected, "classic {} x {}", datatype, count);
+                assert_eq!(entry.byte_len(), Some(count as usize * size));
+            }
+            for &count in &[0, 1, 2, u64::MAX] {
+                let entry = Entry::new_big(DataType::from(datatype), count, [0; 8]);
+                match (count as usize).checked_mul(size) {
+                    Some(len) => {
+                        assert_eq!(entry.overflow(), len > 8, "BigTIFF {} x {}", datatype, count);
+                        assert_eq!(entry.byte_len(), Some(len));
+                    }
+                    // too large to address: never read in place
+                    None => {
+                        assert!(entry.overflow(), "BigTIFF {} x {}", datatype, count);
+                        assert_eq!(entry.byte_len(), None);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,20 +1,32 @@
+// failure's derive puts its impls inside a const item next to the type.
+#![allow(non_local_definitions)]
 
 use error::{
     DecodeError,
     DecodeErrorKind,
 };
+#[cfg(feature = "icc")]
+use error::DecodeResult;
+#[cfg(feature = "icc")]
+use icc;
 use tag::AnyTag;
+use ifd::Rational;
+use std::collections::HashMap;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PhotometricInterpretation {
     WhiteIsZero,
     BlackIsZero,
     RGB,
+    // a single sample per pixel indexing into ColorMap
     Palette,
     TransparencyMask,
     CMYK,
     YCbCr,
     CIELab,
+    // A value this crate doesn't know. Only a lenient decoder returns it, with the
+    // samples left as they are stored.
+    Unknown(u16),
 }
 
 impl PhotometricInterpretation {
@@ -29,26 +41,353 @@ impl PhotometricInterpretation {
             4 => Ok(TransparencyMask),
             5 => Ok(CMYK),
             6 => Ok(YCbCr),
-            7 => Ok(CIELab),
+            8 => Ok(CIELab),
             n => Err(DecodeError::from(DecodeErrorKind::UnsupportedData{ tag: AnyTag::PhotometricInterpretation, data: n as u32 })),
         }
     }
+
+    pub fn value(&self) -> u16 {
+        use self::PhotometricInterpretation::*;
+
+        match *self {
+            WhiteIsZero => 0,
+            BlackIsZero => 1,
+            RGB => 2,
+            Palette => 3,
+            TransparencyMask => 4,
+            CMYK => 5,
+            YCbCr => 6,
+            CIELab => 8,
+            Unknown(n) => n,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Compression {
     No,
+    CCITTRLE,
+    CCITTFax3,
+    CCITTFax4,
     LZW,
+    PackBits,
+    Deflate,
+    OldDeflate,
+    JPEG,
+    #[cfg(feature = "zstd")]
+    Zstd,
+    // A compression the crate doesn't decode itself, handled by a registered codec.
+    Unknown(u16),
 }
 
 impl Compression {
     pub fn from_u16(n: u16) -> Result<Compression, DecodeError> {
         match n {
             1 => Ok(Compression::No),
+            2 => Ok(Compression::CCITTRLE),
+            3 => Ok(Compression::CCITTFax3),
+            4 => Ok(Compression::CCITTFax4),
             5 => Ok(Compression::LZW),
+            7 => Ok(Compression::JPEG),
+            8 => Ok(Compression::Deflate),
+            32773 => Ok(Compression::PackBits),
+            32946 => Ok(Compression::OldDeflate),
+            #[cfg(feature = "zstd")]
+            50000 => Ok(Compression::Zstd),
             n => Err(DecodeError::from(DecodeErrorKind::UnsupportedData{ tag: AnyTag::Compression, data: n as u32 })),
         }
     }
+
+    pub fn value(&self) -> u16 {
+        match *self {
+            Compression::No => 1,
+            Compression::CCITTRLE => 2,
+            Compression::CCITTFax3 => 3,
+            Compression::CCITTFax4 => 4,
+            Compression::LZW => 5,
+            Compression::PackBits => 32773,
+            Compression::Deflate => 8,
+            Compression::OldDeflate => 32946,
+            Compression::JPEG => 7,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => 50000,
+            Compression::Unknown(n) => n,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Predictor {
+    No,
+    Horizontal,
+    FloatingPoint,
+}
+
+impl Predictor {
+    pub fn from_u16(n: u16) -> Result<Predictor, DecodeError> {
+        match n {
+            1 => Ok(Predictor::No),
+            2 => Ok(Predictor::Horizontal),
+            3 => Ok(Predictor::FloatingPoint),
+            n => Err(DecodeError::from(DecodeErrorKind::UnsupportedData{ tag: AnyTag::Predictor, data: n as u32 })),
+        }
+    }
+
+    pub fn value(&self) -> u16 {
+        match *self {
+            Predictor::No => 1,
+            Predictor::Horizontal => 2,
+            Predictor::FloatingPoint => 3,
+        }
+    }
+}
+
+// Whether the samples of a pixel are stored together or each in its own strips.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlanarConfiguration {
+    Chunky,
+    Planar,
+}
+
+impl PlanarConfiguration {
+    pub fn from_u16(n: u16) -> Result<PlanarConfiguration, DecodeError> {
+        match n {
+            1 => Ok(PlanarConfiguration::Chunky),
+            2 => Ok(PlanarConfiguration::Planar),
+            n => Err(DecodeError::from(DecodeErrorKind::UnsupportedData{ tag: AnyTag::PlanarConfiguration, data: n as u32 })),
+        }
+    }
+
+    pub fn value(&self) -> u16 {
+        match *self {
+            PlanarConfiguration::Chunky => 1,
+            PlanarConfiguration::Planar => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FillOrder {
+    HighToLow,
+    LowToHigh,
+}
+
+impl FillOrder {
+    pub fn from_u16(n: u16) -> Result<FillOrder, DecodeError> {
+        match n {
+            1 => Ok(FillOrder::HighToLow),
+            2 => Ok(FillOrder::LowToHigh),
+            n => Err(DecodeError::from(DecodeErrorKind::UnsupportedData{ tag: AnyTag::FillOrder, data: n as u32 })),
+        }
+    }
+}
+
+// Where the first row and the first column of the stored image belong when it is
+// displayed: `RightTop` is the first row on the right, the first column at the
+// top, as a camera held upright writes a portrait photo.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Orientation {
+    TopLeft,
+    TopRight,
+    BottomRight,
+    BottomLeft,
+    LeftTop,
+    RightTop,
+    RightBottom,
+    LeftBottom,
+}
+
+impl Orientation {
+    pub fn from_u16(n: u16) -> Result<Orientation, DecodeError> {
+        match n {
+            1 => Ok(Orientation::TopLeft),
+            2 => Ok(Orientation::TopRight),
+            3 => Ok(Orientation::BottomRight),
+            4 => Ok(Orientation::BottomLeft),
+            5 => Ok(Orientation::LeftTop),
+            6 => Ok(Orientation::RightTop),
+            7 => Ok(Orientation::RightBottom),
+            8 => Ok(Orientation::LeftBottom),
+            n => Err(DecodeError::from(DecodeErrorKind::UnsupportedData{ tag: AnyTag::Orientation, data: n as u32 })),
+        }
+    }
+
+    pub fn value(&self) -> u16 {
+        match *self {
+            Orientation::TopLeft => 1,
+            Orientation::TopRight => 2,
+            Orientation::BottomRight => 3,
+            Orientation::BottomLeft => 4,
+            Orientation::LeftTop => 5,
+            Orientation::RightTop => 6,
+            Orientation::RightBottom => 7,
+            Orientation::LeftBottom => 8,
+        }
+    }
+
+    // Whether displaying the image swaps its width and height.
+    pub fn is_transposed(&self) -> bool {
+        self.value() >= 5
+    }
+}
+
+// What an IFD holds, from NewSubfileType or the SubfileType it replaced.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PageKind {
+    // the image of a single-image file
+    Full,
+    // a thumbnail or lower level of a pyramid, of another image in the file
+    ReducedResolution,
+    // one page of a multi-page document
+    Page,
+    // the TransparencyMask of another image in the file
+    Mask,
+}
+
+impl PageKind {
+    // A NewSubfileType value is a set of flags. Masks of reduced-resolution images
+    // are masks first.
+    pub fn from_new_subfile_type(n: u32) -> PageKind {
+        match n {
+            n if n & 4 != 0 => PageKind::Mask,
+            n if n & 1 != 0 => PageKind::ReducedResolution,
+            n if n & 2 != 0 => PageKind::Page,
+            _ => PageKind::Full,
+        }
+    }
+
+    pub fn from_subfile_type(n: u16) -> Result<PageKind, DecodeError> {
+        match n {
+            1 => Ok(PageKind::Full),
+            2 => Ok(PageKind::ReducedResolution),
+            3 => Ok(PageKind::Page),
+            n => Err(DecodeError::from(DecodeErrorKind::UnsupportedData{ tag: AnyTag::SubfileType, data: n as u32 })),
+        }
+    }
+
+    // Whether this is an image in its own right rather than one derived from another.
+    pub fn is_primary(&self) -> bool {
+        matches!(*self, PageKind::Full | PageKind::Page)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExtraSample {
+    Unspecified,
+    AssociatedAlpha,
+    UnassociatedAlpha,
+}
+
+impl ExtraSample {
+    pub fn from_u16(n: u16) -> Result<ExtraSample, DecodeError> {
+        match n {
+            0 => Ok(ExtraSample::Unspecified),
+            1 => Ok(ExtraSample::AssociatedAlpha),
+            2 => Ok(ExtraSample::UnassociatedAlpha),
+            n => Err(DecodeError::from(DecodeErrorKind::UnsupportedData{ tag: AnyTag::ExtraSamples, data: n as u32 })),
+        }
+    }
+
+    pub fn value(&self) -> u16 {
+        match *self {
+            ExtraSample::Unspecified => 0,
+            ExtraSample::AssociatedAlpha => 1,
+            ExtraSample::UnassociatedAlpha => 2,
+        }
+    }
+}
+
+// A reference white as CIE XYZ, scaled so that Y is 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WhitePoint {
+    x: f64,
+    y: f64,
+    z: f64,
+}
+
+impl WhitePoint {
+    // The white of CIELab TIFFs from Photoshop and most scanners, and of ICC
+    // profile connection spaces.
+    pub const D50: WhitePoint = WhitePoint { x: 0.96422, y: 1., z: 0.82521 };
+    // The white of sRGB.
+    pub const D65: WhitePoint = WhitePoint { x: 0.95047, y: 1., z: 1.08883 };
+
+    pub fn new(x: f64, y: f64, z: f64) -> WhitePoint {
+        WhitePoint {
+            x,
+            y,
+            z,
+        }
+    }
+
+    // From the chromaticity coordinates the WhitePoint tag holds.
+    pub fn from_chromaticity(x: f64, y: f64) -> WhitePoint {
+        WhitePoint::new(x / y, 1., (1. - x - y) / y)
+    }
+
+    pub fn x(&self) -> f64 {
+        self.x
+    }
+
+    pub fn y(&self) -> f64 {
+        self.y
+    }
+
+    pub fn z(&self) -> f64 {
+        self.z
+    }
+}
+
+// How the bits of a sample are interpreted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleFormat {
+    Unsigned,
+    Signed,
+    Float,
+    // undefined data, read as unsigned
+    Void,
+}
+
+impl SampleFormat {
+    pub fn from_u16(n: u16) -> Result<SampleFormat, DecodeError> {
+        match n {
+            1 => Ok(SampleFormat::Unsigned),
+            2 => Ok(SampleFormat::Signed),
+            3 => Ok(SampleFormat::Float),
+            4 => Ok(SampleFormat::Void),
+            n => Err(DecodeError::from(DecodeErrorKind::UnsupportedData{ tag: AnyTag::SampleFormat, data: n as u32 })),
+        }
+    }
+
+    // SampleFormat has a value per sample, but they all have to agree.
+    pub fn from_values(values: &[u16]) -> Result<SampleFormat, DecodeError> {
+        match values.split_first() {
+            Some((first, rest)) if rest.iter().all(|x| x == first) => SampleFormat::from_u16(*first),
+            _ => Err(DecodeError::from(DecodeErrorKind::UnsupportedMultipleData{ tag: AnyTag::SampleFormat, data: values.iter().map(|x| *x as u32).collect() })),
+        }
+    }
+
+    pub fn value(&self) -> u16 {
+        match *self {
+            SampleFormat::Unsigned => 1,
+            SampleFormat::Signed => 2,
+            SampleFormat::Float => 3,
+            SampleFormat::Void => 4,
+        }
+    }
+}
+
+pub fn samples_per_pixel(samples: u16, bits_per_sample: Vec<u16>) -> Result<Vec<u16>, DecodeError> {
+    if samples == 0 {
+        return Err(DecodeError::from(DecodeErrorKind::UnsupportedData { tag: AnyTag::SamplesPerPixel, data: samples as u32 }));
+    }
+
+    match bits_per_sample.len() {
+        // some writers store a single BitsPerSample value for every sample
+        1 => Ok(vec![bits_per_sample[0]; samples as usize]),
+        n if n == samples as usize => Ok(bits_per_sample),
+        _ => Err(DecodeError::from(DecodeErrorKind::InconsistentSamplesPerPixel { samples_per_pixel: samples, bits_per_sample })),
+    }
 }
 
 #[derive(Debug, Fail)]
@@ -66,55 +405,186 @@ impl BitsPerSampleError {
 }
 
 #[allow(non_camel_case_types)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum BitsPerSample {
     U8_1,
+    U8_2,
     U8_3,
     U8_4,
     U16_1,
+    U16_2,
     U16_3,
     U16_4,
+    CN(Vec<u16>),
 }
 
 impl BitsPerSample {
+    // `samples` samples of `bits` bits each.
+    pub fn uniform(bits: u16, samples: usize) -> Result<BitsPerSample, BitsPerSampleError> {
+        BitsPerSample::new(vec![bits; samples])
+    }
+
     pub fn new<T: AsRef<[u16]>>(values: T) -> Result<BitsPerSample, BitsPerSampleError> {
         match values.as_ref() {
             [8] => Ok(BitsPerSample::U8_1),
+            [8, 8] => Ok(BitsPerSample::U8_2),
             [8, 8, 8] => Ok(BitsPerSample::U8_3),
             [8, 8, 8, 8] => Ok(BitsPerSample::U8_4),
             [16] => Ok(BitsPerSample::U16_1),
+            [16, 16] => Ok(BitsPerSample::U16_2),
             [16, 16, 16] => Ok(BitsPerSample::U16_3),
             [16, 16, 16, 16] => Ok(BitsPerSample::U16_4),
-            _ => Err(BitsPerSampleError::InvalidValues { values: values.as_ref().to_vec() }),
+            [] => Err(BitsPerSampleError::InvalidValues { values: vec![] }),
+            values if values.contains(&0) => Err(BitsPerSampleError::InvalidValues { values: values.to_vec() }),
+            values => Ok(BitsPerSample::CN(values.to_vec())),
         }
     }
 
     pub fn len(&self) -> usize {
         match *self {
             BitsPerSample::U8_1 | BitsPerSample::U16_1 => 1,
+            BitsPerSample::U8_2 | BitsPerSample::U16_2 => 2,
             BitsPerSample::U8_3 | BitsPerSample::U16_3 => 3,
             BitsPerSample::U8_4 | BitsPerSample::U16_4 => 4,
+            BitsPerSample::CN(ref values) => values.len(),
         }
     }
 
-    pub fn max_value(&self) -> u16 {
-        let x = u8::max_value() as u16;
-        let y = u16::max_value();
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn values(&self) -> Vec<u16> {
+        match *self {
+            BitsPerSample::CN(ref values) => values.clone(),
+            _ => vec![self.bits() as u16; self.len()],
+        }
+    }
 
+    pub fn is_uniform(&self) -> bool {
         match *self {
-            BitsPerSample::U8_1 | BitsPerSample::U8_3 | BitsPerSample::U8_4 => x,
-            BitsPerSample::U16_1 | BitsPerSample::U16_3 | BitsPerSample::U16_4 => y,
+            BitsPerSample::CN(ref values) => values.iter().all(|x| *x == values[0]),
+            _ => true,
+        }
+    }
+
+    pub fn max_bits(&self) -> usize {
+        match *self {
+            BitsPerSample::CN(ref values) => values.iter().cloned().max().unwrap_or(0) as usize,
+            _ => self.bits(),
+        }
+    }
+
+    pub fn max_value(&self) -> u16 {
+        match self.bits() {
+            n if n < 16 => (1 << n) - 1,
+            _ => u16::MAX,
         }
     }
 
+    // The bits of each sample. Non-uniform layouts report their widest sample.
     pub fn bits(&self) -> usize {
         match self {
-            BitsPerSample::U8_1 | BitsPerSample::U8_3 | BitsPerSample::U8_4 => 8,
-            BitsPerSample::U16_1 | BitsPerSample::U16_3 | BitsPerSample::U16_4 => 16
+            BitsPerSample::U8_1 | BitsPerSample::U8_2 | BitsPerSample::U8_3 | BitsPerSample::U8_4 => 8,
+            BitsPerSample::U16_1 | BitsPerSample::U16_2 | BitsPerSample::U16_3 | BitsPerSample::U16_4 => 16,
+            BitsPerSample::CN(_) => self.max_bits(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ResolutionUnit {
+    NoAbsolute,
+    Inch,
+    Centimeter,
+}
+
+impl ResolutionUnit {
+    pub fn from_u16(n: u16) -> Result<ResolutionUnit, DecodeError> {
+        match n {
+            1 => Ok(ResolutionUnit::NoAbsolute),
+            2 => Ok(ResolutionUnit::Inch),
+            3 => Ok(ResolutionUnit::Centimeter),
+            n => Err(DecodeError::from(DecodeErrorKind::UnsupportedData{ tag: AnyTag::ResolutionUnit, data: n as u32 })),
+        }
+    }
+
+    pub fn value(&self) -> u16 {
+        match *self {
+            ResolutionUnit::NoAbsolute => 1,
+            ResolutionUnit::Inch => 2,
+            ResolutionUnit::Centimeter => 3,
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LengthUnit {
+    Inch,
+    Millimeter,
+}
+
+// Pixels per `unit` along each axis, as stored in XResolution/YResolution.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Resolution {
+    x: Rational,
+    y: Rational,
+    unit: ResolutionUnit,
+}
+
+impl Resolution {
+    pub fn new(x: Rational, y: Rational, unit: ResolutionUnit) -> Resolution {
+        Resolution {
+            x,
+            y,
+            unit,
+        }
+    }
+
+    // Dots per inch along x and y, `None` if either can't be stored as a rational.
+    pub fn from_dpi(x: f64, y: f64) -> Option<Resolution> {
+        Some(Resolution::new(Rational::from_f64(x)?, Rational::from_f64(y)?, ResolutionUnit::Inch))
+    }
+
+    pub fn x(&self) -> Rational {
+        self.x
+    }
+
+    pub fn y(&self) -> Rational {
+        self.y
+    }
+
+    pub fn unit(&self) -> ResolutionUnit {
+        self.unit
+    }
+
+    // Both axes as positive, finite values; 0/0 and 0/n resolutions give `None`.
+    fn values(&self) -> Option<(f64, f64)> {
+        let x = self.x.to_f64()?;
+        let y = self.y.to_f64()?;
+        if x > 0. && y > 0. && x.is_finite() && y.is_finite() {
+            Some((x, y))
+        } else {
+            None
+        }
+    }
+
+    pub fn dpi(&self) -> Option<(f64, f64)> {
+        let (x, y) = self.values()?;
+        match self.unit {
+            ResolutionUnit::NoAbsolute => None,
+            ResolutionUnit::Inch => Some((x, y)),
+            ResolutionUnit::Centimeter => Some((x * 2.54, y * 2.54)),
+        }
+    }
+
+    // Width of a pixel over its height. Meaningful even without an absolute unit.
+    pub fn pixel_aspect_ratio(&self) -> Option<f64> {
+        let (x, y) = self.values()?;
+        Some(y / x)
+    }
+}
+
 #[derive(Debug, Fail)]
 pub enum ImageHeaderError {
     #[fail(display = "Incompatible data ({:?}/{:?}", photometric_interpretation, bits_per_sample)]
@@ -128,6 +598,9 @@ pub struct ImageHeader {
     compression: Compression,
     photometric_interpretation: PhotometricInterpretation,
     bits_per_sample: BitsPerSample,
+    extra_samples: Vec<ExtraSample>,
+    sample_format: SampleFormat,
+    resolution: Option<Resolution>,
 }
 
 impl ImageHeader {
@@ -136,26 +609,63 @@ impl ImageHeader {
         height: u32, 
         compression: Compression, 
         interpretation: PhotometricInterpretation,
-        bits_per_sample: BitsPerSample) -> Result<ImageHeader, ImageHeaderError>
+        bits_per_sample: BitsPerSample,
+        extra_samples: Vec<ExtraSample>) -> Result<ImageHeader, ImageHeaderError>
     {
-        if !is_valid_color_type(interpretation, bits_per_sample) {
+        if !is_valid_color_type(interpretation, &bits_per_sample, extra_samples.len()) {
             return Err(ImageHeaderError::IncompatibleData { 
                 photometric_interpretation: interpretation, 
-                bits_per_sample: bits_per_sample,
+                bits_per_sample,
             });
         }
+        // every sample past the color ones has an ExtraSamples entry
+        let mut extra_samples = extra_samples;
+        if let Some(color_samples) = color_samples(interpretation) {
+            extra_samples.resize(bits_per_sample.len() - color_samples, ExtraSample::Unspecified);
+        }
 
         let header = ImageHeader {
-            width: width,
-            height: height,
-            compression: compression,
+            width,
+            height,
+            compression,
             photometric_interpretation: interpretation,
-            bits_per_sample: bits_per_sample,
+            bits_per_sample,
+            extra_samples,
+            sample_format: SampleFormat::Unsigned,
+            resolution: None,
         };
 
         Ok(header)
     }
 
+    pub fn with_resolution(mut self, resolution: Resolution) -> ImageHeader {
+        self.resolution = Some(resolution);
+        self
+    }
+
+    pub fn with_sample_format(mut self, sample_format: SampleFormat) -> ImageHeader {
+        self.sample_format = sample_format;
+        self
+    }
+
+    pub fn with_dimensions(mut self, width: u32, height: u32) -> ImageHeader {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    // The header of a CMYK image converted to RGB, without the black sample.
+    pub fn cmyk_as_rgb(&self) -> ImageHeader {
+        let mut bits = self.bits_per_sample.values();
+        bits.remove(3);
+
+        ImageHeader {
+            photometric_interpretation: PhotometricInterpretation::RGB,
+            bits_per_sample: BitsPerSample::new(bits).unwrap_or_else(|_| self.bits_per_sample.clone()),
+            ..self.clone()
+        }
+    }
+
     pub fn width(&self) -> u32 {
         self.width
     }
@@ -164,8 +674,8 @@ impl ImageHeader {
         self.height
     }
 
-    pub fn bits_per_sample(&self) -> BitsPerSample {
-        self.bits_per_sample
+    pub fn bits_per_sample(&self) -> &BitsPerSample {
+        &self.bits_per_sample
     }
 
     pub fn compression(&self) -> Compression {
@@ -175,28 +685,222 @@ impl ImageHeader {
     pub fn photometric_interpretation(&self) -> PhotometricInterpretation {
         self.photometric_interpretation
     }
+
+    pub fn extra_samples(&self) -> &[ExtraSample] {
+        &self.extra_samples
+    }
+
+    // What the first extra sample is, if it is an alpha channel. Associated alpha
+    // has already been multiplied into the color samples.
+    pub fn alpha(&self) -> Option<ExtraSample> {
+        self.extra_samples.first().cloned().filter(|&x| x != ExtraSample::Unspecified)
+    }
+
+    pub fn sample_format(&self) -> SampleFormat {
+        self.sample_format
+    }
+
+    pub fn samples_per_pixel(&self) -> usize {
+        self.bits_per_sample.len()
+    }
+
+    pub fn resolution(&self) -> Option<Resolution> {
+        self.resolution
+    }
+
+    // Dots per inch along x and y, converted from centimeters if needed.
+    pub fn dpi(&self) -> Option<(f64, f64)> {
+        self.resolution?.dpi()
+    }
+
+    pub fn pixel_aspect_ratio(&self) -> Option<f64> {
+        self.resolution?.pixel_aspect_ratio()
+    }
+
+    // Printed width and height, or `None` without an absolute resolution.
+    pub fn physical_size(&self, unit: LengthUnit) -> Option<(f64, f64)> {
+        let (x, y) = self.dpi()?;
+        let (width, height) = (self.width as f64 / x, self.height as f64 / y);
+        match unit {
+            LengthUnit::Inch => Some((width, height)),
+            LengthUnit::Millimeter => Some((width * 25.4, height * 25.4)),
+        }
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ImageData { 
     U8(Vec<u8>),
     U16(Vec<u16>),
+    I16(Vec<i16>),
+    I32(Vec<i32>),
+    F32(Vec<f32>),
+    F64(Vec<f64>),
+    // TransparencyMask: one byte per pixel, 255 where the image is visible and 0 elsewhere.
+    Mask(Vec<u8>),
+}
+
+impl ImageData {
+    // The Rust type of the samples.
+    pub fn sample_type(&self) -> &'static str {
+        match *self {
+            ImageData::U8(_) | ImageData::Mask(_) => "u8",
+            ImageData::U16(_) => "u16",
+            ImageData::I16(_) => "i16",
+            ImageData::I32(_) => "i32",
+            ImageData::F32(_) => "f32",
+            ImageData::F64(_) => "f64",
+        }
+    }
+
+    // The number of samples, whatever their type.
+    pub fn len(&self) -> usize {
+        match *self {
+            ImageData::U8(ref data) | ImageData::Mask(ref data) => data.len(),
+            ImageData::U16(ref data) => data.len(),
+            ImageData::I16(ref data) => data.len(),
+            ImageData::I32(ref data) => data.len(),
+            ImageData::F32(ref data) => data.len(),
+            ImageData::F64(ref data) => data.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+// A sample type `Image::samples` converts to. Unsigned samples are scaled from
+// their bit depth to the full range of the type, signed samples are offset so
+// that their minimum becomes zero first. Floats are taken to lie in 0 to 1.
+pub trait Sample: Copy {
+    // `value` is between 0 and 2^`bits` - 1.
+    fn from_unsigned(value: u32, bits: usize) -> Self;
+
+    fn from_float(value: f64) -> Self;
+}
+
+impl Sample for u8 {
+    fn from_unsigned(value: u32, bits: usize) -> u8 {
+        if bits >= 8 {
+            (value >> (bits - 8)) as u8
+        } else {
+            (value * 255 / ((1 << bits) - 1)) as u8
+        }
+    }
+
+    fn from_float(value: f64) -> u8 {
+        (value.clamp(0., 1.) * 255.).round() as u8
+    }
+}
+
+impl Sample for u16 {
+    fn from_unsigned(value: u32, bits: usize) -> u16 {
+        if bits >= 16 {
+            (value >> (bits - 16)) as u16
+        } else {
+            (value * 65535 / ((1 << bits) - 1)) as u16
+        }
+    }
+
+    fn from_float(value: f64) -> u16 {
+        (value.clamp(0., 1.) * 65535.).round() as u16
+    }
+}
+
+// Floats are left as they are, without clamping.
+impl Sample for f32 {
+    fn from_unsigned(value: u32, bits: usize) -> f32 {
+        (value as f64 / ((1u64 << bits) - 1) as f64) as f32
+    }
+
+    fn from_float(value: f64) -> f32 {
+        value as f32
+    }
+}
+
+// Turns the gray sample starting each pixel of `samples` samples from
+// white-is-zero to black-is-zero, or back. Signed samples swap their minimum and
+// maximum, floats have no fixed range and are left alone.
+pub fn invert_gray(data: &mut ImageData, bits: u16, samples: usize) {
+    match *data {
+        ImageData::U8(ref mut data) => {
+            let max = ((1u32 << bits) - 1) as u8;
+            data.iter_mut().step_by(samples).for_each(|x| *x = max - *x)
+        }
+        ImageData::U16(ref mut data) => {
+            let max = ((1u32 << bits) - 1) as u16;
+            data.iter_mut().step_by(samples).for_each(|x| *x = max - *x)
+        }
+        ImageData::I16(ref mut data) => data.iter_mut().step_by(samples).for_each(|x| *x = !*x),
+        ImageData::I32(ref mut data) => data.iter_mut().step_by(samples).for_each(|x| *x = !*x),
+        ImageData::F32(_) | ImageData::F64(_) | ImageData::Mask(_) => {},
+    }
+}
+
+// The `width` x `height` pixels at (`x`, `y`) of `data`, whose rows are `stride`
+// pixels of `samples` samples each.
+pub fn crop(data: ImageData, stride: usize, samples: usize, region: (usize, usize, usize, usize)) -> ImageData {
+    match data {
+        ImageData::U8(data) => ImageData::U8(crop_samples(data, stride, samples, region)),
+        ImageData::U16(data) => ImageData::U16(crop_samples(data, stride, samples, region)),
+        ImageData::I16(data) => ImageData::I16(crop_samples(data, stride, samples, region)),
+        ImageData::I32(data) => ImageData::I32(crop_samples(data, stride, samples, region)),
+        ImageData::F32(data) => ImageData::F32(crop_samples(data, stride, samples, region)),
+        ImageData::F64(data) => ImageData::F64(crop_samples(data, stride, samples, region)),
+        ImageData::Mask(data) => ImageData::Mask(crop_samples(data, stride, samples, region)),
+    }
+}
+
+pub fn crop_samples<T: Copy>(data: Vec<T>, stride: usize, samples: usize, (x, y, width, height): (usize, usize, usize, usize)) -> Vec<T> {
+    if x == 0 && y == 0 && width == stride && data.len() == stride * height * samples {
+        return data;
+    }
+
+    let mut cropped = Vec::with_capacity(width * height * samples);
+    for row in data.chunks((stride * samples).max(1)).skip(y).take(height) {
+        cropped.extend_from_slice(&row[x * samples..(x + width) * samples]);
+    }
+    cropped
+}
+
+// Copies every sample of `data` to `buffer` in native byte order.
+pub fn write_native(data: &ImageData, buffer: &mut [u8]) {
+    match *data {
+        ImageData::U8(ref data) | ImageData::Mask(ref data) => buffer.copy_from_slice(data),
+        ImageData::U16(ref data) => buffer.chunks_mut(2).zip(data).for_each(|(x, y)| x.copy_from_slice(&y.to_ne_bytes())),
+        ImageData::I16(ref data) => buffer.chunks_mut(2).zip(data).for_each(|(x, y)| x.copy_from_slice(&y.to_ne_bytes())),
+        ImageData::I32(ref data) => buffer.chunks_mut(4).zip(data).for_each(|(x, y)| x.copy_from_slice(&y.to_ne_bytes())),
+        ImageData::F32(ref data) => buffer.chunks_mut(4).zip(data).for_each(|(x, y)| x.copy_from_slice(&y.to_ne_bytes())),
+        ImageData::F64(ref data) => buffer.chunks_mut(8).zip(data).for_each(|(x, y)| x.copy_from_slice(&y.to_ne_bytes())),
+    }
 }
 
 #[derive(Debug)]
 pub struct Image {
     header: ImageHeader,
     data: ImageData,
+    mask: Option<Box<Image>>,
 }
 
 impl Image {
     pub fn new(header: ImageHeader, data: ImageData) -> Image {
         Image {
-            header: header,
-            data: data,
+            header,
+            data,
+            mask: None,
         }
     }
 
+    pub fn with_mask(mut self, mask: Image) -> Image {
+        self.mask = Some(Box::new(mask));
+        self
+    }
+
+    pub fn mask(&self) -> Option<&Image> {
+        self.mask.as_deref()
+    }
+
     pub fn header(&self) -> &ImageHeader {
         &self.header
     }
@@ -204,24 +908,835 @@ impl Image {
     pub fn data(&self) -> &ImageData {
         &self.data
     }
+
+    pub fn into_data(self) -> ImageData {
+        self.data
+    }
+
+    // Every sample converted to `T`, see `Sample`.
+    pub fn samples<T: Sample>(&self) -> Vec<T> {
+        // samples narrower than their type are unpacked keeping their range
+        let bits = self.header.bits_per_sample.values();
+        let bits = bits.iter().map(|&x| x as usize).cycle();
+        match self.data {
+            ImageData::U8(ref data) => data.iter().zip(bits).map(|(&x, bits)| T::from_unsigned(x as u32, bits.min(8))).collect(),
+            ImageData::Mask(ref data) => data.iter().map(|&x| T::from_unsigned(x as u32, 8)).collect(),
+            ImageData::U16(ref data) => data.iter().zip(bits).map(|(&x, bits)| T::from_unsigned(x as u32, bits.min(16))).collect(),
+            ImageData::I16(ref data) => data.iter().map(|&x| T::from_unsigned((x as i32 + 0x8000) as u32, 16)).collect(),
+            ImageData::I32(ref data) => data.iter().map(|&x| T::from_unsigned((x as i64 + 0x8000_0000) as u32, 32)).collect(),
+            ImageData::F32(ref data) => data.iter().map(|&x| T::from_float(x as f64)).collect(),
+            ImageData::F64(ref data) => data.iter().map(|&x| T::from_float(x)).collect(),
+        }
+    }
+
+    // Converts the color samples between associated (premultiplied) and
+    // unassociated alpha, multiplying them by the alpha sample or dividing them by
+    // it. Images without an alpha channel, or with one that is already `alpha`,
+    // are returned as they are, and so are signed samples.
+    pub fn convert_alpha(mut self, alpha: ExtraSample) -> Image {
+        let current = match self.header.alpha() {
+            Some(current) if alpha != ExtraSample::Unspecified && current != alpha => current,
+            _ => return self,
+        };
+        let samples = self.header.samples_per_pixel();
+        let index = samples - self.header.extra_samples.len();
+        let premultiply = current == ExtraSample::UnassociatedAlpha;
+        // integers are kept within the range of the widest color sample
+        let bits = self.header.bits_per_sample.values();
+        let alpha_max = 2f64.powi(bits[index] as i32) - 1.;
+        let max = 2f64.powi(bits[..index].iter().cloned().max().unwrap_or(0) as i32) - 1.;
+        match self.data {
+            ImageData::U8(ref mut data) => multiply_alpha(data, samples, index, premultiply, alpha_max, |x| x as f64, |x| x.round().min(max) as u8),
+            ImageData::U16(ref mut data) => multiply_alpha(data, samples, index, premultiply, alpha_max, |x| x as f64, |x| x.round().min(max) as u16),
+            ImageData::F32(ref mut data) => multiply_alpha(data, samples, index, premultiply, 1., |x| x as f64, |x| x as f32),
+            ImageData::F64(ref mut data) => multiply_alpha(data, samples, index, premultiply, 1., |x| x, |x| x),
+            ImageData::I16(_) | ImageData::I32(_) | ImageData::Mask(_) => return self,
+        }
+        self.header.extra_samples[0] = alpha;
+
+        self
+    }
+
+    // Converts CMYK to RGB by taking each ink's complement times that of black,
+    // which ignores how inks really mix; see `cmyk_to_rgb_with_profile` for that.
+    // Samples after the inks are kept. Other images, and signed samples, are
+    // returned as they are.
+    pub fn cmyk_to_rgb(self) -> Image {
+        if self.header.photometric_interpretation != PhotometricInterpretation::CMYK {
+            return self;
+        }
+        let samples = self.header.samples_per_pixel();
+        let bits = self.header.bits_per_sample.values();
+        let max = 2f64.powi(bits[..4].iter().cloned().max().unwrap_or(0) as i32) - 1.;
+        let data = match self.data {
+            ImageData::U8(ref data) => ImageData::U8(invert_cmyk(data, samples, max, |x| x as f64, |x| x.round() as u8)),
+            ImageData::U16(ref data) => ImageData::U16(invert_cmyk(data, samples, max, |x| x as f64, |x| x.round() as u16)),
+            ImageData::F32(ref data) => ImageData::F32(invert_cmyk(data, samples, 1., |x| x as f64, |x| x as f32)),
+            ImageData::F64(ref data) => ImageData::F64(invert_cmyk(data, samples, 1., |x| x, |x| x)),
+            ImageData::I16(_) | ImageData::I32(_) | ImageData::Mask(_) => return self,
+        };
+
+        Image {
+            header: self.header.cmyk_as_rgb(),
+            data,
+            mask: self.mask,
+        }
+    }
+
+    // Converts CMYK to sRGB through `profile`, the image's ICC profile (see
+    // `Decoder::icc_profile`). Samples after the inks are kept. 8-bit, 16-bit and
+    // `f32` samples are converted, other images are returned as they are.
+    #[cfg(feature = "icc")]
+    pub fn cmyk_to_rgb_with_profile(self, profile: &[u8]) -> DecodeResult<Image> {
+        if self.header.photometric_interpretation != PhotometricInterpretation::CMYK {
+            return Ok(self);
+        }
+        match icc::cmyk_to_rgb(&self.data, self.header.samples_per_pixel(), profile)? {
+            Some(data) => Ok(Image {
+                header: self.header.cmyk_as_rgb(),
+                data,
+                mask: self.mask,
+            }),
+            None => Ok(self),
+        }
+    }
+
+    // Converts CIELab to sRGB, taking L*a*b* to be relative to `white` and adapting
+    // it to sRGB's white. 8- and 16-bit samples become samples of the same size,
+    // floats (L* from 0 to 100) become floats from 0 to 1. Samples after L*a*b* are
+    // kept. Other images are returned as they are.
+    pub fn lab_to_rgb(self, white: WhitePoint) -> Image {
+        if self.header.photometric_interpretation != PhotometricInterpretation::CIELab {
+            return self;
+        }
+        let samples = self.header.samples_per_pixel();
+        let to_rgb = LabToRgb::new(white);
+        let data = match self.data {
+            // a* and b* are signed, stored in the same unsigned samples as L*
+            ImageData::U8(ref data) => ImageData::U8(convert_lab(data, samples, |x| {
+                let rgb = to_rgb.convert(x[0] as f64 * 100. / 255., x[1] as i8 as f64, x[2] as i8 as f64);
+                rgb.map(|c| (c * 255.).round() as u8)
+            })),
+            ImageData::U16(ref data) => ImageData::U16(convert_lab(data, samples, |x| {
+                let rgb = to_rgb.convert(x[0] as f64 * 100. / 65535., x[1] as i16 as f64 / 256., x[2] as i16 as f64 / 256.);
+                rgb.map(|c| (c * 65535.).round() as u16)
+            })),
+            ImageData::F32(ref data) => ImageData::F32(convert_lab(data, samples, |x| {
+                to_rgb.convert(x[0] as f64, x[1] as f64, x[2] as f64).map(|c| c as f32)
+            })),
+            ImageData::F64(ref data) => ImageData::F64(convert_lab(data, samples, |x| to_rgb.convert(x[0], x[1], x[2]))),
+            ImageData::I16(_) | ImageData::I32(_) | ImageData::Mask(_) => return self,
+        };
+        let header = ImageHeader {
+            photometric_interpretation: PhotometricInterpretation::RGB,
+            ..self.header.clone()
+        };
+
+        Image {
+            header,
+            data,
+            mask: self.mask,
+        }
+    }
+
+    // The image as it is meant to be displayed, given where its first row and
+    // column belong. Transposing orientations swap the width and height, and the
+    // resolutions with them.
+    pub fn oriented(&self, orientation: Orientation) -> Image {
+        let width = self.header.width as usize;
+        let height = self.header.height as usize;
+        let samples = self.header.samples_per_pixel();
+        let data = match self.data {
+            ImageData::U8(ref data) => ImageData::U8(orient(data, width, height, samples, orientation)),
+            ImageData::U16(ref data) => ImageData::U16(orient(data, width, height, samples, orientation)),
+            ImageData::I16(ref data) => ImageData::I16(orient(data, width, height, samples, orientation)),
+            ImageData::I32(ref data) => ImageData::I32(orient(data, width, height, samples, orientation)),
+            ImageData::F32(ref data) => ImageData::F32(orient(data, width, height, samples, orientation)),
+            ImageData::F64(ref data) => ImageData::F64(orient(data, width, height, samples, orientation)),
+            ImageData::Mask(ref data) => ImageData::Mask(orient(data, width, height, 1, orientation)),
+        };
+        let mut header = self.header.clone();
+        if orientation.is_transposed() {
+            header.width = self.header.height;
+            header.height = self.header.width;
+            header.resolution = self.header.resolution.map(|x| Resolution::new(x.y, x.x, x.unit));
+        }
+
+        Image {
+            header,
+            data,
+            mask: self.mask.as_ref().map(|x| Box::new(x.oriented(orientation))),
+        }
+    }
+
+    // Halves both dimensions (rounding up) with a 2x2 box filter. Pixels on an odd
+    // right or bottom edge average only the samples that exist.
+    pub fn downsample(&self) -> Image {
+        self.downsample_by(2)
+    }
+
+    // Divides both dimensions by `factor` (rounding up), each pixel the average of
+    // a `factor` x `factor` block, or of what's left of one on the right and bottom
+    // edges. Masks stay 0 or 255 by majority.
+    pub fn downsample_by(&self, factor: u32) -> Image {
+        let factor = factor.max(1);
+        let width = self.header.width as usize;
+        let height = self.header.height as usize;
+        let samples = self.header.samples_per_pixel();
+        let n = factor as usize;
+        let data = match self.data {
+            ImageData::U8(ref data) => ImageData::U8(box_filter(data, width, height, samples, n, |x| x as f64, |x| x.round() as u8)),
+            ImageData::U16(ref data) => ImageData::U16(box_filter(data, width, height, samples, n, |x| x as f64, |x| x.round() as u16)),
+            ImageData::I16(ref data) => ImageData::I16(box_filter(data, width, height, samples, n, |x| x as f64, |x| x.round() as i16)),
+            ImageData::I32(ref data) => ImageData::I32(box_filter(data, width, height, samples, n, |x| x as f64, |x| x.round() as i32)),
+            ImageData::F32(ref data) => ImageData::F32(box_filter(data, width, height, samples, n, |x| x as f64, |x| x as f32)),
+            ImageData::F64(ref data) => ImageData::F64(box_filter(data, width, height, samples, n, |x| x, |x| x)),
+            ImageData::Mask(ref data) => ImageData::Mask(box_filter(data, width, height, samples, n, |x| x as f64, |x| if x.round() < 128. { 0 } else { 255 })),
+        };
+        let header = ImageHeader {
+            width: self.header.width.div_ceil(factor),
+            height: self.header.height.div_ceil(factor),
+            ..self.header.clone()
+        };
+
+        Image {
+            header,
+            data,
+            mask: self.mask.as_ref().map(|x| Box::new(x.downsample_by(factor))),
+        }
+    }
+
+    // Successive `downsample`s, stopping at the first level whose width and height
+    // are both below `min_dimension`.
+    pub fn pyramid(&self, min_dimension: u32) -> Vec<Image> {
+        let mut levels: Vec<Image> = vec![];
+        loop {
+            let level = {
+                let prev = levels.last().unwrap_or(self);
+                let (width, height) = (prev.header.width, prev.header.height);
+                if (width < min_dimension && height < min_dimension) || (width <= 1 && height <= 1) {
+                    break;
+                }
+                prev.downsample()
+            };
+            levels.push(level);
+        }
+
+        levels
+    }
+
+    // Reduces an RGB image of 8 or 16-bit samples to at most `max_colors` colors,
+    // up to 256, by median cut, keeping the colors exactly if there are no more.
+    // Returns a Palette image of indices, with as few bits as hold them all, and
+    // the colors with 16 bits a channel as ColorMap holds them, none for an image
+    // without pixels. Samples past the first three and the mask are dropped.
+    // `None` for other images.
+    pub fn quantize(&self, max_colors: usize) -> Option<(Image, Vec<[u16; 3]>)> {
+        let samples = self.header.samples_per_pixel();
+        if self.header.photometric_interpretation != PhotometricInterpretation::RGB || !self.header.bits_per_sample.is_uniform() {
+            return None;
+        }
+        let colors = match self.data {
+            ImageData::U8(ref data) if self.header.bits_per_sample.bits() == 8 => {
+                data.chunks(samples).map(|x| [x[0] as u16 * 257, x[1] as u16 * 257, x[2] as u16 * 257]).collect::<Vec<_>>()
+            }
+            ImageData::U16(ref data) if self.header.bits_per_sample.bits() == 16 => data.chunks(samples).map(|x| [x[0], x[1], x[2]]).collect(),
+            _ => return None,
+        };
+
+        let mut counts = HashMap::new();
+        for color in &colors {
+            *counts.entry(*color).or_insert(0u64) += 1;
+        }
+        let mut unique = counts.into_iter().collect::<Vec<_>>();
+        unique.sort();
+        let mut palette = vec![];
+        let mut indices = HashMap::new();
+        for (i, colors) in median_cut(unique, max_colors.clamp(1, 256)).into_iter().enumerate() {
+            let total = colors.iter().map(|x| x.1).sum::<u64>();
+            palette.push([0, 1, 2].map(|c| ((colors.iter().map(|x| x.0[c] as u64 * x.1).sum::<u64>() + total / 2) / total) as u16));
+            indices.extend(colors.into_iter().map(|x| (x.0, i as u8)));
+        }
+        let bits = [1u16, 2, 4, 8].iter().cloned().find(|&x| palette.len() <= 1 << x).unwrap_or(8);
+        let header = ImageHeader {
+            photometric_interpretation: PhotometricInterpretation::Palette,
+            bits_per_sample: BitsPerSample::new([bits]).ok()?,
+            extra_samples: vec![],
+            sample_format: SampleFormat::Unsigned,
+            ..self.header.clone()
+        };
+        let data = ImageData::U8(colors.iter().map(|x| indices[x]).collect());
+
+        Some((Image::new(header, data), palette))
+    }
+}
+
+// Splits colors, each with its pixel count, into at most `max_colors` groups,
+// halving by pixels the group that spans the widest range of a channel until
+// every group is one color. No colors make no groups.
+fn median_cut(colors: Vec<([u16; 3], u64)>, max_colors: usize) -> Vec<Vec<([u16; 3], u64)>> {
+    let range = |colors: &[([u16; 3], u64)], c: usize| {
+        let (min, max) = colors.iter().fold((u16::MAX, 0), |(min, max), x| (min.min(x.0[c]), max.max(x.0[c])));
+        max.saturating_sub(min)
+    };
+    let mut groups = vec![];
+    if !colors.is_empty() {
+        groups.push(colors);
+    }
+    while groups.len() < max_colors {
+        let widest = groups.iter().enumerate()
+            .filter(|x| x.1.len() > 1)
+            .flat_map(|(i, colors)| (0..3).map(move |c| (range(colors, c), i, c)))
+            .max();
+        let (i, c) = match widest {
+            Some((_, i, c)) => (i, c),
+            None => break,
+        };
+        let mut group = groups.swap_remove(i);
+        group.sort_by_key(|x| x.0[c]);
+        let total = group.iter().map(|x| x.1).sum::<u64>();
+        let mut seen = 0;
+        let half = group.iter().position(|x| {
+            seen += x.1;
+            seen * 2 >= total
+        });
+        let split = half.map_or(1, |x| x + 1).clamp(1, group.len() - 1);
+        let rest = group.split_off(split);
+        groups.push(group);
+        groups.push(rest);
+    }
+
+    groups
+}
+
+// Pixels of `samples` samples rearranged from `width` x `height` stored rows into
+// displayed rows, see `Orientation`.
+fn orient<T: Copy>(data: &[T], width: usize, height: usize, samples: usize, orientation: Orientation) -> Vec<T> {
+    if orientation == Orientation::TopLeft {
+        return data.to_vec();
+    }
+    let (columns, rows) = if orientation.is_transposed() { (height, width) } else { (width, height) };
+    let mut oriented = Vec::with_capacity(data.len());
+    for y in 0..rows {
+        for x in 0..columns {
+            let (from_x, from_y) = match orientation {
+                Orientation::TopLeft => (x, y),
+                Orientation::TopRight => (width - 1 - x, y),
+                Orientation::BottomRight => (width - 1 - x, height - 1 - y),
+                Orientation::BottomLeft => (x, height - 1 - y),
+                Orientation::LeftTop => (y, x),
+                Orientation::RightTop => (y, height - 1 - x),
+                Orientation::RightBottom => (width - 1 - y, height - 1 - x),
+                Orientation::LeftBottom => (width - 1 - y, x),
+            };
+            let start = (from_y * width + from_x) * samples;
+            oriented.extend_from_slice(&data[start..start + samples]);
+        }
+    }
+
+    oriented
+}
+
+// Replaces the L*a*b* that starts each pixel of `samples` samples with the RGB
+// `convert` returns for it.
+fn convert_lab<T: Copy, F: Fn(&[T]) -> [T; 3]>(data: &[T], samples: usize, convert: F) -> Vec<T> {
+    let mut rgb = Vec::with_capacity(data.len());
+    for pixel in data.chunks(samples) {
+        rgb.extend_from_slice(&convert(pixel));
+        rgb.extend_from_slice(&pixel[3..]);
+    }
+
+    rgb
+}
+
+// L*a*b* to XYZ, adapted from a white to sRGB's with the Bradford transform, to
+// gamma-encoded sRGB.
+struct LabToRgb {
+    white: WhitePoint,
+    // XYZ relative to `white` to linear sRGB
+    matrix: [[f64; 3]; 3],
+}
+
+impl LabToRgb {
+    fn new(white: WhitePoint) -> LabToRgb {
+        const BRADFORD: [[f64; 3]; 3] = [
+            [0.8951, 0.2664, -0.1614],
+            [-0.7502, 1.7135, 0.0367],
+            [0.0389, -0.0685, 1.0296],
+        ];
+        const BRADFORD_INVERSE: [[f64; 3]; 3] = [
+            [0.9869929, -0.1470543, 0.1599627],
+            [0.4323053, 0.5183603, 0.0492912],
+            [-0.0085287, 0.0400428, 0.9684867],
+        ];
+        const XYZ_TO_SRGB: [[f64; 3]; 3] = [
+            [3.2404542, -1.5371385, -0.4985314],
+            [-0.9692660, 1.8760108, 0.0415560],
+            [0.0556434, -0.2040259, 1.0572252],
+        ];
+
+        let from = mul_vector(&BRADFORD, [white.x, white.y, white.z]);
+        let to = mul_vector(&BRADFORD, [WhitePoint::D65.x, WhitePoint::D65.y, WhitePoint::D65.z]);
+        let mut scaled = BRADFORD;
+        for (i, row) in scaled.iter_mut().enumerate() {
+            row.iter_mut().for_each(|x| *x *= to[i] / from[i]);
+        }
+        let adapt = mul_matrix(&BRADFORD_INVERSE, &scaled);
+
+        LabToRgb {
+            white,
+            matrix: mul_matrix(&XYZ_TO_SRGB, &adapt),
+        }
+    }
+
+    // Red, green and blue from 0 to 1.
+    fn convert(&self, l: f64, a: f64, b: f64) -> [f64; 3] {
+        let inverse = |t: f64| if t > 6. / 29. { t * t * t } else { 3. * (6. / 29.) * (6. / 29.) * (t - 4. / 29.) };
+        let fy = (l + 16.) / 116.;
+        let xyz = [
+            self.white.x * inverse(fy + a / 500.),
+            self.white.y * inverse(fy),
+            self.white.z * inverse(fy - b / 200.),
+        ];
+        mul_vector(&self.matrix, xyz).map(|c| {
+            let c = c.clamp(0., 1.);
+            if c <= 0.0031308 { 12.92 * c } else { 1.055 * c.powf(1. / 2.4) - 0.055 }
+        })
+    }
+}
+
+fn mul_vector(m: &[[f64; 3]; 3], v: [f64; 3]) -> [f64; 3] {
+    [0, 1, 2].map(|i| m[i][0] * v[0] + m[i][1] * v[1] + m[i][2] * v[2])
+}
+
+fn mul_matrix(a: &[[f64; 3]; 3], b: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    [0, 1, 2].map(|i| [0, 1, 2].map(|j| a[i][0] * b[0][j] + a[i][1] * b[1][j] + a[i][2] * b[2][j]))
+}
+
+// Replaces the four inks that start each pixel of `samples` samples with red,
+// green and blue, `max` standing for full ink.
+fn invert_cmyk<T, F, G>(data: &[T], samples: usize, max: f64, to: F, from: G) -> Vec<T>
+    where T: Copy, F: Fn(T) -> f64, G: Fn(f64) -> T
+{
+    let mut rgb = Vec::with_capacity(data.len() / samples * (samples - 1));
+    for pixel in data.chunks(samples) {
+        let white = max - to(pixel[3]);
+        for &ink in &pixel[..3] {
+            rgb.push(from((max - to(ink)) * white / max));
+        }
+        rgb.extend_from_slice(&pixel[4..]);
+    }
+
+    rgb
+}
+
+// Multiplies (or divides) the samples before `index` in each pixel of `samples`
+// samples by the one at `index`, taken as a fraction of `max`. Divided samples
+// of transparent pixels become zero.
+fn multiply_alpha<T, F, G>(data: &mut [T], samples: usize, index: usize, premultiply: bool, max: f64, to: F, from: G)
+    where T: Copy, F: Fn(T) -> f64, G: Fn(f64) -> T
+{
+    for pixel in data.chunks_mut(samples) {
+        let alpha = to(pixel[index]) / max;
+        for sample in pixel[..index].iter_mut() {
+            let value = to(*sample);
+            *sample = match (premultiply, alpha) {
+                (true, _) => from(value * alpha),
+                (false, alpha) if alpha <= 0. => from(0.),
+                (false, alpha) => from(value / alpha),
+            };
+        }
+    }
+}
+
+fn box_filter<T, F, G>(data: &[T], width: usize, height: usize, samples: usize, factor: usize, to: F, from: G) -> Vec<T>
+    where T: Copy, F: Fn(T) -> f64, G: Fn(f64) -> T
+{
+    let out_width = width.div_ceil(factor);
+    let out_height = height.div_ceil(factor);
+    let mut out = Vec::with_capacity(out_width * out_height * samples);
+    for y in 0..out_height {
+        let rows = (y * factor)..(y * factor + factor).min(height);
+        for x in 0..out_width {
+            let cols = (x * factor)..(x * factor + factor).min(width);
+            let n = (rows.len() * cols.len()) as f64;
+            for s in 0..samples {
+                let mut sum = 0.;
+                for row in rows.clone() {
+                    for col in cols.clone() {
+                        sum += to(data[(row * width + col) * samples + s]);
+                    }
+                }
+                out.push(from(sum / n));
+            }
+        }
+    }
+
+    out
+}
+
+// The samples of each pixel that make up its color, `None` where there is nothing
+// to check the samples against.
+fn color_samples(photometric_interpretation: PhotometricInterpretation) -> Option<usize> {
+    use self::PhotometricInterpretation::*;
+
+    match photometric_interpretation {
+        WhiteIsZero | BlackIsZero | Palette | TransparencyMask => Some(1),
+        RGB | CIELab => Some(3),
+        CMYK => Some(4),
+        _ => None,
+    }
 }
 
 #[inline]
-fn is_valid_color_type(photometric_interpretation: PhotometricInterpretation, bits_per_sample: BitsPerSample) -> bool {
+fn is_valid_color_type(photometric_interpretation: PhotometricInterpretation, bits_per_sample: &BitsPerSample, extra_samples: usize) -> bool {
     use self::PhotometricInterpretation::*;
-    use self::BitsPerSample::*;
-
-    match (photometric_interpretation, bits_per_sample) {
-        (RGB, U8_3) | 
-        (RGB, U8_4) | 
-        (RGB, U16_3) | 
-        (RGB, U16_4) |
-        (CMYK, U8_4) | 
-        (CMYK, U16_4) |
-        (BlackIsZero, U8_1) | 
-        (BlackIsZero, U16_1) |
-        (WhiteIsZero, U8_1) | 
-        (WhiteIsZero, U16_1) => true,
-        _ => false
+
+    let color_samples = match photometric_interpretation {
+        TransparencyMask => return bits_per_sample.values() == [1] && extra_samples == 0,
+        Unknown(_) => return true,
+        interpretation => match color_samples(interpretation) {
+            Some(samples) => samples,
+            None => return false,
+        },
+    };
+
+    // Samples past those ExtraSamples describes are extra bands of their own, as
+    // in multispectral images and RGBA written without ExtraSamples.
+    bits_per_sample.len() >= color_samples + extra_samples
+}
+
+// Expands 1-bit rows, each padded to a whole byte, into one byte per pixel.
+pub fn unpack_mask(packed: &[u8], width: usize, height: usize) -> Vec<u8> {
+    unpack_samples(packed, width, height, 1)
+        .into_iter()
+        .map(|x| if x == 1 { 255 } else { 0 })
+        .collect()
+}
+
+// Expands rows of 1-, 2- or 4-bit samples, each row padded to a whole byte, into
+// one byte per sample. The values keep their range, 0 to 2^bits - 1.
+pub fn unpack_samples(packed: &[u8], samples_per_row: usize, height: usize, bits: usize) -> Vec<u8> {
+    let row_bytes = (samples_per_row * bits).div_ceil(8);
+    let per_byte = 8 / bits;
+    let max = (1u16 << bits) - 1;
+    let mut unpacked = Vec::with_capacity(samples_per_row * height);
+    for row in packed.chunks(row_bytes.max(1)).take(height) {
+        for x in 0..samples_per_row {
+            let shift = 8 - bits * (x % per_byte + 1);
+            let value = row.get(x / per_byte).map_or(0, |b| (*b as u16 >> shift) & max);
+            unpacked.push(value as u8);
+        }
+    }
+    unpacked.resize(samples_per_row * height, 0);
+
+    unpacked
+}
+
+// Packs one byte per sample, each below 2^`bits`, into rows of 1-, 2- or 4-bit
+// samples padded to a whole byte.
+pub fn pack_samples(samples: &[u8], samples_per_row: usize, bits: usize) -> Vec<u8> {
+    let row_bytes = (samples_per_row * bits).div_ceil(8);
+    let per_byte = 8 / bits;
+    let mut packed = vec![];
+    for row in samples.chunks(samples_per_row.max(1)) {
+        let mut bytes = vec![0u8; row_bytes];
+        for (x, value) in row.iter().enumerate() {
+            bytes[x / per_byte] |= value << (8 - bits * (x % per_byte + 1));
+        }
+        packed.extend_from_slice(&bytes);
+    }
+
+    packed
+}
+
+// Expands rows of samples of up to 16 bits, whose sizes repeat `bits`, into one
+// value per sample. Samples follow each other without padding, each row is
+// padded to a whole byte.
+pub fn unpack_fields(packed: &[u8], bits: &[u16], samples_per_row: usize, height: usize) -> Vec<u16> {
+    let row_bits = (0..samples_per_row).map(|x| bits[x % bits.len()] as usize).sum::<usize>();
+    let mut unpacked = Vec::with_capacity(samples_per_row * height);
+    for row in packed.chunks(row_bits.div_ceil(8).max(1)).take(height) {
+        let mut pos = 0;
+        for x in 0..samples_per_row {
+            let mut value = 0;
+            for _ in 0..bits[x % bits.len()] {
+                let bit = row.get(pos / 8).map_or(0, |b| (*b >> (7 - pos % 8)) & 1);
+                value = (value << 1) | bit as u16;
+                pos += 1;
+            }
+            unpacked.push(value);
+        }
+    }
+    unpacked.resize(samples_per_row * height, 0);
+
+    unpacked
+}
+
+// Expands subsampled YCbCr, stored as blocks of `h` x `v` luma samples followed by
+// one Cb and one Cr, into a Y, Cb, Cr triple per pixel. Each block's chroma is
+// repeated over all of its pixels, so YCbCrPositioning makes no difference.
+pub fn upsample_ycbcr(units: &[u8], width: usize, height: usize, h: usize, v: usize) -> Vec<u8> {
+    let unit_len = h * v + 2;
+    let columns = width.div_ceil(h);
+    let mut ycbcr = vec![0; width * height * 3];
+    for (i, unit) in units.chunks(unit_len).enumerate().take(columns * height.div_ceil(v)) {
+        if unit.len() < unit_len {
+            break;
+        }
+        let (left, top) = ((i % columns) * h, (i / columns) * v);
+        for dy in 0..v {
+            for dx in 0..h {
+                let (x, y) = (left + dx, top + dy);
+                if x < width && y < height {
+                    let start = (y * width + x) * 3;
+                    ycbcr[start..start + 3].copy_from_slice(&[unit[dy * h + dx], unit[h * v], unit[h * v + 1]]);
+                }
+            }
+        }
+    }
+
+    ycbcr
+}
+
+// Converts the first three samples of each pixel from YCbCr to RGB in place.
+// `reference` is ReferenceBlackWhite, the codes of black and white luma and of
+// the chroma extremes, and `luma` the YCbCrCoefficients.
+pub fn ycbcr_to_rgb(data: &mut [u8], samples: usize, luma: [f64; 3], reference: [f64; 6]) {
+    let [luma_red, luma_green, luma_blue] = luma;
+    for pixel in data.chunks_mut(samples) {
+        let y = (pixel[0] as f64 - reference[0]) * 255. / (reference[1] - reference[0]);
+        let cb = (pixel[1] as f64 - reference[2]) * 127. / (reference[3] - reference[2]);
+        let cr = (pixel[2] as f64 - reference[4]) * 127. / (reference[5] - reference[4]);
+        let r = cr * (2. - 2. * luma_red) + y;
+        let b = cb * (2. - 2. * luma_blue) + y;
+        let g = (y - luma_blue * b - luma_red * r) / luma_green;
+        for (x, value) in pixel.iter_mut().zip(&[r, g, b]) {
+            *x = value.round().clamp(0., 255.) as u8;
+        }
+    }
+}
+
+// Packs one byte per pixel (non-zero where the image is visible) into 1-bit rows,
+// the inverse of `unpack_mask`.
+pub fn pack_mask(mask: &[u8], width: usize) -> Vec<u8> {
+    let row_bytes = width.div_ceil(8);
+    let mut packed = vec![];
+    for row in mask.chunks(width.max(1)) {
+        let mut bytes = vec![0u8; row_bytes];
+        for (x, value) in row.iter().enumerate() {
+            if *value != 0 {
+                bytes[x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        packed.extend_from_slice(&bytes);
+    }
+
+    packed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(width: u32, height: u32, resolution: Resolution) -> ImageHeader {
+        ImageHeader::new(width, height, Compression::No, PhotometricInterpretation::BlackIsZero, BitsPerSample::U8_1, vec![])
+            .unwrap()
+            .with_resolution(resolution)
+    }
+
+    fn close(a: (f64, f64), b: (f64, f64)) -> bool {
+        (a.0 - b.0).abs() < 1e-9 && (a.1 - b.1).abs() < 1e-9
+    }
+
+    #[test]
+    fn letter_page_at_300_dpi() {
+        let header = header(2550, 3300, Resolution::new(Rational::new(300, 1), Rational::new(300, 1), ResolutionUnit::Inch));
+        assert_eq!(header.dpi(), Some((300., 300.)));
+        assert_eq!(header.pixel_aspect_ratio(), Some(1.));
+        assert!(close(header.physical_size(LengthUnit::Inch).unwrap(), (8.5, 11.)));
+        assert!(close(header.physical_size(LengthUnit::Millimeter).unwrap(), (215.9, 279.4)));
+    }
+
+    #[test]
+    fn pixels_per_centimeter() {
+        let header = header(1180, 590, Resolution::new(Rational::new(118, 1), Rational::new(118, 1), ResolutionUnit::Centimeter));
+        assert!(close(header.dpi().unwrap(), (299.72, 299.72)));
+        assert!(close(header.physical_size(LengthUnit::Millimeter).unwrap(), (100., 50.)));
+    }
+
+    #[test]
+    fn unusable_resolutions() {
+        let relative = header(10, 10, Resolution::new(Rational::new(2, 1), Rational::new(1, 1), ResolutionUnit::NoAbsolute));
+        assert_eq!(relative.dpi(), None);
+        assert_eq!(relative.physical_size(LengthUnit::Inch), None);
+        assert_eq!(relative.pixel_aspect_ratio(), Some(0.5));
+
+        for &(x, y) in &[((0, 0), (300, 1)), ((300, 1), (0, 1)), ((1, 0), (1, 0))] {
+            let header = header(10, 10, Resolution::new(Rational::new(x.0, x.1), Rational::new(y.0, y.1), ResolutionUnit::Inch));
+            assert_eq!(header.dpi(), None);
+            assert_eq!(header.pixel_aspect_ratio(), None);
+            assert_eq!(header.physical_size(LengthUnit::Millimeter), None);
+        }
+
+        let header = ImageHeader::new(10, 10, Compression::No, PhotometricInterpretation::BlackIsZero, BitsPerSample::U8_1, vec![]).unwrap();
+        assert_eq!(header.dpi(), None);
+    }
+
+    fn rgb(width: u32, height: u32, data: Vec<u8>) -> Image {
+        let header = ImageHeader::new(width, height, Compression::No, PhotometricInterpretation::RGB, BitsPerSample::U8_3, vec![]).unwrap();
+        Image::new(header, ImageData::U8(data))
+    }
+
+    #[test]
+    fn quantize_keeps_few_colors_exactly() {
+        let image = rgb(3, 1, vec![255, 0, 0, 0, 0, 255, 255, 0, 0]);
+        let (indices, palette) = image.quantize(256).unwrap();
+        assert_eq!(indices.header().photometric_interpretation(), PhotometricInterpretation::Palette);
+        assert_eq!(indices.header().bits_per_sample().values(), vec![1]);
+        let data = match *indices.data() {
+            ImageData::U8(ref data) => data.clone(),
+            _ => unreachable!(),
+        };
+        let colors = data.iter().map(|&x| palette[x as usize]).collect::<Vec<_>>();
+        assert_eq!(colors, vec![[65535, 0, 0], [0, 0, 65535], [65535, 0, 0]]);
+    }
+
+    #[test]
+    fn quantize_reduces_to_max_colors() {
+        let data = (0..64u32).flat_map(|i| vec![(i * 4) as u8, 0, (255 - i * 4) as u8]).collect();
+        let (indices, palette) = rgb(64, 1, data).quantize(4).unwrap();
+        assert_eq!(palette.len(), 4);
+        assert_eq!(indices.header().bits_per_sample().values(), vec![2]);
+        // the groups split by pixels, so each covers a quarter of the ramp
+        match *indices.data() {
+            ImageData::U8(ref data) => assert!(data.chunks(16).all(|x| x.iter().all(|&i| i == x[0]))),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn quantize_without_pixels() {
+        let (indices, palette) = rgb(0, 0, vec![]).quantize(16).unwrap();
+        assert!(palette.is_empty());
+        assert_eq!(indices.data().len(), 0);
+    }
+
+    #[test]
+    fn quantize_needs_rgb() {
+        let header = ImageHeader::new(1, 1, Compression::No, PhotometricInterpretation::BlackIsZero, BitsPerSample::U8_1, vec![]).unwrap();
+        assert!(Image::new(header, ImageData::U8(vec![0])).quantize(16).is_none());
+    }
+
+    // The Y, Cb, Cr triples of `luma` with the chroma of the `blocks` they lie in,
+    // block n having Cb 50 + n and Cr 60 + n.
+    fn triples(luma: &[u8], blocks: &[u8]) -> Vec<u8> {
+        luma.iter().zip(blocks).flat_map(|(&y, &n)| vec![y, 50 + n, 60 + n]).collect()
+    }
+
+    #[test]
+    fn upsample_420_with_edge_blocks() {
+        // 5x3 pixels in 3x2 blocks of 2x2; the last column and row of blocks
+        // hang over the edges
+        let units = (0..6u8).flat_map(|n| vec![n * 4 + 1, n * 4 + 2, n * 4 + 3, n * 4 + 4, 50 + n, 60 + n]).collect::<Vec<_>>();
+        let expected = triples(
+            &[1, 2, 5, 6, 9, 3, 4, 7, 8, 11, 13, 14, 17, 18, 21],
+            &[0, 0, 1, 1, 2, 0, 0, 1, 1, 2, 3, 3, 4, 4, 5],
+        );
+        assert_eq!(upsample_ycbcr(&units, 5, 3, 2, 2), expected);
+    }
+
+    #[test]
+    fn upsample_422_with_edge_blocks() {
+        // 3x2 pixels in 2x2 blocks of 2x1
+        let units = (0..4u8).flat_map(|n| vec![n * 2 + 1, n * 2 + 2, 50 + n, 60 + n]).collect::<Vec<_>>();
+        let expected = triples(&[1, 2, 3, 5, 6, 7], &[0, 0, 1, 2, 2, 3]);
+        assert_eq!(upsample_ycbcr(&units, 3, 2, 2, 1), expected);
+
+        // pixels of a block cut off by the end of the data stay zero
+        let mut cut = expected.clone();
+        cut[15..].iter_mut().for_each(|x| *x = 0);
+        assert_eq!(upsample_ycbcr(&units[..14], 3, 2, 2, 1), cut);
+    }
+
+    #[test]
+    fn ycbcr_to_rgb_known_values() {
+        let luma = [0.299, 0.587, 0.114];
+        // pure red, green, blue and grays as BT.601 codes them
+        let mut full = vec![76, 85, 255, 150, 44, 21, 29, 255, 107, 235, 128, 128, 0, 128, 128];
+        ycbcr_to_rgb(&mut full, 3, luma, [0., 255., 128., 255., 128., 255.]);
+        assert_eq!(full, vec![254, 0, 0, 0, 255, 1, 0, 0, 254, 235, 235, 235, 0, 0, 0]);
+
+        // studio range, with an alpha sample that is left alone
+        let mut studio = vec![76, 85, 255, 9, 235, 128, 128, 10, 16, 128, 128, 11, 126, 128, 128, 12];
+        ycbcr_to_rgb(&mut studio, 4, luma, [16., 235., 128., 240., 128., 240.]);
+        assert_eq!(studio, vec![255, 0, 0, 9, 255, 255, 255, 10, 0, 0, 0, 11, 128, 128, 128, 12]);
+    }
+
+    fn cmyk(bits: &[u16], extra_samples: Vec<ExtraSample>, data: ImageData) -> Image {
+        let header = ImageHeader::new(2, 3, Compression::No, PhotometricInterpretation::CMYK, BitsPerSample::new(bits).unwrap(), extra_samples).unwrap();
+        Image::new(header, data)
+    }
+
+    #[test]
+    fn cmyk_to_rgb_known_values() {
+        // paper, cyan, red, black, half black and a mix, each with an alpha sample
+        let data = vec![
+            0, 0, 0, 0, 10,
+            255, 0, 0, 0, 20,
+            0, 255, 255, 0, 30,
+            0, 0, 0, 255, 40,
+            0, 0, 0, 128, 50,
+            128, 64, 0, 64, 60,
+        ];
+        let image = cmyk(&[8; 5], vec![ExtraSample::UnassociatedAlpha], ImageData::U8(data)).cmyk_to_rgb();
+        assert_eq!(image.header().photometric_interpretation(), PhotometricInterpretation::RGB);
+        assert_eq!(image.header().bits_per_sample().values(), vec![8; 4]);
+        assert_eq!(image.data(), &ImageData::U8(vec![
+            255, 255, 255, 10,
+            0, 255, 255, 20,
+            255, 0, 0, 30,
+            0, 0, 0, 40,
+            127, 127, 127, 50,
+            95, 143, 191, 60,
+        ]));
+
+        let data = vec![65535, 0, 0, 0, 0, 0, 0, 32768, 0, 65535, 0, 0, 0, 0, 65535, 0, 0, 0, 0, 65535, 16384, 0, 0, 0];
+        let image = cmyk(&[16; 4], vec![], ImageData::U16(data)).cmyk_to_rgb();
+        assert_eq!(image.data(), &ImageData::U16(vec![0, 65535, 65535, 32767, 32767, 32767, 65535, 0, 65535, 65535, 65535, 0, 0, 0, 0, 49151, 65535, 65535]));
+
+        // anything else is left alone
+        let image = rgb(1, 1, vec![1, 2, 3]).cmyk_to_rgb();
+        assert_eq!(image.data(), &ImageData::U8(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn lab_to_rgb_reference_values() {
+        // the sRGB primaries and white in L*a*b*, relative to D65 and to D50 by the
+        // Bradford transform, as Bruce Lindbloom's calculator gives them
+        let cases = [
+            (WhitePoint::D65, [53.2408, 80.0925, 67.2032], [1., 0., 0.]),
+            (WhitePoint::D65, [87.7347, -86.1827, 83.1793], [0., 1., 0.]),
+            (WhitePoint::D65, [32.2970, 79.1875, -107.8602], [0., 0., 1.]),
+            (WhitePoint::D65, [100., 0., 0.], [1., 1., 1.]),
+            (WhitePoint::D50, [54.2917, 80.8124, 69.8851], [1., 0., 0.]),
+            (WhitePoint::D50, [87.8181, -79.2873, 80.9903], [0., 1., 0.]),
+            (WhitePoint::D50, [29.5676, 68.2986, -112.0294], [0., 0., 1.]),
+            (WhitePoint::D50, [100., 0., 0.], [1., 1., 1.]),
+            (WhitePoint::D50, [50., 0., 0.], [0.4663; 3]),
+        ];
+        for &(white, lab, rgb) in &cases {
+            let converted = LabToRgb::new(white).convert(lab[0], lab[1], lab[2]);
+            for (c, expected) in converted.iter().zip(&rgb) {
+                assert!((c - expected).abs() < 0.001, "{:?} {:?} to {:?}", white, lab, converted);
+            }
+        }
+
+        // 8-bit L*a*b* with signed a* and b*, and an alpha sample that is kept
+        let header = ImageHeader::new(3, 1, Compression::No, PhotometricInterpretation::CIELab, BitsPerSample::new([8; 4]).unwrap(), vec![ExtraSample::AssociatedAlpha]).unwrap();
+        let data = vec![255, 0, 0, 1, 0, 0, 0, 2, 138, 81, 70, 3];
+        let image = Image::new(header, ImageData::U8(data)).lab_to_rgb(WhitePoint::D50);
+        assert_eq!(image.header().photometric_interpretation(), PhotometricInterpretation::RGB);
+        assert_eq!(image.data(), &ImageData::U8(vec![255, 255, 255, 1, 0, 0, 0, 2, 255, 0, 0, 3]));
     }
 }
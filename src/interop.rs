@@ -0,0 +1,136 @@
+use std::convert::TryFrom;
+use std::io::{
+    Read,
+    Seek,
+};
+
+use failure::Fail;
+use image_crate::{
+    ColorType,
+    DynamicImage,
+    ImageBuffer,
+    ImageDecoder,
+    ImageError,
+    ImageFormat,
+    ImageResult,
+};
+use image_crate::error::{
+    DecodingError,
+    ImageFormatHint,
+};
+
+use decode::Decoder;
+use error::{
+    DecodeError,
+    DecodeErrorKind,
+    DecodeResult,
+};
+use image::{
+    ExtraSample,
+    Image,
+    ImageData,
+    PhotometricInterpretation,
+};
+
+// Gray images become Luma, RGB (including converted YCbCr and expanded palettes)
+// becomes Rgb, each with an alpha channel if they have one extra sample. 8-bit
+// and smaller samples are 8-bit, other integers 16-bit and floats 32-bit, scaled
+// as `Image::samples` does. Associated alpha is converted to the unassociated
+// alpha the image crate expects.
+impl TryFrom<Image> for DynamicImage {
+    type Error = DecodeError;
+
+    fn try_from(image: Image) -> DecodeResult<DynamicImage> {
+        let image = image.convert_alpha(ExtraSample::UnassociatedAlpha);
+        let header = image.header();
+        let (width, height) = (header.width(), header.height());
+        let samples = header.samples_per_pixel();
+        let gray = match (header.photometric_interpretation(), samples) {
+            (PhotometricInterpretation::WhiteIsZero, 1) | (PhotometricInterpretation::WhiteIsZero, 2) => true,
+            (PhotometricInterpretation::BlackIsZero, 1) | (PhotometricInterpretation::BlackIsZero, 2) => true,
+            (PhotometricInterpretation::TransparencyMask, 1) => true,
+            (PhotometricInterpretation::RGB, 3) | (PhotometricInterpretation::RGB, 4) => false,
+            (photometric_interpretation, samples) => {
+                let reason = format!("{:?} with {} samples per pixel", photometric_interpretation, samples);
+                return Err(DecodeError::from(DecodeErrorKind::UnsupportedColorType { reason }));
+            }
+        };
+        let alpha = samples == 2 || samples == 4;
+
+        let dynamic = match *image.data() {
+            ImageData::U8(_) | ImageData::Mask(_) => {
+                let data = image.samples::<u8>();
+                match (gray, alpha) {
+                    (true, false) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma8),
+                    (true, true) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA8),
+                    (false, false) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb8),
+                    (false, true) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba8),
+                }
+            }
+            ImageData::U16(_) | ImageData::I16(_) | ImageData::I32(_) => {
+                let data = image.samples::<u16>();
+                match (gray, alpha) {
+                    (true, false) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLuma16),
+                    (true, true) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageLumaA16),
+                    (false, false) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb16),
+                    (false, true) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba16),
+                }
+            }
+            ImageData::F32(_) | ImageData::F64(_) => {
+                let data = image.samples::<f32>();
+                match (gray, alpha) {
+                    (false, false) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb32F),
+                    (false, true) => ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba32F),
+                    (true, _) => return Err(DecodeError::from(DecodeErrorKind::UnsupportedColorType { reason: "gray floating point samples".to_string() })),
+                }
+            }
+        };
+
+        dynamic.ok_or_else(|| {
+            let calc = width as usize * height as usize * samples;
+            DecodeError::from(DecodeErrorKind::IncorrectBufferSize { calc, sum: image.data().len() })
+        })
+    }
+}
+
+// An `ImageDecoder` for the current image of a `Decoder`. The image crate wants
+// its dimensions and color type up front, so the image is decoded when this is
+// created and `read_image` copies it out.
+pub struct ImageCrateDecoder {
+    image: DynamicImage,
+}
+
+impl ImageCrateDecoder {
+    pub fn new<R: Read + Seek>(decoder: &mut Decoder<R>) -> DecodeResult<ImageCrateDecoder> {
+        let image = DynamicImage::try_from(decoder.image()?)?;
+
+        Ok(ImageCrateDecoder {
+            image,
+        })
+    }
+}
+
+impl ImageDecoder for ImageCrateDecoder {
+    fn dimensions(&self) -> (u32, u32) {
+        (self.image.width(), self.image.height())
+    }
+
+    fn color_type(&self) -> ColorType {
+        self.image.color()
+    }
+
+    fn read_image(self, buf: &mut [u8]) -> ImageResult<()> {
+        buf.copy_from_slice(self.image.as_bytes());
+        Ok(())
+    }
+
+    fn read_image_boxed(self: Box<Self>, buf: &mut [u8]) -> ImageResult<()> {
+        (*self).read_image(buf)
+    }
+}
+
+impl From<DecodeError> for ImageError {
+    fn from(error: DecodeError) -> ImageError {
+        ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(ImageFormat::Tiff), error.compat()))
+    }
+}
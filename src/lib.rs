@@ -1,29 +1,129 @@
-
 extern crate byteorder;
 extern crate lzw;
+extern crate flate2;
+extern crate jpeg_decoder;
+#[cfg(feature = "zstd")]
+extern crate zstd;
+#[cfg(feature = "image")]
+extern crate image_crate;
+#[cfg(feature = "ndarray")]
+extern crate ndarray;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "icc")]
+extern crate moxcms;
 #[macro_use] extern crate failure;
 
 mod error;
+#[cfg(feature = "ndarray")]
+mod array;
 mod byte;
+mod codec;
+mod ccitt;
 mod decode;
+mod encode;
+mod gps;
+mod header;
+#[cfg(feature = "icc")]
+mod icc;
 mod ifd;
 mod image;
+mod range;
+mod unseekable;
+#[cfg(feature = "image")]
+mod interop;
 pub mod tag;
+pub mod validate;
+#[cfg(feature = "tokio")]
+pub mod async_decode;
+#[cfg(any(test, feature = "testutil"))]
+pub mod testutil;
 
-pub use decode::Decoder;
-pub use ifd::IFD;
+pub use byte::Endian;
+pub use header::{
+    FileHeader,
+    sniff,
+};
+pub use decode::{
+    Decoder,
+    DecoderBuilder,
+    Images,
+    Level,
+    Limits,
+    Salvage,
+};
+pub use encode::{
+    Encoder,
+    EncoderBuilder,
+    OverviewPlacement,
+    EdgePadding,
+    PlannedPage,
+    SequentialEncoder,
+    copy_image,
+};
+pub use gps::Gps;
+pub use range::{
+    RangeReader,
+    CachedRanges,
+};
+pub use unseekable::Unseekable;
+pub use ifd::{
+    IFD,
+    Entry,
+    DataType,
+    AnyValues,
+    Rational,
+    SRational,
+};
+pub use codec::{
+    DecodeBytes,
+    EncodeBytes,
+    Uncompressed,
+    LZWDecoder,
+    LZWEncoder,
+    PackBitsDecoder,
+    PackBitsEncoder,
+    DeflateDecoder,
+    DeflateEncoder,
+    JpegDecoder,
+    CCITTDecoder,
+    CCITTFax4Encoder,
+};
+#[cfg(feature = "zstd")]
+pub use codec::ZstdDecoder;
+#[cfg(feature = "image")]
+pub use interop::ImageCrateDecoder;
 pub use error::{
     DecodeError,
     DecodeErrorKind,
+    DecodeErrorCategory,
     DecodeResult,
+    DecodeWarning,
+    ChunkProblem,
+    Position,
+    EncodeError,
+    EncodeErrorKind,
+    EncodeResult,
 };
 pub use image::{
     Image,
     ImageData,
+    Sample,
     ImageHeader,
     ImageHeaderError,
     Compression,
+    Predictor,
+    Resolution,
+    ResolutionUnit,
+    LengthUnit,
+    ExtraSample,
+    FillOrder,
+    Orientation,
+    PageKind,
+    PlanarConfiguration,
+    SampleFormat,
     BitsPerSample,
     BitsPerSampleError,
     PhotometricInterpretation,
+    WhitePoint,
 };
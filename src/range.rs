@@ -0,0 +1,208 @@
+use std::collections::BTreeMap;
+use std::io::{
+    self,
+    Read,
+    Seek,
+    SeekFrom,
+};
+
+use decode::{
+    Decoder,
+    DecoderBuilder,
+};
+use error::DecodeResult;
+
+// Reads shorter than `SMALL_READ`, those of the header and IFD fields, are
+// extended to `READ_AHEAD` bytes, so that an IFD and the values after it usually
+// arrive in one request. Strips and tiles are read as they are.
+pub const SMALL_READ: u64 = 4 * 1024;
+pub const READ_AHEAD: u64 = 64 * 1024;
+
+// How much to read for `len` bytes at `pos`, in a gap of the cache ending at `end`.
+pub fn fetch_len(pos: u64, len: u64, end: u64) -> u64 {
+    let len = if len < SMALL_READ { READ_AHEAD } else { len };
+    len.min(end - pos)
+}
+
+// Random access to a file by byte range, such as HTTP range requests to object
+// storage. `Decoder::from_range_reader` decodes from one.
+pub trait RangeReader {
+    // The size of the file.
+    fn len(&mut self) -> io::Result<u64>;
+
+    fn is_empty(&mut self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    // `len` bytes at `offset`. `offset + len` never passes the end of the file.
+    fn read_range(&mut self, offset: u64, len: u64) -> io::Result<Vec<u8>>;
+}
+
+// Parts of a file read so far. They never overlap.
+#[derive(Debug, Default)]
+pub struct Chunks(BTreeMap<u64, Vec<u8>>);
+
+impl Chunks {
+    // The bytes from `pos` to the end of the chunk holding it.
+    pub fn get(&self, pos: u64) -> Option<&[u8]> {
+        let (&start, chunk) = self.0.range(..=pos).next_back()?;
+        chunk.get((pos - start) as usize..).filter(|x| !x.is_empty())
+    }
+
+    pub fn insert(&mut self, start: u64, chunk: Vec<u8>) {
+        self.0.insert(start, chunk);
+    }
+
+    // Where the unread bytes from `pos` end: at the next chunk, or at `len`.
+    pub fn gap_end(&self, pos: u64, len: u64) -> u64 {
+        self.0.range(pos..).next().map(|(&start, _)| start).unwrap_or(len).min(len)
+    }
+}
+
+// `Read + Seek` over a `RangeReader`. Every range read is kept, so nothing is
+// requested twice; only the parts of the file the decoder looks at are read.
+#[derive(Debug)]
+pub struct CachedRanges<T> {
+    inner: T,
+    chunks: Chunks,
+    pos: u64,
+    len: u64,
+}
+
+impl<T: RangeReader> CachedRanges<T> {
+    pub fn new(mut inner: T) -> io::Result<CachedRanges<T>> {
+        let len = inner.len()?;
+        Ok(CachedRanges {
+            inner,
+            chunks: Chunks::default(),
+            pos: 0,
+            len,
+        })
+    }
+}
+
+impl<T: RangeReader> Read for CachedRanges<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.chunks.get(self.pos).is_none() {
+            let end = self.chunks.gap_end(self.pos, self.len);
+            let chunk = self.inner.read_range(self.pos, fetch_len(self.pos, buf.len() as u64, end))?;
+            if chunk.is_empty() {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            self.chunks.insert(self.pos, chunk);
+        }
+        let n = match self.chunks.get(self.pos) {
+            Some(bytes) => {
+                let n = bytes.len().min(buf.len());
+                buf[..n].copy_from_slice(&bytes[..n]);
+                n
+            }
+            None => 0,
+        };
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl<T> Seek for CachedRanges<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_to(pos, self.pos, self.len)?;
+        Ok(self.pos)
+    }
+}
+
+// The position `pos` moves to in a file of `len` bytes from `current`.
+pub fn seek_to(pos: SeekFrom, current: u64, len: u64) -> io::Result<u64> {
+    let pos = match pos {
+        SeekFrom::Start(n) => Some(n),
+        SeekFrom::End(n) => len.checked_add_signed(n),
+        SeekFrom::Current(n) => current.checked_add_signed(n),
+    };
+    pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek to a negative position"))
+}
+
+impl<T: RangeReader> Decoder<CachedRanges<T>> {
+    pub fn from_range_reader(reader: T) -> DecodeResult<Decoder<CachedRanges<T>>> {
+        DecoderBuilder::new().build_ranged(reader)
+    }
+}
+
+impl DecoderBuilder {
+    pub fn build_ranged<T: RangeReader>(self, reader: T) -> DecodeResult<Decoder<CachedRanges<T>>> {
+        self.build(CachedRanges::new(reader)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encode::EncoderBuilder;
+    use image::{
+        BitsPerSample,
+        Compression,
+        Image,
+        ImageData,
+        ImageHeader,
+        PhotometricInterpretation,
+    };
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+    use tag;
+
+    // A file in memory that logs the ranges asked of it.
+    struct Logged {
+        file: Vec<u8>,
+        log: Rc<RefCell<Vec<(u64, u64)>>>,
+    }
+
+    impl RangeReader for Logged {
+        fn len(&mut self) -> io::Result<u64> {
+            Ok(self.file.len() as u64)
+        }
+
+        fn read_range(&mut self, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+            self.log.borrow_mut().push((offset, len));
+            Ok(self.file[offset as usize..(offset + len) as usize].to_vec())
+        }
+    }
+
+    fn gray(width: u32, height: u32) -> Image {
+        let header = ImageHeader::new(width, height, Compression::No, PhotometricInterpretation::BlackIsZero, BitsPerSample::new([8]).unwrap(), vec![]).unwrap();
+        Image::new(header, ImageData::U8((0..width * height).map(|i| (i % 251) as u8).collect()))
+    }
+
+    #[test]
+    fn cog_range_requests() {
+        // 64 KiB tiles: four for the image, one for each overview
+        let image = gray(512, 512);
+        let overviews = [image.downsample_by(2), image.downsample_by(4)];
+        let file = EncoderBuilder::new().build(Cursor::new(vec![])).unwrap().encode_cog(&image, &overviews).unwrap().into_inner();
+        let mut whole = Decoder::new(Cursor::new(file.clone())).unwrap();
+        let ifd = whole.ifd().unwrap();
+        let tile_offsets = whole.get_value(&ifd, tag::TileOffsets).unwrap();
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut decoder = Decoder::from_range_reader(Logged { file, log: log.clone() }).unwrap();
+
+        // the header and every IFD arrive in the first request
+        assert_eq!(decoder.levels().unwrap().len(), 3);
+        assert_eq!(*log.borrow(), vec![(0, READ_AHEAD)]);
+
+        // a region within the second tile reads just that tile, once
+        let region = decoder.read_region_at_level(0, 300, 10, 20, 20).unwrap();
+        assert_eq!(region.data(), whole.read_region(300, 10, 20, 20).unwrap().data());
+        assert_eq!(log.borrow()[1..], [(tile_offsets[1], 256 * 256)]);
+        decoder.read_region_at_level(0, 300, 10, 20, 20).unwrap();
+        assert_eq!(log.borrow().len(), 2);
+
+        // the tile of the smallest overview begins within the first request
+        let smallest = decoder.read_region_at_level(2, 0, 0, 128, 128).unwrap();
+        assert_eq!(smallest.data(), overviews[1].data());
+        assert_eq!(log.borrow().len(), 3);
+        assert_eq!(log.borrow()[2].0, READ_AHEAD);
+    }
+}
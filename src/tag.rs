@@ -1,3 +1,5 @@
+// failure's derive puts its impls inside a const item next to the type.
+#![allow(non_local_definitions)]
 
 use std::fmt::{
     self,
@@ -11,11 +13,18 @@ use error::{
     DecodeResult,
     DecodeError,
     DecodeErrorKind,
+    DecodeWarning,
+};
+use ifd::{
+    self,
+    AnyValues,
+    DataType,
+    Rational,
 };
-use ifd::DataType;
 use byte::{
     Endian,
     EndianReadExt,
+    ReadExt,
     SeekExt,
 };
 
@@ -25,6 +34,12 @@ pub trait TagType: Clone + Copy {
     fn id(&self) -> u16;
     fn default_value() -> Option<Self::Value>;
     fn decode<'a, R: Read + Seek + 'a>(&'a self, reader: R, offset: &'a [u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<Self::Value>;
+
+    // Used instead of `decode` by a lenient decoder. Tags that can repair malformed
+    // values override it and report what they fixed through `warnings`.
+    fn decode_lenient<'a, R: Read + Seek + 'a>(&'a self, reader: R, offset: &'a [u8], endian: Endian, datatype: DataType, count: usize, _warnings: &mut Vec<DecodeWarning>) -> DecodeResult<Self::Value> {
+        self.decode(reader, offset, endian, datatype, count)
+    }
 }
 
 macro_rules! define_tags {
@@ -34,11 +49,11 @@ macro_rules! define_tags {
 
         impl Display for $name {
             fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-                write!(f, "{}", $name)
+                f.write_str(stringify!($name))
             }
         })*
         
-        #[derive(Debug, Fail)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Fail)]
         pub enum AnyTag {
             $($name,)*
             Unknown(u16),
@@ -51,13 +66,25 @@ macro_rules! define_tags {
                     AnyTag::Unknown(n) => n,
                 }
             }
+
+            // The name of the tag, from `REGISTERED_TAGS` for those the crate has
+            // no type for. `None` if it isn't a tag of TIFF 6.0, Exif or GeoTIFF.
+            pub fn name(&self) -> Option<&'static str> {
+                match *self {
+                    $(AnyTag::$name => Some(stringify!($name)),)*
+                    AnyTag::Unknown(n) => registered_name(n),
+                }
+            }
         }
 
         impl Display for AnyTag {
             fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
                 match *self {
                     $(AnyTag::$name => $name.fmt(f),)*
-                    AnyTag::Unknown(n) => write!(f, "Unknown tag: {}", n),
+                    AnyTag::Unknown(n) => match registered_name(n) {
+                        Some(name) => write!(f, "Tag {} ({})", n, name),
+                        None => write!(f, "Unknown tag: {}", n),
+                    },
                 }
             }
         }
@@ -82,19 +109,449 @@ macro_rules! define_tags {
     }
 }
 
-macro_rules! tag_short_or_long_value {
-    ($($name:ident, $id:expr, $def:expr;)*) => {
+// Names of the tags of TIFF 6.0, Exif 2.32 (with its GPS IFD) and GeoTIFF that
+// the crate has no type for, sorted by id. GPS tags share their ids with the
+// Exif interoperability IFD, and are the ones listed.
+pub const REGISTERED_TAGS: &[(u16, &str)] = &[
+    (0, "GPSVersionID"),
+    (8, "GPSSatellites"),
+    (9, "GPSStatus"),
+    (10, "GPSMeasureMode"),
+    (11, "GPSDOP"),
+    (12, "GPSSpeedRef"),
+    (13, "GPSSpeed"),
+    (14, "GPSTrackRef"),
+    (15, "GPSTrack"),
+    (16, "GPSImgDirectionRef"),
+    (17, "GPSImgDirection"),
+    (18, "GPSMapDatum"),
+    (19, "GPSDestLatitudeRef"),
+    (20, "GPSDestLatitude"),
+    (21, "GPSDestLongitudeRef"),
+    (22, "GPSDestLongitude"),
+    (23, "GPSDestBearingRef"),
+    (24, "GPSDestBearing"),
+    (25, "GPSDestDistanceRef"),
+    (26, "GPSDestDistance"),
+    (27, "GPSProcessingMethod"),
+    (28, "GPSAreaInformation"),
+    (30, "GPSDifferential"),
+    (31, "GPSHPositioningError"),
+    (263, "Threshholding"),
+    (264, "CellWidth"),
+    (265, "CellLength"),
+    (269, "DocumentName"),
+    (280, "MinSampleValue"),
+    (281, "MaxSampleValue"),
+    (285, "PageName"),
+    (286, "XPosition"),
+    (287, "YPosition"),
+    (288, "FreeOffsets"),
+    (289, "FreeByteCounts"),
+    (290, "GrayResponseUnit"),
+    (291, "GrayResponseCurve"),
+    (297, "PageNumber"),
+    (301, "TransferFunction"),
+    (316, "HostComputer"),
+    (318, "WhitePoint"),
+    (319, "PrimaryChromaticities"),
+    (321, "HalftoneHints"),
+    (326, "BadFaxLines"),
+    (327, "CleanFaxData"),
+    (328, "ConsecutiveBadFaxLines"),
+    (332, "InkSet"),
+    (333, "InkNames"),
+    (334, "NumberOfInks"),
+    (336, "DotRange"),
+    (337, "TargetPrinter"),
+    (340, "SMinSampleValue"),
+    (341, "SMaxSampleValue"),
+    (342, "TransferRange"),
+    (343, "ClipPath"),
+    (344, "XClipPathUnits"),
+    (345, "YClipPathUnits"),
+    (346, "Indexed"),
+    (351, "OPIProxy"),
+    (400, "GlobalParametersIFD"),
+    (401, "ProfileType"),
+    (402, "FaxProfile"),
+    (403, "CodingMethods"),
+    (404, "VersionYear"),
+    (405, "ModeNumber"),
+    (433, "Decode"),
+    (434, "DefaultImageColor"),
+    (512, "JPEGProc"),
+    (513, "JPEGInterchangeFormat"),
+    (514, "JPEGInterchangeFormatLength"),
+    (515, "JPEGRestartInterval"),
+    (517, "JPEGLosslessPredictors"),
+    (518, "JPEGPointTransforms"),
+    (519, "JPEGQTables"),
+    (520, "JPEGDCTables"),
+    (521, "JPEGACTables"),
+    (559, "StripRowCounts"),
+    (32781, "ImageID"),
+    (32995, "Matteing"),
+    (32996, "DataType"),
+    (32997, "ImageDepth"),
+    (32998, "TileDepth"),
+    (33421, "CFARepeatPatternDim"),
+    (33422, "CFAPattern"),
+    (33434, "ExposureTime"),
+    (33437, "FNumber"),
+    (33550, "ModelPixelScaleTag"),
+    (33723, "IPTC"),
+    (33920, "IntergraphMatrixTag"),
+    (33922, "ModelTiepointTag"),
+    (34264, "ModelTransformationTag"),
+    (34377, "Photoshop"),
+    (34732, "ImageLayer"),
+    (34735, "GeoKeyDirectoryTag"),
+    (34736, "GeoDoubleParamsTag"),
+    (34737, "GeoAsciiParamsTag"),
+    (34850, "ExposureProgram"),
+    (34852, "SpectralSensitivity"),
+    (34855, "PhotographicSensitivity"),
+    (34856, "OECF"),
+    (34864, "SensitivityType"),
+    (34865, "StandardOutputSensitivity"),
+    (34866, "RecommendedExposureIndex"),
+    (34867, "ISOSpeed"),
+    (34868, "ISOSpeedLatitudeyyy"),
+    (34869, "ISOSpeedLatitudezzz"),
+    (36864, "ExifVersion"),
+    (36867, "DateTimeOriginal"),
+    (36868, "DateTimeDigitized"),
+    (36880, "OffsetTime"),
+    (36881, "OffsetTimeOriginal"),
+    (36882, "OffsetTimeDigitized"),
+    (37121, "ComponentsConfiguration"),
+    (37122, "CompressedBitsPerPixel"),
+    (37377, "ShutterSpeedValue"),
+    (37378, "ApertureValue"),
+    (37379, "BrightnessValue"),
+    (37380, "ExposureBiasValue"),
+    (37381, "MaxApertureValue"),
+    (37382, "SubjectDistance"),
+    (37383, "MeteringMode"),
+    (37384, "LightSource"),
+    (37385, "Flash"),
+    (37386, "FocalLength"),
+    (37396, "SubjectArea"),
+    (37500, "MakerNote"),
+    (37510, "UserComment"),
+    (37520, "SubSecTime"),
+    (37521, "SubSecTimeOriginal"),
+    (37522, "SubSecTimeDigitized"),
+    (37724, "ImageSourceData"),
+    (37888, "Temperature"),
+    (37889, "Humidity"),
+    (37890, "Pressure"),
+    (37891, "WaterDepth"),
+    (37892, "Acceleration"),
+    (37893, "CameraElevationAngle"),
+    (40091, "XPTitle"),
+    (40092, "XPComment"),
+    (40093, "XPAuthor"),
+    (40094, "XPKeywords"),
+    (40095, "XPSubject"),
+    (40960, "FlashpixVersion"),
+    (40961, "ColorSpace"),
+    (40962, "PixelXDimension"),
+    (40963, "PixelYDimension"),
+    (40964, "RelatedSoundFile"),
+    (40965, "InteroperabilityIFD"),
+    (41483, "FlashEnergy"),
+    (41484, "SpatialFrequencyResponse"),
+    (41486, "FocalPlaneXResolution"),
+    (41487, "FocalPlaneYResolution"),
+    (41488, "FocalPlaneResolutionUnit"),
+    (41492, "SubjectLocation"),
+    (41493, "ExposureIndex"),
+    (41495, "SensingMethod"),
+    (41728, "FileSource"),
+    (41729, "SceneType"),
+    (41730, "ExifCFAPattern"),
+    (41985, "CustomRendered"),
+    (41986, "ExposureMode"),
+    (41987, "WhiteBalance"),
+    (41988, "DigitalZoomRatio"),
+    (41989, "FocalLengthIn35mmFilm"),
+    (41990, "SceneCaptureType"),
+    (41991, "GainControl"),
+    (41992, "Contrast"),
+    (41993, "Saturation"),
+    (41994, "Sharpness"),
+    (41995, "DeviceSettingDescription"),
+    (41996, "SubjectDistanceRange"),
+    (42016, "ImageUniqueID"),
+    (42032, "CameraOwnerName"),
+    (42033, "BodySerialNumber"),
+    (42034, "LensSpecification"),
+    (42035, "LensMake"),
+    (42036, "LensModel"),
+    (42037, "LensSerialNumber"),
+    (42080, "CompositeImage"),
+    (42112, "GDAL_METADATA"),
+    (42113, "GDAL_NODATA"),
+    (42240, "Gamma"),
+];
+
+fn registered_name(id: u16) -> Option<&'static str> {
+    REGISTERED_TAGS.binary_search_by_key(&id, |x| x.0).ok().map(|i| REGISTERED_TAGS[i].1)
+}
+
+// How a tag's value is read from its entry. Each type stands for the datatypes a
+// tag of that kind may use, so a tag picks its datatypes by picking its `Value`.
+pub trait TagValue: Sized {
+    fn decode_value<R: Read + Seek>(tag: AnyTag, reader: R, offset: &[u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<Self>;
+
+    fn decode_value_lenient<R: Read + Seek>(tag: AnyTag, reader: R, offset: &[u8], endian: Endian, datatype: DataType, count: usize, _warnings: &mut Vec<DecodeWarning>) -> DecodeResult<Self> {
+        Self::decode_value(tag, reader, offset, endian, datatype, count)
+    }
+}
+
+fn unsupported<T>(tag: AnyTag, datatype: DataType, count: usize) -> DecodeResult<T> {
+    Err(DecodeError::from(DecodeErrorKind::NoSupportDataType { tag, datatype, count }))
+}
+
+// SHORT
+impl TagValue for u16 {
+    fn decode_value<R: Read + Seek>(tag: AnyTag, _reader: R, mut offset: &[u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<u16> {
+        match datatype {
+            DataType::Short if count == 1 => Ok(offset.read_u16(endian)?),
+            _ => unsupported(tag, datatype, count),
+        }
+    }
+}
+
+// SHORT or LONG
+impl TagValue for u32 {
+    fn decode_value<R: Read + Seek>(tag: AnyTag, _reader: R, mut offset: &[u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<u32> {
+        match datatype {
+            DataType::Short if count == 1 => Ok(offset.read_u16(endian)? as u32),
+            DataType::Long if count == 1 => Ok(offset.read_u32(endian)?),
+            _ => unsupported(tag, datatype, count),
+        }
+    }
+}
+
+// an offset: LONG or IFD, LONG8 or IFD8 in BigTIFF
+impl TagValue for u64 {
+    fn decode_value<R: Read + Seek>(tag: AnyTag, _reader: R, mut offset: &[u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<u64> {
+        match datatype {
+            DataType::Long | DataType::IFD if count == 1 => Ok(offset.read_u32(endian)? as u64),
+            DataType::Long8 | DataType::IFD8 if count == 1 => Ok(offset.read_u64(endian)?),
+            _ => unsupported(tag, datatype, count),
+        }
+    }
+}
+
+// SHORTs
+impl TagValue for Vec<u16> {
+    fn decode_value<R: Read + Seek>(tag: AnyTag, reader: R, offset: &[u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<Vec<u16>> {
+        match datatype {
+            DataType::Short if count >= 1 => {
+                let bytes = value_bytes(tag, reader, offset, endian, datatype, count)?;
+                let mut bytes = &bytes[..];
+                (0..count).map(|_| Ok(bytes.read_u16(endian)?)).collect()
+            }
+            _ => unsupported(tag, datatype, count),
+        }
+    }
+}
+
+// SHORTs or LONGs, chosen for the array as a whole
+impl TagValue for Vec<u32> {
+    fn decode_value<R: Read + Seek>(tag: AnyTag, reader: R, offset: &[u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<Vec<u32>> {
+        match datatype {
+            DataType::Short if count >= 1 => {
+                let bytes = value_bytes(tag, reader, offset, endian, datatype, count)?;
+                let mut bytes = &bytes[..];
+                (0..count).map(|_| Ok(bytes.read_u16(endian)? as u32)).collect()
+            }
+            DataType::Long if count >= 1 => {
+                let bytes = value_bytes(tag, reader, offset, endian, datatype, count)?;
+                let mut bytes = &bytes[..];
+                (0..count).map(|_| Ok(bytes.read_u32(endian)?)).collect()
+            }
+            _ => unsupported(tag, datatype, count),
+        }
+    }
+}
+
+// SHORTs, LONGs or IFDs, and LONG8s or IFD8s in BigTIFF
+impl TagValue for Vec<u64> {
+    fn decode_value<R: Read + Seek>(tag: AnyTag, reader: R, offset: &[u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<Vec<u64>> {
+        match datatype {
+            DataType::Short if count >= 1 => {
+                let bytes = value_bytes(tag, reader, offset, endian, datatype, count)?;
+                let mut bytes = &bytes[..];
+                (0..count).map(|_| Ok(bytes.read_u16(endian)? as u64)).collect()
+            }
+            DataType::Long | DataType::IFD if count >= 1 => {
+                let bytes = value_bytes(tag, reader, offset, endian, datatype, count)?;
+                let mut bytes = &bytes[..];
+                (0..count).map(|_| Ok(bytes.read_u32(endian)? as u64)).collect()
+            }
+            DataType::Long8 | DataType::IFD8 if count >= 1 => {
+                let bytes = value_bytes(tag, reader, offset, endian, datatype, count)?;
+                let mut bytes = &bytes[..];
+                (0..count).map(|_| Ok(bytes.read_u64(endian)?)).collect()
+            }
+            _ => unsupported(tag, datatype, count),
+        }
+    }
+}
+
+impl TagValue for Rational {
+    fn decode_value<R: Read + Seek>(tag: AnyTag, reader: R, offset: &[u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<Rational> {
+        match datatype {
+            DataType::Rational if count == 1 => {
+                let bytes = value_bytes(tag, reader, offset, endian, datatype, count)?;
+                let mut bytes = &bytes[..];
+                let numerator = bytes.read_u32(endian)?;
+                let denominator = bytes.read_u32(endian)?;
+
+                Ok(Rational::new(numerator, denominator))
+            }
+            _ => unsupported(tag, datatype, count),
+        }
+    }
+}
+
+impl TagValue for Vec<Rational> {
+    fn decode_value<R: Read + Seek>(tag: AnyTag, reader: R, offset: &[u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<Vec<Rational>> {
+        match datatype {
+            DataType::Rational if count >= 1 => {
+                let bytes = value_bytes(tag, reader, offset, endian, datatype, count)?;
+                let mut bytes = &bytes[..];
+                (0..count).map(|_| {
+                    let numerator = bytes.read_u32(endian)?;
+                    let denominator = bytes.read_u32(endian)?;
+                    Ok(Rational::new(numerator, denominator))
+                }).collect()
+            }
+            _ => unsupported(tag, datatype, count),
+        }
+    }
+}
+
+// BYTEs or UNDEFINED
+impl TagValue for Vec<u8> {
+    fn decode_value<R: Read + Seek>(tag: AnyTag, reader: R, offset: &[u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<Vec<u8>> {
+        match datatype {
+            DataType::Byte | DataType::Undefined => value_bytes(tag, reader, offset, endian, datatype, count),
+            _ => unsupported(tag, datatype, count),
+        }
+    }
+}
+
+// ASCII; a lenient decoder repairs a missing NUL and non-ASCII text
+impl TagValue for String {
+    fn decode_value<R: Read + Seek>(tag: AnyTag, reader: R, offset: &[u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<String> {
+        let bytes = read_ascii_bytes(tag, reader, offset, endian, datatype, count)?;
+        decode_ascii(tag, bytes)
+    }
+
+    fn decode_value_lenient<R: Read + Seek>(tag: AnyTag, reader: R, offset: &[u8], endian: Endian, datatype: DataType, count: usize, warnings: &mut Vec<DecodeWarning>) -> DecodeResult<String> {
+        let bytes = read_ascii_bytes(tag, reader, offset, endian, datatype, count)?;
+        Ok(decode_ascii_lenient(tag, bytes, warnings))
+    }
+}
+
+// How a tag's value is written: as the narrowest datatype its `TagValue` reads
+// that holds it.
+pub trait IntoAnyValues {
+    fn into_any_values(self) -> AnyValues;
+}
+
+impl IntoAnyValues for u16 {
+    fn into_any_values(self) -> AnyValues {
+        AnyValues::Short(vec![self])
+    }
+}
+
+impl IntoAnyValues for u32 {
+    fn into_any_values(self) -> AnyValues {
+        vec![self].into_any_values()
+    }
+}
+
+impl IntoAnyValues for u64 {
+    fn into_any_values(self) -> AnyValues {
+        match self {
+            n if n <= u32::MAX as u64 => AnyValues::Long(vec![n as u32]),
+            n => AnyValues::Long8(vec![n]),
+        }
+    }
+}
+
+impl IntoAnyValues for Vec<u16> {
+    fn into_any_values(self) -> AnyValues {
+        AnyValues::Short(self)
+    }
+}
+
+impl IntoAnyValues for Vec<u32> {
+    fn into_any_values(self) -> AnyValues {
+        match DataType::short_or_long(&self) {
+            DataType::Short => AnyValues::Short(self.into_iter().map(|x| x as u16).collect()),
+            _ => AnyValues::Long(self),
+        }
+    }
+}
+
+impl IntoAnyValues for Vec<u64> {
+    fn into_any_values(self) -> AnyValues {
+        if self.iter().all(|x| *x <= u32::MAX as u64) {
+            self.into_iter().map(|x| x as u32).collect::<Vec<_>>().into_any_values()
+        } else {
+            AnyValues::Long8(self)
+        }
+    }
+}
+
+impl IntoAnyValues for Rational {
+    fn into_any_values(self) -> AnyValues {
+        AnyValues::Rational(vec![self])
+    }
+}
+
+impl IntoAnyValues for Vec<Rational> {
+    fn into_any_values(self) -> AnyValues {
+        AnyValues::Rational(self)
+    }
+}
+
+impl IntoAnyValues for Vec<u8> {
+    fn into_any_values(self) -> AnyValues {
+        AnyValues::Byte(self)
+    }
+}
+
+impl IntoAnyValues for String {
+    fn into_any_values(self) -> AnyValues {
+        let mut bytes = self.into_bytes();
+        bytes.push(0);
+        AnyValues::Ascii(bytes)
+    }
+}
+
+// Implements `TagType` for tags defined with `define_tags!`, reading each value
+// as its `TagValue`.
+macro_rules! tag_values {
+    ($($name:ident, $id:expr, $value:ty, $def:expr;)*) => {
         $(impl TagType for $name {
-            type Value = u32;
+            type Value = $value;
 
             fn id(&self) -> u16 { $id }
-            fn default_value() -> Option<u32> { $def }
-            fn decode<'a, R: Read + Seek + 'a>(&'a self, mut _reader: R, mut offset: &'a [u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<Self::Value> {
-                match datatype {
-                    DataType::Short if count == 1 => Ok(offset.read_u16(endian)? as u32),
-                    DataType::Long if count == 1 => Ok(offset.read_u32(endian)?),
-                    _ => Err(DecodeError::from(DecodeErrorKind::NoSupportDataType { tag: AnyTag::from(*self), datatype: datatype, count: count })),
-                }
+            fn default_value() -> Option<$value> { $def }
+            fn decode<'a, R: Read + Seek + 'a>(&'a self, reader: R, offset: &'a [u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<Self::Value> {
+                <$value as TagValue>::decode_value(AnyTag::from(*self), reader, offset, endian, datatype, count)
+            }
+            fn decode_lenient<'a, R: Read + Seek + 'a>(&'a self, reader: R, offset: &'a [u8], endian: Endian, datatype: DataType, count: usize, warnings: &mut Vec<DecodeWarning>) -> DecodeResult<Self::Value> {
+                <$value as TagValue>::decode_value_lenient(AnyTag::from(*self), reader, offset, endian, datatype, count, warnings)
             }
         })*
     };
@@ -102,125 +559,331 @@ macro_rules! tag_short_or_long_value {
 
 macro_rules! tag_short_value {
     ($($name:ident, $id:expr, $def:expr;)*) => {
-        $(impl TagType for $name {
-            type Value = u16;
+        tag_values! { $($name, $id, u16, $def;)* }
+    };
+}
 
-            fn id(&self) -> u16 { $id }
-            fn default_value() -> Option<u16> { $def }
-            fn decode<'a, R: Read + Seek + 'a>(&'a self, mut _reader: R, mut offset: &'a [u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<Self::Value> {
-                match datatype {
-                    DataType::Short if count == 1 => Ok(offset.read_u16(endian)?),
-                    _ => Err(DecodeError::from(DecodeErrorKind::NoSupportDataType { tag: AnyTag::from(*self), datatype: datatype, count: count })),
-                }
-            }
-        })*
+macro_rules! tag_short_or_long_value {
+    ($($name:ident, $id:expr, $def:expr;)*) => {
+        tag_values! { $($name, $id, u32, $def;)* }
+    };
+}
+
+macro_rules! tag_ifd_value {
+    ($($name:ident, $id:expr, $def:expr;)*) => {
+        tag_values! { $($name, $id, u64, $def;)* }
+    };
+}
+
+macro_rules! tag_short_values {
+    ($($name:ident, $id:expr, $def:expr;)*) => {
+        tag_values! { $($name, $id, Vec<u16>, $def;)* }
     };
 }
 
 macro_rules! tag_short_or_long_values {
     ($($name:ident, $id:expr, $def:expr;)*) => {
-        $(impl TagType for $name {
-            type Value = Vec<u32>;
+        tag_values! { $($name, $id, Vec<u64>, $def;)* }
+    };
+}
 
-            fn id(&self) -> u16 { $id }
-            fn default_value() -> Option<Vec<u32>> { $def }
-            fn decode<'a, R: Read + Seek + 'a>(&'a self, mut reader: R, mut offset: &'a [u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<Self::Value> {
-                match datatype {
-                    DataType::Short if count == 1 => Ok(vec![offset.read_u16(endian)? as u32]),
-                    DataType::Short if count == 2 => Ok(vec![
-                        offset.read_u16(endian)? as u32,
-                        offset.read_u16(endian)? as u32,
-                    ]),
-                    DataType::Short if count > 2 => {
-                        let offset = offset.read_u32(endian)? as u64;
-                        reader.goto(offset)?;
-                        let mut v = Vec::with_capacity(count);
-                        for _ in 0..count {
-                            v.push(reader.read_u16(endian)? as u32);
-                        }
-
-                        Ok(v)
-                    }
-                    DataType::Long if count == 1 => Ok(vec![offset.read_u32(endian)?]),
-                    DataType::Long if count > 1 => {
-                        let offset = offset.read_u32(endian)? as u64;
-                        reader.goto(offset)?;
-                        let mut v = Vec::with_capacity(count);
-                        for _ in 0..count {
-                            v.push(reader.read_u32(endian)?);
-                        }
-
-                        Ok(v)
-                    }
-                    _ => Err(DecodeError::from(DecodeErrorKind::NoSupportDataType { tag: AnyTag::from(*self), datatype: datatype, count: count })),
-                }
-            }
-        })*
+macro_rules! tag_rational_value {
+    ($($name:ident, $id:expr, $def:expr;)*) => {
+        tag_values! { $($name, $id, Rational, $def;)* }
     };
 }
 
-macro_rules! tag_short_values {
+macro_rules! tag_rational_values {
     ($($name:ident, $id:expr, $def:expr;)*) => {
-        $(impl TagType for $name {
-            type Value = Vec<u16>;
+        tag_values! { $($name, $id, Vec<Rational>, $def;)* }
+    };
+}
+
+macro_rules! tag_bytes_value {
+    ($($name:ident, $id:expr, $def:expr;)*) => {
+        tag_values! { $($name, $id, Vec<u8>, $def;)* }
+    };
+}
+
+macro_rules! tag_ascii_value {
+    ($($name:ident, $id:expr, $def:expr;)*) => {
+        tag_values! { $($name, $id, String, $def;)* }
+    };
+}
+
+// Defines a tag the crate doesn't know, such as a private one, for use with
+// `Decoder::get_value`. `Value` is any `TagValue`, which decides the datatypes
+// the entry may have:
+//
+//     define_tag!(MyTag, 65001, Value = Vec<u32>);
+//     define_tag!(MyFlag, 65002, Value = u16, default = Some(0));
+#[macro_export]
+macro_rules! define_tag {
+    ($name:ident, $id:expr, Value = $value:ty) => {
+        define_tag!($name, $id, Value = $value, default = None);
+    };
+    ($name:ident, $id:expr, Value = $value:ty, default = $def:expr) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub struct $name;
+
+        impl $crate::tag::TagType for $name {
+            type Value = $value;
 
             fn id(&self) -> u16 { $id }
-            fn default_value() -> Option<Vec<u16>> { $def }
-            fn decode<'a, R: Read + Seek + 'a>(&'a self, mut reader: R, mut offset: &'a [u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<Self::Value> {
-                match datatype {
-                    DataType::Short if count == 1 => Ok(vec![offset.read_u16(endian)?]),
-                    DataType::Short if count == 2 => Ok(vec![
-                        offset.read_u16(endian)?,
-                        offset.read_u16(endian)?,
-                    ]),
-                    DataType::Short if count > 2 => {
-                        let offset = offset.read_u32(endian)? as u64;
-                        reader.goto(offset)?;
-                        let mut v = Vec::with_capacity(count);
-                        for _ in 0..count {
-                            v.push(reader.read_u16(endian)?);
-                        }
-
-                        Ok(v)
-                    }
-                    _ => Err(DecodeError::from(DecodeErrorKind::NoSupportDataType { tag: AnyTag::from(*self), datatype: datatype, count: count })),
-                }
+            fn default_value() -> Option<$value> { $def }
+            fn decode<'a, R: ::std::io::Read + ::std::io::Seek + 'a>(&'a self, reader: R, offset: &'a [u8], endian: $crate::Endian, datatype: $crate::DataType, count: usize) -> $crate::DecodeResult<$value> {
+                <$value as $crate::tag::TagValue>::decode_value($crate::tag::AnyTag::from(*self), reader, offset, endian, datatype, count)
             }
-        })*
+            fn decode_lenient<'a, R: ::std::io::Read + ::std::io::Seek + 'a>(&'a self, reader: R, offset: &'a [u8], endian: $crate::Endian, datatype: $crate::DataType, count: usize, warnings: &mut Vec<$crate::DecodeWarning>) -> $crate::DecodeResult<$value> {
+                <$value as $crate::tag::TagValue>::decode_value_lenient($crate::tag::AnyTag::from(*self), reader, offset, endian, datatype, count, warnings)
+            }
+        }
     };
 }
 
+// The bytes of `count` values, taken from the value field when they fit there
+// (4 bytes in TIFF, 8 in BigTIFF) and read from where it points otherwise.
+fn value_bytes<R: Read + Seek>(tag: AnyTag, mut reader: R, offset: &[u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<Vec<u8>> {
+    let len = match count.checked_mul(datatype.size_in_bytes()) {
+        Some(len) => len,
+        None => return Err(DecodeError::from(DecodeErrorKind::NoSupportDataType { tag, datatype, count })),
+    };
+    if len <= offset.len() {
+        return Ok(offset[..len].to_vec());
+    }
+
+    reader.goto(ifd::read_offset(offset, endian)?)?;
+    Ok(reader.read_bytes(len)?)
+}
+
+fn read_ascii_bytes<R: Read + Seek>(tag: AnyTag, reader: R, offset: &[u8], endian: Endian, datatype: DataType, count: usize) -> DecodeResult<Vec<u8>> {
+    match datatype {
+        DataType::Ascii => value_bytes(tag, reader, offset, endian, datatype, count),
+        _ => unsupported(tag, datatype, count),
+    }
+}
+
+// An ASCII value is 7-bit text terminated by NUL. Strict decoding accepts nothing else.
+fn decode_ascii(tag: AnyTag, mut bytes: Vec<u8>) -> DecodeResult<String> {
+    match bytes.pop() {
+        Some(0) => {},
+        _ => return Err(DecodeError::from(DecodeErrorKind::InvalidAscii { tag, reason: "missing NUL terminator".to_string() })),
+    }
+    if let Some(x) = bytes.iter().find(|x| !x.is_ascii()) {
+        return Err(DecodeError::from(DecodeErrorKind::InvalidAscii { tag, reason: format!("non-ASCII byte 0x{:02X}", x) }));
+    }
+
+    Ok(bytes.into_iter().map(char::from).collect())
+}
+
+fn decode_ascii_lenient(tag: AnyTag, mut bytes: Vec<u8>, warnings: &mut Vec<DecodeWarning>) -> String {
+    match bytes.iter().rposition(|x| *x == 0) {
+        Some(nul) => {
+            // drop whatever follows the last terminator, and terminators that pad it
+            let mut end = nul;
+            while end > 0 && bytes[end - 1] == 0 {
+                end -= 1;
+            }
+            if end + 1 != bytes.len() {
+                warnings.push(DecodeWarning::AsciiPadding { tag, bytes: bytes.len() - end - 1 });
+            }
+            bytes.truncate(end);
+        }
+        None => warnings.push(DecodeWarning::AsciiMissingNul { tag }),
+    }
+
+    if bytes.is_ascii() {
+        return bytes.into_iter().map(char::from).collect();
+    }
+
+    match String::from_utf8(bytes) {
+        Ok(s) => {
+            warnings.push(DecodeWarning::AsciiNonAscii { tag, encoding: "UTF-8" });
+            s
+        }
+        Err(e) => {
+            warnings.push(DecodeWarning::AsciiNonAscii { tag, encoding: "Latin-1" });
+            e.into_bytes().into_iter().map(char::from).collect()
+        }
+    }
+}
+
 define_tags! {
+    // only found in the GPS IFD
+    GPSLatitudeRef, 1;
+    GPSLatitude, 2;
+    GPSLongitudeRef, 3;
+    GPSLongitude, 4;
+    GPSAltitudeRef, 5;
+    GPSAltitude, 6;
+    GPSTimeStamp, 7;
+    GPSDateStamp, 29;
+    NewSubfileType, 254;
+    SubfileType, 255;
     ImageWidth, 256;
     ImageLength, 257;
     BitsPerSample, 258;
     Compression, 259;
     PhotometricInterpretation, 262;
+    FillOrder, 266;
+    ImageDescription, 270;
+    Make, 271;
+    Model, 272;
     StripOffsets, 273;
+    Orientation, 274;
     SamplesPerPixel, 277;
     RowsPerStrip, 278;
     StripByteCounts, 279;
+    XResolution, 282;
+    YResolution, 283;
+    PlanarConfiguration, 284;
+    T4Options, 292;
+    T6Options, 293;
+    ResolutionUnit, 296;
+    Software, 305;
+    DateTime, 306;
+    Artist, 315;
+    Predictor, 317;
+    ColorMap, 320;
+    TileWidth, 322;
+    TileLength, 323;
+    TileOffsets, 324;
+    TileByteCounts, 325;
+    SubIFDs, 330;
+    ExtraSamples, 338;
+    SampleFormat, 339;
+    JPEGTables, 347;
+    XMP, 700;
+    YCbCrCoefficients, 529;
+    YCbCrSubSampling, 530;
+    YCbCrPositioning, 531;
+    ReferenceBlackWhite, 532;
+    Copyright, 33432;
+    ExifIFD, 34665;
+    ICCProfile, 34675;
+    GPSIFD, 34853;
 }
 
+// The spec permits SHORT or LONG for these; writers pick with `DataType::short_or_long`.
 tag_short_or_long_value! {
+    NewSubfileType, 254, Some(0);
     ImageWidth, 256, None;
     ImageLength, 257, None;
-    RowsPerStrip, 278, Some(u32::max_value());
+    RowsPerStrip, 278, Some(u32::MAX);
+    T4Options, 292, Some(0);
+    T6Options, 293, Some(0);
+    TileWidth, 322, None;
+    TileLength, 323, None;
 }
 
+// SHORT or LONG as well (SubIFDs may also be IFD), chosen for the array as a whole.
+// BigTIFF files may use LONG8/IFD8 instead.
 tag_short_or_long_values! {
     StripOffsets, 273, None;
     StripByteCounts, 279, None;
+    TileOffsets, 324, None;
+    TileByteCounts, 325, None;
+    SubIFDs, 330, None;
+}
+
+// Offsets of secondary IFDs, LONG or IFD (LONG8 or IFD8 in BigTIFF).
+tag_ifd_value! {
+    ExifIFD, 34665, None;
+    GPSIFD, 34853, None;
 }
 
 tag_short_value! {
+    // superseded by NewSubfileType
+    SubfileType, 255, None;
     PhotometricInterpretation, 262, None;
     Compression, 259, Some(1);
+    FillOrder, 266, Some(1);
+    Orientation, 274, Some(1);
+    Predictor, 317, Some(1);
     SamplesPerPixel, 277, Some(1);
+    PlanarConfiguration, 284, Some(1);
+    ResolutionUnit, 296, Some(2);
+    YCbCrPositioning, 531, Some(1);
 }
 
 tag_short_values! {
     BitsPerSample, 258, Some(vec![1]);
+    ColorMap, 320, None;
+    ExtraSamples, 338, Some(vec![]);
+    SampleFormat, 339, Some(vec![1]);
+    YCbCrSubSampling, 530, Some(vec![2, 2]);
+}
+
+tag_rational_value! {
+    XResolution, 282, None;
+    YResolution, 283, None;
+    GPSAltitude, 6, None;
+}
+
+// ReferenceBlackWhite's default depends on the PhotometricInterpretation.
+tag_rational_values! {
+    YCbCrCoefficients, 529, Some(vec![Rational::new(299, 1000), Rational::new(587, 1000), Rational::new(114, 1000)]);
+    ReferenceBlackWhite, 532, None;
+    GPSLatitude, 2, None;
+    GPSLongitude, 4, None;
+    GPSTimeStamp, 7, None;
+}
+
+tag_bytes_value! {
+    GPSAltitudeRef, 5, None;
+    JPEGTables, 347, None;
+    XMP, 700, None;
+    ICCProfile, 34675, None;
 }
 
+tag_ascii_value! {
+    GPSLatitudeRef, 1, None;
+    GPSLongitudeRef, 3, None;
+    GPSDateStamp, 29, None;
+    ImageDescription, 270, None;
+    Make, 271, None;
+    Model, 272, None;
+    Software, 305, None;
+    // "YYYY:MM:DD HH:MM:SS"
+    DateTime, 306, None;
+    Artist, 315, None;
+    Copyright, 33432, None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ImageDescription as a flatbed scanner wrote it: Latin-1, NUL, then padding
+    const SCANNER_DESCRIPTION: &[u8] = b"Scanned 600 dpi, lamp 40\xB0C\0\0\x20";
 
+    #[test]
+    fn strict_ascii() {
+        let tag = AnyTag::ImageDescription;
+        assert_eq!(decode_ascii(tag, b"plain\0".to_vec()).unwrap(), "plain");
+        assert!(decode_ascii(tag, b"plain".to_vec()).is_err());
+        assert!(decode_ascii(tag, SCANNER_DESCRIPTION.to_vec()).is_err());
+    }
+
+    #[test]
+    fn lenient_ascii() {
+        let tag = AnyTag::ImageDescription;
+        let mut warnings = vec![];
+        assert_eq!(decode_ascii_lenient(tag, SCANNER_DESCRIPTION.to_vec(), &mut warnings), "Scanned 600 dpi, lamp 40\u{B0}C");
+        assert_eq!(warnings, [
+            DecodeWarning::AsciiPadding { tag, bytes: 2 },
+            DecodeWarning::AsciiNonAscii { tag, encoding: "Latin-1" },
+        ]);
+
+        let mut warnings = vec![];
+        assert_eq!(decode_ascii_lenient(tag, "40\u{B0}C".as_bytes().to_vec(), &mut warnings), "40\u{B0}C");
+        assert_eq!(warnings, [
+            DecodeWarning::AsciiMissingNul { tag },
+            DecodeWarning::AsciiNonAscii { tag, encoding: "UTF-8" },
+        ]);
+
+        let mut warnings = vec![];
+        assert_eq!(decode_ascii_lenient(tag, b"plain\0".to_vec(), &mut warnings), "plain");
+        assert!(warnings.is_empty());
+    }
+}
@@ -0,0 +1,436 @@
+
+// Builds small TIFF files byte by byte for tests and fuzzing. It doesn't go through
+// any encoder, so it can produce files to test the encoder against, and it can
+// produce broken ones on purpose: wrong counts, bad offsets, truncation and IFD
+// cycles.
+
+use byte::Endian;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Byte(Vec<u8>),
+    // written with a trailing NUL
+    Ascii(String),
+    Short(Vec<u16>),
+    Long(Vec<u32>),
+    Rational(Vec<(u32, u32)>),
+    Long8(Vec<u64>),
+    // any datatype, with `bytes` already in the file's byte order
+    Raw { datatype: u16, count: u32, bytes: Vec<u8> },
+}
+
+impl Value {
+    fn encode(&self, endian: Endian) -> (u16, u32, Vec<u8>) {
+        let mut bytes = vec![];
+        match *self {
+            Value::Byte(ref v) => (1, v.len() as u32, v.clone()),
+            Value::Ascii(ref s) => {
+                bytes.extend_from_slice(s.as_bytes());
+                bytes.push(0);
+                (2, bytes.len() as u32, bytes)
+            }
+            Value::Short(ref v) => {
+                for x in v {
+                    push_u16(&mut bytes, *x, endian);
+                }
+                (3, v.len() as u32, bytes)
+            }
+            Value::Long(ref v) => {
+                for x in v {
+                    push_u32(&mut bytes, *x, endian);
+                }
+                (4, v.len() as u32, bytes)
+            }
+            Value::Rational(ref v) => {
+                for &(numerator, denominator) in v {
+                    push_u32(&mut bytes, numerator, endian);
+                    push_u32(&mut bytes, denominator, endian);
+                }
+                (5, v.len() as u32, bytes)
+            }
+            Value::Long8(ref v) => {
+                for x in v {
+                    push_u64(&mut bytes, *x, endian);
+                }
+                (16, v.len() as u32, bytes)
+            }
+            Value::Raw { datatype, count, ref bytes } => (datatype, count, bytes.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PageEntry {
+    tag: u16,
+    value: Value,
+    count: Option<u32>,
+    offset: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Page {
+    entries: Vec<PageEntry>,
+    strips: Option<Vec<Vec<u8>>>,
+    tiles: Option<Vec<Vec<u8>>>,
+}
+
+impl Page {
+    pub fn new() -> Page {
+        Page::default()
+    }
+
+    // An 8-bit BlackIsZero page stored as a single uncompressed strip.
+    pub fn gray8(width: u32, height: u32, pixels: Vec<u8>) -> Page {
+        Page::new()
+            .tag(256, Value::Long(vec![width]))
+            .tag(257, Value::Long(vec![height]))
+            .tag(258, Value::Short(vec![8]))
+            .tag(259, Value::Short(vec![1]))
+            .tag(262, Value::Short(vec![1]))
+            .tag(278, Value::Long(vec![height]))
+            .strips(vec![pixels])
+    }
+
+    // Sets a tag, replacing an earlier value for the same id.
+    pub fn tag(mut self, tag: u16, value: Value) -> Page {
+        self.entries.retain(|e| e.tag != tag);
+        self.entries.push(PageEntry { tag, value, count: None, offset: None });
+        self
+    }
+
+    // Writes `count` into the entry instead of the number of values it carries.
+    pub fn count(mut self, tag: u16, count: u32) -> Page {
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.tag == tag) {
+            entry.count = Some(count);
+        }
+        self
+    }
+
+    // Writes `offset` into the entry instead of where its values actually went.
+    pub fn offset(mut self, tag: u16, offset: u32) -> Page {
+        if let Some(entry) = self.entries.iter_mut().find(|e| e.tag == tag) {
+            entry.offset = Some(offset);
+        }
+        self
+    }
+
+    // Strip payloads, laid out before the IFD. StripOffsets and StripByteCounts are
+    // filled in unless the page sets them itself.
+    pub fn strips(mut self, strips: Vec<Vec<u8>>) -> Page {
+        self.strips = Some(strips);
+        self
+    }
+
+    // Same as `strips`, for TileOffsets and TileByteCounts.
+    pub fn tiles(mut self, tiles: Vec<Vec<u8>>) -> Page {
+        self.tiles = Some(tiles);
+        self
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TiffBuilder {
+    endian: Endian,
+    big_tiff: bool,
+    pages: Vec<Page>,
+    first_ifd: Option<u32>,
+    cycle: Option<(usize, usize)>,
+    truncate: Option<usize>,
+}
+
+impl TiffBuilder {
+    pub fn new(endian: Endian) -> TiffBuilder {
+        TiffBuilder {
+            endian,
+            big_tiff: false,
+            pages: vec![],
+            first_ifd: None,
+            cycle: None,
+            truncate: None,
+        }
+    }
+
+    // Writes a BigTIFF: 16-byte header, 8-byte counts and offsets in the IFDs.
+    pub fn big_tiff(mut self) -> TiffBuilder {
+        self.big_tiff = true;
+        self
+    }
+
+    pub fn page(mut self, page: Page) -> TiffBuilder {
+        self.pages.push(page);
+        self
+    }
+
+    // Header points here instead of at the first page.
+    pub fn first_ifd_offset(mut self, offset: u32) -> TiffBuilder {
+        self.first_ifd = Some(offset);
+        self
+    }
+
+    // The next-IFD pointer of page `from` points back at page `to`.
+    pub fn cycle(mut self, from: usize, to: usize) -> TiffBuilder {
+        self.cycle = Some((from, to));
+        self
+    }
+
+    // Cuts the finished file to `len` bytes.
+    pub fn truncate(mut self, len: usize) -> TiffBuilder {
+        self.truncate = Some(len);
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let endian = self.endian;
+        let mut out = match endian {
+            Endian::Big => b"MM".to_vec(),
+            Endian::Little => b"II".to_vec(),
+        };
+        let big = self.big_tiff;
+        let field_len = if big { 8 } else { 4 };
+        if big {
+            push_u16(&mut out, 43, endian);
+            push_u16(&mut out, 8, endian);
+            push_u16(&mut out, 0, endian);
+            push_u64(&mut out, 0, endian);
+        } else {
+            push_u16(&mut out, 42, endian);
+            push_u32(&mut out, 0, endian);
+        }
+
+        let mut ifds = vec![];
+        let mut next_pointers = vec![];
+        for page in &self.pages {
+            let mut entries = page.entries.clone();
+            for &(payloads, offsets_tag, counts_tag) in &[(&page.strips, 273, 279), (&page.tiles, 324, 325)] {
+                let payloads = match *payloads {
+                    Some(ref payloads) => payloads,
+                    None => continue,
+                };
+                let mut offsets = vec![];
+                let mut counts = vec![];
+                for payload in payloads {
+                    align(&mut out);
+                    offsets.push(out.len() as u32);
+                    counts.push(payload.len() as u32);
+                    out.extend_from_slice(payload);
+                }
+                if !entries.iter().any(|e| e.tag == offsets_tag) {
+                    entries.push(PageEntry { tag: offsets_tag, value: Value::Long(offsets), count: None, offset: None });
+                }
+                if !entries.iter().any(|e| e.tag == counts_tag) {
+                    entries.push(PageEntry { tag: counts_tag, value: Value::Long(counts), count: None, offset: None });
+                }
+            }
+            entries.sort_by_key(|e| e.tag);
+
+            let mut encoded = vec![];
+            for entry in &entries {
+                let (datatype, count, bytes) = entry.value.encode(endian);
+                let field = if bytes.len() > field_len {
+                    align(&mut out);
+                    let mut field = vec![];
+                    push_offset(&mut field, out.len() as u64, endian, big);
+                    out.extend_from_slice(&bytes);
+                    field
+                } else {
+                    let mut field = bytes;
+                    field.resize(field_len, 0);
+                    field
+                };
+                let field = match entry.offset {
+                    Some(offset) => {
+                        let mut field = vec![];
+                        push_offset(&mut field, offset as u64, endian, big);
+                        field
+                    }
+                    None => field,
+                };
+                encoded.push((entry.tag, datatype, entry.count.unwrap_or(count), field));
+            }
+
+            align(&mut out);
+            ifds.push(out.len() as u32);
+            if big {
+                push_u64(&mut out, encoded.len() as u64, endian);
+            } else {
+                push_u16(&mut out, encoded.len() as u16, endian);
+            }
+            for (tag, datatype, count, field) in encoded {
+                push_u16(&mut out, tag, endian);
+                push_u16(&mut out, datatype, endian);
+                push_offset(&mut out, count as u64, endian, big);
+                out.extend_from_slice(&field);
+            }
+            next_pointers.push(out.len());
+            push_offset(&mut out, 0, endian, big);
+        }
+
+        let first = self.first_ifd.unwrap_or_else(|| ifds.first().cloned().unwrap_or(0));
+        put_offset(&mut out, if big { 8 } else { 4 }, first, endian, big);
+        for (page, &pointer) in next_pointers.iter().enumerate() {
+            let next = match self.cycle {
+                Some((from, to)) if from == page => ifds[to],
+                _ => ifds.get(page + 1).cloned().unwrap_or(0),
+            };
+            put_offset(&mut out, pointer, next, endian, big);
+        }
+        if let Some(len) = self.truncate {
+            out.truncate(len);
+        }
+
+        out
+    }
+}
+
+fn align(out: &mut Vec<u8>) {
+    if out.len() % 2 == 1 {
+        out.push(0);
+    }
+}
+
+fn push_u16(out: &mut Vec<u8>, n: u16, endian: Endian) {
+    match endian {
+        Endian::Big => out.extend_from_slice(&n.to_be_bytes()),
+        Endian::Little => out.extend_from_slice(&n.to_le_bytes()),
+    }
+}
+
+fn push_u32(out: &mut Vec<u8>, n: u32, endian: Endian) {
+    match endian {
+        Endian::Big => out.extend_from_slice(&n.to_be_bytes()),
+        Endian::Little => out.extend_from_slice(&n.to_le_bytes()),
+    }
+}
+
+fn push_u64(out: &mut Vec<u8>, n: u64, endian: Endian) {
+    match endian {
+        Endian::Big => out.extend_from_slice(&n.to_be_bytes()),
+        Endian::Little => out.extend_from_slice(&n.to_le_bytes()),
+    }
+}
+
+// A count or offset field: LONG in TIFF, LONG8 in BigTIFF.
+fn push_offset(out: &mut Vec<u8>, n: u64, endian: Endian, big: bool) {
+    if big {
+        push_u64(out, n, endian);
+    } else {
+        push_u32(out, n as u32, endian);
+    }
+}
+
+fn put_offset(out: &mut [u8], at: usize, n: u32, endian: Endian, big: bool) {
+    let mut bytes = vec![];
+    push_offset(&mut bytes, n as u64, endian, big);
+    out[at..at + bytes.len()].copy_from_slice(&bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decode::Decoder;
+    use error::DecodeErrorKind;
+    use image::ImageData;
+    use std::io::Cursor;
+
+    fn u16_at(file: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([file[at], file[at + 1]])
+    }
+
+    fn u32_at(file: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([file[at], file[at + 1], file[at + 2], file[at + 3]])
+    }
+
+    // (tag, datatype, count, field) of every entry in the little-endian classic IFD at `at`.
+    fn entries(file: &[u8], at: usize) -> Vec<(u16, u16, u32, u32)> {
+        (0..u16_at(file, at) as usize)
+            .map(|i| at + 2 + i * 12)
+            .map(|e| (u16_at(file, e), u16_at(file, e + 2), u32_at(file, e + 4), u32_at(file, e + 8)))
+            .collect()
+    }
+
+    #[test]
+    fn headers() {
+        let page = || Page::gray8(1, 1, vec![0]);
+        let file = TiffBuilder::new(Endian::Little).page(page()).build();
+        assert_eq!(&file[..4], b"II\x2a\x00");
+        let file = TiffBuilder::new(Endian::Big).page(page()).build();
+        assert_eq!(&file[..4], b"MM\x00\x2a");
+        let file = TiffBuilder::new(Endian::Little).big_tiff().page(page()).build();
+        assert_eq!(&file[..8], b"II\x2b\x00\x08\x00\x00\x00");
+        let file = TiffBuilder::new(Endian::Big).big_tiff().page(page()).build();
+        assert_eq!(&file[..8], b"MM\x00\x2b\x00\x08\x00\x00");
+    }
+
+    #[test]
+    fn entries_are_sorted_with_strips_filled_in() {
+        let page = Page::gray8(3, 2, vec![7; 6]).tag(270, Value::Ascii("a longer description".to_string()));
+        let file = TiffBuilder::new(Endian::Little).page(page).build();
+        let ifd = u32_at(&file, 4) as usize;
+        assert_eq!(ifd % 2, 0);
+
+        let entries = entries(&file, ifd);
+        let tags = entries.iter().map(|e| e.0).collect::<Vec<_>>();
+        assert_eq!(tags, vec![256, 257, 258, 259, 262, 270, 273, 278, 279]);
+        let field = |tag| entries.iter().find(|e| e.0 == tag).cloned().unwrap();
+
+        // inline values sit in the field itself
+        assert_eq!(field(256), (256, 4, 1, 3));
+        assert_eq!(field(258), (258, 3, 1, 8));
+
+        // longer ones are written elsewhere and pointed at
+        let (_, datatype, count, offset) = field(270);
+        assert_eq!((datatype, count), (2, 21));
+        assert_eq!(&file[offset as usize..offset as usize + 21], b"a longer description\0");
+
+        let strip = field(273).3 as usize;
+        assert_eq!(field(279).3, 6);
+        assert_eq!(&file[strip..strip + 6], &[7; 6]);
+    }
+
+    #[test]
+    fn every_layout_decodes() {
+        for &endian in &[Endian::Little, Endian::Big] {
+            for &big in &[false, true] {
+                let mut builder = TiffBuilder::new(endian)
+                    .page(Page::gray8(2, 2, vec![1, 2, 3, 4]))
+                    .page(Page::gray8(3, 1, vec![5, 6, 7]));
+                if big {
+                    builder = builder.big_tiff();
+                }
+
+                let mut decoder = Decoder::new(Cursor::new(builder.build())).unwrap();
+                assert_eq!(decoder.ifd_count().unwrap(), 2);
+                assert_eq!(decoder.image().unwrap().data(), &ImageData::U8(vec![1, 2, 3, 4]));
+                decoder.change_ifd(1).unwrap();
+                assert_eq!(decoder.image().unwrap().data(), &ImageData::U8(vec![5, 6, 7]));
+            }
+        }
+    }
+
+    #[test]
+    fn malformations() {
+        let page = || Page::gray8(2, 1, vec![0; 2]);
+
+        let file = TiffBuilder::new(Endian::Little)
+            .page(page().tag(270, Value::Ascii("elsewhere".to_string())).count(258, 3).offset(270, 9999))
+            .build();
+        let entries = entries(&file, u32_at(&file, 4) as usize);
+        assert!(entries.contains(&(258, 3, 3, 8)));
+        assert!(entries.iter().any(|&(tag, _, _, field)| tag == 270 && field == 9999));
+
+        let file = TiffBuilder::new(Endian::Little).page(page()).first_ifd_offset(0).build();
+        assert_eq!(u32_at(&file, 4), 0);
+
+        let full = TiffBuilder::new(Endian::Little).page(page()).build();
+        let file = TiffBuilder::new(Endian::Little).page(page()).truncate(20).build();
+        assert_eq!(file, &full[..20]);
+
+        let file = TiffBuilder::new(Endian::Little).page(page()).page(page()).cycle(1, 0).build();
+        let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+        let error = decoder.ifd_offsets().unwrap_err();
+        match *error.kind() {
+            DecodeErrorKind::IFDCycle { .. } => {}
+            ref kind => panic!("{:?}", kind),
+        }
+    }
+}
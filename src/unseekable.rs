@@ -0,0 +1,162 @@
+use std::io::{
+    self,
+    Seek,
+    SeekFrom,
+    Write,
+};
+
+// Lets the encoder write to a plain `io::Write`, like a socket or stdout, see
+// `EncoderBuilder::build_unseekable`. Bytes the encoder may still go back to are
+// held here in memory and handed on once it's done with them.
+#[derive(Debug)]
+pub struct Unseekable<W> {
+    writer: W,
+    // where `held` starts in the file
+    start: u64,
+    held: Vec<u8>,
+    position: u64,
+}
+
+impl<W: Write> Unseekable<W> {
+    pub fn new(writer: W) -> Unseekable<W> {
+        Unseekable {
+            writer,
+            start: 0,
+            held: vec![],
+            position: 0,
+        }
+    }
+
+    // Hands the held bytes before `position` on to the writer. There's no going
+    // back to them afterwards.
+    pub fn release(&mut self, position: u64) -> io::Result<()> {
+        let len = (position.saturating_sub(self.start) as usize).min(self.held.len());
+        self.writer.write_all(&self.held[..len])?;
+        self.held.drain(..len);
+        self.start += len as u64;
+
+        Ok(())
+    }
+
+    // Bytes held back from the writer.
+    pub fn held(&self) -> usize {
+        self.held.len()
+    }
+
+    // The writer, once whatever is still held is written to it.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for Unseekable<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let at = (self.position - self.start) as usize;
+        let overlap = buf.len().min(self.held.len() - at);
+        self.held[at..at + overlap].copy_from_slice(&buf[..overlap]);
+        self.held.extend_from_slice(&buf[overlap..]);
+        self.position += buf.len() as u64;
+
+        Ok(buf.len())
+    }
+
+    // Hands on everything held; the file is meant to be complete.
+    fn flush(&mut self) -> io::Result<()> {
+        let end = self.start + self.held.len() as u64;
+        self.release(end)?;
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> Seek for Unseekable<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let end = self.start + self.held.len() as u64;
+        let position = match pos {
+            SeekFrom::Start(x) => Some(x),
+            SeekFrom::End(x) => end.checked_add_signed(x),
+            SeekFrom::Current(x) => self.position.checked_add_signed(x),
+        };
+        match position {
+            Some(position) if position >= self.start && position <= end => {
+                self.position = position;
+                Ok(position)
+            }
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "can't seek outside the bytes held back from the writer")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decode::Decoder;
+    use encode::EncoderBuilder;
+    use image::{
+        BitsPerSample,
+        Compression,
+        Image,
+        ImageData,
+        ImageHeader,
+        PhotometricInterpretation,
+    };
+    use std::io::Cursor;
+
+    // a writer that can't seek
+    #[derive(Debug)]
+    struct WriteOnly(Vec<u8>);
+
+    impl Write for WriteOnly {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn gray(width: u32, height: u32, seed: u8) -> Image {
+        let header = ImageHeader::new(width, height, Compression::No, PhotometricInterpretation::BlackIsZero, BitsPerSample::new([8]).unwrap(), vec![]).unwrap();
+        Image::new(header, ImageData::U8((0..width * height).map(|i| (i as u8).wrapping_mul(13).wrapping_add(seed)).collect()))
+    }
+
+    #[test]
+    fn unseekable_output_decodes() {
+        let builder = || EncoderBuilder::new().rows_per_strip(5);
+        let pages = [gray(20, 17, 0), gray(9, 30, 1), gray(33, 3, 2)];
+
+        let mut encoder = builder().build_unseekable(WriteOnly(vec![])).unwrap();
+        encoder.append_image(&pages[0]).unwrap();
+        encoder.append_sub_image(&gray(10, 9, 3)).unwrap();
+        encoder.append_image(&pages[1]).unwrap();
+        encoder.begin_image(pages[2].header().clone()).unwrap();
+        if let ImageData::U8(ref data) = *pages[2].data() {
+            data.chunks(40).for_each(|x| encoder.write_rows(x).unwrap());
+        }
+        let file = encoder.finish().unwrap().into_inner().unwrap().0;
+
+        let mut seekable = builder().build(Cursor::new(vec![])).unwrap();
+        seekable.append_image(&pages[0]).unwrap();
+        seekable.append_sub_image(&gray(10, 9, 3)).unwrap();
+        seekable.append_image(&pages[1]).unwrap();
+        seekable.append_image(&pages[2]).unwrap();
+        assert_eq!(file, seekable.finish().unwrap().into_inner());
+
+        let mut decoder = Decoder::new(Cursor::new(file)).unwrap();
+        let images = decoder.images().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(images.len(), 3);
+        for (image, page) in images.iter().zip(&pages) {
+            assert_eq!(image.data(), page.data());
+        }
+    }
+
+    #[test]
+    fn into_inner_writes_what_is_held() {
+        let mut unseekable = Unseekable::new(WriteOnly(vec![]));
+        unseekable.write_all(b"held back").unwrap();
+        unseekable.release(4).unwrap();
+        assert_eq!(unseekable.held(), 5);
+        assert_eq!(unseekable.into_inner().unwrap().0, b"held back");
+    }
+}
@@ -0,0 +1,283 @@
+// failure's derive puts its impls inside a const item next to the type.
+#![allow(non_local_definitions)]
+
+use std::fmt::{
+    self,
+    Display,
+};
+use std::io::{
+    Read,
+    Seek,
+};
+
+use decode::{
+    Decoder,
+    DecoderBuilder,
+};
+use error::{
+    ChunkProblem,
+    DecodeWarning,
+};
+use ifd::IFD;
+use tag::{
+    self,
+    AnyTag,
+};
+
+// Errors break a requirement of baseline TIFF 6.0. Warnings flag what the spec
+// allows as an extension but a baseline reader needn't support, such as
+// compressions other than PackBits and CCITT RLE.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq, Fail)]
+pub enum ViolationKind {
+    #[fail(display = "The file can't be read: {}", reason)]
+    Unreadable { reason: String },
+
+    #[fail(display = "Required tag ({}) is missing", tag)]
+    MissingTag { tag: AnyTag },
+
+    #[fail(display = "The IFD at {} doesn't start on a word boundary", offset)]
+    OddIFDOffset { offset: u64 },
+
+    #[fail(display = "The values of tag ({}) at {} don't start on a word boundary", tag, offset)]
+    OddValueOffset { tag: AnyTag, offset: u64 },
+
+    #[fail(display = "Tag ({}) has the value {}, which is not baseline", tag, value)]
+    NotBaseline { tag: AnyTag, value: u32 },
+
+    #[fail(display = "Tag ({}) is implausible: {}", tag, reason)]
+    Implausible { tag: AnyTag, reason: String },
+
+    #[fail(display = "The image can't be decoded: {}", reason)]
+    Undecodable { reason: String },
+
+    #[fail(display = "{}", _0)]
+    Chunk(ChunkProblem),
+
+    // a defect the decoder had to work around
+    #[fail(display = "{}", _0)]
+    Defect(DecodeWarning),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    severity: Severity,
+    ifd: Option<u64>,
+    kind: ViolationKind,
+}
+
+impl Violation {
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
+    // Offset of the IFD it was found in, `None` for the file as a whole.
+    pub fn ifd(&self) -> Option<u64> {
+        self.ifd
+    }
+
+    pub fn kind(&self) -> &ViolationKind {
+        &self.kind
+    }
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match self.ifd {
+            Some(ifd) => write!(f, "{} (IFD at {}): {}", severity, ifd, self.kind),
+            None => write!(f, "{}: {}", severity, self.kind),
+        }
+    }
+}
+
+// The result of `validate`, one line per violation when displayed.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Report {
+    violations: Vec<Violation>,
+}
+
+impl Report {
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    pub fn errors(&self) -> Vec<&Violation> {
+        self.violations.iter().filter(|x| x.severity == Severity::Error).collect()
+    }
+
+    pub fn warnings(&self) -> Vec<&Violation> {
+        self.violations.iter().filter(|x| x.severity == Severity::Warning).collect()
+    }
+
+    // Whether the file meets the baseline, warnings aside.
+    pub fn is_valid(&self) -> bool {
+        self.violations.iter().all(|x| x.severity != Severity::Error)
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for violation in &self.violations {
+            writeln!(f, "{}", violation)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Checks every IFD in the chain against baseline TIFF 6.0: the tags each image
+// requires, word alignment of IFDs and values, baseline values of Compression,
+// PhotometricInterpretation and BitsPerSample, and the strips or tiles. A file
+// that can't be parsed at all gets a single `Unreadable` error.
+pub fn validate<R: Read + Seek>(reader: R) -> Report {
+    let mut decoder = match DecoderBuilder::new().lenient(true).build(reader) {
+        Ok(decoder) => decoder,
+        Err(e) => return Report { violations: vec![unreadable(None, e)] },
+    };
+    let mut violations = vec![];
+    let offsets = match decoder.ifd_offsets() {
+        Ok(offsets) => offsets,
+        Err(e) => {
+            violations.push(unreadable(None, e));
+            vec![]
+        }
+    };
+    for warning in decoder.take_warnings() {
+        violations.push(Violation { severity: Severity::Error, ifd: None, kind: ViolationKind::Defect(warning) });
+    }
+
+    for offset in offsets {
+        let ifd = match decoder.load_ifd_at(offset).and_then(|()| decoder.ifd()) {
+            Ok(ifd) => ifd,
+            Err(e) => {
+                violations.push(unreadable(Some(offset), e));
+                continue;
+            }
+        };
+        let mut found = vec![];
+        if offset % 2 != 0 {
+            found.push((Severity::Error, ViolationKind::OddIFDOffset { offset }));
+        }
+        check_value_offsets(&mut decoder, &ifd, &mut found);
+        check_required(&ifd, &mut found);
+        check_values(&mut decoder, &ifd, &mut found);
+        match decoder.check_chunks_with(&ifd) {
+            Ok(problems) => found.extend(problems.into_iter().map(|x| (Severity::Error, ViolationKind::Chunk(x)))),
+            Err(e) => found.push((Severity::Error, ViolationKind::Undecodable { reason: e.to_string() })),
+        }
+        if let Err(e) = decoder.header_with(&ifd) {
+            found.push((Severity::Error, ViolationKind::Undecodable { reason: e.to_string() }));
+        }
+        found.extend(decoder.take_warnings().into_iter().map(|x| (Severity::Error, ViolationKind::Defect(x))));
+
+        violations.extend(found.into_iter().map(|(severity, kind)| Violation { severity, ifd: Some(offset), kind }));
+    }
+
+    Report { violations }
+}
+
+fn unreadable<E: Display>(ifd: Option<u64>, e: E) -> Violation {
+    Violation { severity: Severity::Error, ifd, kind: ViolationKind::Unreadable { reason: e.to_string() } }
+}
+
+fn check_value_offsets<R: Read + Seek>(decoder: &mut Decoder<R>, ifd: &IFD, found: &mut Vec<(Severity, ViolationKind)>) {
+    for (tag, entry) in ifd.iter() {
+        if !entry.overflow() {
+            continue;
+        }
+        match entry.value_offset(decoder.endian()) {
+            Ok(offset) if offset % 2 != 0 => found.push((Severity::Error, ViolationKind::OddValueOffset { tag, offset })),
+            _ => {},
+        }
+    }
+}
+
+// BitsPerSample, Compression, RowsPerStrip and ResolutionUnit have defaults; the
+// byte counts are left to `Decoder::check_chunks`.
+fn check_required(ifd: &IFD, found: &mut Vec<(Severity, ViolationKind)>) {
+    let mut required = vec![
+        (ifd.get(tag::ImageWidth).is_some(), AnyTag::ImageWidth),
+        (ifd.get(tag::ImageLength).is_some(), AnyTag::ImageLength),
+        (ifd.get(tag::PhotometricInterpretation).is_some(), AnyTag::PhotometricInterpretation),
+        (ifd.get(tag::XResolution).is_some(), AnyTag::XResolution),
+        (ifd.get(tag::YResolution).is_some(), AnyTag::YResolution),
+    ];
+    if ifd.get(tag::TileWidth).is_some() {
+        required.push((ifd.get(tag::TileLength).is_some(), AnyTag::TileLength));
+        required.push((ifd.get(tag::TileOffsets).is_some(), AnyTag::TileOffsets));
+    } else {
+        required.push((ifd.get(tag::StripOffsets).is_some(), AnyTag::StripOffsets));
+    }
+    found.extend(required.into_iter().filter(|x| !x.0).map(|(_, tag)| (Severity::Error, ViolationKind::MissingTag { tag })));
+}
+
+fn check_values<R: Read + Seek>(decoder: &mut Decoder<R>, ifd: &IFD, found: &mut Vec<(Severity, ViolationKind)>) {
+    let implausible = |tag: AnyTag, reason: String| (Severity::Error, ViolationKind::Implausible { tag, reason });
+    let not_baseline = |tag: AnyTag, value: u32| (Severity::Warning, ViolationKind::NotBaseline { tag, value });
+
+    for (tag, value) in [(AnyTag::ImageWidth, decoder.get_value(ifd, tag::ImageWidth)), (AnyTag::ImageLength, decoder.get_value(ifd, tag::ImageLength))] {
+        if let Ok(0) = value {
+            found.push(implausible(tag, "it is zero".to_string()));
+        }
+    }
+    if let Ok(compression) = decoder.get_value(ifd, tag::Compression) {
+        if ![1, 2, 32773].contains(&compression) {
+            found.push(not_baseline(AnyTag::Compression, compression as u32));
+        }
+    }
+
+    let bits = decoder.get_value(ifd, tag::BitsPerSample).unwrap_or_default();
+    if ifd.get(tag::SamplesPerPixel).is_some() && ifd.get(tag::BitsPerSample).is_some() {
+        if let Ok(samples) = decoder.get_value(ifd, tag::SamplesPerPixel) {
+            if bits.len() != samples as usize {
+                found.push(implausible(AnyTag::BitsPerSample, format!("{} values for {} samples per pixel", bits.len(), samples)));
+            }
+        }
+    }
+    // the bit depths a baseline reader supports for each PhotometricInterpretation
+    let allowed: &[u16] = match decoder.get_value(ifd, tag::PhotometricInterpretation) {
+        Ok(0) | Ok(1) => &[1, 4, 8],
+        Ok(2) => {
+            if ifd.get(tag::SamplesPerPixel).is_none() {
+                found.push((Severity::Error, ViolationKind::MissingTag { tag: AnyTag::SamplesPerPixel }));
+            }
+            &[8]
+        }
+        Ok(3) => {
+            if ifd.get(tag::ColorMap).is_none() {
+                found.push((Severity::Error, ViolationKind::MissingTag { tag: AnyTag::ColorMap }));
+            }
+            &[4, 8]
+        }
+        Ok(4) => &[1],
+        Ok(photometric) => {
+            found.push(not_baseline(AnyTag::PhotometricInterpretation, photometric as u32));
+            &[]
+        }
+        Err(_) => &[],
+    };
+    if let Some(&bits) = bits.iter().find(|x| !allowed.is_empty() && !allowed.contains(x)) {
+        found.push(not_baseline(AnyTag::BitsPerSample, bits as u32));
+    }
+
+    for (tag, value) in [(AnyTag::XResolution, decoder.get_value(ifd, tag::XResolution)), (AnyTag::YResolution, decoder.get_value(ifd, tag::YResolution))] {
+        match value.map(|x| x.to_f64()) {
+            Ok(Some(x)) if x > 0. => {},
+            Ok(_) => found.push(implausible(tag, "it is not positive".to_string())),
+            Err(_) => {},
+        }
+    }
+    match decoder.get_value(ifd, tag::ResolutionUnit) {
+        Ok(1..=3) | Err(_) => {},
+        Ok(unit) => found.push(implausible(AnyTag::ResolutionUnit, format!("{} is not a unit", unit))),
+    }
+}
@@ -0,0 +1,102 @@
+// Runs the tiffdump binary; needs `--features cli,testutil`.
+#![cfg(all(feature = "cli", feature = "testutil"))]
+
+extern crate rustiff;
+
+use rustiff::testutil::{
+    Page,
+    TiffBuilder,
+    Value,
+};
+use rustiff::Endian;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process::{
+    Command,
+    Output,
+};
+
+fn tiffdump(name: &str, file: &[u8], args: &[&str]) -> Output {
+    let path = env::temp_dir().join(format!("tiffdump-{}-{}.tif", name, std::process::id()));
+    fs::write(&path, file).unwrap();
+    let output = run(&path, args);
+    fs::remove_file(&path).unwrap();
+    output
+}
+
+fn run(path: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_tiffdump")).arg(path).args(args).output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+// XResolution and YResolution, which baseline TIFF requires.
+fn resolution(page: Page) -> Page {
+    page.tag(282, Value::Rational(vec![(72, 1)])).tag(283, Value::Rational(vec![(72, 1)]))
+}
+
+#[test]
+fn valid_file_exits_with_0() {
+    let file = TiffBuilder::new(Endian::Big)
+        .page(resolution(Page::gray8(2, 2, vec![0; 4])).tag(270, Value::Ascii("a test page".to_string())))
+        .build();
+    let output = tiffdump("valid", &file, &["--strips"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stdout(&output));
+
+    let stdout = stdout(&output);
+    assert!(stdout.starts_with("TIFF, Big endian, first IFD at"), "{}", stdout);
+    assert!(stdout.contains("IFD 0 at"), "{}", stdout);
+    assert!(stdout.contains("ImageDescription (270) Ascii[12]: \"a test page\""), "{}", stdout);
+    assert!(stdout.contains("IFD 0 strips:\n    strip     offset      bytes\n        0 "), "{}", stdout);
+    assert!(!stdout.contains("warning") && !stdout.contains("error"), "{}", stdout);
+}
+
+#[test]
+fn tiles_are_listed_with_their_size() {
+    let page = Page::new()
+        .tag(256, Value::Long(vec![32]))
+        .tag(257, Value::Long(vec![20]))
+        .tag(258, Value::Short(vec![8]))
+        .tag(262, Value::Short(vec![1]))
+        .tag(322, Value::Long(vec![16]))
+        .tag(323, Value::Long(vec![16]))
+        .tiles(vec![vec![0; 256]; 4]);
+    let file = TiffBuilder::new(Endian::Little).page(resolution(page)).build();
+    let output = tiffdump("tiles", &file, &["--strips"]);
+    assert_eq!(output.status.code(), Some(0), "{}", stdout(&output));
+
+    let stdout = stdout(&output);
+    assert!(stdout.contains("IFD 0 tiles, 16x16:\n     tile     offset      bytes\n"), "{}", stdout);
+    for tile in 0..4 {
+        assert!(stdout.contains(&format!("\n  {:>7} ", tile)), "{}", stdout);
+    }
+    assert!(!stdout.contains("strip "), "{}", stdout);
+}
+
+#[test]
+fn validator_warnings_exit_with_1() {
+    // 16-bit gray decodes, but isn't baseline
+    let page = resolution(Page::gray8(2, 2, vec![0; 8])).tag(258, Value::Short(vec![16]));
+    let file = TiffBuilder::new(Endian::Little).page(page).build();
+    let output = tiffdump("warning", &file, &[]);
+    assert_eq!(output.status.code(), Some(1), "{}", stdout(&output));
+    assert!(stdout(&output).contains("warning (IFD at 32): Tag (BitsPerSample) has the value 16, which is not baseline"), "{}", stdout(&output));
+}
+
+#[test]
+fn validator_errors_exit_with_2() {
+    let file = TiffBuilder::new(Endian::Little).page(Page::gray8(2, 2, vec![0; 4])).build();
+    let output = tiffdump("missing", &file, &[]);
+    assert_eq!(output.status.code(), Some(2), "{}", stdout(&output));
+    assert!(stdout(&output).contains("error (IFD at 12): Required tag (XResolution) is missing"), "{}", stdout(&output));
+
+    let output = tiffdump("garbage", b"not a tiff", &[]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(!output.stderr.is_empty());
+
+    let output = run(&env::temp_dir().join("tiffdump-does-not-exist.tif"), &[]);
+    assert_eq!(output.status.code(), Some(2));
+}